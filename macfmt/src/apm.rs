@@ -1,10 +1,36 @@
 use std::fmt;
+use std::io;
 
-use binrw::io::{Read, Seek, SeekFrom};
-use binrw::{BinRead, BinResult, NullString, binread};
+use binrw::io::{Cursor, Read, Seek, SeekFrom, Write};
+use binrw::{BinRead, BinResult, BinWrite, NullString, binread};
 use derivative::Derivative;
+use thiserror::Error;
 
-use crate::fs::hfs::Hfs;
+use crate::fs::hfs::{FormatOptions, Hfs, HfsError, HfsVolume};
+
+// New disks get the partition map in blocks 1 to 63 like Apple's own formatters lay it out,
+// with 512-byte blocks.
+const BLOCK_SIZE: u32 = 512;
+const MAP_BLOCKS: u32 = 63;
+// pmPartStatus as Apple's formatters set it for the map and for data partitions
+const MAP_STATUS: u32 = 0x37;
+const PARTITION_STATUS: u32 = 0xb7;
+
+#[derive(Error, Debug)]
+pub enum ApmError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse or write a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("Failed to format a partition: {0}")]
+    Hfs(#[from] HfsError),
+    #[error("{0:?} is not a valid partition name")]
+    InvalidName(String),
+    #[error("The partitions need {0} blocks but the disk only has {1}")]
+    TooBig(u64, u32),
+    #[error("{0} partitions don't fit in the partition map")]
+    TooManyPartitions(usize),
+}
 
 pub struct ApmDrive<'a, R: Read + Seek> {
     table: ApmTable,
@@ -19,25 +45,146 @@ impl<'a, R: Read + Seek> ApmDrive<'a, R> {
         let table = ApmTable::read(reader)?;
         Ok(Self { table, reader })
     }
+    pub fn map_kind(&self) -> MapKind {
+        self.table.map.0
+    }
     pub fn partitions(&self) -> &[Partition] {
-        &self.table.partitions
+        &self.table.map.1
     }
     pub fn drivers(&self) -> &[Driver] {
         &self.table.driver_descriptor.drivers
     }
-    pub fn partition_hfs(&mut self, _p: &Partition) -> BinResult<Hfs> {
-        todo!()
+    // The byte offset and length of a partition's data area. Partitions that don't say how big
+    // their data is use everything after data_start.
+    pub fn partition_bounds(&self, p: &Partition) -> (u64, u64) {
+        let block_size = self.block_size() as u64;
+        let data_blocks = match p.data_size {
+            0 => p.size.saturating_sub(p.data_start),
+            n => n,
+        };
+        ((p.start as u64 + p.data_start as u64) * block_size, data_blocks as u64 * block_size)
+    }
+    pub fn partition_reader(&mut self, p: &Partition) -> PartitionReader<&mut R> {
+        let (offset, len) = self.partition_bounds(p);
+        PartitionReader::new(&mut *self.reader, offset, len)
+    }
+    pub fn partition_hfs(&mut self, p: &Partition) -> BinResult<Hfs> {
+        Hfs::read(&mut self.partition_reader(p))
+    }
+    // The first driver partition along with its driver descriptor, to install the same driver
+    // on a new disk.
+    pub fn driver_image(&mut self) -> BinResult<Option<DriverImage>> {
+        let block_size = self.block_size() as u64;
+        let Some(entry) = self.partitions().iter().find(|p| {
+            matches!(p.kind, PartitionType::AppleDriver43 | PartitionType::AppleDriver)
+        }) else {
+            return Ok(None);
+        };
+        // driver descriptors always count 512-byte blocks
+        let Some(driver) = self.drivers().iter().find(|d| d.start as u64 * 512 == entry.start as u64 * block_size) else {
+            return Ok(None);
+        };
+        let (entry, os_type, blocks) = (entry.clone(), driver.os_type, driver.size);
+
+        let mut code = vec![0; (entry.size as u64 * block_size) as usize];
+        self.reader.seek(SeekFrom::Start(entry.start as u64 * block_size))?;
+        self.reader.read_exact(&mut code)?;
+
+        Ok(Some(DriverImage {
+            entry,
+            os_type,
+            blocks,
+            code,
+        }))
     }
     pub fn read_partition_data(&mut self, p: &Partition) -> BinResult<Vec<u8>> {
-        let read_start = (p.start + p.data_start) * self.block_size() as u32;
-        let read_size = p.data_size as usize;
-        let mut buf: Vec<u8> = vec![0; read_size];
-        self.reader.seek(SeekFrom::Start(read_start as u64))?;
-        self.reader.read_exact(&mut buf)?;
+        let mut buf = Vec::new();
+        self.partition_reader(p).read_to_end(&mut buf)?;
         Ok(buf)
     }
 }
 
+// A window over one partition, so that file systems can be opened on it like on a disk of
+// their own. Reads stop at the end of the partition and writes past it fail.
+#[derive(Debug)]
+pub struct PartitionReader<R> {
+    inner: R,
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R> PartitionReader<R> {
+    pub fn new(inner: R, offset: u64, len: u64) -> Self {
+        Self {
+            inner,
+            offset,
+            len,
+            pos: 0,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+    fn remaining(&self, want: usize) -> usize {
+        self.len.saturating_sub(self.pos).min(want as u64) as usize
+    }
+}
+
+impl<R: Read + Seek> Read for PartitionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.remaining(buf.len());
+        if max == 0 {
+            return Ok(0);
+        }
+        self.inner.seek(SeekFrom::Start(self.offset + self.pos))?;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Write + Seek> Write for PartitionReader<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let max = self.remaining(buf.len());
+        if max == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "write past the end of the partition"));
+        }
+        self.inner.seek(SeekFrom::Start(self.offset + self.pos))?;
+        let n = self.inner.write(&buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R> Seek for PartitionReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.len.checked_add_signed(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        let Some(new_pos) = new_pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+        self.pos = new_pos;
+
+        Ok(self.pos)
+    }
+}
+
 #[binread]
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -45,25 +192,97 @@ impl<'a, R: Read + Seek> ApmDrive<'a, R> {
 pub struct ApmTable {
     #[br(pad_size_to = 512)]
     driver_descriptor: Block0,
-    #[br(temp, restore_position)]
-    first_partition: Partition,
-    #[br(count = first_partition.partition_count)]
-    partitions: Vec<Partition>,
-    #[br(count = apm_pad_size(&partitions))]
-    #[derivative(Debug = "ignore")]
-    _pad: Vec<[u8; 512]>,
+    #[br(parse_with = partition_map_parser, args(driver_descriptor.block_size))]
+    map: (MapKind, Vec<Partition>),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MapKind {
+    // one "PM" entry per block, starting at block 1
+    Apple,
+    // the "TS" map of SCSI disks from before the Mac Plus shipped, all in one sector
+    OldStyle,
+}
+
+// The map starts at the second block, which CD-ROMs make 2048 bytes into the disk.
+#[binrw::parser(reader, endian)]
+fn partition_map_parser(block_size: u16) -> BinResult<(MapKind, Vec<Partition>)> {
+    let block_size = block_size.max(512) as u64;
+    let map_start = reader.seek(SeekFrom::Start(block_size))?;
+    let mut sig = [0u8; 2];
+    reader.read_exact(&mut sig)?;
+    reader.seek(SeekFrom::Start(map_start))?;
+    if &sig == b"TS" {
+        return Ok((MapKind::OldStyle, OldMap::read_options(reader, endian, ())?.partitions()));
+    }
+
+    let first = Partition::read_options(reader, endian, ())?;
+    // the count is only trusted as far as the map partition and the disk reach
+    let count = first.partition_count as u64;
+    let disk_blocks = reader.seek(SeekFrom::End(0))? / block_size;
+    let map_blocks = match first.kind {
+        PartitionType::ApplePartitionMap => first.size as u64,
+        _ => u64::MAX,
+    };
+    if count > map_blocks || count + 1 > disk_blocks {
+        return Err(binrw::Error::AssertFail {
+            pos: map_start,
+            message: format!("{} partition map entries don't fit in the map or on the disk", count),
+        });
+    }
+    let mut partitions = vec![first];
+    for i in 1..count {
+        reader.seek(SeekFrom::Start((i + 1) * block_size))?;
+        partitions.push(Partition::read_options(reader, endian, ())?);
+    }
+
+    Ok((MapKind::Apple, partitions))
+}
+
+#[derive(BinRead, Derivative)]
+#[derivative(Debug)]
+#[brw(big, magic = b"TS")]
+struct OldMap {
+    // as many entries as fit in the sector, unless a zeroed one ends the map early
+    #[br(count = 42, map = |v: Vec<OldMapEntry>| v.into_iter().take_while(|e| !e.is_end()).collect())]
+    entries: Vec<OldMapEntry>,
 }
 
-fn apm_pad_size(partitions: &[Partition]) -> u32 {
-    partitions
-        .iter()
-        .find(|entry| entry.kind == PartitionType::ApplePartitionMap)
-        .map(|entry| entry.size - entry.partition_count)
-        .unwrap_or(0)
+impl OldMap {
+    fn partitions(&self) -> Vec<Partition> {
+        let count = self.entries.len() as u32;
+        self.entries
+            .iter()
+            .map(|e| {
+                let kind = match &e.fs_id {
+                    b"TFS1" => PartitionType::AppleHfs,
+                    other => PartitionType::Other(String::from_utf8_lossy(other).into_owned()),
+                };
+                let mut p = Partition::new("", kind, e.start, e.size, 0);
+                p.partition_count = count;
+                p
+            })
+            .collect()
+    }
 }
 
 #[derive(BinRead, Derivative)]
 #[derivative(Debug)]
+#[brw(big)]
+struct OldMapEntry {
+    start: u32,
+    size: u32,
+    fs_id: [u8; 4],
+}
+
+impl OldMapEntry {
+    fn is_end(&self) -> bool {
+        self.start == 0 && self.size == 0 && self.fs_id == [0; 4]
+    }
+}
+
+#[derive(BinRead, BinWrite, Derivative)]
+#[derivative(Debug)]
 #[brw(big, magic = b"ER")]
 pub struct Block0 {
     block_size: u16,
@@ -76,13 +295,14 @@ pub struct Block0 {
     drivers: Vec<Driver>,
 }
 
-#[derive(BinRead, Derivative)]
+#[derive(BinRead, BinWrite, Derivative)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Driver {
     start: u32,
     size: u16,
     #[br(parse_with = os_type_parser)]
+    #[bw(write_with = os_type_writer)]
     os_type: OsType,
 }
 
@@ -123,7 +343,17 @@ fn os_type_parser() -> BinResult<OsType> {
     }
 }
 
-#[derive(BinRead, Derivative)]
+#[binrw::writer(writer)]
+fn os_type_writer(os_type: &OsType) -> BinResult<()> {
+    let ty = match os_type {
+        OsType::MacOs => 0x0001,
+        OsType::Other(ty) => *ty,
+    };
+    writer.write_all(&u16::to_be_bytes(ty))?;
+    Ok(())
+}
+
+#[derive(Clone, BinRead, BinWrite, Derivative)]
 #[derivative(Debug)]
 #[brw(big, magic = b"PM")]
 pub struct Partition {
@@ -135,6 +365,7 @@ pub struct Partition {
     #[brw(pad_size_to = 32)]
     name: NullString,
     #[br(parse_with = partition_type_parser)]
+    #[bw(write_with = partition_type_writer)]
     kind: PartitionType,
     data_start: u32,
     data_size: u32,
@@ -149,12 +380,35 @@ pub struct Partition {
     _pad3: u32,
     boot_checksum: u32,
     #[br(parse_with = processor_type_parser)]
+    #[bw(write_with = processor_type_writer)]
     proc_type: ProcessorType,
     #[derivative(Debug = "ignore")]
     _pad4: [u16; 188],
 }
 
 impl Partition {
+    fn new(name: &str, kind: PartitionType, start: u32, size: u32, status: u32) -> Self {
+        Self {
+            _pad: 0,
+            partition_count: 0,
+            start,
+            size,
+            name: NullString::from(name),
+            kind,
+            data_start: 0,
+            data_size: size,
+            status,
+            boot_start: 0,
+            boot_size: 0,
+            boot_load_addr: 0,
+            _pad2: 0,
+            boot_entry: 0,
+            _pad3: 0,
+            boot_checksum: 0,
+            proc_type: ProcessorType::Unspecified,
+            _pad4: [0; 188],
+        }
+    }
     pub fn start(&self) -> u32 {
         self.start
     }
@@ -194,6 +448,37 @@ fn partition_type_parser() -> BinResult<PartitionType> {
     }
 }
 
+// Type and processor names are zero-padded ASCII, cut off if they don't fit.
+fn write_padded<W: Write>(writer: &mut W, s: &[u8], len: usize) -> BinResult<()> {
+    let mut buf = vec![0; len];
+    let copied = s.len().min(len);
+    buf[..copied].copy_from_slice(&s[..copied]);
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+#[binrw::writer(writer)]
+fn partition_type_writer(kind: &PartitionType) -> BinResult<()> {
+    let name = match kind {
+        PartitionType::ApplePartitionMap => "Apple_partition_map",
+        PartitionType::AppleDriver => "Apple_Driver",
+        PartitionType::AppleDriver43 => "Apple_Driver43",
+        PartitionType::AppleMfs => "Apple_MFS",
+        PartitionType::AppleHfs => "Apple_HFS",
+        PartitionType::AppleUnixSvr2 => "Apple_Unix_SVR2",
+        PartitionType::AppleProDos => "Apple_PRODOS",
+        PartitionType::AppleFree => "Apple_Free",
+        PartitionType::AppleScratch => "Apple_Scratch",
+        PartitionType::AppleBootstrap => "Apple_Bootstrap",
+        PartitionType::Linux => "Linux",
+        PartitionType::LinuxRaid => "Linux_RAID",
+        PartitionType::LinuxSwap => "Linux_swap",
+        PartitionType::Other(ty) => ty,
+        PartitionType::NonUtf8(ty) => return write_padded(writer, ty, 32),
+    };
+    write_padded(writer, name.as_bytes(), 32)
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum PartitionType {
     ApplePartitionMap,
@@ -271,11 +556,199 @@ fn processor_type_parser() -> BinResult<ProcessorType> {
     }
 }
 
+#[binrw::writer(writer)]
+fn processor_type_writer(proc_type: &ProcessorType) -> BinResult<()> {
+    let name = match proc_type {
+        ProcessorType::M68000 => "68000",
+        ProcessorType::M68008 => "68008",
+        ProcessorType::M68010 => "68010",
+        ProcessorType::M68012 => "68012",
+        ProcessorType::M68020 => "68020",
+        ProcessorType::M68030 => "68030",
+        ProcessorType::M68040 => "68040",
+        ProcessorType::PowerPc => "powerpc",
+        ProcessorType::Unspecified => "",
+        ProcessorType::Other(ty) => ty,
+        ProcessorType::NonUtf8(ty) => return write_padded(writer, ty, 16),
+    };
+    write_padded(writer, name.as_bytes(), 16)
+}
+
+// A driver partition taken from an existing disk: its partition map entry (which also holds
+// the boot code size and checksum), the OS type and size from the driver descriptor, and the
+// contents of the whole partition.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct DriverImage {
+    entry: Partition,
+    os_type: OsType,
+    blocks: u16,
+    #[derivative(Debug = "ignore")]
+    code: Vec<u8>,
+}
+
+impl DriverImage {
+    pub fn kind(&self) -> &PartitionType {
+        &self.entry.kind
+    }
+    pub fn os_type(&self) -> OsType {
+        self.os_type
+    }
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+}
+
+#[derive(Debug)]
+enum PartitionContents {
+    Data(Vec<u8>),
+    Hfs(String, FormatOptions),
+}
+
+// Lays out a new partitioned disk: block 0, the partition map, an optional driver and then
+// the partitions in the order they were added. Whatever is left at the end becomes an
+// Apple_Free partition.
+#[derive(Debug)]
+pub struct ApmBuilder {
+    block_count: u32,
+    driver: Option<DriverImage>,
+    partitions: Vec<(Partition, PartitionContents)>,
+}
+
+impl ApmBuilder {
+    pub fn new(size: u64) -> Result<Self, ApmError> {
+        let block_count = u32::try_from(size / BLOCK_SIZE as u64)
+            .map_err(|_| ApmError::TooBig(size / BLOCK_SIZE as u64, u32::MAX))?;
+        Ok(Self {
+            block_count,
+            driver: None,
+            partitions: Vec::new(),
+        })
+    }
+    pub fn set_driver(&mut self, driver: DriverImage) {
+        self.driver = Some(driver);
+    }
+    // `size` is rounded up to whole blocks and must be big enough for `data`.
+    pub fn add_partition(&mut self, name: &str, kind: PartitionType, size: u64, data: Vec<u8>) -> Result<(), ApmError> {
+        let blocks = Self::blocks_for(size.max(data.len() as u64))?;
+        let entry = Self::entry(name, kind, blocks)?;
+        self.partitions.push((entry, PartitionContents::Data(data)));
+        Ok(())
+    }
+    // An empty HFS volume named `volume_name`, formatted when the disk is written.
+    pub fn add_hfs(&mut self, volume_name: &str, size: u64, options: FormatOptions) -> Result<(), ApmError> {
+        let entry = Self::entry("MacOS", PartitionType::AppleHfs, Self::blocks_for(size)?)?;
+        self.partitions.push((entry, PartitionContents::Hfs(volume_name.to_string(), options)));
+        Ok(())
+    }
+    fn blocks_for(size: u64) -> Result<u32, ApmError> {
+        let blocks = size.div_ceil(BLOCK_SIZE as u64);
+        u32::try_from(blocks).map_err(|_| ApmError::TooBig(blocks, u32::MAX))
+    }
+    fn entry(name: &str, kind: PartitionType, blocks: u32) -> Result<Partition, ApmError> {
+        if name.len() > 31 || !name.is_ascii() {
+            return Err(ApmError::InvalidName(name.to_string()));
+        }
+        Ok(Partition::new(name, kind, 0, blocks, PARTITION_STATUS))
+    }
+
+    fn layout(&self) -> Result<(Block0, Vec<Partition>), ApmError> {
+        let mut entries = vec![Partition::new("Apple", PartitionType::ApplePartitionMap, 1, MAP_BLOCKS, MAP_STATUS)];
+        let mut drivers = Vec::new();
+        let mut next = 1 + MAP_BLOCKS as u64;
+        if let Some(driver) = self.driver.as_ref() {
+            let mut entry = driver.entry.clone();
+            entry.start = next as u32;
+            drivers.push(Driver {
+                start: entry.start,
+                size: driver.blocks,
+                os_type: driver.os_type,
+            });
+            next += entry.size as u64;
+            entries.push(entry);
+        }
+        for (entry, _) in self.partitions.iter() {
+            let mut entry = entry.clone();
+            entry.start = next as u32;
+            next += entry.size as u64;
+            entries.push(entry);
+        }
+        if next > self.block_count as u64 {
+            return Err(ApmError::TooBig(next, self.block_count));
+        }
+        if next < self.block_count as u64 {
+            let free = self.block_count - next as u32;
+            entries.push(Partition::new("Extra", PartitionType::AppleFree, next as u32, free, 0));
+        }
+        if entries.len() > MAP_BLOCKS as usize {
+            return Err(ApmError::TooManyPartitions(entries.len()));
+        }
+        let count = entries.len() as u32;
+        for entry in entries.iter_mut() {
+            entry.partition_count = count;
+        }
+
+        let block0 = Block0 {
+            block_size: BLOCK_SIZE as u16,
+            block_count: self.block_count,
+            _dev_type: 1,
+            _dev_id: 1,
+            _sb_data: 0,
+            driver_count: drivers.len() as u16,
+            drivers,
+        };
+        Ok((block0, entries))
+    }
+    pub fn write<W: Read + Write + Seek>(&self, writer: &mut W) -> Result<(), ApmError> {
+        let (block0, entries) = self.layout()?;
+        let block = |n: u32| SeekFrom::Start(n as u64 * BLOCK_SIZE as u64);
+
+        // make sure the image is as big as the disk, even if it ends in free space
+        writer.seek(block(self.block_count))?;
+        writer.seek(SeekFrom::Current(-1))?;
+        writer.write_all(&[0])?;
+
+        let mut buf = Cursor::new(Vec::new());
+        block0.write(&mut buf)?;
+        let mut buf = buf.into_inner();
+        buf.resize(BLOCK_SIZE as usize, 0);
+        writer.seek(block(0))?;
+        writer.write_all(&buf)?;
+        for entry in entries.iter() {
+            entry.write(writer)?;
+        }
+        writer.write_all(&vec![0; (MAP_BLOCKS as usize - entries.len()) * BLOCK_SIZE as usize])?;
+
+        let mut entries = entries.iter().skip(1);
+        if let Some(driver) = self.driver.as_ref() {
+            let entry = entries.next().unwrap();
+            writer.seek(block(entry.start))?;
+            writer.write_all(&driver.code)?;
+        }
+        for ((_, contents), entry) in self.partitions.iter().zip(entries) {
+            writer.seek(block(entry.start))?;
+            match contents {
+                PartitionContents::Data(data) => writer.write_all(data)?,
+                PartitionContents::Hfs(name, options) => {
+                    let size = entry.size as u64 * BLOCK_SIZE as u64;
+                    HfsVolume::format(&mut *writer, size, name, options.clone())?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::{Cursor, Read};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
-    use crate::apm::{ApmDrive, PartitionType};
+    use binrw::BinWrite;
+
+    use crate::apm::{ApmBuilder, ApmDrive, MapKind, Partition, PartitionType};
+    use crate::fs::hfs::{FormatOptions, Fork as HfsFork, HfsVolume};
+    use crate::fs::mfs::{Fork as MfsFork, Mfs};
 
     use flate2::read::GzDecoder;
 
@@ -304,4 +777,152 @@ mod tests {
 
         assert_eq!(&got_partitions, &expected_partitions);
     }
+    #[test]
+    fn open_partitions() {
+        let mut mfs = Mfs::format("Flat", 400 * 1024, None).unwrap();
+        let file = mfs.add_file("Note", *b"TEXT", *b"ttxt");
+        mfs.overwrite_contents(file, MfsFork::Data, b"hello").unwrap();
+        let mut mfs_image = Cursor::new(Vec::new());
+        mfs.write_be(&mut mfs_image).unwrap();
+
+        let mut builder = ApmBuilder::new(4 * 1024 * 1024).unwrap();
+        builder.add_partition("Flat", PartitionType::AppleMfs, 400 * 1024, mfs_image.into_inner()).unwrap();
+        builder.add_hfs("Tree", 1024 * 1024, FormatOptions::default()).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        builder.write(&mut image).unwrap();
+
+        image.rewind().unwrap();
+        let mut disk = ApmDrive::new(&mut image).unwrap();
+        let flat = disk.partitions()[1].clone();
+        assert_eq!(disk.partition_bounds(&flat), (64 * 512, 400 * 1024));
+        let mfs = Mfs::new(&mut disk.partition_reader(&flat)).unwrap();
+        assert_eq!(mfs.file_data(mfs.file_by_name("Note").unwrap()), b"hello");
+
+        let tree = disk.partitions()[2].clone();
+        let hdr = disk.partition_hfs(&tree).unwrap();
+        assert!(hdr.boot_blocks().is_none());
+        let mut vol = HfsVolume::new(disk.partition_reader(&tree)).unwrap();
+        let root = vol.root_dir();
+        let file = vol.create_file(&root, "Note", *b"TEXT", *b"ttxt").unwrap();
+        vol.write_fork(&file, HfsFork::Data, b"hello").unwrap();
+        vol.flush().unwrap();
+        let mut reader = disk.partition_reader(&tree);
+        assert!(reader.seek(SeekFrom::End(1)).is_ok());
+        assert!(reader.write_all(b"x").is_err());
+
+        let mut vol = HfsVolume::new(disk.partition_reader(&tree)).unwrap();
+        let file = vol.root_dir().file("Note").unwrap().clone();
+        assert_eq!(vol.file_data(&file).unwrap(), b"hello");
+    }
+    #[test]
+    fn old_style_map() {
+        let mut disk = vec![0u8; 64 * 512];
+        disk[..8].copy_from_slice(b"ER\x02\x00\x00\x00\x00\x40");
+        disk[512..514].copy_from_slice(b"TS");
+        disk[514..526].copy_from_slice(b"\x00\x00\x00\x04\x00\x00\x00\x20TFS1");
+        disk[526..538].copy_from_slice(b"\x00\x00\x00\x24\x00\x00\x00\x1cXENX");
+        let mut disk = Cursor::new(disk);
+        let drive = ApmDrive::new(&mut disk).unwrap();
+        assert_eq!(drive.map_kind(), MapKind::OldStyle);
+        let got_partitions: Vec<(PartitionType, u32, u32)> = drive
+            .partitions()
+            .iter()
+            .map(|p| (p.kind().clone(), p.start(), p.size()))
+            .collect();
+        let expected_partitions = [
+            (PartitionType::AppleHfs, 4, 32),
+            (PartitionType::Other("XENX".to_string()), 36, 28),
+        ];
+        assert_eq!(&got_partitions, &expected_partitions);
+        assert_eq!(drive.partition_bounds(&drive.partitions()[0]), (4 * 512, 32 * 512));
+    }
+    #[test]
+    fn cd_rom_map() {
+        // hybrid CDs use 2048-byte blocks, entries included
+        let mut disk = Cursor::new(vec![0u8; 16 * 2048]);
+        disk.write_all(b"ER\x08\x00\x00\x00\x00\x10").unwrap();
+        let mut map = Partition::new("Apple", PartitionType::ApplePartitionMap, 1, 2, 0);
+        let mut hfs = Partition::new("MacOS", PartitionType::AppleHfs, 4, 12, 0);
+        map.partition_count = 2;
+        hfs.partition_count = 2;
+        disk.set_position(2048);
+        map.write(&mut disk).unwrap();
+        disk.set_position(2 * 2048);
+        hfs.write(&mut disk).unwrap();
+
+        disk.rewind().unwrap();
+        let drive = ApmDrive::new(&mut disk).unwrap();
+        assert_eq!(drive.map_kind(), MapKind::Apple);
+        assert_eq!(drive.block_size(), 2048);
+        assert_eq!(drive.partitions().len(), 2);
+        assert_eq!(drive.partitions()[1].name(), Ok("MacOS"));
+        assert_eq!(drive.partition_bounds(&drive.partitions()[1]), (4 * 2048, 12 * 2048));
+    }
+    #[test]
+    fn partition_count_bounds() {
+        let mut disk = Cursor::new(vec![0u8; 64 * 512]);
+        disk.write_all(b"ER\x02\x00\x00\x00\x00\x40").unwrap();
+        let mut map = Partition::new("Apple", PartitionType::ApplePartitionMap, 1, 4, 0);
+        for count in [5, u32::MAX] {
+            map.partition_count = count;
+            disk.set_position(512);
+            map.write(&mut disk).unwrap();
+            disk.rewind().unwrap();
+            assert!(ApmDrive::new(&mut disk).is_err());
+        }
+        // without a map partition to go by, only the disk bounds the count
+        let mut hfs = Partition::new("MacOS", PartitionType::AppleHfs, 4, 12, 0);
+        hfs.partition_count = 64;
+        disk.set_position(512);
+        hfs.write(&mut disk).unwrap();
+        disk.rewind().unwrap();
+        assert!(ApmDrive::new(&mut disk).is_err());
+    }
+    #[test]
+    fn build() {
+        let mut decoder = GzDecoder::new(HD_100MB);
+        let mut vec = Vec::new();
+        decoder.read_to_end(&mut vec).unwrap();
+        let mut bytes = Cursor::new(vec);
+        let driver = ApmDrive::new(&mut bytes).unwrap().driver_image().unwrap().unwrap();
+        assert_eq!(driver.kind(), &PartitionType::AppleDriver43);
+
+        let mut builder = ApmBuilder::new(20 * 1024 * 1024).unwrap();
+        builder.set_driver(driver.clone());
+        builder.add_hfs("Scratch", 8 * 1024 * 1024, FormatOptions::default()).unwrap();
+        builder.add_partition("Notes", PartitionType::AppleScratch, 0, b"hello".to_vec()).unwrap();
+        let mut image = Cursor::new(Vec::new());
+        builder.write(&mut image).unwrap();
+        assert_eq!(image.get_ref().len(), 20 * 1024 * 1024);
+
+        image.rewind().unwrap();
+        let mut disk = ApmDrive::new(&mut image).unwrap();
+        let got_partitions: Vec<(PartitionType, u32, u32)> = disk
+            .partitions()
+            .iter()
+            .map(|p| (p.kind().clone(), p.start(), p.size()))
+            .collect();
+        let expected_partitions = [
+            (PartitionType::ApplePartitionMap, 1, 63),
+            (PartitionType::AppleDriver43, 64, 32),
+            (PartitionType::AppleHfs, 96, 16384),
+            (PartitionType::AppleScratch, 16480, 1),
+            (PartitionType::AppleFree, 16481, 40960 - 16481),
+        ];
+        assert_eq!(&got_partitions, &expected_partitions);
+        assert_eq!(disk.drivers().len(), 1);
+        assert_eq!(disk.drivers()[0].start(), 64);
+        assert_eq!(disk.drivers()[0].size(), 19);
+        assert_eq!(disk.driver_image().unwrap().unwrap().code(), driver.code());
+
+        let hfs = disk.partitions()[2].clone();
+        let vol = HfsVolume::new(disk.partition_reader(&hfs)).unwrap();
+        assert_eq!(vol.root_dir().name(), "Scratch");
+        let notes = disk.partitions()[3].clone();
+        assert_eq!(&disk.read_partition_data(&notes).unwrap()[..5], b"hello");
+
+        let mut builder = ApmBuilder::new(1024 * 1024).unwrap();
+        builder.add_hfs("Too Big", 2 * 1024 * 1024, FormatOptions::default()).unwrap();
+        assert!(builder.write(&mut Cursor::new(Vec::new())).is_err());
+    }
 }
@@ -47,9 +47,27 @@ pub struct PascalString<const CAP: usize> {
 }
 
 impl<const CAP: usize> PascalString<CAP> {
+    pub fn new(s: &str) -> Option<Self> {
+        let mut data = [0; CAP];
+        let mut len = 0;
+        for ch in s.chars() {
+            *data.get_mut(len)? = MacRoman::encode(ch).ok()?;
+            len += 1;
+        }
+        Some(Self {
+            len: len as u8,
+            data,
+        })
+    }
     pub fn try_as_str(&self) -> Result<&str, Utf8Error> {
         str::from_utf8(&self.data[..(self.len as usize)])
     }
+    pub fn decode(&self) -> String {
+        self.data[..(self.len as usize).min(CAP)]
+            .iter()
+            .map(|b| MacRoman::decode(*b))
+            .collect()
+    }
 }
 
 impl<const CAP: usize> fmt::Debug for PascalString<CAP> {
@@ -61,9 +79,13 @@ impl<const CAP: usize> fmt::Debug for PascalString<CAP> {
     }
 }
 
-#[derive(Clone, BinRead, BinWrite, Eq, PartialEq)]
+// The length is worked out again when writing, so the string can be edited in place.
+#[binrw::binrw]
+#[derive(Clone, Eq, PartialEq)]
 #[brw(big)]
 pub struct DynamicPascalString {
+    #[br(temp)]
+    #[bw(try_calc = u8::try_from(data.chars().count()))]
     len: u8,
     #[br(count = len, map = |buf: Vec<u8>| buf.into_iter().map(|b| MacRoman::decode(b)).collect())]
     #[bw(try_map = |s: &String| s.chars().map(|v| MacRoman::encode(v)).collect::<Result<Vec<u8>, _>>())]
@@ -72,14 +94,11 @@ pub struct DynamicPascalString {
 
 impl DynamicPascalString {
     pub fn new(t: impl Into<String>) -> Self {
-        let data: String = t.into();
-        Self {
-            len: data.len() as u8,
-            data,
-        }
+        Self { data: t.into() }
     }
+    // the length in Mac Roman bytes, as stored
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.data.chars().count()
     }
     pub fn as_str(&self) -> &str {
         self.data.as_str()
@@ -178,19 +197,22 @@ impl fmt::Debug for DateTime2k {
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq, BinRead, BinWrite)]
+#[derive(Clone, Copy, Default, Eq, PartialEq, BinRead, BinWrite)]
 #[brw(big)]
 #[repr(transparent)]
 pub struct DateTime(u32);
 
 impl DateTime {
     const OFFSET_FROM_UNIX_EPOCH: u32 = 2082844800;
+    // Dates before 1970 (including unset ones) end up before UNIX_EPOCH.
     pub fn to_system_time(self) -> SystemTime {
-        UNIX_EPOCH
-            .checked_add(std::time::Duration::from_secs(
-                (self.0 - Self::OFFSET_FROM_UNIX_EPOCH) as u64,
-            ))
-            .unwrap()
+        let since_epoch = self.0 as i64 - Self::OFFSET_FROM_UNIX_EPOCH as i64;
+        let offset = std::time::Duration::from_secs(since_epoch.unsigned_abs());
+        if since_epoch >= 0 {
+            UNIX_EPOCH + offset
+        } else {
+            UNIX_EPOCH - offset
+        }
     }
     pub fn now() -> Self {
         DateTime(
@@ -251,6 +273,37 @@ pub struct FinderInfo {
     window: FInfoWindow,
 }
 
+impl FinderInfo {
+    pub fn new(file_type: [u8; 4], file_creator: [u8; 4]) -> Self {
+        Self {
+            file_type: SizedString::new(file_type),
+            file_creator: SizedString::new(file_creator),
+            ..Self::default()
+        }
+    }
+    pub fn file_type(&self) -> &SizedString<4> {
+        &self.file_type
+    }
+    pub fn creator(&self) -> &SizedString<4> {
+        &self.file_creator
+    }
+    pub fn flags(&self) -> FInfoFlags {
+        self.flags
+    }
+}
+
+impl Default for FinderInfo {
+    fn default() -> Self {
+        Self {
+            file_type: SizedString::new([0; 4]),
+            file_creator: SizedString::new([0; 4]),
+            flags: FInfoFlags::empty(),
+            location: Point { y: 0, x: 0 },
+            window: FInfoWindow::Disk,
+        }
+    }
+}
+
 bitflags! {
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct FInfoFlags: u16 {
@@ -269,7 +322,7 @@ pub enum FInfoWindow {
     Folder(u16),
 }
 
-#[derive(Debug, Clone, BinRead, BinWrite, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, BinRead, BinWrite, Eq, PartialEq)]
 #[brw(big)]
 pub struct ExtraFinderInfo {
     #[brw(pad_after = 6)]
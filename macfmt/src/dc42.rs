@@ -0,0 +1,341 @@
+use std::io;
+
+use binrw::io::{Cursor, Read, Seek, Write};
+use binrw::{BinRead, BinWrite};
+use derivative::Derivative;
+use thiserror::Error;
+
+use crate::common::{DateTime, PascalString};
+use crate::device::{BlockDevice, WritableBlockDevice, check_range};
+
+const SECTOR_SIZE: usize = 512;
+const TAG_SIZE: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum Dc42Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse or write a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("{0:?} is not a valid image name")]
+    InvalidName(String),
+    #[error("Data is {0} bytes, which isn't a whole number of sectors")]
+    BadDataSize(u32),
+    #[error("Tags are {0} bytes, expected none or 12 per sector")]
+    BadTagSize(u32),
+    #[error("Data checksum is {got:#010x}, expected {expected:#010x}")]
+    DataChecksum { expected: u32, got: u32 },
+    #[error("Tag checksum is {got:#010x}, expected {expected:#010x}")]
+    TagChecksum { expected: u32, got: u32 },
+}
+
+#[derive(Clone, Derivative, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+struct Header {
+    name: PascalString<63>,
+    data_size: u32,
+    tag_size: u32,
+    data_checksum: u32,
+    tag_checksum: u32,
+    disk_format: DiskFormat,
+    format_byte: u8,
+    #[derivative(Debug = "ignore")]
+    #[brw(magic = b"\x01\x00")]
+    _private: (),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, BinRead, BinWrite)]
+#[brw(big, repr = u8)]
+pub enum DiskFormat {
+    Gcr400K = 0,
+    Gcr800K = 1,
+    Mfm720K = 2,
+    Mfm1440K = 3,
+    Other = 0xff,
+}
+
+impl DiskFormat {
+    fn for_size(size: usize) -> Self {
+        match size {
+            0x64000 => DiskFormat::Gcr400K,
+            0xc8000 => DiskFormat::Gcr800K,
+            0xb4000 => DiskFormat::Mfm720K,
+            0x168000 => DiskFormat::Mfm1440K,
+            _ => DiskFormat::Other,
+        }
+    }
+}
+
+// The tag bytes the Sony driver keeps next to every sector. The file system fills them in to
+// help scavenging tools rebuild files; MFS and HFS both use this layout.
+#[derive(Debug, Clone, Eq, PartialEq, BinRead, BinWrite)]
+#[brw(big)]
+pub struct SectorTag {
+    file_num: u32,
+    // 0x00 for the data fork, 0x01 for the resource fork
+    fork_type: u8,
+    // bit 7 means open, bit 0 locked
+    attrs: u8,
+    // which block of the file this is
+    file_seq: u16,
+    mtime: DateTime,
+}
+
+impl SectorTag {
+    pub fn file_num(&self) -> u32 {
+        self.file_num
+    }
+    pub fn is_resource_fork(&self) -> bool {
+        self.fork_type & 1 == 1
+    }
+    pub fn attrs(&self) -> u8 {
+        self.attrs
+    }
+    pub fn file_seq(&self) -> u16 {
+        self.file_seq
+    }
+    pub fn mtime(&self) -> DateTime {
+        self.mtime
+    }
+}
+
+// A Disk Copy 4.2 image: an 84-byte header, the sectors and then their tags. Checksums are
+// verified when reading and recomputed when writing, so the data can be changed in between.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct DiskCopy42 {
+    header: Header,
+    #[derivative(Debug = "ignore")]
+    data: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    tags: Vec<u8>,
+}
+
+impl DiskCopy42 {
+    pub fn new(name: &str, data: Vec<u8>, tags: Option<Vec<u8>>) -> Result<Self, Dc42Error> {
+        let name = PascalString::new(name).ok_or_else(|| Dc42Error::InvalidName(name.to_string()))?;
+        let tags = tags.unwrap_or_default();
+        let header = Header {
+            name,
+            data_size: data.len() as u32,
+            tag_size: tags.len() as u32,
+            data_checksum: checksum(&data),
+            tag_checksum: tag_checksum(&tags),
+            disk_format: DiskFormat::for_size(data.len()),
+            // 0x12 for single-sided 400K disks, 0x22 for everything double-sided
+            format_byte: if data.len() == 0x64000 { 0x12 } else { 0x22 },
+            _private: (),
+        };
+        Self::check_sizes(&header)?;
+
+        Ok(Self { header, data, tags })
+    }
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, Dc42Error> {
+        let header = Header::read(reader)?;
+        Self::check_sizes(&header)?;
+        let mut data = vec![0; header.data_size as usize];
+        reader.read_exact(&mut data)?;
+        let mut tags = vec![0; header.tag_size as usize];
+        reader.read_exact(&mut tags)?;
+
+        let got = checksum(&data);
+        if got != header.data_checksum {
+            return Err(Dc42Error::DataChecksum { expected: header.data_checksum, got });
+        }
+        let got = tag_checksum(&tags);
+        if got != header.tag_checksum {
+            return Err(Dc42Error::TagChecksum { expected: header.tag_checksum, got });
+        }
+
+        Ok(Self { header, data, tags })
+    }
+    // Quick check for autodetection: the header's private word and the sizes have to match the
+    // length of the image.
+    pub fn probe<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+        let start = reader.stream_position()?;
+        let len = reader.seek(io::SeekFrom::End(0))? - start;
+        reader.seek(io::SeekFrom::Start(start))?;
+        let header = Header::read(reader);
+        reader.seek(io::SeekFrom::Start(start))?;
+
+        Ok(match header {
+            Ok(header) => 84 + header.data_size as u64 + header.tag_size as u64 == len,
+            Err(_) => false,
+        })
+    }
+    fn check_sizes(header: &Header) -> Result<(), Dc42Error> {
+        if !(header.data_size as usize).is_multiple_of(SECTOR_SIZE) {
+            return Err(Dc42Error::BadDataSize(header.data_size));
+        }
+        let sectors = header.data_size / SECTOR_SIZE as u32;
+        if header.tag_size != 0 && header.tag_size != sectors * TAG_SIZE as u32 {
+            return Err(Dc42Error::BadTagSize(header.tag_size));
+        }
+        Ok(())
+    }
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<(), Dc42Error> {
+        let mut header = self.header.clone();
+        header.data_size = self.data.len() as u32;
+        header.data_checksum = checksum(&self.data);
+        header.tag_size = self.tags.len() as u32;
+        header.tag_checksum = tag_checksum(&self.tags);
+        Self::check_sizes(&header)?;
+
+        header.write(writer)?;
+        writer.write_all(&self.data)?;
+        writer.write_all(&self.tags)?;
+        Ok(())
+    }
+    pub fn name(&self) -> String {
+        self.header.name.decode()
+    }
+    pub fn disk_format(&self) -> DiskFormat {
+        self.header.disk_format
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+    // The sectors as a disk of their own, to hand to Mfs or HfsVolume.
+    pub fn data_reader(&self) -> Cursor<&[u8]> {
+        Cursor::new(&self.data)
+    }
+    // Like data_reader, but writable. The size of the image stays the same.
+    pub fn data_writer(&mut self) -> Cursor<&mut [u8]> {
+        Cursor::new(&mut self.data)
+    }
+    pub fn has_tags(&self) -> bool {
+        !self.tags.is_empty()
+    }
+    pub fn raw_tags(&self) -> &[u8] {
+        &self.tags
+    }
+    pub fn sector_tag(&self, sector: usize) -> Option<SectorTag> {
+        let raw = self.tags.get(sector * TAG_SIZE..)?.get(..TAG_SIZE)?;
+        SectorTag::read(&mut Cursor::new(raw)).ok()
+    }
+    pub fn set_sector_tag(&mut self, sector: usize, tag: &SectorTag) -> Result<(), Dc42Error> {
+        if self.tags.is_empty() {
+            self.tags = vec![0; self.data.len() / SECTOR_SIZE * TAG_SIZE];
+        }
+        let Some(raw) = self.tags.get_mut(sector * TAG_SIZE..).and_then(|t| t.get_mut(..TAG_SIZE)) else {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        };
+        tag.write(&mut Cursor::new(raw))?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for DiskCopy42 {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    fn block_count(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        buf.copy_from_slice(&self.data[start as usize * SECTOR_SIZE..][..buf.len()]);
+        Ok(())
+    }
+}
+
+impl WritableBlockDevice for DiskCopy42 {
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        self.data[start as usize * SECTOR_SIZE..][..buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+// Add every big endian word and rotate right.
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(2).fold(0u32, |sum, word| {
+        let word = u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]);
+        sum.wrapping_add(word as u32).rotate_right(1)
+    })
+}
+
+// Disk Copy 4.2 leaves the first sector's tag out of the checksum.
+fn tag_checksum(tags: &[u8]) -> u32 {
+    checksum(tags.get(TAG_SIZE..).unwrap_or(&[]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::{Dc42Error, DiskCopy42, DiskFormat, SectorTag};
+    use crate::common::DateTime;
+    use crate::fs::mfs::Mfs;
+
+    const INFINITE_DSK: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/infinite.dsk"
+    ));
+
+    #[test]
+    fn round_trip() {
+        let img = DiskCopy42::new("Infinite HD", INFINITE_DSK.to_vec(), None).unwrap();
+        assert_eq!(img.disk_format(), DiskFormat::Mfm1440K);
+        let mut file = Cursor::new(Vec::new());
+        img.write(&mut file).unwrap();
+        assert_eq!(file.get_ref().len(), 84 + INFINITE_DSK.len());
+        assert_eq!(&file.get_ref()[0x52..0x54], b"\x01\x00");
+
+        file.set_position(0);
+        assert!(DiskCopy42::probe(&mut file).unwrap());
+        let img = DiskCopy42::read(&mut file).unwrap();
+        assert_eq!(img.name(), "Infinite HD");
+        assert!(!img.has_tags());
+        let mfs = Mfs::new(&mut img.data_reader()).unwrap();
+        assert_eq!(mfs.files().len(), 32);
+
+        let mut corrupt = file.into_inner();
+        corrupt[84 + 0x1000] ^= 1;
+        assert!(matches!(
+            DiskCopy42::read(&mut Cursor::new(corrupt)),
+            Err(Dc42Error::DataChecksum { .. })
+        ));
+    }
+
+    #[test]
+    fn tags() {
+        let mut img = DiskCopy42::new("Tagged", vec![0; 800 * 512], None).unwrap();
+        let tag = SectorTag {
+            file_num: 17,
+            fork_type: 1,
+            attrs: 0,
+            file_seq: 3,
+            mtime: DateTime::now(),
+        };
+        img.set_sector_tag(0, &tag).unwrap();
+        img.set_sector_tag(799, &tag).unwrap();
+        assert!(img.set_sector_tag(800, &tag).is_err());
+        img.data_writer().write_all(b"hello").unwrap();
+
+        let mut file = Cursor::new(Vec::new());
+        img.write(&mut file).unwrap();
+        file.set_position(0);
+        let img = DiskCopy42::read(&mut file).unwrap();
+        assert_eq!(img.disk_format(), DiskFormat::Gcr400K);
+        assert_eq!(img.raw_tags().len(), 800 * 12);
+        assert_eq!(img.sector_tag(799), Some(tag.clone()));
+        assert!(img.sector_tag(799).unwrap().is_resource_fork());
+        assert_eq!(img.sector_tag(1).unwrap().file_num(), 0);
+        assert_eq!(&img.data()[..5], b"hello");
+
+        // the first tag isn't part of the checksum
+        let mut file = file.into_inner();
+        file[84 + 800 * 512] ^= 1;
+        assert!(DiskCopy42::read(&mut Cursor::new(file.clone())).is_ok());
+        file[84 + 800 * 512 + 12] ^= 1;
+        assert!(matches!(
+            DiskCopy42::read(&mut Cursor::new(file)),
+            Err(Dc42Error::TagChecksum { .. })
+        ));
+    }
+}
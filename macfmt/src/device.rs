@@ -0,0 +1,595 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use binrw::BinRead;
+use derivative::Derivative;
+use thiserror::Error;
+
+use crate::dc42::{Dc42Error, DiskCopy42};
+use crate::dmg::{DiskImage, DmgError};
+use crate::macbinary::{MacBinary2, is_macbinary2};
+use crate::moof::{Moof, MoofError};
+use crate::single::AppleFile;
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Error, Debug)]
+pub enum DetectError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("Failed to open Disk Copy 4.2 image: {0}")]
+    Dc42(#[from] Dc42Error),
+    #[error("Failed to open Disk Copy image: {0}")]
+    Dmg(#[from] DmgError),
+    #[error("Failed to decode floppy capture: {0}")]
+    Moof(#[from] MoofError),
+    #[error("Unknown file format")]
+    Unknown,
+    #[error("Nothing recognizable inside the {0:?} image")]
+    UnknownContents(Container),
+}
+
+// Fixed-size sector I/O, which is what every disk image format comes down to in the end. Reads
+// and writes always cover whole blocks.
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+pub trait WritableBlockDevice: BlockDevice {
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> io::Result<()>;
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_blocks(start, buf)
+    }
+}
+
+impl<D: WritableBlockDevice + ?Sized> WritableBlockDevice for &mut D {
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> io::Result<()> {
+        (**self).write_blocks(start, buf)
+    }
+}
+
+// Checks that a transfer of `len` bytes starting at block `start` is whole blocks and stays on
+// the device.
+pub(crate) fn check_range<D: BlockDevice + ?Sized>(device: &D, start: u64, len: usize) -> io::Result<()> {
+    if !len.is_multiple_of(device.block_size()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "transfer isn't a whole number of blocks"));
+    }
+    let blocks = (len / device.block_size()) as u64;
+    if start.checked_add(blocks).is_none_or(|end| end > device.block_count()) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "transfer past the end of the device"));
+    }
+    Ok(())
+}
+
+// A plain image file, or anything else that can already be read like one. A partial block at
+// the end is left out.
+#[derive(Debug)]
+pub struct RawDevice<R> {
+    inner: R,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl<R: Seek> RawDevice<R> {
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::with_block_size(inner, BLOCK_SIZE)
+    }
+    pub fn with_block_size(mut inner: R, block_size: usize) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner,
+            block_size,
+            block_count: len / block_size as u64,
+        })
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> BlockDevice for RawDevice<R> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        self.inner.seek(SeekFrom::Start(start * self.block_size as u64))?;
+        self.inner.read_exact(buf)
+    }
+}
+
+impl<R: Read + Write + Seek> WritableBlockDevice for RawDevice<R> {
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        self.inner.seek(SeekFrom::Start(start * self.block_size as u64))?;
+        self.inner.write_all(buf)
+    }
+}
+
+// A run of blocks on another device, like a partition, as a device of its own.
+#[derive(Debug)]
+pub struct PartitionDevice<D> {
+    inner: D,
+    start: u64,
+    block_count: u64,
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    pub fn new(inner: D, start: u64, block_count: u64) -> io::Result<Self> {
+        if start.checked_add(block_count).is_none_or(|end| end > inner.block_count()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "partition doesn't fit on the device"));
+        }
+        Ok(Self {
+            inner,
+            start,
+            block_count,
+        })
+    }
+    // Same, but from a byte offset and length like ApmDrive::partition_bounds gives.
+    pub fn from_bounds(inner: D, offset: u64, len: u64) -> io::Result<Self> {
+        let block_size = inner.block_size() as u64;
+        if !offset.is_multiple_of(block_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "partition doesn't start on a block"));
+        }
+        Self::new(inner, offset / block_size, len / block_size)
+    }
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        self.inner.read_blocks(self.start + start, buf)
+    }
+}
+
+impl<D: WritableBlockDevice> WritableBlockDevice for PartitionDevice<D> {
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        self.inner.write_blocks(self.start + start, buf)
+    }
+}
+
+// The bytes of a device, to hand to the file systems and ApmDrive. The last block read is kept
+// so that small reads next to each other don't go back to the device every time.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct DeviceReader<D> {
+    device: D,
+    pos: u64,
+    #[derivative(Debug = "ignore")]
+    block: Vec<u8>,
+    cached: Option<u64>,
+}
+
+impl<D: BlockDevice> DeviceReader<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            block: vec![0; device.block_size()],
+            device,
+            pos: 0,
+            cached: None,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        self.device.block_count() * self.device.block_size() as u64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+    fn load(&mut self, block: u64) -> io::Result<()> {
+        if self.cached != Some(block) {
+            self.cached = None;
+            self.device.read_blocks(block, &mut self.block)?;
+            self.cached = Some(block);
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Read for DeviceReader<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block_size = self.device.block_size();
+        let want = self.len().saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if want == 0 {
+            return Ok(0);
+        }
+        let block = self.pos / block_size as u64;
+        let off = (self.pos % block_size as u64) as usize;
+
+        // whole blocks go straight to the caller
+        let n = if off == 0 && want >= block_size {
+            let n = want - want % block_size;
+            self.device.read_blocks(block, &mut buf[..n])?;
+            n
+        } else {
+            self.load(block)?;
+            let n = want.min(block_size - off);
+            buf[..n].copy_from_slice(&self.block[off..off + n]);
+            n
+        };
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<D: WritableBlockDevice> Write for DeviceReader<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let block_size = self.device.block_size();
+        let want = self.len().saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        if want == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "write past the end of the device"));
+        }
+        let block = self.pos / block_size as u64;
+        let off = (self.pos % block_size as u64) as usize;
+
+        let n = if off == 0 && want >= block_size {
+            let n = want - want % block_size;
+            self.cached = None;
+            self.device.write_blocks(block, &buf[..n])?;
+            n
+        } else {
+            // partial blocks are read, patched and written back
+            self.load(block)?;
+            let n = want.min(block_size - off);
+            self.block[off..off + n].copy_from_slice(&buf[..n]);
+            self.device.write_blocks(block, &self.block)?;
+            n
+        };
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Seek for DeviceReader<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.len().checked_add_signed(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        let Some(new_pos) = new_pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+        self.pos = new_pos;
+
+        Ok(self.pos)
+    }
+}
+
+// What a disk image came wrapped in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Container {
+    Raw,
+    Dc42,
+    Udif,
+    Ndif,
+    Moof,
+}
+
+// What's on a disk once it's unwrapped.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Layout {
+    Apm,
+    Mfs,
+    Hfs,
+    HfsPlus,
+}
+
+// Files that carry a resource fork rather than a disk.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FileFormat {
+    MacBinary,
+    AppleSingle,
+    AppleDouble,
+    ResourceFork,
+}
+
+// Any of the disk image formats, as a block device.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub enum Disk<R> {
+    Raw(#[derivative(Debug = "ignore")] RawDevice<R>),
+    // Disk Copy 4.2 images and floppy captures, which get decoded up front
+    Memory(DiskCopy42),
+    Udif(DiskImage<R>),
+    Ndif(DiskImage<Cursor<Vec<u8>>>),
+}
+
+impl<R: Read + Seek> BlockDevice for Disk<R> {
+    fn block_size(&self) -> usize {
+        match self {
+            Disk::Raw(d) => d.block_size(),
+            Disk::Memory(d) => d.block_size(),
+            Disk::Udif(d) => d.block_size(),
+            Disk::Ndif(d) => d.block_size(),
+        }
+    }
+    fn block_count(&self) -> u64 {
+        match self {
+            Disk::Raw(d) => d.block_count(),
+            Disk::Memory(d) => d.block_count(),
+            Disk::Udif(d) => d.block_count(),
+            Disk::Ndif(d) => d.block_count(),
+        }
+    }
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Disk::Raw(d) => d.read_blocks(start, buf),
+            Disk::Memory(d) => d.read_blocks(start, buf),
+            Disk::Udif(d) => d.read_blocks(start, buf),
+            Disk::Ndif(d) => d.read_blocks(start, buf),
+        }
+    }
+}
+
+// Only raw images and ones in memory can be written; the compressed ones would have to be
+// rebuilt from scratch.
+impl<R: Read + Write + Seek> WritableBlockDevice for Disk<R> {
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Disk::Raw(d) => d.write_blocks(start, buf),
+            Disk::Memory(d) => d.write_blocks(start, buf),
+            Disk::Udif(_) | Disk::Ndif(_) => Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "compressed disk images can't be written to",
+            )),
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub enum Detected<R> {
+    Disk {
+        container: Container,
+        layout: Layout,
+        disk: Disk<R>,
+    },
+    File {
+        format: FileFormat,
+        #[derivative(Debug = "ignore")]
+        data_fork: Vec<u8>,
+        #[derivative(Debug = "ignore")]
+        rsrc_fork: Vec<u8>,
+    },
+}
+
+// Works out what a file is, unwrapping disk images from whatever they came in along the way.
+pub fn detect<R: Read + Seek>(mut reader: R) -> Result<Detected<R>, DetectError> {
+    let mut head = Vec::new();
+    reader.rewind()?;
+    (&mut reader).take(128).read_to_end(&mut head)?;
+    reader.rewind()?;
+
+    let unwrapped = |container, mut disk: Disk<R>| -> Result<Detected<R>, DetectError> {
+        match probe_layout(&mut disk)? {
+            Some(layout) => Ok(Detected::Disk { container, layout, disk }),
+            None => Err(DetectError::UnknownContents(container)),
+        }
+    };
+    if Moof::probe(&mut reader)? {
+        let img = Moof::read(&mut reader)?.decode()?;
+        return unwrapped(Container::Moof, Disk::Memory(img));
+    }
+    if DiskCopy42::probe(&mut reader)? {
+        let img = DiskCopy42::read(&mut reader)?;
+        return unwrapped(Container::Dc42, Disk::Memory(img));
+    }
+    if DiskImage::is_udif(&mut reader)? {
+        return unwrapped(Container::Udif, Disk::Udif(DiskImage::open_udif(reader)?));
+    }
+    if let Some(format) = match head.get(..4) {
+        Some(b"\x00\x05\x16\x00") => Some(FileFormat::AppleSingle),
+        Some(b"\x00\x05\x16\x07") => Some(FileFormat::AppleDouble),
+        _ => None,
+    } {
+        let file = AppleFile::read(&mut reader)?;
+        let data_fork = file.data_fork().unwrap_or_default().to_vec();
+        let rsrc_fork = file.resource_fork().unwrap_or_default().to_vec();
+        return Ok(Detected::File { format, data_fork, rsrc_fork });
+    }
+
+    let mut disk = RawDevice::new(reader)?;
+    if let Some(layout) = probe_layout(&mut disk)? {
+        return Ok(Detected::Disk {
+            container: Container::Raw,
+            layout,
+            disk: Disk::Raw(disk),
+        });
+    }
+    let mut reader = disk.into_inner();
+    reader.rewind()?;
+
+    // The checksum alone also matches a run of zeros, so this comes after the disks. Disk Copy 6
+    // images only make it off a Mac in MacBinary.
+    if is_macbinary2(&head) && (1..=63).contains(&head[1]) {
+        let file = MacBinary2::read(&mut reader)?;
+        let data_fork = file.data_fork().to_vec();
+        if let Ok(img) = DiskImage::open_ndif(Cursor::new(data_fork.clone()), file.resource_fork()) {
+            return unwrapped(Container::Ndif, Disk::Ndif(img));
+        }
+        let rsrc_fork = file.resource_fork().to_vec();
+        return Ok(Detected::File { format: FileFormat::MacBinary, data_fork, rsrc_fork });
+    }
+
+    let mut rsrc_fork = Vec::new();
+    reader.read_to_end(&mut rsrc_fork)?;
+    if is_resource_fork(&rsrc_fork) {
+        return Ok(Detected::File {
+            format: FileFormat::ResourceFork,
+            data_fork: Vec::new(),
+            rsrc_fork,
+        });
+    }
+
+    Err(DetectError::Unknown)
+}
+
+// Looks for a partition map or a file system at the start of a device.
+pub fn probe_layout<D: BlockDevice>(device: &mut D) -> io::Result<Option<Layout>> {
+    let block_size = device.block_size();
+    let blocks = 1026usize.div_ceil(block_size) as u64;
+    if device.block_count() < blocks {
+        return Ok(None);
+    }
+    let mut head = vec![0; blocks as usize * block_size];
+    device.read_blocks(0, &mut head)?;
+
+    if &head[..2] == b"ER" {
+        return Ok(Some(Layout::Apm));
+    }
+    Ok(match &head[1024..1026] {
+        b"\xd2\xd7" => Some(Layout::Mfs),
+        b"BD" => Some(Layout::Hfs),
+        b"H+" | b"HX" => Some(Layout::HfsPlus),
+        _ => None,
+    })
+}
+
+// A resource fork has no magic, but its header has to agree with the length of the file.
+fn is_resource_fork(data: &[u8]) -> bool {
+    let Some(header) = data.get(..16) else {
+        return false;
+    };
+    let word = |i: usize| u32::from_be_bytes(header[i..i + 4].try_into().unwrap()) as u64;
+    let (data_offset, map_offset, data_len, map_len) = (word(0), word(4), word(8), word(12));
+    data_offset >= 16
+        && map_len >= 30
+        && data_offset + data_len <= map_offset
+        && map_offset + map_len <= data.len() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use binrw::BinWrite;
+
+    use super::{BlockDevice, Container, DeviceReader, Detected, Disk, FileFormat, Layout};
+    use super::{PartitionDevice, RawDevice, detect};
+    use crate::apm::ApmBuilder;
+    use crate::dc42::DiskCopy42;
+    use crate::fs::hfs::FormatOptions;
+    use crate::fs::mfs::Mfs;
+
+    const INFINITE_DSK: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/infinite.dsk"
+    ));
+
+    #[test]
+    fn reader() {
+        let mut disk = Cursor::new(vec![0; 8 * 512]);
+        let device = RawDevice::new(&mut disk).unwrap();
+        let mut part = DeviceReader::new(PartitionDevice::new(device, 2, 4).unwrap());
+        assert_eq!(part.len(), 4 * 512);
+        part.seek(SeekFrom::Start(510)).unwrap();
+        part.write_all(b"across blocks").unwrap();
+        part.write_all(&[7; 1024]).unwrap();
+        assert!(part.write_all(&[1; 1024]).is_err());
+
+        part.seek(SeekFrom::Start(510)).unwrap();
+        let mut buf = [0; 13];
+        part.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"across blocks");
+        assert_eq!(&disk.get_ref()[2 * 512 + 510..][..13], b"across blocks");
+        assert_eq!(disk.get_ref()[2 * 512 + 523 + 1023], 7);
+
+        let mut device = RawDevice::new(Cursor::new(vec![0; 8 * 512 + 100])).unwrap();
+        assert_eq!(device.block_count(), 8);
+        assert!(device.read_blocks(7, &mut [0; 1024]).is_err());
+        assert!(device.read_blocks(0, &mut [0; 100]).is_err());
+        assert!(PartitionDevice::new(device, 4, 5).is_err());
+    }
+
+    #[test]
+    fn detect_disks() {
+        let Detected::Disk { container, layout, disk } = detect(Cursor::new(INFINITE_DSK)).unwrap() else {
+            panic!("not a disk");
+        };
+        assert_eq!((container, layout), (Container::Raw, Layout::Mfs));
+        assert!(Mfs::new(&mut DeviceReader::new(disk)).is_ok());
+
+        let mut file = Cursor::new(Vec::new());
+        let img = DiskCopy42::new("Infinite HD", INFINITE_DSK.to_vec(), None).unwrap();
+        img.write(&mut file).unwrap();
+        let Detected::Disk { container, layout, disk } = detect(file).unwrap() else {
+            panic!("not a disk");
+        };
+        assert_eq!((container, layout), (Container::Dc42, Layout::Mfs));
+        assert!(matches!(disk, Disk::Memory(_)));
+
+        let mut builder = ApmBuilder::new(4 * 1024 * 1024).unwrap();
+        builder.add_hfs("Inside", 2 * 1024 * 1024, FormatOptions::default()).unwrap();
+        let mut file = Cursor::new(vec![0; 4 * 1024 * 1024]);
+        builder.write(&mut file).unwrap();
+        let Detected::Disk { layout, .. } = detect(file).unwrap() else {
+            panic!("not a disk");
+        };
+        assert_eq!(layout, Layout::Apm);
+    }
+
+    #[test]
+    fn detect_files() {
+        // an empty resource fork: the header, no data and a map with no types
+        let mut fork = Vec::new();
+        (256u32, 256u32, 0u32, 30u32).write_be(&mut Cursor::new(&mut fork)).unwrap();
+        fork.resize(256 + 28, 0);
+        fork.extend_from_slice(&[0, 30]);
+        let Detected::File { format, rsrc_fork, .. } = detect(Cursor::new(fork.clone())).unwrap() else {
+            panic!("not a file");
+        };
+        assert_eq!(format, FileFormat::ResourceFork);
+        assert_eq!(rsrc_fork, fork);
+
+        let mut double = b"\x00\x05\x16\x07\x00\x02\x00\x00".to_vec();
+        double.resize(24, 0);
+        double.extend_from_slice(&[0, 1, 0, 0, 0, 2, 0, 0, 0, 38, 0, 0, 0, 4]);
+        double.extend_from_slice(b"rsrc");
+        let Detected::File { format, rsrc_fork, .. } = detect(Cursor::new(double)).unwrap() else {
+            panic!("not a file");
+        };
+        assert_eq!(format, FileFormat::AppleDouble);
+        assert_eq!(rsrc_fork, b"rsrc");
+
+        assert!(detect(Cursor::new(vec![0x55; 4096])).is_err());
+    }
+}
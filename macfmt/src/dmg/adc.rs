@@ -0,0 +1,57 @@
+use std::io;
+
+// Apple Data Compression, the LZ77 variant Disk Copy 6 uses. Every run starts with a byte that
+// tells what follows:
+//  1xxxxxxx: x+1 literal bytes
+//  01xxxxxx oooooooo oooooooo: copy x+4 bytes from o+1 bytes back
+//  00xxxxoo oooooooo: copy x+3 bytes from o+1 bytes back
+pub fn decompress(data: &[u8], size_hint: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size_hint);
+    let mut i = 0;
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated ADC run");
+
+    while i < data.len() {
+        let b = data[i];
+        let (count, distance) = if b & 0x80 != 0 {
+            let count = (b & 0x7f) as usize + 1;
+            let literal = data.get(i + 1..i + 1 + count).ok_or_else(truncated)?;
+            out.extend_from_slice(literal);
+            i += 1 + count;
+            continue;
+        } else if b & 0x40 != 0 {
+            let hi = *data.get(i + 1).ok_or_else(truncated)? as usize;
+            let lo = *data.get(i + 2).ok_or_else(truncated)? as usize;
+            i += 3;
+            ((b & 0x3f) as usize + 4, (hi << 8 | lo) + 1)
+        } else {
+            let lo = *data.get(i + 1).ok_or_else(truncated)? as usize;
+            i += 2;
+            (((b & 0x3f) >> 2) as usize + 3, (((b & 0x03) as usize) << 8 | lo) + 1)
+        };
+
+        let Some(start) = out.len().checked_sub(distance) else {
+            let msg = "ADC copy from before the start";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        };
+        // the source may overlap what's being written, so copy a byte at a time
+        for n in 0..count {
+            out.push(out[start + n]);
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress;
+
+    #[test]
+    fn runs() {
+        // literal "abc", then 6 bytes from 3 back, then 5 bytes from 9 back
+        let data = [0x82, b'a', b'b', b'c', 0x0c, 0x02, 0x41, 0x00, 0x08];
+        assert_eq!(decompress(&data, 0).unwrap(), b"abcabcabcabcab");
+        assert!(decompress(&[0x83, b'a'], 0).is_err());
+        assert!(decompress(&[0x00, 0x05], 0).is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use derivative::Derivative;
+use flate2::read::ZlibDecoder;
+use thiserror::Error;
+
+use crate::device::{BlockDevice, check_range};
+
+pub mod adc;
+mod ndif;
+mod udif;
+
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Error, Debug)]
+pub enum DmgError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("Bad resource fork: {0}")]
+    Rsrc(#[from] crate::rsrc::RsrcError),
+    #[error("No {0} found in the image")]
+    Missing(&'static str),
+    #[error("Corrupt image: {0}")]
+    Corrupt(&'static str),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChunkKind {
+    Zero,
+    Raw,
+    Adc,
+    Zlib,
+    // bzip2, LZFSE and whatever else newer versions of Disk Copy came up with
+    Unsupported(u32),
+}
+
+// A run of sectors and where its (maybe compressed) contents are in the image file.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    kind: ChunkKind,
+    sector: u64,
+    sector_count: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl Chunk {
+    pub fn kind(&self) -> ChunkKind {
+        self.kind
+    }
+    pub fn sector(&self) -> u64 {
+        self.sector
+    }
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+    // Only called once DiskImage::new has checked that this doesn't overflow.
+    fn end(&self) -> u64 {
+        (self.sector + self.sector_count) * SECTOR_SIZE
+    }
+}
+
+// The disk inside an NDIF (Disk Copy 6) or UDIF (.dmg) image. Chunks are decompressed when
+// something in them is read, and the last one is kept around since reads tend to be sequential.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct DiskImage<R> {
+    #[derivative(Debug = "ignore")]
+    inner: R,
+    chunks: Vec<Chunk>,
+    len: u64,
+    pos: u64,
+    #[derivative(Debug = "ignore")]
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> DiskImage<R> {
+    // A .dmg, found by the "koly" trailer in its last 512 bytes.
+    pub fn open_udif(mut inner: R) -> Result<Self, DmgError> {
+        let (chunks, sectors) = udif::read_chunks(&mut inner)?;
+        Self::new(inner, chunks, sectors)
+    }
+    // Disk Copy 6 keeps the chunk table in the resource fork and the chunks in the data fork.
+    pub fn open_ndif(data_fork: R, rsrc_fork: &[u8]) -> Result<Self, DmgError> {
+        let (chunks, sectors) = ndif::read_chunks(rsrc_fork)?;
+        Self::new(data_fork, chunks, sectors)
+    }
+    // Probes for the UDIF trailer without consuming the reader.
+    pub fn is_udif(reader: &mut R) -> io::Result<bool> {
+        udif::probe(reader)
+    }
+    // Every size here comes from the image, so chunks have to stay within the disk it declares
+    // and their data within the image file.
+    fn new(mut inner: R, mut chunks: Vec<Chunk>, sectors: u64) -> Result<Self, DmgError> {
+        let input_len = inner.seek(SeekFrom::End(0))?;
+        let len = sectors
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(DmgError::Corrupt("disk size overflows"))?;
+        chunks.retain(|c| c.sector_count != 0);
+        for chunk in chunks.iter() {
+            let end = chunk.sector
+                .checked_add(chunk.sector_count)
+                .and_then(|end| end.checked_mul(SECTOR_SIZE));
+            if end.is_none_or(|end| end > len) {
+                return Err(DmgError::Corrupt("chunk past the end of the disk"));
+            }
+            let stored = !matches!(chunk.kind, ChunkKind::Zero | ChunkKind::Unsupported(_));
+            if stored && chunk.offset.checked_add(chunk.len).is_none_or(|end| end > input_len) {
+                return Err(DmgError::Corrupt("chunk data past the end of the image"));
+            }
+        }
+        chunks.sort_by_key(|c| c.sector);
+
+        Ok(Self {
+            inner,
+            chunks,
+            len,
+            pos: 0,
+            cache: None,
+        })
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn chunk_at(&self, pos: u64) -> Option<usize> {
+        let sector = pos / SECTOR_SIZE;
+        let idx = self.chunks.partition_point(|c| c.sector <= sector).checked_sub(1)?;
+        (pos < self.chunks[idx].end()).then_some(idx)
+    }
+    // What's stored for a chunk, which may come up short of its sectors. The rest of them read as
+    // zeros, so the allocation never depends on the sector count alone.
+    fn load(&mut self, idx: usize) -> io::Result<&[u8]> {
+        if !matches!(self.cache, Some((cached, _)) if cached == idx) {
+            let chunk = &self.chunks[idx];
+            let size = chunk.sector_count * SECTOR_SIZE;
+            let mut raw = Vec::new();
+            if !matches!(chunk.kind, ChunkKind::Zero | ChunkKind::Unsupported(_)) {
+                raw.resize(chunk.len as usize, 0);
+                self.inner.seek(SeekFrom::Start(chunk.offset))?;
+                self.inner.read_exact(&mut raw)?;
+            }
+            let mut data = match chunk.kind {
+                ChunkKind::Zero => Vec::new(),
+                ChunkKind::Raw => raw,
+                ChunkKind::Adc => adc::decompress(&raw, raw.len())?,
+                ChunkKind::Zlib => {
+                    let mut data = Vec::new();
+                    ZlibDecoder::new(&raw[..]).take(size).read_to_end(&mut data)?;
+                    data
+                },
+                ChunkKind::Unsupported(kind) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("chunk compression {:#010x} isn't supported", kind),
+                    ));
+                },
+            };
+            data.truncate(size as usize);
+            self.cache = Some((idx, data));
+        }
+
+        Ok(&self.cache.as_ref().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek> BlockDevice for DiskImage<R> {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE as usize
+    }
+    fn block_count(&self) -> u64 {
+        self.len / SECTOR_SIZE
+    }
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self, start, buf.len())?;
+        self.seek(SeekFrom::Start(start * SECTOR_SIZE))?;
+        self.read_exact(buf)
+    }
+}
+
+impl<R: Read + Seek> Read for DiskImage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = match self.chunk_at(self.pos) {
+            Some(idx) => {
+                let chunk = &self.chunks[idx];
+                let off = (self.pos - chunk.sector * SECTOR_SIZE) as usize;
+                let n = (buf.len() as u64).min(chunk.end() - self.pos) as usize;
+                let data = self.load(idx)?;
+                let stored = data.get(off..).unwrap_or_default();
+                let copied = n.min(stored.len());
+                buf[..copied].copy_from_slice(&stored[..copied]);
+                buf[copied..n].fill(0);
+                n
+            },
+            // sectors no chunk covers read as zeros, up to the next chunk
+            None => {
+                let next = self
+                    .chunks
+                    .iter()
+                    .map(|c| c.sector * SECTOR_SIZE)
+                    .find(|start| *start > self.pos)
+                    .unwrap_or(self.len);
+                let n = buf.len().min((next - self.pos) as usize);
+                buf[..n].fill(0);
+                n
+            },
+        };
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R> Seek for DiskImage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.len.checked_add_signed(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        let Some(new_pos) = new_pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+        self.pos = new_pos;
+
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    use super::{Chunk, ChunkKind, DiskImage, DmgError, SECTOR_SIZE};
+    use crate::fs::mfs::Mfs;
+
+    const INFINITE_DSK: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/infinite.dsk"
+    ));
+    const CHUNK_SECTORS: usize = 64;
+
+    // Splits the disk into chunks, taking turns between the compression schemes. All-zero
+    // chunks are left out of the data entirely.
+    fn split(disk: &[u8]) -> Vec<(ChunkKind, u64, u64, Vec<u8>)> {
+        let kinds = [ChunkKind::Raw, ChunkKind::Zlib, ChunkKind::Adc];
+        disk.chunks(CHUNK_SECTORS * SECTOR_SIZE as usize)
+            .enumerate()
+            .map(|(i, data)| {
+                let sector = (i * CHUNK_SECTORS) as u64;
+                let count = data.len() as u64 / SECTOR_SIZE;
+                let kind = match data.iter().all(|b| *b == 0) {
+                    true => ChunkKind::Zero,
+                    false => kinds[i % 3],
+                };
+                let packed = match kind {
+                    ChunkKind::Zero => Vec::new(),
+                    ChunkKind::Zlib => {
+                        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+                        enc.write_all(data).unwrap();
+                        enc.finish().unwrap()
+                    },
+                    // literal runs only, the decoder's own test covers the copies
+                    ChunkKind::Adc => data.chunks(128).flat_map(|run| {
+                        std::iter::once(0x80 | (run.len() - 1) as u8).chain(run.iter().copied())
+                    }).collect(),
+                    _ => data.to_vec(),
+                };
+                (kind, sector, count, packed)
+            })
+            .collect()
+    }
+
+    fn base64(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for group in data.chunks(3) {
+            let bits = group
+                .iter()
+                .enumerate()
+                .fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                out.push(match i <= group.len() {
+                    true => ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char,
+                    false => '=',
+                });
+            }
+        }
+        out
+    }
+
+    fn check(image: &mut DiskImage<Cursor<Vec<u8>>>) {
+        assert_eq!(image.len(), INFINITE_DSK.len() as u64);
+        let mut disk = Vec::new();
+        image.read_to_end(&mut disk).unwrap();
+        assert!(disk == INFINITE_DSK);
+
+        image.seek(SeekFrom::Start(0x10000 - 3)).unwrap();
+        let mut buf = [0; 6];
+        image.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, INFINITE_DSK[0x10000 - 3..][..6]);
+
+        image.rewind().unwrap();
+        let mfs = Mfs::new(image).unwrap();
+        assert_eq!(mfs.files().len(), 32);
+    }
+
+    #[test]
+    fn udif() {
+        let chunks = split(INFINITE_DSK);
+        let mut file = Vec::new();
+        let mut mish = Vec::new();
+        mish.extend_from_slice(b"mish");
+        mish.extend_from_slice(&1u32.to_be_bytes());
+        mish.extend_from_slice(&0u64.to_be_bytes());
+        mish.extend_from_slice(&(INFINITE_DSK.len() as u64 / SECTOR_SIZE).to_be_bytes());
+        mish.extend_from_slice(&0u64.to_be_bytes());
+        mish.extend_from_slice(&[0; 4 + 4 + 24 + 136]);
+        mish.extend_from_slice(&(chunks.len() as u32 + 2).to_be_bytes());
+        let push_chunk = |mish: &mut Vec<u8>, kind: u32, fields: [u64; 4]| {
+            mish.extend_from_slice(&kind.to_be_bytes());
+            mish.extend_from_slice(&0u32.to_be_bytes());
+            for v in fields {
+                mish.extend_from_slice(&v.to_be_bytes());
+            }
+        };
+        push_chunk(&mut mish, 0x7fff_fffe, [0; 4]);
+        for (kind, sector, count, data) in chunks.iter() {
+            let kind = match kind {
+                ChunkKind::Zero => 2,
+                ChunkKind::Raw => 1,
+                ChunkKind::Adc => 0x8000_0004,
+                ChunkKind::Zlib => 0x8000_0005,
+                ChunkKind::Unsupported(kind) => *kind,
+            };
+            push_chunk(&mut mish, kind, [*sector, *count, file.len() as u64, data.len() as u64]);
+            file.extend_from_slice(data);
+        }
+        let end = INFINITE_DSK.len() as u64 / SECTOR_SIZE;
+        push_chunk(&mut mish, 0xffff_ffff, [end, 0, file.len() as u64, 0]);
+
+        let data_len = file.len() as u64;
+        let xml = format!(
+            "<?xml version=\"1.0\"?>\n<plist version=\"1.0\">\n<dict>\n\
+             <key>resource-fork</key>\n<dict>\n<key>blkx</key>\n<array>\n<dict>\n\
+             <key>Attributes</key>\n<string>0x0050</string>\n\
+             <key>Data</key>\n<data>\n{}\n</data>\n\
+             </dict>\n</array>\n</dict>\n</dict>\n</plist>\n",
+            base64(&mish)
+        );
+        let xml_offset = file.len() as u64;
+        file.extend_from_slice(xml.as_bytes());
+
+        let mut koly = Vec::new();
+        koly.extend_from_slice(b"koly");
+        koly.extend_from_slice(&4u32.to_be_bytes());
+        koly.extend_from_slice(&512u32.to_be_bytes());
+        koly.extend_from_slice(&1u32.to_be_bytes());
+        for v in [0u64, 0, data_len, 0, 0] {
+            koly.extend_from_slice(&v.to_be_bytes());
+        }
+        koly.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]);
+        koly.extend_from_slice(&[0; 16 + 136]);
+        koly.extend_from_slice(&xml_offset.to_be_bytes());
+        koly.extend_from_slice(&(xml.len() as u64).to_be_bytes());
+        koly.extend_from_slice(&[0; 120 + 136 + 4]);
+        koly.extend_from_slice(&(INFINITE_DSK.len() as u64 / SECTOR_SIZE).to_be_bytes());
+        koly.resize(512, 0);
+        file.extend_from_slice(&koly);
+
+        let mut file = Cursor::new(file);
+        assert!(DiskImage::is_udif(&mut file).unwrap());
+        let mut image = DiskImage::open_udif(file).unwrap();
+        check(&mut image);
+
+        assert!(!DiskImage::is_udif(&mut Cursor::new(INFINITE_DSK.to_vec())).unwrap());
+    }
+
+    #[test]
+    fn ndif() {
+        let chunks = split(INFINITE_DSK);
+        let mut data_fork = Vec::new();
+        let mut bcem = vec![0; 128];
+        bcem[1] = 1;
+        bcem[2] = 8;
+        bcem[3..11].copy_from_slice(b"Infinite");
+        bcem[0x42..0x46].copy_from_slice(&(INFINITE_DSK.len() as u32 / 512).to_be_bytes());
+        bcem[0x7c..0x80].copy_from_slice(&(chunks.len() as u32 + 1).to_be_bytes());
+        let push_chunk = |bcem: &mut Vec<u8>, kind: u8, sector: u64, offset: usize, len: usize| {
+            bcem.extend_from_slice(&(sector as u32).to_be_bytes()[1..]);
+            bcem.push(kind);
+            bcem.extend_from_slice(&(offset as u32).to_be_bytes());
+            bcem.extend_from_slice(&(len as u32).to_be_bytes());
+        };
+        for (kind, sector, _, data) in chunks.iter() {
+            // NDIF has no zlib, so those go in uncompressed
+            let (kind, data) = match kind {
+                ChunkKind::Zero => (0x00, &[][..]),
+                ChunkKind::Adc => (0x80, &data[..]),
+                _ => (0x02, &INFINITE_DSK[*sector as usize * 512..][..CHUNK_SECTORS * 512]),
+            };
+            push_chunk(&mut bcem, kind, *sector, data_fork.len(), data.len());
+            data_fork.extend_from_slice(data);
+        }
+        push_chunk(&mut bcem, 0xff, INFINITE_DSK.len() as u64 / SECTOR_SIZE, data_fork.len(), 0);
+
+        // a resource fork holding nothing but 'bcem' 128
+        let map_len = 28 + 2 + 8 + 12;
+        let mut rsrc = Vec::new();
+        for v in [256, 256 + 4 + bcem.len(), 4 + bcem.len(), map_len] {
+            rsrc.extend_from_slice(&(v as u32).to_be_bytes());
+        }
+        rsrc.resize(256, 0);
+        rsrc.extend_from_slice(&(bcem.len() as u32).to_be_bytes());
+        rsrc.extend_from_slice(&bcem);
+        rsrc.extend_from_slice(&[0; 16 + 4 + 2 + 2]);
+        rsrc.extend_from_slice(&[0, 28, 0, map_len as u8, 0, 0]);
+        rsrc.extend_from_slice(b"bcem\x00\x00\x00\x0a");
+        rsrc.extend_from_slice(&[0, 128, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut image = DiskImage::open_ndif(Cursor::new(data_fork), &rsrc).unwrap();
+        assert_eq!(image.chunks().len(), chunks.len());
+        check(&mut image);
+
+        assert!(DiskImage::open_ndif(Cursor::new(Vec::new()), &rsrc[..255]).is_err());
+    }
+
+    #[test]
+    fn untrusted_sizes() {
+        let chunk = |kind, sector, sector_count, offset, len| Chunk { kind, sector, sector_count, offset, len };
+        let open = |chunks, sectors| DiskImage::new(Cursor::new(vec![0; 1024]), chunks, sectors);
+        let corrupt = |result: Result<DiskImage<_>, DmgError>| matches!(result, Err(DmgError::Corrupt(_)));
+        assert!(corrupt(open(vec![], u64::MAX)));
+        assert!(corrupt(open(vec![chunk(ChunkKind::Zero, 1, 2, 0, 0)], 2)));
+        assert!(corrupt(open(vec![chunk(ChunkKind::Zero, u64::MAX, 2, 0, 0)], 2)));
+        assert!(corrupt(open(vec![chunk(ChunkKind::Raw, 0, 1, 1000, 100)], 1)));
+        assert!(corrupt(open(vec![chunk(ChunkKind::Raw, 0, 1, u64::MAX, 2)], 1)));
+        assert!(open(vec![chunk(ChunkKind::Zero, 0, 1 << 40, 0, 0)], 1 << 40).is_ok());
+
+        // a chunk that inflates to far more than its sectors only gives those
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&[0x55; 1 << 20]).unwrap();
+        let packed = enc.finish().unwrap();
+        let len = packed.len() as u64;
+        let mut image = DiskImage::new(Cursor::new(packed), vec![chunk(ChunkKind::Zlib, 0, 1, 0, len)], 2).unwrap();
+        let mut disk = Vec::new();
+        image.read_to_end(&mut disk).unwrap();
+        assert_eq!(disk.len(), 1024);
+        assert!(disk[..512].iter().all(|b| *b == 0x55) && disk[512..].iter().all(|b| *b == 0));
+        assert_eq!(image.cache.as_ref().unwrap().1.len(), 512);
+    }
+}
@@ -0,0 +1,73 @@
+use binrw::BinRead;
+use binrw::io::Cursor;
+use derivative::Derivative;
+
+use super::{Chunk, ChunkKind, DmgError};
+use crate::common::{PascalString, SizedString};
+use crate::rsrc::{RawResource, ResourceType};
+
+// The 'bcem' resource Disk Copy 6 uses to map the data fork to sectors. Only the fields needed
+// to find the chunks are known, the rest of the 128-byte header is left alone.
+#[derive(BinRead, Derivative)]
+#[derivative(Debug)]
+#[br(big)]
+struct Bcem {
+    _version: u16,
+    _name: PascalString<63>,
+    sector_count: u32,
+    #[derivative(Debug = "ignore")]
+    _unknown: [u8; 0x7c - 0x46],
+    chunk_count: u32,
+    #[br(count = chunk_count)]
+    chunks: Vec<BcemChunk>,
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+struct BcemChunk {
+    #[br(parse_with = binrw::helpers::read_u24)]
+    sector: u32,
+    kind: u8,
+    offset: u32,
+    len: u32,
+}
+
+impl BcemChunk {
+    fn kind(&self) -> ChunkKind {
+        match self.kind {
+            0x00 => ChunkKind::Zero,
+            0x02 => ChunkKind::Raw,
+            0x80 => ChunkKind::Adc,
+            other => ChunkKind::Unsupported(other as u32),
+        }
+    }
+}
+
+pub(super) fn read_chunks(rsrc_fork: &[u8]) -> Result<(Vec<Chunk>, u64), DmgError> {
+    let rsrc = RawResource::read(&mut Cursor::new(rsrc_fork))?;
+    let bcem = ResourceType::from(SizedString::new(*b"bcem"));
+    let Some((_, _, data)) = rsrc.resources_of(&bcem)?.into_iter().next() else {
+        return Err(DmgError::Missing("bcem resource"));
+    };
+    let bcem = Bcem::read(&mut Cursor::new(data))?;
+
+    // Each chunk runs up to where the next one starts, and an 0xff one marks the end of the disk.
+    let mut chunks = Vec::new();
+    for (chunk, next) in bcem.chunks.iter().zip(bcem.chunks.iter().skip(1)) {
+        if chunk.kind == 0xff {
+            break;
+        }
+        let Some(sector_count) = next.sector.checked_sub(chunk.sector) else {
+            return Err(DmgError::Corrupt("bcem chunks out of order"));
+        };
+        chunks.push(Chunk {
+            kind: chunk.kind(),
+            sector: chunk.sector as u64,
+            sector_count: sector_count as u64,
+            offset: chunk.offset as u64,
+            len: chunk.len as u64,
+        });
+    }
+
+    Ok((chunks, bcem.sector_count as u64))
+}
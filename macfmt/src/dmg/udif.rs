@@ -0,0 +1,222 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use binrw::BinRead;
+use derivative::Derivative;
+
+use super::{Chunk, ChunkKind, DmgError};
+use crate::common::SizedString;
+use crate::rsrc::{RawResource, ResourceType};
+
+const KOLY_SIZE: u64 = 512;
+
+#[derive(BinRead, Derivative)]
+#[derivative(Debug)]
+#[br(big, magic = b"koly")]
+struct Koly {
+    version: u32,
+    header_size: u32,
+    _flags: u32,
+    _running_data_fork_offset: u64,
+    data_fork_offset: u64,
+    _data_fork_len: u64,
+    rsrc_fork_offset: u64,
+    rsrc_fork_len: u64,
+    _segment_number: u32,
+    _segment_count: u32,
+    _segment_id: [u8; 16],
+    #[derivative(Debug = "ignore")]
+    _data_checksum: Checksum,
+    xml_offset: u64,
+    xml_len: u64,
+    #[derivative(Debug = "ignore")]
+    _reserved: [u8; 120],
+    #[derivative(Debug = "ignore")]
+    _checksum: Checksum,
+    _image_variant: u32,
+    sector_count: u64,
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+struct Checksum {
+    _kind: u32,
+    _bits: u32,
+    _data: [u32; 32],
+}
+
+// One "mish" block per partition, each with its own list of chunks.
+#[derive(BinRead, Derivative)]
+#[derivative(Debug)]
+#[br(big, magic = b"mish")]
+struct BlkxTable {
+    _version: u32,
+    first_sector: u64,
+    _sector_count: u64,
+    data_offset: u64,
+    _buffers_needed: u32,
+    _block_descriptors: u32,
+    _reserved: [u32; 6],
+    #[derivative(Debug = "ignore")]
+    _checksum: Checksum,
+    chunk_count: u32,
+    #[br(count = chunk_count)]
+    chunks: Vec<BlkxChunk>,
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+struct BlkxChunk {
+    kind: u32,
+    _comment: u32,
+    sector: u64,
+    sector_count: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl BlkxChunk {
+    fn kind(&self) -> Option<ChunkKind> {
+        match self.kind {
+            0x0000_0000 | 0x0000_0002 => Some(ChunkKind::Zero),
+            0x0000_0001 => Some(ChunkKind::Raw),
+            0x8000_0004 => Some(ChunkKind::Adc),
+            0x8000_0005 => Some(ChunkKind::Zlib),
+            // comments and the end marker
+            0x7fff_fffe | 0xffff_ffff => None,
+            other => Some(ChunkKind::Unsupported(other)),
+        }
+    }
+}
+
+pub(super) fn probe<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    let start = reader.stream_position()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut magic = [0u8; 4];
+    let found = len >= KOLY_SIZE && {
+        reader.seek(SeekFrom::End(-(KOLY_SIZE as i64)))?;
+        reader.read_exact(&mut magic)?;
+        &magic == b"koly"
+    };
+    reader.seek(SeekFrom::Start(start))?;
+
+    Ok(found)
+}
+
+pub(super) fn read_chunks<R: Read + Seek>(reader: &mut R) -> Result<(Vec<Chunk>, u64), DmgError> {
+    if !probe(reader)? {
+        return Err(DmgError::Missing("UDIF trailer"));
+    }
+    reader.seek(SeekFrom::End(-(KOLY_SIZE as i64)))?;
+    let koly = Koly::read(reader)?;
+    if koly.version != 4 || koly.header_size != KOLY_SIZE as u32 {
+        return Err(DmgError::Corrupt("unknown UDIF version"));
+    }
+
+    // Newer images describe their partitions in an XML property list, the oldest ones in a
+    // resource fork appended to the data.
+    let tables = if koly.xml_len != 0 {
+        let xml = read_at(reader, koly.xml_offset, koly.xml_len)?;
+        blkx_from_plist(&String::from_utf8_lossy(&xml))?
+    } else if koly.rsrc_fork_len != 0 {
+        let rsrc = read_at(reader, koly.rsrc_fork_offset, koly.rsrc_fork_len)?;
+        let rsrc = RawResource::read(&mut Cursor::new(rsrc))?;
+        let blkx = ResourceType::from(SizedString::new(*b"blkx"));
+        rsrc.resources_of(&blkx)?.into_iter().map(|(_, _, data)| data.to_vec()).collect()
+    } else {
+        return Err(DmgError::Missing("partition list"));
+    };
+
+    let mut chunks = Vec::new();
+    for table in tables {
+        let table = BlkxTable::read(&mut Cursor::new(table))?;
+        for chunk in table.chunks.iter() {
+            let Some(kind) = chunk.kind() else {
+                continue;
+            };
+            let sector = table.first_sector.checked_add(chunk.sector);
+            let offset = koly.data_fork_offset
+                .checked_add(table.data_offset)
+                .and_then(|offset| offset.checked_add(chunk.offset));
+            let (Some(sector), Some(offset)) = (sector, offset) else {
+                return Err(DmgError::Corrupt("chunk position overflows"));
+            };
+            chunks.push(Chunk {
+                kind,
+                sector,
+                sector_count: chunk.sector_count,
+                offset,
+                len: chunk.len,
+            });
+        }
+    }
+
+    Ok((chunks, koly.sector_count))
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: u64) -> Result<Vec<u8>, DmgError> {
+    let input_len = reader.seek(SeekFrom::End(0))?;
+    if offset.checked_add(len).is_none_or(|end| end > input_len) {
+        return Err(DmgError::Corrupt("partition list past the end of the image"));
+    }
+    let mut buf = vec![0; len as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Just enough of a property list parser to dig out resource-fork/blkx/*/Data, which is all
+// that's needed to find the chunks.
+fn blkx_from_plist(xml: &str) -> Result<Vec<Vec<u8>>, DmgError> {
+    let corrupt = || DmgError::Corrupt("malformed property list");
+    let start = xml.find("<key>blkx</key>").ok_or(DmgError::Missing("blkx list"))?;
+    let array = &xml[start..];
+    let array = &array[..array.find("</array>").ok_or_else(corrupt)?];
+
+    let mut tables = Vec::new();
+    let mut rest = array;
+    while let Some(key) = rest.find("<key>Data</key>") {
+        rest = &rest[key..];
+        let data = rest.find("<data>").ok_or_else(corrupt)? + "<data>".len();
+        let end = rest.find("</data>").ok_or_else(corrupt)?;
+        tables.push(base64_decode(rest.get(data..end).ok_or_else(corrupt)?).ok_or_else(corrupt)?);
+        rest = &rest[end..];
+    }
+
+    Ok(tables)
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for ch in s.bytes().filter(|b| !b.is_ascii_whitespace() && *b != b'=') {
+        let val = match ch {
+            b'A'..=b'Z' => ch - b'A',
+            b'a'..=b'z' => ch - b'a' + 26,
+            b'0'..=b'9' => ch - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | val as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::base64_decode;
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_decode("bWlzaA==").unwrap(), b"mish");
+        assert_eq!(base64_decode("AAEC\n\t/w==").unwrap(), [0, 1, 2, 0xff]);
+        assert!(base64_decode("a*b").is_none());
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use binrw::BinWrite;
+use binrw::io::Cursor;
+use libc::{EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, ERANGE};
+use fuser::{Filesystem, Request, MountOption, FileType, ReplyEntry, ReplyAttr, ReplyData, ReplyDirectory, ReplyXattr, FileAttr};
+
+use super::lazy::{DirEntry, LazyHfsVolume};
+use super::{Cnid, Directory, File, Fork, HfsError};
+use crate::common::{ExtraFinderInfo, FinderInfo};
+
+const TTL: std::time::Duration = std::time::Duration::from_secs(1);
+
+const ROOT_INO: u64 = 1;
+
+const FINDER_INFO_XATTR: &str = "com.apple.FinderInfo";
+
+const DIR_ATTR: FileAttr = FileAttr {
+    ino: 0,
+    size: 0,
+    blocks: 0,
+    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::Directory,
+    perm: 0o555,
+    nlink: 2,
+    uid: 501,
+    gid: 20,
+    rdev: 0,
+    flags: 0,
+    blksize: 512,
+};
+
+const FILE_ATTR: FileAttr = FileAttr {
+    ino: 0,
+    size: 0,
+    blocks: 0,
+    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::RegularFile,
+    perm: 0o444,
+    nlink: 1,
+    uid: 501,
+    gid: 20,
+    rdev: 0,
+    flags: 0,
+    blksize: 512,
+};
+
+// A read-only mount of an HFS volume. Resource forks show up as separate files with a ".rsrc"
+// suffix, and the Finder info of files and directories as the "com.apple.FinderInfo" xattr.
+pub struct HfsFuse<R: Read + Seek> {
+    vol: LazyHfsVolume<R>,
+    // everything the kernel has been handed an inode for, indexed by CNID
+    entries: HashMap<u32, DirEntry>,
+}
+
+impl<R: Read + Seek> HfsFuse<R> {
+    pub fn new(mut vol: LazyHfsVolume<R>) -> Result<Self, HfsError> {
+        let root = vol.root()?;
+        let mut entries = HashMap::new();
+        entries.insert(root.id().as_u32(), DirEntry::Directory(root));
+
+        Ok(Self {
+            vol,
+            entries,
+        })
+    }
+    pub fn mount(self, dir: &Path) -> std::io::Result<()> {
+        let opts = [
+            MountOption::FSName("hfs".to_string()),
+            MountOption::RO,
+        ];
+        fuser::mount2(self, dir, &opts)
+    }
+
+    // Inode numbers keep the fork in their lowest bit, except for the root which FUSE expects
+    // to be 1.
+    fn ino(id: Cnid, fork: Fork) -> u64 {
+        match id {
+            Cnid::RootDir | Cnid::ParentOfRoot => ROOT_INO,
+            _ => (id.as_u32() as u64) << 1 | (fork == Fork::Resource) as u64,
+        }
+    }
+    fn entry_by_ino(&self, ino: u64) -> Option<(&DirEntry, Fork)> {
+        let (id, fork) = if ino == ROOT_INO {
+            (Cnid::RootDir.as_u32(), Fork::Data)
+        } else if ino & 1 == 1 {
+            ((ino >> 1) as u32, Fork::Resource)
+        } else {
+            ((ino >> 1) as u32, Fork::Data)
+        };
+        let entry = self.entries.get(&id)?;
+        match entry {
+            DirEntry::Directory(_) if fork == Fork::Resource => None,
+            _ => Some((entry, fork)),
+        }
+    }
+    fn dir_by_ino(&self, ino: u64) -> Result<Directory, i32> {
+        match self.entry_by_ino(ino) {
+            Some((DirEntry::Directory(dir), _)) => Ok(dir.clone()),
+            Some((DirEntry::File(_), _)) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn dir_attr(&self, dir: &Directory) -> FileAttr {
+        let mut attr = DIR_ATTR;
+        attr.ino = Self::ino(dir.id(), Fork::Data);
+        attr.atime = dir.mtime().to_system_time();
+        attr.mtime = dir.mtime().to_system_time();
+        attr.ctime = dir.mtime().to_system_time();
+        attr.crtime = dir.ctime().to_system_time();
+
+        attr
+    }
+    fn file_attr(&self, file: &File, fork: Fork) -> FileAttr {
+        let mut attr = FILE_ATTR;
+        attr.ino = Self::ino(file.id(), fork);
+        attr.size = file.fork_len(fork) as u64;
+        attr.blocks = attr.size.div_ceil(512);
+        attr.atime = file.mtime().to_system_time();
+        attr.mtime = file.mtime().to_system_time();
+        attr.ctime = file.mtime().to_system_time();
+        attr.crtime = file.ctime().to_system_time();
+
+        attr
+    }
+    fn attr(&self, entry: &DirEntry, fork: Fork) -> FileAttr {
+        match entry {
+            DirEntry::Directory(dir) => self.dir_attr(dir),
+            DirEntry::File(file) => self.file_attr(file, fork),
+        }
+    }
+
+    // HFS names may contain slashes but not colons, so they're swapped like macOS does.
+    fn host_name(name: &str) -> String {
+        name.replace('/', ":")
+    }
+    fn hfs_name(name: &str) -> String {
+        name.replace(':', "/")
+    }
+
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<(DirEntry, Fork), i32> {
+        let dir = self.dir_by_ino(parent)?;
+        let name = Self::hfs_name(&name.to_string_lossy());
+        let found = self.vol.lookup(&dir, &name).map_err(|_| EIO)?;
+        let (entry, fork) = match (found, name.strip_suffix(".rsrc")) {
+            (Some(entry), _) => (entry, Fork::Data),
+            (None, Some(name)) => match self.vol.lookup(&dir, name).map_err(|_| EIO)? {
+                Some(entry @ DirEntry::File(_)) => (entry, Fork::Resource),
+                _ => return Err(ENOENT),
+            },
+            (None, None) => return Err(ENOENT),
+        };
+        self.entries.insert(entry.id().as_u32(), entry.clone());
+
+        Ok((entry, fork))
+    }
+    fn read_fork(&mut self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, i32> {
+        let (file, fork) = match self.entry_by_ino(ino) {
+            Some((DirEntry::File(file), fork)) => (file.clone(), fork),
+            Some((DirEntry::Directory(_), _)) => return Err(EISDIR),
+            None => return Err(ENOENT),
+        };
+        let mut reader = self.vol.file_reader(&file, fork).map_err(|_| EIO)?;
+        reader.seek(SeekFrom::Start(offset.max(0) as u64)).map_err(|_| EIO)?;
+        let mut buf = Vec::with_capacity(size as usize);
+        reader.take(size as u64).read_to_end(&mut buf).map_err(|_| EIO)?;
+
+        Ok(buf)
+    }
+    fn finder_info(&self, ino: u64) -> Result<Vec<u8>, i32> {
+        let (finder_info, more_finder_info): (&FinderInfo, &ExtraFinderInfo) = match self.entry_by_ino(ino) {
+            Some((DirEntry::File(file), _)) => (file.finder_info(), file.more_finder_info()),
+            Some((DirEntry::Directory(dir), _)) => (dir.finder_info(), dir.more_finder_info()),
+            None => return Err(ENOENT),
+        };
+        let mut buf = Cursor::new(Vec::new());
+        finder_info.write_be(&mut buf).map_err(|_| EIO)?;
+        more_finder_info.write_be(&mut buf).map_err(|_| EIO)?;
+
+        Ok(buf.into_inner())
+    }
+}
+
+// A zero size asks for the length of the value only.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+impl<R: Read + Seek> Filesystem for HfsFuse<R> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_entry(parent, name) {
+            Ok((entry, fork)) => reply.entry(&TTL, &self.attr(&entry, fork), 0),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.entry_by_ino(ino) {
+            Some((entry, fork)) => reply.attr(&TTL, &self.attr(entry, fork)),
+            None => reply.error(ENOENT),
+        }
+    }
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_fork(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let dir = match self.dir_by_ino(ino) {
+            Ok(dir) => dir,
+            Err(err) => {
+                reply.error(err);
+                return;
+            },
+        };
+        let Ok(contents) = self.vol.read_dir(&dir) else {
+            reply.error(EIO);
+            return;
+        };
+
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (Self::ino(dir.parent(), Fork::Data), FileType::Directory, "..".to_string()),
+        ];
+        for entry in contents {
+            let name = Self::host_name(entry.name());
+            match &entry {
+                DirEntry::Directory(subdir) => {
+                    entries.push((Self::ino(subdir.id(), Fork::Data), FileType::Directory, name));
+                },
+                DirEntry::File(file) => {
+                    if file.rsrc_len() != 0 {
+                        entries.push((Self::ino(file.id(), Fork::Resource), FileType::RegularFile, format!("{}.rsrc", name)));
+                    }
+                    entries.push((Self::ino(file.id(), Fork::Data), FileType::RegularFile, name));
+                },
+            }
+            self.entries.insert(entry.id().as_u32(), entry);
+        }
+
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            // i + 1 means the index of the next entry
+            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        if name != FINDER_INFO_XATTR {
+            reply.error(ENODATA);
+            return;
+        }
+        match self.finder_info(ino) {
+            Ok(data) => reply_xattr(reply, size, &data),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        if self.entry_by_ino(ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+        reply_xattr(reply, size, format!("{}\0", FINDER_INFO_XATTR).as_bytes());
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use binrw::{BinRead, Endian};
+use binrw::io::{Read, Seek, SeekFrom};
+
+use super::{
+    BTreeNode, BTreeRecord, CatalogKey, CatalogRecord, CatalogRecordData, Cnid, Directory,
+    ExtDescriptor, ExtentsOverflowFile, File, FileReader, Fork, HeaderNode, HfsError, Mdb,
+    NodeType,
+};
+
+const CACHE_NODES: usize = 256;
+
+#[derive(Debug, Clone)]
+pub enum DirEntry {
+    File(File),
+    // only the directory itself, its contents have to be listed with `read_dir`
+    Directory(Directory),
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            DirEntry::File(file) => file.name(),
+            DirEntry::Directory(dir) => dir.name(),
+        }
+    }
+    pub fn id(&self) -> Cnid {
+        match self {
+            DirEntry::File(file) => file.id(),
+            DirEntry::Directory(dir) => dir.id(),
+        }
+    }
+}
+
+// Keeps the most recently loaded catalog nodes around, dropping the oldest ones first.
+struct NodeCache {
+    nodes: HashMap<u32, Rc<BTreeNode<CatalogRecord>>>,
+    order: VecDeque<u32>,
+}
+
+impl NodeCache {
+    fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    fn get(&self, n: u32) -> Option<Rc<BTreeNode<CatalogRecord>>> {
+        self.nodes.get(&n).cloned()
+    }
+    fn insert(&mut self, n: u32, node: Rc<BTreeNode<CatalogRecord>>) {
+        if self.order.len() >= CACHE_NODES && let Some(old) = self.order.pop_front() {
+            self.nodes.remove(&old);
+        }
+        self.order.push_back(n);
+        self.nodes.insert(n, node);
+    }
+}
+
+// A read-only view of an HFS volume which only reads the catalog nodes it needs, instead of
+// parsing the whole volume up front like `HfsVolume`.
+pub struct LazyHfsVolume<R: Read + Seek> {
+    mdb: Mdb,
+    base: u64,
+    reader: R,
+    extents_overflow: ExtentsOverflowFile,
+    catalog_extents: Vec<ExtDescriptor>,
+    catalog_header: HeaderNode,
+    cache: NodeCache,
+}
+
+impl<R: Read + Seek> LazyHfsVolume<R> {
+    pub fn new(mut reader: R) -> Result<Self, HfsError> {
+        let base = reader.stream_position()?;
+        reader.seek(SeekFrom::Start(base + 1024))?;
+        let mdb = Mdb::read(&mut reader)?;
+
+        let alloc_start = base + mdb.alloc_block_offset(0) as u64;
+        let extents_overflow = {
+            let mut fork = FileReader::new(
+                &mut reader,
+                alloc_start,
+                mdb.alloc_blk_size,
+                mdb.extents_overflow_record.extents(),
+                mdb.extents_overflow_size,
+            );
+            ExtentsOverflowFile::read_options(&mut fork, Endian::Big, ())?
+        };
+        let catalog_extents = extents_overflow.fork_extents(&mdb.catalog_file_extent, Fork::Data, Cnid::CatalogFile)?;
+        let catalog_header = {
+            let mut fork = FileReader::new(
+                &mut reader,
+                alloc_start,
+                mdb.alloc_blk_size,
+                catalog_extents.clone(),
+                mdb.catalog_file_size,
+            );
+            HeaderNode::read(&mut fork)?
+        };
+
+        Ok(Self {
+            mdb,
+            base,
+            reader,
+            extents_overflow,
+            catalog_extents,
+            catalog_header,
+            cache: NodeCache::new(),
+        })
+    }
+    pub fn name(&self) -> String {
+        self.mdb.name.decode()
+    }
+    fn alloc_start(&self) -> u64 {
+        self.base + self.mdb.alloc_block_offset(0) as u64
+    }
+    fn node(&mut self, n: u32) -> Result<Rc<BTreeNode<CatalogRecord>>, HfsError> {
+        if let Some(node) = self.cache.get(n) {
+            return Ok(node);
+        }
+        if n == 0 || n >= self.catalog_header.header_record.node_count {
+            return Err(HfsError::Corrupt(format!("catalog node {} is out of range", n)));
+        }
+
+        let alloc_start = self.alloc_start();
+        let mut fork = FileReader::new(
+            &mut self.reader,
+            alloc_start,
+            self.mdb.alloc_blk_size,
+            self.catalog_extents.clone(),
+            self.mdb.catalog_file_size,
+        );
+        fork.seek(SeekFrom::Start(n as u64 * super::NODE_SIZE as u64))?;
+        let node = Rc::new(BTreeNode::read_options(&mut fork, Endian::Big, ())?);
+        self.cache.insert(n, node.clone());
+
+        Ok(node)
+    }
+    // Returns the leaf node which contains (or would contain) `key`, and the position of the
+    // first record not smaller than it.
+    fn seek_leaf(&mut self, key: &CatalogKey) -> Result<Option<(u32, usize)>, HfsError> {
+        let hr = &self.catalog_header.header_record;
+        if hr.root == 0 {
+            return Ok(None);
+        }
+        let depth = hr.depth;
+        let mut n = hr.root;
+        for _ in 0..depth {
+            let node = self.node(n)?;
+            match node.desc.ty {
+                NodeType::Leaf => {
+                    let pos = node.recs.partition_point(|rec| rec.key() < *key);
+                    return Ok(Some((n, pos)));
+                },
+                NodeType::Index => {
+                    n = node.recs
+                        .iter()
+                        .take_while(|rec| rec.key() <= *key)
+                        .last()
+                        .or(node.recs.first())
+                        .and_then(|rec| rec.child())
+                        .ok_or_else(|| HfsError::Corrupt(format!("empty catalog index node {}", n)))?;
+                },
+                ty => return Err(HfsError::Corrupt(format!("unexpected {:?} node {} in the catalog", ty, n))),
+            }
+        }
+
+        Err(HfsError::Corrupt("catalog B-tree is deeper than its header says".to_string()))
+    }
+    // Collects leaf records in key order, starting at `key` for as long as `keep` holds.
+    fn records_from(&mut self, key: &CatalogKey, mut keep: impl FnMut(&CatalogRecord) -> bool) -> Result<Vec<CatalogRecord>, HfsError> {
+        let mut recs = Vec::new();
+        let Some((mut n, mut pos)) = self.seek_leaf(key)? else {
+            return Ok(recs);
+        };
+        let mut nodes_left = self.catalog_header.header_record.node_count;
+        while n != 0 && nodes_left > 0 {
+            let node = self.node(n)?;
+            for rec in node.recs.iter().skip(pos) {
+                if !keep(rec) {
+                    return Ok(recs);
+                }
+                recs.push(rec.clone());
+            }
+            n = node.desc.forward_link;
+            pos = 0;
+            nodes_left -= 1;
+        }
+
+        Ok(recs)
+    }
+    fn find(&mut self, key: &CatalogKey) -> Result<Option<CatalogRecord>, HfsError> {
+        let mut first = true;
+        let recs = self.records_from(key, |rec| std::mem::take(&mut first) && rec.key() == *key)?;
+        Ok(recs.into_iter().next())
+    }
+    pub fn root(&mut self) -> Result<Directory, HfsError> {
+        self.records_from(&CatalogKey::thread(Cnid::ParentOfRoot), |rec| rec.key().parent_id == Cnid::ParentOfRoot)?
+            .into_iter()
+            .find_map(|rec| match entry(rec) {
+                Some(DirEntry::Directory(dir)) => Some(dir),
+                _ => None,
+            })
+            .ok_or_else(|| HfsError::Corrupt("no root directory record".to_string()))
+    }
+    pub fn read_dir(&mut self, dir: &Directory) -> Result<Vec<DirEntry>, HfsError> {
+        let id = dir.id();
+        let recs = self.records_from(&CatalogKey::thread(id), |rec| rec.key().parent_id == id)?;
+        Ok(recs.into_iter().filter_map(entry).collect())
+    }
+    pub fn lookup(&mut self, dir: &Directory, name: &str) -> Result<Option<DirEntry>, HfsError> {
+        Ok(self.find(&CatalogKey::new(dir.id(), name))?.and_then(entry))
+    }
+    // Only directories, and the files that have one, can be found through their thread record.
+    // Other files aren't found: that would take a scan of the whole catalog.
+    pub fn entry_by_id(&mut self, id: Cnid) -> Result<Option<DirEntry>, HfsError> {
+        if let Some(CatalogRecord::Leaf {
+            data: CatalogRecordData::DirectoryThread { parent_id, name } | CatalogRecordData::FileThread { parent_id, name },
+            ..
+        }) = self.find(&CatalogKey::thread(id))? {
+            return Ok(self.find(&CatalogKey::new(parent_id, name.decode()))?.and_then(entry));
+        }
+        Ok(None)
+    }
+    pub fn file_reader<'a>(&'a mut self, file: &File, fork: Fork) -> Result<FileReader<'a, R>, HfsError> {
+        let Some(CatalogRecord::Leaf { data: CatalogRecordData::File { data_extent, data_len, rsrc_extent, rsrc_len, .. }, .. }) =
+            self.find(&file.key())?
+        else {
+            return Err(HfsError::NotFound(file.name().to_string()));
+        };
+        let (first_extents, len) = match fork {
+            Fork::Data => (data_extent, data_len),
+            Fork::Resource => (rsrc_extent, rsrc_len),
+        };
+        let extents = self.extents_overflow.fork_extents(&first_extents, fork, file.id())?;
+        let alloc_start = self.alloc_start();
+
+        Ok(FileReader::new(&mut self.reader, alloc_start, self.mdb.alloc_blk_size, extents, len))
+    }
+    pub fn file_contents(&mut self, file: &File, fork: Fork) -> Result<Vec<u8>, HfsError> {
+        let mut buf = Vec::new();
+        self.file_reader(file, fork)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+fn entry(rec: CatalogRecord) -> Option<DirEntry> {
+    let CatalogRecord::Leaf { name, parent_id, data, .. } = rec else {
+        return None;
+    };
+    Directory::from_record(name.as_str(), parent_id, &data)
+        .map(DirEntry::Directory)
+        .or_else(|| File::from_record(name.as_str(), parent_id, &data).map(DirEntry::File))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::{DirEntry, LazyHfsVolume};
+    use crate::fs::hfs::{Fork, HfsError};
+
+    use flate2::read::GzDecoder;
+
+    static HD_100MB: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/100mb-hfs.hda.gz"
+    ));
+
+    #[test]
+    fn browse_blank_volume() {
+        let mut decoder = GzDecoder::new(HD_100MB);
+        let mut vec = Vec::new();
+        decoder.read_to_end(&mut vec).unwrap();
+        let mut disk = Cursor::new(vec);
+        disk.set_position(96 * 512);
+        let mut vol = LazyHfsVolume::new(disk).unwrap();
+        assert_eq!(vol.name(), "Blank 100MB");
+
+        let root = vol.root().unwrap();
+        let names: Vec<String> = vol.read_dir(&root)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(names, ["Desktop DB", "Desktop DF", "Desktop Folder", "Trash"]);
+
+        let Some(DirEntry::File(desktop_db)) = vol.lookup(&root, "desktop db").unwrap() else {
+            panic!("Desktop DB is missing");
+        };
+        assert_eq!(vol.file_contents(&desktop_db, Fork::Data).unwrap().len(), 6144);
+        let Some(DirEntry::Directory(trash)) = vol.lookup(&root, "Trash").unwrap() else {
+            panic!("Trash is missing");
+        };
+        assert!(vol.read_dir(&trash).unwrap().is_empty());
+        assert_eq!(vol.entry_by_id(trash.id()).unwrap().unwrap().name(), "Trash");
+        // the Finder doesn't make file threads
+        assert!(vol.entry_by_id(desktop_db.id()).unwrap().is_none());
+        assert!(vol.lookup(&root, "Nothing").unwrap().is_none());
+    }
+
+    #[test]
+    fn damaged_node() {
+        let mut decoder = GzDecoder::new(HD_100MB);
+        let mut vec = Vec::new();
+        decoder.read_to_end(&mut vec).unwrap();
+        let mut disk = Cursor::new(vec);
+        disk.set_position(96 * 512);
+        let vol = LazyHfsVolume::new(disk).unwrap();
+        let extent = &vol.catalog_extents[0];
+        let root = vol.alloc_start()
+            + extent.first_alloc_blk as u64 * vol.mdb.alloc_blk_size as u64
+            + vol.catalog_header.header_record.root as u64 * 512;
+
+        // more records than a node has room for offsets
+        let mut vec = vol.reader.into_inner();
+        vec[root as usize + 10..root as usize + 12].copy_from_slice(&300u16.to_be_bytes());
+        let mut disk = Cursor::new(vec);
+        disk.set_position(96 * 512);
+        let mut vol = LazyHfsVolume::new(disk).unwrap();
+        assert!(matches!(vol.root(), Err(HfsError::BinRw(_))));
+    }
+}
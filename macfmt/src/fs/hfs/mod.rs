@@ -0,0 +1,2353 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::common::{DateTime, PascalString, DynamicPascalString, FinderInfo, ExtraFinderInfo};
+use crate::i18n::{MacRoman, MacScript};
+use bitflags::bitflags;
+use binrw::{BinRead, BinWrite, BinResult, Endian};
+use binrw::io::{Cursor, Read, Seek, SeekFrom, Write};
+use derivative::Derivative;
+use thiserror::Error;
+use super::{BootBlocks, Entry, EntryKind, Volume};
+
+pub mod fuse;
+pub mod lazy;
+
+#[derive(Error, Debug)]
+pub enum HfsError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse or write a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("{0:?} does not exist")]
+    NotFound(String),
+    #[error("{0:?} already exists")]
+    AlreadyExists(String),
+    #[error("{0:?} is not a valid HFS name")]
+    InvalidName(String),
+    #[error("Directory {0:?} is not empty")]
+    DirectoryNotEmpty(String),
+    #[error("The root directory can't be deleted")]
+    RootDirectory,
+    #[error("Not enough free allocation blocks")]
+    DiskFull,
+    #[error("No free nodes left in the {0} B-tree")]
+    BTreeFull(&'static str),
+    #[error("Can't lay out a volume: {0}")]
+    BadLayout(&'static str),
+    #[error("Damaged volume: {0}")]
+    Corrupt(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+    name: String,
+    id: Cnid,
+    parent: Cnid,
+    data_len: u32,
+    rsrc_len: u32,
+    finder_info: FinderInfo,
+    more_finder_info: ExtraFinderInfo,
+    ctime: DateTime,
+    mtime: DateTime,
+}
+
+impl File {
+    fn from_record(name: &str, parent: Cnid, data: &CatalogRecordData) -> Option<File> {
+        let CatalogRecordData::File { id, data_len, rsrc_len, finder_info, more_finder_info, ctime, mtime, .. } = data else {
+            return None;
+        };
+        Some(File {
+            name: name.to_string(),
+            id: *id,
+            parent,
+            data_len: *data_len,
+            rsrc_len: *rsrc_len,
+            finder_info: finder_info.clone(),
+            more_finder_info: more_finder_info.clone(),
+            ctime: *ctime,
+            mtime: *mtime,
+        })
+    }
+    pub fn rsrc_len(&self) -> u32 {
+        self.rsrc_len
+    }
+    pub fn data_len(&self) -> u32 {
+        self.data_len
+    }
+    pub fn fork_len(&self, fork: Fork) -> u32 {
+        match fork {
+            Fork::Data => self.data_len,
+            Fork::Resource => self.rsrc_len,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> Cnid {
+        self.id
+    }
+    pub fn parent(&self) -> Cnid {
+        self.parent
+    }
+    pub fn finder_info(&self) -> &FinderInfo {
+        &self.finder_info
+    }
+    pub fn more_finder_info(&self) -> &ExtraFinderInfo {
+        &self.more_finder_info
+    }
+    pub fn ctime(&self) -> DateTime {
+        self.ctime
+    }
+    pub fn mtime(&self) -> DateTime {
+        self.mtime
+    }
+    fn key(&self) -> CatalogKey {
+        CatalogKey::new(self.parent, self.name.as_str())
+    }
+    fn entry(&self) -> Entry<Cnid> {
+        let kind = EntryKind::File { data_len: self.data_len, rsrc_len: self.rsrc_len };
+        let (ctime, mtime) = (self.ctime.to_system_time(), self.mtime.to_system_time());
+        Entry::new(self.id, &self.name, kind, Some(self.finder_info.clone()), ctime, mtime)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Directory {
+    name: String,
+    id: Cnid,
+    parent: Cnid,
+    finder_info: FinderInfo,
+    more_finder_info: ExtraFinderInfo,
+    ctime: DateTime,
+    mtime: DateTime,
+    files: Vec<File>,
+    subdirs: Vec<Directory>,
+}
+
+impl Directory {
+    fn from_record(name: &str, parent: Cnid, data: &CatalogRecordData) -> Option<Directory> {
+        let CatalogRecordData::Directory { id, finder_info, more_finder_info, ctime, mtime, .. } = data else {
+            return None;
+        };
+        Some(Directory {
+            name: name.to_string(),
+            id: *id,
+            parent,
+            finder_info: finder_info.clone(),
+            more_finder_info: more_finder_info.clone(),
+            ctime: *ctime,
+            mtime: *mtime,
+            files: Vec::new(),
+            subdirs: Vec::new(),
+        })
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> Cnid {
+        self.id
+    }
+    pub fn parent(&self) -> Cnid {
+        self.parent
+    }
+    pub fn finder_info(&self) -> &FinderInfo {
+        &self.finder_info
+    }
+    pub fn more_finder_info(&self) -> &ExtraFinderInfo {
+        &self.more_finder_info
+    }
+    pub fn ctime(&self) -> DateTime {
+        self.ctime
+    }
+    pub fn mtime(&self) -> DateTime {
+        self.mtime
+    }
+    pub fn subdirs(&self) -> &[Directory] {
+        &self.subdirs
+    }
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+    pub fn subdir(&self, name: &str) -> Option<&Directory> {
+        self.subdirs.iter().find(|dir| dir.name == name)
+    }
+    pub fn file(&self, name: &str) -> Option<&File> {
+        self.files.iter().find(|file| file.name == name)
+    }
+    fn key(&self) -> CatalogKey {
+        CatalogKey::new(self.parent, self.name.as_str())
+    }
+    fn entry(&self) -> Entry<Cnid> {
+        let (ctime, mtime) = (self.ctime.to_system_time(), self.mtime.to_system_time());
+        Entry::new(self.id, &self.name, EntryKind::Directory, None, ctime, mtime)
+    }
+}
+
+// Also used by HFS+, so the extents are kept as (first block, block count) pairs wide enough for
+// both.
+pub struct FileReader<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    alloc_start: u64,
+    alloc_blk_size: u64,
+    extents: Vec<(u64, u64)>,
+    cur_offset: u64,
+    len: u64,
+}
+
+impl<'a, R: Read + Seek> FileReader<'a, R> {
+    fn new(reader: &'a mut R, alloc_start: u64, alloc_blk_size: u32, extents: Vec<ExtDescriptor>, len: u32) -> Self {
+        let extents = extents
+            .iter()
+            .map(|extent| (extent.first_alloc_blk as u64, extent.alloc_blk_count as u64))
+            .collect();
+        Self::from_runs(reader, alloc_start, alloc_blk_size as u64, extents, len as u64)
+    }
+    pub(crate) fn from_runs(reader: &'a mut R, alloc_start: u64, alloc_blk_size: u64, extents: Vec<(u64, u64)>, len: u64) -> Self {
+        Self {
+            reader,
+            alloc_start,
+            alloc_blk_size,
+            extents,
+            cur_offset: 0,
+            len,
+        }
+    }
+    // Returns the on-disk offset of a logical file offset together with how many bytes are left
+    // in the extent containing it.
+    fn physical_offset(&self, offset: u64) -> Option<(u64, u64)> {
+        let mut extent_start = 0;
+        for &(first_blk, blk_count) in self.extents.iter() {
+            let extent_len = blk_count * self.alloc_blk_size;
+            if offset < extent_start + extent_len {
+                let in_extent = offset - extent_start;
+                let disk_offset = self.alloc_start
+                    + first_blk * self.alloc_blk_size
+                    + in_extent;
+                return Some((disk_offset, extent_len - in_extent));
+            }
+            extent_start += extent_len;
+        }
+
+        None
+    }
+}
+
+impl<'a, R: Read + Seek> Read for FileReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cur_offset >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let Some((start, extent_left)) = self.physical_offset(self.cur_offset) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "file is longer than its extents",
+            ));
+        };
+        let read_len = (buf.len() as u64)
+            .min(self.len - self.cur_offset)
+            .min(extent_left) as usize;
+        self.reader.seek(SeekFrom::Start(start))?;
+        self.reader.read_exact(&mut buf[..read_len])?;
+        self.cur_offset += read_len as u64;
+
+        Ok(read_len)
+    }
+}
+
+impl<'a, R: Read + Seek> Seek for FileReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => self.len.checked_add_signed(off),
+            SeekFrom::Current(off) => self.cur_offset.checked_add_signed(off),
+        };
+        let Some(new_offset) = new_offset else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+        self.cur_offset = new_offset;
+
+        Ok(self.cur_offset)
+    }
+}
+
+impl<'a, R: Read + Write + Seek> Write for FileReader<'a, R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let Some((start, extent_left)) = self.physical_offset(self.cur_offset) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                "write past the end of the file's extents",
+            ));
+        };
+        let write_len = (buf.len() as u64).min(extent_left) as usize;
+        self.reader.seek(SeekFrom::Start(start))?;
+        self.reader.write_all(&buf[..write_len])?;
+        self.cur_offset += write_len as u64;
+
+        Ok(write_len)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.reader.flush()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    // picked from the volume size when unset, as a multiple of 512
+    pub alloc_blk_size: Option<u32>,
+    pub boot_blocks: Option<BootBlocks>,
+}
+
+pub struct HfsVolume<R: Read + Seek> {
+    hdr: Hfs,
+    base: u64,
+    root_dir: Directory,
+    reader: R,
+}
+
+impl<R: Read + Seek> HfsVolume<R> {
+    pub fn new(mut reader: R) -> Result<Self, HfsError> {
+        let base = reader.stream_position()?;
+        let hdr = Hfs::read(&mut reader)?;
+        let root_dir = hdr.root_dir()?;
+        Ok(Self {
+            hdr,
+            base,
+            root_dir,
+            reader,
+        })
+    }
+    pub fn file_reader<'a>(&'a mut self, file: &File, fork: Fork) -> std::io::Result<FileReader<'a, R>> {
+        let Some(CatalogRecordData::File { data_extent, data_len, rsrc_extent, rsrc_len, .. }) =
+            self.hdr.catalog_file.record_data(&file.key())
+        else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no file record for {:?}", file.id),
+            ));
+        };
+        let (first_extents, len) = match fork {
+            Fork::Data => (data_extent, *data_len),
+            Fork::Resource => (rsrc_extent, *rsrc_len),
+        };
+        let extents = self.hdr.extents_overflow
+            .fork_extents(first_extents, fork, file.id)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+        let alloc_start = self.hdr.mdb.alloc_block_offset(0) as u64 + self.base;
+        let alloc_blk_size = self.hdr.mdb.alloc_blk_size;
+
+        Ok(FileReader::new(&mut self.reader, alloc_start, alloc_blk_size, extents, len))
+    }
+    pub fn file_contents(&mut self, file: &File, fork: Fork) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.file_reader(file, fork)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+    pub fn file_data(&mut self, file: &File) -> std::io::Result<Vec<u8>> {
+        self.file_contents(file, Fork::Data)
+    }
+    pub fn file_rsrc(&mut self, file: &File) -> std::io::Result<Vec<u8>> {
+        self.file_contents(file, Fork::Resource)
+    }
+    pub fn root_dir(&self) -> Directory {
+        self.root_dir.clone()
+    }
+    pub fn file_by_id(&self, id: Cnid) -> Option<File> {
+        match self.hdr.catalog_file.record_by_id(id)? {
+            CatalogRecord::Leaf { parent_id, name, data, .. } => File::from_record(name.as_str(), *parent_id, data),
+            _ => None,
+        }
+    }
+    pub fn dir_by_id(&self, id: Cnid) -> Option<Directory> {
+        match self.hdr.catalog_file.record_by_id(id)? {
+            CatalogRecord::Leaf { parent_id, name, data, .. } => {
+                let mut dir = Directory::from_record(name.as_str(), *parent_id, data)?;
+                self.hdr.fill_dir(&mut dir, &mut BTreeSet::new()).ok()?;
+                Some(dir)
+            },
+            _ => None,
+        }
+    }
+    pub fn hdr(&self) -> &Hfs {
+        &self.hdr
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read + Seek> Volume for HfsVolume<R> {
+    type Id = Cnid;
+    type ForkReader<'a> = FileReader<'a, R> where R: 'a;
+
+    fn volume_name(&self) -> String {
+        self.root_dir.name.clone()
+    }
+    fn created(&self) -> std::time::SystemTime {
+        self.hdr.mdb.ctime.to_system_time()
+    }
+    fn root(&self) -> Entry<Cnid> {
+        self.root_dir.entry()
+    }
+    // Straight from the catalog, the cached tree doesn't see changes made since opening.
+    fn read_dir(&mut self, dir: &Entry<Cnid>) -> std::io::Result<Vec<Entry<Cnid>>> {
+        if !dir.is_dir() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotADirectory));
+        }
+        let mut entries = Vec::new();
+        for rec in self.hdr.catalog_file.children(dir.id()) {
+            let CatalogRecord::Leaf { name, parent_id, data, .. } = rec else {
+                continue;
+            };
+            if let Some(subdir) = Directory::from_record(name.as_str(), *parent_id, data) {
+                entries.push(subdir.entry());
+            } else if let Some(file) = File::from_record(name.as_str(), *parent_id, data) {
+                entries.push(file.entry());
+            }
+        }
+        Ok(entries)
+    }
+    fn fork_reader(&mut self, file: &Entry<Cnid>, fork: super::Fork) -> std::io::Result<FileReader<'_, R>> {
+        if file.is_dir() {
+            return Err(std::io::Error::from(std::io::ErrorKind::IsADirectory));
+        }
+        let Some(file) = self.file_by_id(file.id()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no file record for {:?}", file.id()),
+            ));
+        };
+        self.file_reader(&file, fork.into())
+    }
+}
+
+impl<R: Read + Write + Seek> HfsVolume<R> {
+    // Lays out an empty volume of `size` bytes at the writer's current position: boot blocks,
+    // MDB, bitmap, the extents overflow and catalog B-trees right at the start of the allocation
+    // blocks, and the alternate MDB in the second to last sector.
+    pub fn format(mut writer: R, size: u64, name: &str, options: FormatOptions) -> Result<Self, HfsError> {
+        if !is_valid_name(name) || name.chars().count() > 27 {
+            return Err(HfsError::InvalidName(name.to_string()));
+        }
+        let sectors = size / 512;
+        if sectors < 16 {
+            return Err(HfsError::BadLayout("volume is too small"));
+        }
+        let blk_sectors = match options.alloc_blk_size {
+            Some(blk_size) if blk_size == 0 || blk_size % 512 != 0 => {
+                return Err(HfsError::BadLayout("allocation block size must be a multiple of 512"));
+            },
+            Some(blk_size) => blk_size as u64 / 512,
+            None => (sectors - 5).div_ceil(0xffff),
+        };
+        let bitmap_sectors = ((sectors - 5) / blk_sectors).div_ceil(512 * 8);
+        let alloc_blk_start = 3 + bitmap_sectors;
+        let alloc_blk_count = u16::try_from((sectors - alloc_blk_start - 2) / blk_sectors)
+            .map_err(|_| HfsError::BadLayout("allocation block size is too small for the volume"))?;
+        let alloc_blk_size = (blk_sectors * 512) as u32;
+
+        // both B-trees get 1/128th of the volume, but no more than their header map can track
+        let btree_blks = (size / 128)
+            .min(2048 * NODE_SIZE as u64)
+            .max(4 * NODE_SIZE as u64)
+            .div_ceil(alloc_blk_size as u64) as u16;
+        let btree_size = btree_blks as u32 * alloc_blk_size;
+        if alloc_blk_count <= 2 * btree_blks {
+            return Err(HfsError::BadLayout("volume is too small"));
+        }
+
+        let now = DateTime::now();
+        let vol_name = PascalString::new(name).ok_or_else(|| HfsError::InvalidName(name.to_string()))?;
+        let mdb = Mdb {
+            ctime: now,
+            mtime: now,
+            attrs: MdbAttrs::UNMOUNTED_SUCCESSFULLY,
+            root_dir_file_count: 0,
+            bitmap_start: 3,
+            alloc_ptr: 2 * btree_blks,
+            alloc_blk_count,
+            alloc_blk_size,
+            clump_size: 4 * alloc_blk_size,
+            alloc_blk_start: alloc_blk_start as u16,
+            next_catalog_id: 16,
+            free_blks: alloc_blk_count - 2 * btree_blks,
+            name: vol_name,
+            backup_date: DateTime::default(),
+            backup_seq: 0,
+            write_count: 0,
+            extents_overflow_clump_size: btree_size,
+            catalog_clump_size: btree_size,
+            root_dir_dir_count: 0,
+            file_count: 0,
+            dir_count: 0,
+            finder_info: [0; 8],
+            cache_size: 0,
+            bitmap_cache_size: 0,
+            common_volume_cache_size: 0,
+            extents_overflow_size: btree_size,
+            extents_overflow_record: ExtDataRec::new(&[ExtDescriptor::new(0, btree_blks)]),
+            catalog_file_size: btree_size,
+            catalog_file_extent: ExtDataRec::new(&[ExtDescriptor::new(btree_blks, btree_blks)]),
+        };
+
+        let node_count = btree_size / NODE_SIZE as u32;
+        let mut catalog_file = CatalogFile::new(node_count, 0x25);
+        catalog_file.insert(leaf_record(Cnid::ParentOfRoot, name, CatalogRecordData::Directory {
+            flags: 0,
+            valence: 0,
+            id: Cnid::RootDir,
+            ctime: now,
+            mtime: now,
+            backup_time: DateTime::default(),
+            finder_info: FinderInfo::default(),
+            more_finder_info: ExtraFinderInfo::default(),
+        }))?;
+        catalog_file.insert(leaf_record(Cnid::RootDir, "", CatalogRecordData::DirectoryThread {
+            parent_id: Cnid::ParentOfRoot,
+            name: PascalString::new(name).ok_or_else(|| HfsError::InvalidName(name.to_string()))?,
+        }))?;
+        let mut hdr = Hfs {
+            boot_blks: options.boot_blocks,
+            volume_bitmap: vec![0; mdb.volume_bitmap_len()],
+            extents_overflow: ExtentsOverflowFile::new(node_count, 7),
+            catalog_file,
+            mdb,
+        };
+        for blk in 0..2 * btree_blks {
+            hdr.set_alloc_blk(blk, true);
+        }
+
+        let base = writer.stream_position()?;
+        let zeros = vec![0; 0x10000];
+        let mut left = sectors * 512;
+        while left > 0 {
+            let len = left.min(zeros.len() as u64) as usize;
+            writer.write_all(&zeros[..len])?;
+            left -= len as u64;
+        }
+        if let Some(boot_blks) = hdr.boot_blks.as_ref() {
+            writer.seek(SeekFrom::Start(base))?;
+            boot_blks.write(&mut writer)?;
+        }
+        // flush() only rewrites the alternate MDB if it can find one
+        writer.seek(SeekFrom::Start(base + (sectors - 2) * 512))?;
+        hdr.mdb.write(&mut writer)?;
+
+        let root_dir = hdr.root_dir()?;
+        let mut vol = Self {
+            hdr,
+            base,
+            root_dir,
+            reader: writer,
+        };
+        vol.flush()?;
+
+        Ok(vol)
+    }
+    pub fn create_file(&mut self, parent: &Directory, name: &str, file_type: [u8; 4], creator: [u8; 4]) -> Result<File, HfsError> {
+        self.hdr.check_new_entry(parent.id, name)?;
+        let id = self.hdr.next_catalog_id();
+        let now = DateTime::now();
+        let data = CatalogRecordData::File {
+            flags: FileFlags::FILE_USED,
+            kind: 0,
+            finder_info: FinderInfo::new(file_type, creator),
+            id,
+            data_start: 0,
+            data_len: 0,
+            data_allocated_len: 0,
+            rsrc_start: 0,
+            rsrc_len: 0,
+            rsrc_allocated_len: 0,
+            ctime: now,
+            mtime: now,
+            backup_time: DateTime::default(),
+            more_finder_info: ExtraFinderInfo::default(),
+            clump_size: 0,
+            data_extent: ExtDataRec::default(),
+            rsrc_extent: ExtDataRec::default(),
+        };
+        let file = File::from_record(name, parent.id, &data).expect("a file record");
+        self.hdr.catalog_file.insert(leaf_record(parent.id, name, data))?;
+        self.hdr.adjust_valence(parent.id, 1)?;
+        self.hdr.mdb.file_count += 1;
+        if parent.id == Cnid::RootDir {
+            self.hdr.mdb.root_dir_file_count += 1;
+        }
+        self.hdr.mdb.mtime = now;
+        self.root_dir = self.hdr.root_dir()?;
+
+        Ok(file)
+    }
+    pub fn create_dir(&mut self, parent: &Directory, name: &str) -> Result<Directory, HfsError> {
+        self.hdr.check_new_entry(parent.id, name)?;
+        self.hdr.catalog_file.ensure_free_nodes(2)?;
+        let id = self.hdr.next_catalog_id();
+        let now = DateTime::now();
+        let data = CatalogRecordData::Directory {
+            flags: 0,
+            valence: 0,
+            id,
+            ctime: now,
+            mtime: now,
+            backup_time: DateTime::default(),
+            finder_info: FinderInfo::default(),
+            more_finder_info: ExtraFinderInfo::default(),
+        };
+        let thread = CatalogRecordData::DirectoryThread {
+            parent_id: parent.id,
+            name: PascalString::new(name).ok_or_else(|| HfsError::InvalidName(name.to_string()))?,
+        };
+        let dir = Directory::from_record(name, parent.id, &data).expect("a directory record");
+        self.hdr.catalog_file.insert(leaf_record(parent.id, name, data))?;
+        self.hdr.catalog_file.insert(leaf_record(id, "", thread))?;
+        self.hdr.adjust_valence(parent.id, 1)?;
+        self.hdr.mdb.dir_count += 1;
+        if parent.id == Cnid::RootDir {
+            self.hdr.mdb.root_dir_dir_count += 1;
+        }
+        self.hdr.mdb.mtime = now;
+        self.root_dir = self.hdr.root_dir()?;
+
+        Ok(dir)
+    }
+    // Replaces the whole contents of a fork, reallocating its blocks.
+    pub fn write_fork(&mut self, file: &File, fork: Fork, contents: &[u8]) -> Result<File, HfsError> {
+        let blk_size = self.hdr.mdb.alloc_blk_size;
+        let len = u32::try_from(contents.len()).map_err(|_| HfsError::DiskFull)?;
+        let blk_count = u16::try_from(len.div_ceil(blk_size)).map_err(|_| HfsError::DiskFull)?;
+        let Some(CatalogRecordData::File { data_extent, rsrc_extent, .. }) = self.hdr.catalog_file.record_data(&file.key()) else {
+            return Err(HfsError::NotFound(file.name.clone()));
+        };
+        let first = match fork {
+            Fork::Data => data_extent.clone(),
+            Fork::Resource => rsrc_extent.clone(),
+        };
+        let old_extents = self.hdr.extents_overflow.fork_extents(&first, fork, file.id)?;
+
+        // the new blocks and records go in before the old ones are let go, so a failure leaves
+        // the fork as it was
+        let extents = self.hdr.alloc_blks(blk_count)?;
+        let mut chunks = extents.chunks(3);
+        let inline = ExtDataRec::new(chunks.next().unwrap_or(&[]));
+        if let Err(err) = self.hdr.extents_overflow.ensure_free_nodes(chunks.len() as u32) {
+            self.hdr.free_extents(&extents);
+            return Err(err);
+        }
+        self.hdr.remove_overflow_extents(&first, fork, file.id)?;
+        let mut start = inline.blk_count()?;
+        for chunk in chunks {
+            let data = ExtDataRec::new(chunk);
+            let count = data.blk_count()?;
+            self.hdr.extents_overflow.insert(ExtentsRecord::Leaf {
+                key: ExtentKey::new(fork, file.id, start),
+                data,
+            })?;
+            start += count;
+        }
+
+        let allocated_len = blk_count as u32 * blk_size;
+        let first_blk = extents.first().map_or(0, |extent| extent.first_alloc_blk);
+        let Some(CatalogRecordData::File {
+            data_start, data_len, data_allocated_len, data_extent,
+            rsrc_start, rsrc_len, rsrc_allocated_len, rsrc_extent,
+            mtime, ..
+        }) = self.hdr.catalog_file.record_data_mut(&file.key()) else {
+            return Err(HfsError::NotFound(file.name.clone()));
+        };
+        match fork {
+            Fork::Data => {
+                *data_start = first_blk;
+                *data_len = len;
+                *data_allocated_len = allocated_len;
+                *data_extent = inline;
+            },
+            Fork::Resource => {
+                *rsrc_start = first_blk;
+                *rsrc_len = len;
+                *rsrc_allocated_len = allocated_len;
+                *rsrc_extent = inline;
+            },
+        }
+        *mtime = DateTime::now();
+        self.hdr.mdb.mtime = *mtime;
+        self.hdr.free_extents(&old_extents);
+
+        let alloc_start = self.hdr.mdb.alloc_block_offset(0) as u64 + self.base;
+        let mut writer = FileReader::new(&mut self.reader, alloc_start, blk_size, extents, allocated_len);
+        writer.write_all(contents)?;
+        writer.write_all(&vec![0; (allocated_len - len) as usize])?;
+        self.root_dir = self.hdr.root_dir()?;
+
+        let mut file = file.clone();
+        file.mtime = self.hdr.mdb.mtime;
+        match fork {
+            Fork::Data => file.data_len = len,
+            Fork::Resource => file.rsrc_len = len,
+        }
+        Ok(file)
+    }
+    pub fn rename_file(&mut self, file: &File, name: &str) -> Result<File, HfsError> {
+        self.hdr.rename_entry(&file.key(), file.id, name)?;
+        self.root_dir = self.hdr.root_dir()?;
+
+        Ok(File {
+            name: name.to_string(),
+            ..file.clone()
+        })
+    }
+    pub fn rename_dir(&mut self, dir: &Directory, name: &str) -> Result<Directory, HfsError> {
+        if dir.id == Cnid::RootDir {
+            self.hdr.mdb.name = PascalString::new(name).ok_or_else(|| HfsError::InvalidName(name.to_string()))?;
+        }
+        self.hdr.rename_entry(&dir.key(), dir.id, name)?;
+        self.root_dir = self.hdr.root_dir()?;
+
+        Ok(Directory {
+            name: name.to_string(),
+            ..dir.clone()
+        })
+    }
+    pub fn delete_file(&mut self, file: &File) -> Result<(), HfsError> {
+        let Some(CatalogRecordData::File { flags, data_extent, rsrc_extent, .. }) =
+            self.hdr.catalog_file.record_data(&file.key()).cloned()
+        else {
+            return Err(HfsError::NotFound(file.name.clone()));
+        };
+        self.hdr.free_fork(&data_extent, Fork::Data, file.id)?;
+        self.hdr.free_fork(&rsrc_extent, Fork::Resource, file.id)?;
+        self.hdr.catalog_file.remove(&file.key())?;
+        if flags.contains(FileFlags::THREAD_EXISTS) {
+            self.hdr.catalog_file.remove(&CatalogKey::thread(file.id))?;
+        }
+        self.hdr.adjust_valence(file.parent, -1)?;
+        self.hdr.mdb.file_count -= 1;
+        if file.parent == Cnid::RootDir {
+            self.hdr.mdb.root_dir_file_count -= 1;
+        }
+        self.hdr.mdb.mtime = DateTime::now();
+        self.root_dir = self.hdr.root_dir()?;
+
+        Ok(())
+    }
+    pub fn delete_dir(&mut self, dir: &Directory) -> Result<(), HfsError> {
+        if dir.id == Cnid::RootDir {
+            return Err(HfsError::RootDirectory);
+        }
+        let Some(CatalogRecordData::Directory { valence, .. }) = self.hdr.catalog_file.record_data(&dir.key()) else {
+            return Err(HfsError::NotFound(dir.name.clone()));
+        };
+        if *valence != 0 {
+            return Err(HfsError::DirectoryNotEmpty(dir.name.clone()));
+        }
+        self.hdr.catalog_file.remove(&dir.key())?;
+        self.hdr.catalog_file.remove(&CatalogKey::thread(dir.id))?;
+        self.hdr.adjust_valence(dir.parent, -1)?;
+        self.hdr.mdb.dir_count -= 1;
+        if dir.parent == Cnid::RootDir {
+            self.hdr.mdb.root_dir_dir_count -= 1;
+        }
+        self.hdr.mdb.mtime = DateTime::now();
+        self.root_dir = self.hdr.root_dir()?;
+
+        Ok(())
+    }
+    // Writes the MDB (and its alternate copy), the volume bitmap and every modified B-tree node
+    // back to the disk.
+    pub fn flush(&mut self) -> Result<(), HfsError> {
+        self.hdr.mdb.write_count += 1;
+        self.reader.seek(SeekFrom::Start(self.base + 1024))?;
+        self.hdr.mdb.write(&mut self.reader)?;
+        if let Some(offset) = self.alt_mdb_offset()? {
+            self.reader.seek(SeekFrom::Start(offset))?;
+            self.hdr.mdb.write(&mut self.reader)?;
+        }
+
+        self.reader.seek(SeekFrom::Start(self.base + self.hdr.mdb.bitmap_start as u64 * 512))?;
+        self.reader.write_all(&self.hdr.volume_bitmap)?;
+
+        let mdb = &self.hdr.mdb;
+        let alloc_start = mdb.alloc_block_offset(0) as u64 + self.base;
+        let extents = FileReader::new(
+            &mut self.reader,
+            alloc_start,
+            mdb.alloc_blk_size,
+            mdb.extents_overflow_record.extents(),
+            mdb.extents_overflow_size,
+        );
+        write_btree(&mut self.hdr.extents_overflow, extents)?;
+        let catalog = FileReader::new(
+            &mut self.reader,
+            alloc_start,
+            mdb.alloc_blk_size,
+            self.hdr.extents_overflow.fork_extents(&mdb.catalog_file_extent, Fork::Data, Cnid::CatalogFile)?,
+            mdb.catalog_file_size,
+        );
+        write_btree(&mut self.hdr.catalog_file, catalog)?;
+        self.reader.flush()?;
+
+        Ok(())
+    }
+    // The alternate MDB lives in the second to last sector of the volume, which is somewhere
+    // after the last allocation block.
+    fn alt_mdb_offset(&mut self) -> std::io::Result<Option<u64>> {
+        let mdb = &self.hdr.mdb;
+        let alloc_end = self.base
+            + mdb.alloc_block_offset(0) as u64
+            + mdb.alloc_blk_count as u64 * mdb.alloc_blk_size as u64;
+        let mut sig = [0; 2];
+        for sector in 0..(mdb.alloc_blk_size as u64 / 512 + 2) {
+            let offset = alloc_end + sector * 512;
+            self.reader.seek(SeekFrom::Start(offset))?;
+            if self.reader.read_exact(&mut sig).is_err() {
+                break;
+            }
+            if &sig == b"BD" {
+                return Ok(Some(offset));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn leaf_record(parent_id: Cnid, name: &str, data: CatalogRecordData) -> CatalogRecord {
+    CatalogRecord::Leaf {
+        key_len: 6 + name.chars().count() as u8,
+        parent_id,
+        name: DynamicPascalString::new(name),
+        data,
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct Hfs {
+    #[br(try)]
+    #[brw(pad_size_to = 1024)]
+    boot_blks: Option<BootBlocks>,
+    #[brw(pad_size_to = 512)]
+    mdb: Mdb,
+    #[br(count = mdb.volume_bitmap_len())]
+    #[brw(align_after = 512)]
+    #[derivative(Debug = "ignore")]
+    volume_bitmap: Vec<u8>,
+    #[br(parse_with = read_fork_file, args(&mdb, mdb.extents_overflow_record.extents(), mdb.extents_overflow_size))]
+    #[bw(seek_before = SeekFrom::Start(mdb.extents_overflow_file_start() as u64))]
+    extents_overflow: ExtentsOverflowFile,
+    #[br(parse_with = read_fork_file, args(
+        &mdb,
+        extents_overflow
+            .fork_extents(&mdb.catalog_file_extent, Fork::Data, Cnid::CatalogFile)
+            .map_err(|err| binrw::Error::AssertFail { pos: 0, message: err.to_string() })?,
+        mdb.catalog_file_size,
+    ))]
+    #[bw(seek_before = SeekFrom::Start(mdb.catalog_file_start() as u64))]
+    catalog_file: CatalogFile,
+}
+
+// The B-tree files are read through their extents, as nothing guarantees they are contiguous.
+#[binrw::parser(reader, endian)]
+fn read_fork_file<T>(mdb: &Mdb, extents: Vec<ExtDescriptor>, len: u32) -> BinResult<T>
+where
+    T: for<'a> BinRead<Args<'a> = ()>,
+{
+    let alloc_start = mdb.alloc_block_offset(0) as u64;
+    let mut fork = FileReader::new(reader, alloc_start, mdb.alloc_blk_size, extents, len);
+    T::read_options(&mut fork, endian, ())
+}
+
+impl Hfs {
+    pub fn boot_blocks(&self) -> Option<&BootBlocks> {
+        self.boot_blks.as_ref()
+    }
+    fn alloc_blk_occupied(&self, blk: u16) -> bool {
+        self.volume_bitmap[blk as usize / 8] & (0x80 >> (blk % 8)) != 0
+    }
+    fn set_alloc_blk(&mut self, blk: u16, occupied: bool) {
+        let mask = 0x80 >> (blk % 8);
+        if occupied {
+            self.volume_bitmap[blk as usize / 8] |= mask;
+        } else {
+            self.volume_bitmap[blk as usize / 8] &= !mask;
+        }
+    }
+    // Free runs of allocation blocks, starting from the allocation pointer and wrapping around.
+    fn free_runs(&self) -> Vec<ExtDescriptor> {
+        let total = self.mdb.alloc_blk_count;
+        let start = if self.mdb.alloc_ptr < total { self.mdb.alloc_ptr } else { 0 };
+        let mut runs = Vec::new();
+        for (from, to) in [(start, total), (0, start)] {
+            let mut blk = from;
+            while blk < to {
+                if self.alloc_blk_occupied(blk) {
+                    blk += 1;
+                    continue;
+                }
+                let first = blk;
+                while blk < to && !self.alloc_blk_occupied(blk) {
+                    blk += 1;
+                }
+                runs.push(ExtDescriptor::new(first, blk - first));
+            }
+        }
+
+        runs
+    }
+    // Prefers a single contiguous run, only fragmenting the allocation when there's none big enough.
+    fn alloc_blks(&mut self, count: u16) -> Result<Vec<ExtDescriptor>, HfsError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if self.mdb.free_blks < count {
+            return Err(HfsError::DiskFull);
+        }
+
+        let runs = self.free_runs();
+        let extents = match runs.iter().find(|run| run.alloc_blk_count >= count) {
+            Some(run) => vec![ExtDescriptor::new(run.first_alloc_blk, count)],
+            None => {
+                let mut left = count;
+                let mut extents = Vec::new();
+                for run in runs.iter() {
+                    if left == 0 {
+                        break;
+                    }
+                    let taken = run.alloc_blk_count.min(left);
+                    extents.push(ExtDescriptor::new(run.first_alloc_blk, taken));
+                    left -= taken;
+                }
+                if left > 0 {
+                    return Err(HfsError::DiskFull);
+                }
+                extents
+            },
+        };
+
+        for extent in extents.iter() {
+            for blk in extent.first_alloc_blk..extent.first_alloc_blk + extent.alloc_blk_count {
+                self.set_alloc_blk(blk, true);
+            }
+        }
+        self.mdb.free_blks -= count;
+        let last = extents.last().unwrap();
+        self.mdb.alloc_ptr = (last.first_alloc_blk + last.alloc_blk_count) % self.mdb.alloc_blk_count;
+
+        Ok(extents)
+    }
+    // Releases every allocation block of a fork, including its extents overflow records.
+    fn free_fork(&mut self, first: &ExtDataRec, fork: Fork, id: Cnid) -> Result<(), HfsError> {
+        let extents = self.extents_overflow.fork_extents(first, fork, id)?;
+        self.free_extents(&extents);
+        self.remove_overflow_extents(first, fork, id)
+    }
+    fn free_extents(&mut self, extents: &[ExtDescriptor]) {
+        for extent in extents {
+            for blk in extent.first_alloc_blk..extent.first_alloc_blk + extent.alloc_blk_count {
+                self.set_alloc_blk(blk, false);
+            }
+            self.mdb.free_blks += extent.alloc_blk_count;
+        }
+    }
+    fn remove_overflow_extents(&mut self, first: &ExtDataRec, fork: Fork, id: Cnid) -> Result<(), HfsError> {
+        let mut key = ExtentKey::new(fork, id, first.blk_count()?);
+        while let Some(ExtentsRecord::Leaf { data, .. }) = self.extents_overflow.find(&key) {
+            let count = data.blk_count()?;
+            self.extents_overflow.remove(&key)?;
+            if count == 0 {
+                break;
+            }
+            key.start = key.start
+                .checked_add(count)
+                .ok_or_else(|| HfsError::Corrupt(format!("the extents of {:?} cover more than 65535 blocks", id)))?;
+        }
+
+        Ok(())
+    }
+    fn next_catalog_id(&mut self) -> Cnid {
+        let id = self.mdb.next_catalog_id;
+        self.mdb.next_catalog_id += 1;
+        Cnid::from(id)
+    }
+    // Directories are found from their ID through their thread record.
+    fn dir_key(&self, id: Cnid) -> Option<CatalogKey> {
+        match self.catalog_file.record_data(&CatalogKey::thread(id))? {
+            CatalogRecordData::DirectoryThread { parent_id, name } => Some(CatalogKey::new(*parent_id, name.decode())),
+            _ => None,
+        }
+    }
+    fn check_new_entry(&self, parent: Cnid, name: &str) -> Result<(), HfsError> {
+        if !is_valid_name(name) {
+            return Err(HfsError::InvalidName(name.to_string()));
+        }
+        if self.dir_key(parent).is_none() {
+            return Err(HfsError::NotFound(format!("{:?}", parent)));
+        }
+        if self.catalog_file.find(&CatalogKey::new(parent, name)).is_some() {
+            return Err(HfsError::AlreadyExists(name.to_string()));
+        }
+
+        Ok(())
+    }
+    fn adjust_valence(&mut self, dir: Cnid, delta: i16) -> Result<(), HfsError> {
+        let key = self.dir_key(dir).ok_or_else(|| HfsError::NotFound(format!("{:?}", dir)))?;
+        let Some(CatalogRecordData::Directory { valence, mtime, .. }) = self.catalog_file.record_data_mut(&key) else {
+            return Err(HfsError::NotFound(key.name));
+        };
+        *valence = valence.saturating_add_signed(delta);
+        *mtime = DateTime::now();
+
+        Ok(())
+    }
+    // The record is reinserted under its new key, and its thread record (if any) points to the new
+    // name.
+    fn rename_entry(&mut self, key: &CatalogKey, id: Cnid, name: &str) -> Result<(), HfsError> {
+        if !is_valid_name(name) {
+            return Err(HfsError::InvalidName(name.to_string()));
+        }
+        let new_key = CatalogKey::new(key.parent_id, name);
+        if new_key != *key && self.catalog_file.find(&new_key).is_some() {
+            return Err(HfsError::AlreadyExists(name.to_string()));
+        }
+        self.catalog_file.ensure_free_nodes(1)?;
+
+        let CatalogRecord::Leaf { data, .. } = self.catalog_file.remove(key)? else {
+            return Err(HfsError::NotFound(key.name.clone()));
+        };
+        self.catalog_file.insert(leaf_record(key.parent_id, name, data))?;
+        if let Some(
+            CatalogRecordData::DirectoryThread { name: thread_name, .. } |
+            CatalogRecordData::FileThread { name: thread_name, .. }
+        ) = self.catalog_file.record_data_mut(&CatalogKey::thread(id)) {
+            *thread_name = PascalString::new(name).ok_or_else(|| HfsError::InvalidName(name.to_string()))?;
+        }
+        self.mdb.mtime = DateTime::now();
+
+        Ok(())
+    }
+    // The root directory is the only record whose parent is the root's parent.
+    fn root_dir(&self) -> Result<Directory, HfsError> {
+        let mut root = self.catalog_file
+            .children(Cnid::ParentOfRoot)
+            .find_map(|rec| match rec {
+                CatalogRecord::Leaf { name, parent_id, data, .. } => Directory::from_record(name.as_str(), *parent_id, data),
+                _ => None,
+            })
+            .ok_or_else(|| HfsError::Corrupt("no root directory record".to_string()))?;
+        self.fill_dir(&mut root, &mut BTreeSet::new())?;
+
+        Ok(root)
+    }
+    fn fill_dir(&self, dir: &mut Directory, visited: &mut BTreeSet<u32>) -> Result<(), HfsError> {
+        if !visited.insert(dir.id.as_u32()) {
+            return Err(HfsError::Corrupt(format!("directory {:?} contains itself", dir.name)));
+        }
+        for rec in self.catalog_file.children(dir.id) {
+            let CatalogRecord::Leaf { name, parent_id, data, .. } = rec else {
+                continue;
+            };
+            if let Some(mut subdir) = Directory::from_record(name.as_str(), *parent_id, data) {
+                self.fill_dir(&mut subdir, visited)?;
+                dir.subdirs.push(subdir);
+            } else if let Some(file) = File::from_record(name.as_str(), *parent_id, data) {
+                dir.files.push(file);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big, magic = b"BD")]
+pub struct Mdb {
+    ctime: DateTime,
+    mtime: DateTime,
+    #[br(map = |v: u16| MdbAttrs::from_bits_retain(v))]
+    #[bw(map = |v: &MdbAttrs| v.bits())]
+    attrs: MdbAttrs,
+    root_dir_file_count: u16,
+    bitmap_start: u16,
+    alloc_ptr: u16,
+    alloc_blk_count: u16,
+    alloc_blk_size: u32,
+    clump_size: u32,
+    alloc_blk_start: u16,
+    next_catalog_id: u32,
+    free_blks: u16,
+    name: PascalString<27>,
+    backup_date: DateTime,
+    backup_seq: u16,
+    write_count: u32,
+    extents_overflow_clump_size: u32,
+    catalog_clump_size: u32,
+    root_dir_dir_count: u16,
+    file_count: u32,
+    dir_count: u32,
+    #[derivative(Debug = "ignore")]
+    finder_info: [u32; 8],
+    cache_size: u16,
+    bitmap_cache_size: u16,
+    common_volume_cache_size: u16,
+    extents_overflow_size: u32,
+    extents_overflow_record: ExtDataRec,
+    catalog_file_size: u32,
+    catalog_file_extent: ExtDataRec,
+}
+
+impl Mdb {
+    fn volume_bitmap_len(&self) -> usize {
+        (self.alloc_blk_count as usize + 7) / 8
+    }
+    fn alloc_block_offset(&self, blk: u16) -> usize {
+        (self.alloc_blk_start as usize * 512 ) + (blk as usize * self.alloc_blk_size as usize)
+    }
+    fn catalog_file_start(&self) -> usize {
+        self.alloc_block_offset(self.catalog_file_extent.0[0].first_alloc_blk)
+    }
+    fn extents_overflow_file_start(&self) -> usize {
+        self.alloc_block_offset(self.extents_overflow_record.0[0].first_alloc_blk)
+    }
+    // HFS wrapper volumes keep the location of the HFS+ volume in what used to be cache fields,
+    // returned as the byte offset and length from the start of the wrapper.
+    pub(crate) fn embedded_hfs_plus(&self) -> Option<(u64, u64)> {
+        if self.cache_size != u16::from_be_bytes(*b"H+") {
+            return None;
+        }
+        let start = self.alloc_block_offset(self.bitmap_cache_size) as u64;
+        let len = self.common_volume_cache_size as u64 * self.alloc_blk_size as u64;
+        Some((start, len))
+    }
+}
+
+
+#[derive(Derivative, Clone, Default, BinRead, BinWrite, Eq, PartialEq)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct ExtDataRec([ExtDescriptor; 3]);
+
+impl ExtDataRec {
+    fn new(extents: &[ExtDescriptor]) -> Self {
+        let mut rec = Self::default();
+        for (slot, extent) in rec.0.iter_mut().zip(extents) {
+            *slot = extent.clone();
+        }
+        rec
+    }
+    fn extents(&self) -> Vec<ExtDescriptor> {
+        self.0.iter()
+            .filter(|extent| extent.alloc_blk_count != 0)
+            .cloned()
+            .collect()
+    }
+    fn blk_count(&self) -> Result<u16, HfsError> {
+        self.0.iter()
+            .try_fold(0u16, |sum, extent| sum.checked_add(extent.alloc_blk_count))
+            .ok_or_else(|| HfsError::Corrupt("an extent record covers more than 65535 blocks".to_string()))
+    }
+}
+
+#[derive(Derivative, Clone, Default, BinRead, BinWrite, Eq, PartialEq)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct ExtDescriptor {
+    first_alloc_blk: u16,
+    alloc_blk_count: u16,
+}
+
+impl ExtDescriptor {
+    fn new(first_alloc_blk: u16, alloc_blk_count: u16) -> Self {
+        Self {
+            first_alloc_blk,
+            alloc_blk_count,
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Copy, Clone)]
+    pub struct MdbAttrs: u16 {
+        const HW_LOCKED = 1 << 7;
+        const UNMOUNTED_SUCCESSFULLY = 1 << 8;
+        const BADBLOCKS_SPARED = 1 << 9;
+        const SW_LOCKED = 1 << 15;
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct BTreeFile<T: BTreeRecord> {
+    header: HeaderNode,
+    #[br(count = header.header_record.node_count - 1)]
+    nodes: Vec<BTreeNode<T>>,
+    #[brw(ignore)]
+    #[derivative(Debug = "ignore")]
+    dirty: BTreeSet<u32>,
+}
+
+pub trait BTreeRecord:
+    for<'a> BinRead<Args<'a> = (NodeType,)> + for<'a> BinWrite<Args<'a> = ()> + Clone + fmt::Debug + 'static
+{
+    const NAME: &'static str;
+    type Key: Ord + Clone + fmt::Debug;
+    fn key(&self) -> Self::Key;
+    fn child(&self) -> Option<u32>;
+    fn index(key: &Self::Key, child: u32) -> Self;
+}
+
+impl BTreeRecord for CatalogRecord {
+    const NAME: &'static str = "catalog";
+    type Key = CatalogKey;
+    fn key(&self) -> CatalogKey {
+        match self {
+            CatalogRecord::Index { parent_id, name, .. } => CatalogKey::new(*parent_id, name.decode()),
+            CatalogRecord::Leaf { parent_id, name, .. } => CatalogKey::new(*parent_id, name.as_str()),
+        }
+    }
+    fn child(&self) -> Option<u32> {
+        match self {
+            CatalogRecord::Index { child, .. } => Some(*child),
+            CatalogRecord::Leaf { .. } => None,
+        }
+    }
+    fn index(key: &CatalogKey, child: u32) -> Self {
+        CatalogRecord::Index {
+            key_len: 0x25,
+            parent_id: key.parent_id,
+            // keys only ever come from valid catalog names, which always fit
+            name: PascalString::new(&key.name).unwrap_or_else(|| PascalString::new("").unwrap()),
+            child,
+        }
+    }
+}
+
+impl BTreeRecord for ExtentsRecord {
+    const NAME: &'static str = "extents overflow";
+    type Key = ExtentKey;
+    fn key(&self) -> ExtentKey {
+        match self {
+            ExtentsRecord::Index { key, .. } => key.clone(),
+            ExtentsRecord::Leaf { key, .. } => key.clone(),
+        }
+    }
+    fn child(&self) -> Option<u32> {
+        match self {
+            ExtentsRecord::Index { child, .. } => Some(*child),
+            ExtentsRecord::Leaf { .. } => None,
+        }
+    }
+    fn index(key: &ExtentKey, child: u32) -> Self {
+        ExtentsRecord::Index {
+            key: key.clone(),
+            child,
+        }
+    }
+}
+
+const NODE_SIZE: usize = 512;
+const NODE_DESCRIPTOR_SIZE: usize = 14;
+
+impl<T: BTreeRecord> BTreeFile<T> {
+    // An empty tree with every node marked dirty, so that all of them get written out.
+    fn new(node_count: u32, max_key_len: u16) -> Self {
+        Self {
+            header: HeaderNode::new(node_count, max_key_len),
+            nodes: (1..node_count).map(|_| BTreeNode::new(NodeType::Index, 0)).collect(),
+            dirty: (1..node_count).collect(),
+        }
+    }
+    fn node(&self, n: u32) -> &BTreeNode<T> {
+        &self.nodes[n as usize - 1]
+    }
+    fn node_mut(&mut self, n: u32) -> &mut BTreeNode<T> {
+        self.dirty.insert(n);
+        &mut self.nodes[n as usize - 1]
+    }
+    // Node numbers from the root down to the leaf node which contains (or would contain) `key`.
+    fn leaf_path(&self, key: &T::Key) -> Option<Vec<u32>> {
+        let hr = &self.header.header_record;
+        if hr.root == 0 {
+            return None;
+        }
+        let mut path = vec![hr.root];
+        let mut node = self.node(hr.root);
+        while node.desc.ty == NodeType::Index {
+            let child = node.recs
+                .iter()
+                .take_while(|rec| rec.key() <= *key)
+                .last()
+                .or(node.recs.first())?
+                .child()?;
+            path.push(child);
+            node = self.node(child);
+        }
+
+        Some(path)
+    }
+    // Leaf records in key order, starting from the first one not smaller than `key` and following
+    // the leaf nodes' forward links.
+    fn records_from(&self, key: &T::Key) -> LeafRecords<'_, T> {
+        let (node, pos) = match self.leaf_path(key).and_then(|path| path.last().copied()) {
+            Some(leaf) => (leaf, self.node(leaf).recs.partition_point(|rec| rec.key() < *key)),
+            None => (0, 0),
+        };
+        LeafRecords {
+            tree: self,
+            node,
+            pos,
+            nodes_left: self.header.header_record.node_count,
+        }
+    }
+    fn find(&self, key: &T::Key) -> Option<&T> {
+        let leaf = *self.leaf_path(key)?.last()?;
+        self.node(leaf).recs.iter().find(|rec| rec.key() == *key)
+    }
+    fn find_mut(&mut self, key: &T::Key) -> Option<&mut T> {
+        let leaf = *self.leaf_path(key)?.last()?;
+        let pos = self.node(leaf).recs.iter().position(|rec| rec.key() == *key)?;
+        Some(&mut self.node_mut(leaf).recs[pos])
+    }
+    fn alloc_node(&mut self, ty: NodeType, level: u8) -> Result<u32, HfsError> {
+        let map = &mut self.header.map_record.0;
+        let limit = (self.header.header_record.node_count as usize).min(map.len() * 8);
+        let n = (1..limit)
+            .find(|n| map[n / 8] & (0x80 >> (n % 8)) == 0)
+            .ok_or(HfsError::BTreeFull(T::NAME))?;
+        map[n / 8] |= 0x80 >> (n % 8);
+        self.header.header_record.free_nodes -= 1;
+        *self.node_mut(n as u32) = BTreeNode::new(ty, level);
+
+        Ok(n as u32)
+    }
+    fn free_node(&mut self, n: u32) {
+        let map = &mut self.header.map_record.0;
+        map[n as usize / 8] &= !(0x80 >> (n % 8));
+        self.header.header_record.free_nodes += 1;
+        *self.node_mut(n) = BTreeNode::new(NodeType::Index, 0);
+    }
+    // A split can cascade all the way up and add a new root on top, so every insertion needs up to
+    // depth + 1 free nodes.
+    fn ensure_free_nodes(&self, inserts: u32) -> Result<(), HfsError> {
+        let hr = &self.header.header_record;
+        if hr.free_nodes < inserts * (hr.depth as u32 + 1) {
+            return Err(HfsError::BTreeFull(T::NAME));
+        }
+        Ok(())
+    }
+    fn insert(&mut self, rec: T) -> Result<(), HfsError> {
+        let key = rec.key();
+        self.ensure_free_nodes(1)?;
+
+        let Some(path) = self.leaf_path(&key) else {
+            let root = self.alloc_node(NodeType::Leaf, 1)?;
+            self.node_mut(root).recs.push(rec);
+            let hr = &mut self.header.header_record;
+            hr.root = root;
+            hr.depth = 1;
+            hr.first_leaf = root;
+            hr.last_leaf = root;
+            hr.leaf_count = 1;
+            return Ok(());
+        };
+
+        let leaf = *path.last().unwrap();
+        if self.node(leaf).recs.iter().any(|r| r.key() == key) {
+            return Err(HfsError::AlreadyExists(format!("{:?}", key)));
+        }
+        self.header.header_record.leaf_count += 1;
+        self.insert_at(&path, path.len() - 1, rec)
+    }
+    fn insert_at(&mut self, path: &[u32], depth: usize, rec: T) -> Result<(), HfsError> {
+        let n = path[depth];
+        let key = rec.key();
+        let pos = self.node(n).recs.partition_point(|r| r.key() < key);
+        self.node_mut(n).recs.insert(pos, rec);
+        if pos == 0 && depth > 0 {
+            self.update_parent_key(path, depth);
+        }
+        if self.node(n).fits() {
+            return Ok(());
+        }
+
+        let NodeDescriptor { ty, level, forward_link, .. } = self.node(n).desc.clone();
+        let new = self.alloc_node(ty, level)?;
+        // appending (the usual case for sequentially named files) leaves the old node full
+        let len = self.node(n).recs.len();
+        let mid = if pos == len - 1 { len - 1 } else { len / 2 };
+        let right = self.node_mut(n).recs.split_off(mid);
+        self.node_mut(n).desc.forward_link = new;
+        let new_node = self.node_mut(new);
+        new_node.recs = right;
+        new_node.desc.backward_link = n;
+        new_node.desc.forward_link = forward_link;
+        if forward_link != 0 {
+            self.node_mut(forward_link).desc.backward_link = new;
+        }
+        if ty == NodeType::Leaf && self.header.header_record.last_leaf == n {
+            self.header.header_record.last_leaf = new;
+        }
+
+        let new_index = T::index(&self.node(new).recs[0].key(), new);
+        if depth == 0 {
+            let root = self.alloc_node(NodeType::Index, level + 1)?;
+            let old_index = T::index(&self.node(n).recs[0].key(), n);
+            self.node_mut(root).recs = vec![old_index, new_index];
+            self.header.header_record.root = root;
+            self.header.header_record.depth += 1;
+            Ok(())
+        } else {
+            self.insert_at(path, depth - 1, new_index)
+        }
+    }
+    // Index records carry the first key of their child, so it has to be refreshed whenever the
+    // first record of a node changes.
+    fn update_parent_key(&mut self, path: &[u32], depth: usize) {
+        let n = path[depth];
+        let parent = path[depth - 1];
+        let Some(first) = self.node(n).recs.first().map(|rec| rec.key()) else {
+            return;
+        };
+        let Some(pos) = self.node(parent).recs.iter().position(|rec| rec.child() == Some(n)) else {
+            return;
+        };
+        self.node_mut(parent).recs[pos] = T::index(&first, n);
+        if pos == 0 && depth > 1 {
+            self.update_parent_key(path, depth - 1);
+        }
+    }
+    fn remove(&mut self, key: &T::Key) -> Result<T, HfsError> {
+        let path = self.leaf_path(key).ok_or_else(|| HfsError::NotFound(format!("{:?}", key)))?;
+        let leaf = *path.last().unwrap();
+        let pos = self.node(leaf).recs
+            .iter()
+            .position(|rec| rec.key() == *key)
+            .ok_or_else(|| HfsError::NotFound(format!("{:?}", key)))?;
+        let rec = self.node_mut(leaf).recs.remove(pos);
+        self.header.header_record.leaf_count -= 1;
+        self.remove_fixup(&path, path.len() - 1, pos);
+
+        Ok(rec)
+    }
+    // Nodes are not rebalanced after removals, only dropped once they become empty.
+    fn remove_fixup(&mut self, path: &[u32], depth: usize, pos: usize) {
+        let n = path[depth];
+        if self.node(n).recs.is_empty() {
+            let NodeDescriptor { ty, forward_link, backward_link, .. } = self.node(n).desc.clone();
+            if backward_link != 0 {
+                self.node_mut(backward_link).desc.forward_link = forward_link;
+            }
+            if forward_link != 0 {
+                self.node_mut(forward_link).desc.backward_link = backward_link;
+            }
+            let hr = &mut self.header.header_record;
+            if ty == NodeType::Leaf && hr.first_leaf == n {
+                hr.first_leaf = forward_link;
+            }
+            if ty == NodeType::Leaf && hr.last_leaf == n {
+                hr.last_leaf = backward_link;
+            }
+            self.free_node(n);
+
+            if depth == 0 {
+                let hr = &mut self.header.header_record;
+                hr.root = 0;
+                hr.depth = 0;
+                hr.first_leaf = 0;
+                hr.last_leaf = 0;
+            } else {
+                let parent = path[depth - 1];
+                if let Some(ppos) = self.node(parent).recs.iter().position(|rec| rec.child() == Some(n)) {
+                    self.node_mut(parent).recs.remove(ppos);
+                    self.remove_fixup(path, depth - 1, ppos);
+                }
+            }
+            return;
+        }
+
+        if depth > 0 {
+            if pos == 0 {
+                self.update_parent_key(path, depth);
+            }
+            return;
+        }
+        // a root left with a single child is replaced by that child
+        loop {
+            let root = self.header.header_record.root;
+            let node = self.node(root);
+            if node.desc.ty != NodeType::Index || node.recs.len() != 1 {
+                break;
+            }
+            let Some(child) = node.recs[0].child() else {
+                break;
+            };
+            self.free_node(root);
+            self.header.header_record.root = child;
+            self.header.header_record.depth -= 1;
+        }
+    }
+}
+
+pub struct LeafRecords<'a, T: BTreeRecord> {
+    tree: &'a BTreeFile<T>,
+    node: u32,
+    pos: usize,
+    // guards against cycles in the forward links of a damaged tree
+    nodes_left: u32,
+}
+
+impl<'a, T: BTreeRecord> Iterator for LeafRecords<'a, T> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            if self.node == 0 || self.node >= self.tree.header.header_record.node_count {
+                return None;
+            }
+            let node = self.tree.node(self.node);
+            if let Some(rec) = node.recs.get(self.pos) {
+                self.pos += 1;
+                return Some(rec);
+            }
+            self.nodes_left = self.nodes_left.checked_sub(1)?;
+            self.node = node.desc.forward_link;
+            self.pos = 0;
+        }
+    }
+}
+
+// Writes the header node and every modified node back through the file's extents.
+fn write_btree<T: BTreeRecord, W: Read + Write + Seek>(tree: &mut BTreeFile<T>, mut file: FileReader<'_, W>) -> BinResult<()> {
+    file.seek(SeekFrom::Start(0))?;
+    tree.header.write(&mut file)?;
+    for n in std::mem::take(&mut tree.dirty) {
+        file.seek(SeekFrom::Start(n as u64 * NODE_SIZE as u64))?;
+        tree.node(n).write_options(&mut file, Endian::Big, ())?;
+    }
+
+    Ok(())
+}
+
+pub type CatalogFile = BTreeFile<CatalogRecord>;
+pub type ExtentsOverflowFile = BTreeFile<ExtentsRecord>;
+
+impl CatalogFile {
+    fn record_data(&self, key: &CatalogKey) -> Option<&CatalogRecordData> {
+        match self.find(key)? {
+            CatalogRecord::Leaf { data, .. } => Some(data),
+            CatalogRecord::Index { .. } => None,
+        }
+    }
+    fn record_data_mut(&mut self, key: &CatalogKey) -> Option<&mut CatalogRecordData> {
+        match self.find_mut(key)? {
+            CatalogRecord::Leaf { data, .. } => Some(data),
+            CatalogRecord::Index { .. } => None,
+        }
+    }
+    pub fn root_node(&self) -> Option<&BTreeNode<CatalogRecord>> {
+        match self.header.header_record.root {
+            0 => None,
+            root => Some(self.node(root)),
+        }
+    }
+    // The records of a directory's children directly follow its thread record, which has the
+    // smallest possible key for that parent.
+    fn children(&self, id: Cnid) -> impl Iterator<Item = &CatalogRecord> {
+        self.records_from(&CatalogKey::thread(id))
+            .take_while(move |rec| rec.key().parent_id == id)
+    }
+    // Directories always have a thread record, files only when `THREAD_EXISTS` is set, so the
+    // rest of them can only be found by going through every leaf.
+    fn record_by_id(&self, id: Cnid) -> Option<&CatalogRecord> {
+        match self.record_data(&CatalogKey::thread(id)) {
+            Some(
+                CatalogRecordData::DirectoryThread { parent_id, name } |
+                CatalogRecordData::FileThread { parent_id, name }
+            ) => self.find(&CatalogKey::new(*parent_id, name.decode())),
+            _ => self.records_from(&CatalogKey::thread(Cnid::ParentOfRoot)).find(|rec| match rec {
+                CatalogRecord::Leaf { data: CatalogRecordData::Directory { id: rec_id, .. }, .. } |
+                CatalogRecord::Leaf { data: CatalogRecordData::File { id: rec_id, .. }, .. } => *rec_id == id,
+                _ => false,
+            }),
+        }
+    }
+}
+
+impl ExtentsOverflowFile {
+    fn record_by_key(&self, key: &ExtentKey) -> Option<&ExtDataRec> {
+        match self.find(key)? {
+            ExtentsRecord::Leaf { data, .. } => Some(data),
+            ExtentsRecord::Index { .. } => None,
+        }
+    }
+    // Resolves every extent of a fork: the ones stored inline in the catalog (or MDB), followed by
+    // the records chained in the extents overflow file, each keyed by the first allocation block
+    // of the fork it covers.
+    fn fork_extents(&self, first: &ExtDataRec, fork: Fork, id: Cnid) -> Result<Vec<ExtDescriptor>, HfsError> {
+        let mut extents = first.extents();
+        let mut key = ExtentKey::new(fork, id, first.blk_count()?);
+        while let Some(rec) = self.record_by_key(&key) {
+            let count = rec.blk_count()?;
+            if count == 0 {
+                break;
+            }
+            extents.extend(rec.extents());
+            key.start = key.start
+                .checked_add(count)
+                .ok_or_else(|| HfsError::Corrupt(format!("the extents of {:?} cover more than 65535 blocks", id)))?;
+        }
+
+        Ok(extents)
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct BTreeNode<T: BTreeRecord> {
+    // the offsets of the records have to fit in the node along with the descriptor
+    #[br(assert(
+        desc.record_count as usize <= (NODE_SIZE - NODE_DESCRIPTOR_SIZE) / 2,
+        "{} records don't fit in a B-tree node", desc.record_count
+    ))]
+    desc: NodeDescriptor,
+    #[br(restore_position)]
+    #[br(args { count: desc.record_count as usize, inner: (desc.ty,) })]
+    recs: Vec<T>,
+    #[br(seek_before = SeekFrom::Current((NODE_SIZE - NODE_DESCRIPTOR_SIZE - desc.record_count as usize * 2) as i64))]
+    #[br(count = desc.record_count)]
+    #[br(assert(offsets_fit(&recs_offsets), "B-tree node record offsets are out of order or outside the node"))]
+    recs_offsets: Vec<u16>,
+}
+
+// The offsets are stored backwards from the end of the node, and the records they point to all
+// lie between the descriptor and the offsets.
+fn offsets_fit(offsets: &[u16]) -> bool {
+    let end = NODE_SIZE - 2 * offsets.len();
+    offsets.iter().all(|off| (NODE_DESCRIPTOR_SIZE..end).contains(&(*off as usize)))
+        && offsets.windows(2).all(|pair| pair[0] > pair[1])
+}
+
+impl<T: BTreeRecord> BTreeNode<T> {
+    fn new(ty: NodeType, level: u8) -> Self {
+        Self {
+            desc: NodeDescriptor {
+                forward_link: 0,
+                backward_link: 0,
+                ty,
+                level,
+                record_count: 0,
+            },
+            recs: Vec::new(),
+            recs_offsets: Vec::new(),
+        }
+    }
+    fn fits(&self) -> bool {
+        let recs_len: usize = self.recs.iter().map(record_len).sum();
+        NODE_DESCRIPTOR_SIZE + recs_len + 2 * (self.recs.len() + 1) <= NODE_SIZE
+    }
+}
+
+fn record_len<T: BTreeRecord>(rec: &T) -> usize {
+    let mut buf = Cursor::new(Vec::new());
+    // writing into memory can't fail
+    let _ = rec.write_options(&mut buf, Endian::Big, ());
+    buf.into_inner().len()
+}
+
+// Records are packed after the descriptor and located through a table of offsets growing
+// backwards from the end of the node, which ends with the offset of the free space.
+impl<T: BTreeRecord> BinWrite for BTreeNode<T> {
+    type Args<'a> = ();
+    fn write_options<W: Write + Seek>(&self, writer: &mut W, endian: Endian, _: ()) -> BinResult<()> {
+        let mut node = Cursor::new(Vec::with_capacity(NODE_SIZE));
+        let desc = NodeDescriptor {
+            record_count: self.recs.len() as u16,
+            ..self.desc.clone()
+        };
+        desc.write_options(&mut node, endian, ())?;
+        let mut offsets = Vec::with_capacity(self.recs.len() + 1);
+        for rec in self.recs.iter() {
+            offsets.push(node.position() as u16);
+            rec.write_options(&mut node, endian, ())?;
+        }
+        offsets.push(node.position() as u16);
+
+        let mut buf = node.into_inner();
+        if buf.len() + offsets.len() * 2 > NODE_SIZE {
+            return Err(binrw::Error::AssertFail {
+                pos: writer.stream_position()?,
+                message: "B-tree node records overflow the node".to_string(),
+            });
+        }
+        buf.resize(NODE_SIZE, 0);
+        for (i, off) in offsets.iter().enumerate() {
+            let end = NODE_SIZE - 2 * i;
+            buf[end - 2..end].copy_from_slice(&off.to_be_bytes());
+        }
+        writer.write_all(&buf)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+#[br(import(ty: NodeType))]
+pub enum ExtentsRecord {
+    #[br(pre_assert(ty == NodeType::Index))]
+    Index {
+        key: ExtentKey,
+        child: u32,
+    },
+    #[br(pre_assert(ty == NodeType::Leaf))]
+    Leaf {
+        key: ExtentKey,
+        data: ExtDataRec,
+    },
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct ExtentKey {
+    key_len: u8,
+    fork: Fork,
+    id: Cnid,
+    start: u16,
+}
+
+impl Ord for ExtentKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.as_u32().cmp(&other.id.as_u32())
+            .then(self.fork.cmp(&other.fork))
+            .then(self.start.cmp(&other.start))
+    }
+}
+
+impl PartialOrd for ExtentKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ExtentKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ExtentKey {}
+
+impl ExtentKey {
+    fn new(fork: Fork, id: Cnid, start: u16) -> Self {
+        Self {
+            key_len: 7,
+            fork,
+            id,
+            start,
+        }
+    }
+}
+
+#[derive(Derivative, Clone, Copy, BinRead, BinWrite, Eq, PartialEq, Ord, PartialOrd)]
+#[derivative(Debug)]
+#[brw(big)]
+pub enum Fork {
+    #[brw(magic = b"\x00")] Data,
+    #[brw(magic = b"\xff")] Resource,
+}
+
+impl From<super::Fork> for Fork {
+    fn from(fork: super::Fork) -> Fork {
+        match fork {
+            super::Fork::Data => Fork::Data,
+            super::Fork::Resource => Fork::Resource,
+        }
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+#[br(import(ty: NodeType))]
+pub enum CatalogRecord {
+    #[br(pre_assert(ty == NodeType::Index))]
+    Index {
+        #[brw(pad_after = 1)]
+        key_len: u8,
+        parent_id: Cnid,
+        name: PascalString<31>,
+        child: u32,
+    },
+    #[br(pre_assert(ty == NodeType::Leaf))]
+    Leaf {
+        #[brw(pad_after = 1)]
+        key_len: u8,
+        parent_id: Cnid,
+        #[brw(align_after = 2)]
+        name: DynamicPascalString,
+        data: CatalogRecordData,
+    },
+}
+
+#[derive(Derivative, Clone, Eq, PartialEq, Copy, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub enum Cnid {
+    #[brw(magic = b"\x00\x00\x00\x01")]
+    ParentOfRoot,
+    #[brw(magic = b"\x00\x00\x00\x02")]
+    RootDir,
+    #[brw(magic = b"\x00\x00\x00\x03")]
+    ExtentsFile,
+    #[brw(magic = b"\x00\x00\x00\x04")]
+    CatalogFile,
+    #[brw(magic = b"\x00\x00\x00\x05")]
+    BadBlocksFile,
+    Other(u32),
+}
+
+impl Cnid {
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            Cnid::ParentOfRoot => 1,
+            Cnid::RootDir => 2,
+            Cnid::ExtentsFile => 3,
+            Cnid::CatalogFile => 4,
+            Cnid::BadBlocksFile => 5,
+            Cnid::Other(id) => *id,
+        }
+    }
+}
+
+impl From<u32> for Cnid {
+    fn from(id: u32) -> Cnid {
+        match id {
+            1 => Cnid::ParentOfRoot,
+            2 => Cnid::RootDir,
+            3 => Cnid::ExtentsFile,
+            4 => Cnid::CatalogFile,
+            5 => Cnid::BadBlocksFile,
+            other => Cnid::Other(other),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CatalogKey {
+    parent_id: Cnid,
+    name: String,
+}
+
+impl CatalogKey {
+    fn new(parent_id: Cnid, name: impl Into<String>) -> Self {
+        Self {
+            parent_id,
+            name: name.into(),
+        }
+    }
+    fn thread(id: Cnid) -> Self {
+        Self::new(id, "")
+    }
+}
+
+impl Ord for CatalogKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parent_id.as_u32().cmp(&other.parent_id.as_u32())
+            .then_with(|| compare_names(&self.name, &other.name))
+    }
+}
+
+impl PartialOrd for CatalogKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for CatalogKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CatalogKey {}
+
+// Catalog names are ordered like the File Manager does it: case-insensitively, with accented
+// letters sorting right after their unaccented counterpart.
+fn name_sort_weight(ch: char) -> u16 {
+    let upper = mac_roman_upper(ch);
+    let base = match upper {
+        'À' | 'Á' | 'Â' | 'Ã' | 'Ä' | 'Å' | 'Æ' | 'ª' => 'A',
+        'Ç' => 'C',
+        'È' | 'É' | 'Ê' | 'Ë' => 'E',
+        'Ì' | 'Í' | 'Î' | 'Ï' => 'I',
+        'Ñ' => 'N',
+        'Ò' | 'Ó' | 'Ô' | 'Õ' | 'Ö' | 'Ø' | 'Œ' | 'º' => 'O',
+        'Ù' | 'Ú' | 'Û' | 'Ü' => 'U',
+        'Ÿ' => 'Y',
+        other => other,
+    };
+    let Ok(byte) = MacRoman::encode(base) else {
+        return u16::MAX;
+    };
+    let variant = if base == upper {
+        0
+    } else {
+        MacRoman::encode(upper).unwrap_or(0xff)
+    };
+
+    (byte as u16) << 8 | variant as u16
+}
+
+// Case folding within Mac Roman only: letters without an uppercase form there, like 'ß', 'ı' and
+// the ligatures, stay distinct instead of turning into the letters Unicode would make of them.
+fn mac_roman_upper(ch: char) -> char {
+    match ch {
+        'a'..='z' => ch.to_ascii_uppercase(),
+        'á' => 'Á', 'à' => 'À', 'â' => 'Â', 'ä' => 'Ä', 'ã' => 'Ã', 'å' => 'Å', 'æ' => 'Æ',
+        'ç' => 'Ç',
+        'é' => 'É', 'è' => 'È', 'ê' => 'Ê', 'ë' => 'Ë',
+        'í' => 'Í', 'ì' => 'Ì', 'î' => 'Î', 'ï' => 'Ï',
+        'ñ' => 'Ñ',
+        'ó' => 'Ó', 'ò' => 'Ò', 'ô' => 'Ô', 'ö' => 'Ö', 'õ' => 'Õ', 'ø' => 'Ø', 'œ' => 'Œ',
+        'ú' => 'Ú', 'ù' => 'Ù', 'û' => 'Û', 'ü' => 'Ü',
+        'ÿ' => 'Ÿ',
+        other => other,
+    }
+}
+
+fn compare_names(a: &str, b: &str) -> Ordering {
+    a.chars()
+        .map(name_sort_weight)
+        .cmp(b.chars().map(name_sort_weight))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let len = name.chars().count();
+    (1..=31).contains(&len)
+        && !name.contains(':')
+        && name.chars().all(|ch| MacRoman::encode(ch).is_ok())
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite, Eq, PartialEq)]
+#[derivative(Debug)]
+#[brw(big)]
+pub enum CatalogRecordData {
+    #[brw(magic = b"\x01")]
+    Directory {
+        #[brw(pad_before = 1)]
+        flags: u16,
+        valence: u16,
+        id: Cnid,
+        ctime: DateTime,
+        mtime: DateTime,
+        backup_time: DateTime,
+        finder_info: FinderInfo,
+        #[brw(pad_after = 16)]
+        more_finder_info: ExtraFinderInfo,
+    },
+    #[brw(magic = b"\x02")]
+    File {
+        #[brw(pad_before = 1)]
+        #[br(map = |v: u8| FileFlags::from_bits_retain(v))]
+        #[bw(map = |v: &FileFlags| v.bits())]
+        flags: FileFlags,
+        kind: u8,
+        finder_info: FinderInfo,
+        id: Cnid,
+        data_start: u16,
+        data_len: u32,
+        data_allocated_len: u32,
+        rsrc_start: u16,
+        rsrc_len: u32,
+        rsrc_allocated_len: u32,
+        ctime: DateTime,
+        mtime: DateTime,
+        backup_time: DateTime,
+        more_finder_info: ExtraFinderInfo,
+        clump_size: u16,
+        data_extent: ExtDataRec,
+        #[brw(pad_after = 4)]
+        rsrc_extent: ExtDataRec,
+    },
+    #[brw(magic = b"\x03")]
+    DirectoryThread {
+        #[brw(pad_before = 1 + 8)]
+        parent_id: Cnid,
+        name: PascalString<31>,
+    },
+    #[brw(magic = b"\x04")]
+    FileThread {
+        #[brw(pad_before = 1 + 8)]
+        parent_id: Cnid,
+        name: PascalString<31>,
+    },
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub struct FileFlags: u8 {
+        const LOCKED = 1 << 0;
+        const THREAD_EXISTS = 1 << 1;
+        const FILE_USED = 1 << 7;
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct HeaderNode {
+    desc: NodeDescriptor,
+    header_record: HeaderRecord,
+    #[derivative(Debug = "ignore")]
+    reserved: [u8; 128],
+    map_record: MapRecord,
+    free_offset: u16,
+    map_offset: u16,
+    reserved_offset: u16,
+    header_offset: u16,
+}
+
+impl HeaderNode {
+    fn new(node_count: u32, max_key_len: u16) -> Self {
+        let mut map = [0; 256];
+        // the header node itself
+        map[0] = 0x80;
+        Self {
+            desc: NodeDescriptor {
+                forward_link: 0,
+                backward_link: 0,
+                ty: NodeType::Header,
+                level: 0,
+                record_count: 3,
+            },
+            header_record: HeaderRecord {
+                depth: 0,
+                root: 0,
+                leaf_count: 0,
+                first_leaf: 0,
+                last_leaf: 0,
+                node_size: NODE_SIZE as u16,
+                max_key_len,
+                node_count,
+                free_nodes: node_count - 1,
+            },
+            reserved: [0; 128],
+            map_record: MapRecord(map),
+            free_offset: 0x1f8,
+            map_offset: 0xf8,
+            reserved_offset: 0x78,
+            header_offset: 0x0e,
+        }
+    }
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct Node {
+    #[brw(pad_size_to = 512)]
+    desc: NodeDescriptor,
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct NodeDescriptor {
+    forward_link: u32,
+    backward_link: u32,
+    ty: NodeType,
+    level: u8,
+    #[brw(pad_after = 2)]
+    record_count: u16,
+}
+
+#[derive(Derivative, Clone, Copy, BinRead, BinWrite, Eq, PartialEq)]
+#[derivative(Debug)]
+#[brw(big)]
+pub enum NodeType {
+    #[brw(magic = b"\x00")] Index,
+    #[brw(magic = b"\x01")] Header,
+    #[brw(magic = b"\x02")] Map,
+    #[brw(magic = b"\xff")] Leaf,
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct HeaderRecord {
+    depth: u16,
+    root: u32,
+    leaf_count: u32,
+    first_leaf: u32,
+    last_leaf: u32,
+    #[br(assert(node_size == 512))]
+    node_size: u16,
+    max_key_len: u16,
+    node_count: u32,
+    #[brw(pad_after = 76)]
+    free_nodes: u32,
+}
+
+#[derive(Derivative, Clone, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct MapRecord(
+    #[derivative(Debug = "ignore")]
+    [u8; 256]
+);
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use super::{CatalogKey, Cnid, ExtDataRec, ExtDescriptor, FileReader, FormatOptions, Fork, HfsError, HfsVolume};
+    use crate::fs::{self, Volume};
+
+    use flate2::read::GzDecoder;
+
+    static HD_100MB: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/100mb-hfs.hda.gz"
+    ));
+    const HFS_PARTITION_START: usize = 96 * 512;
+
+    fn blank_volume() -> HfsVolume<Cursor<Vec<u8>>> {
+        let mut decoder = GzDecoder::new(HD_100MB);
+        let mut vec = Vec::new();
+        decoder.read_to_end(&mut vec).unwrap();
+        HfsVolume::new(Cursor::new(vec.split_off(HFS_PARTITION_START))).unwrap()
+    }
+
+    #[test]
+    fn open_blank_volume() {
+        let mut vol = blank_volume();
+        let root = vol.root_dir();
+        assert_eq!(root.name(), "Blank 100MB");
+        assert!(root.subdir("Desktop Folder").is_some());
+        let desktop_db = root.file("Desktop DB").unwrap();
+        assert_eq!(vol.file_data(desktop_db).unwrap().len(), 6144);
+        assert!(vol.file_rsrc(desktop_db).unwrap().is_empty());
+
+        let desktop_folder = root.subdir("Desktop Folder").unwrap();
+        assert_eq!(vol.dir_by_id(desktop_folder.id()).unwrap().name(), "Desktop Folder");
+        assert_eq!(vol.file_by_id(desktop_db.id()).unwrap().name(), "Desktop DB");
+        assert!(vol.file_by_id(desktop_folder.id()).is_none());
+    }
+
+    #[test]
+    fn volume() {
+        let mut vol = blank_volume();
+        let root = vol.root_dir();
+        let dir = vol.create_dir(&root, "Projects").unwrap();
+        let file = vol.create_file(&dir, "Note", *b"TEXT", *b"ttxt").unwrap();
+        vol.write_fork(&file, Fork::Resource, b"resources").unwrap();
+
+        assert_eq!(vol.volume_name(), "Blank 100MB");
+        let entry = vol.lookup("Projects/Note").unwrap().unwrap();
+        assert_eq!(entry.id(), file.id());
+        assert_eq!(entry.fork_len(fs::Fork::Data), 0);
+        assert_eq!(entry.finder_info().unwrap().creator().as_inner(), b"ttxt");
+        assert_eq!(vol.read_fork(&entry, fs::Fork::Resource).unwrap(), b"resources");
+        let projects = vol.lookup("/Projects/").unwrap().unwrap();
+        assert!(projects.is_dir());
+        assert_eq!(vol.read_dir(&projects).unwrap().len(), 1);
+        assert!(vol.lookup("/Projects/Nope").unwrap().is_none());
+    }
+
+    #[test]
+    fn catalog_key_order() {
+        let key = |parent: u32, name: &str| CatalogKey::new(Cnid::from(parent), name);
+        assert!(key(2, "") < key(2, "a"));
+        assert!(key(2, "zebra") < key(3, "apple"));
+        assert_eq!(key(2, "Read Me"), key(2, "READ ME"));
+        assert!(key(2, "Abc") < key(2, "abd"));
+        assert!(key(2, "a") < key(2, "á"));
+        assert!(key(2, "á") < key(2, "b"));
+        assert!(key(2, "File 10") < key(2, "File 9"));
+        // letters Unicode uppercases into other letters are names of their own
+        assert_ne!(key(2, "ß"), key(2, "S"));
+        assert_ne!(key(2, "ı"), key(2, "I"));
+        assert_ne!(key(2, "ﬁ"), key(2, "F"));
+        assert_eq!(key(2, "Ökonomie"), key(2, "öKONOMIE"));
+    }
+
+    #[test]
+    fn fragmented_read() {
+        let disk: Vec<u8> = (0..8u8).flat_map(|blk| [blk; 512]).collect();
+        let mut disk = Cursor::new(disk);
+        let extents = vec![
+            ExtDescriptor { first_alloc_blk: 5, alloc_blk_count: 2 },
+            ExtDescriptor { first_alloc_blk: 1, alloc_blk_count: 1 },
+            ExtDescriptor { first_alloc_blk: 3, alloc_blk_count: 1 },
+        ];
+        let mut reader = FileReader::new(&mut disk, 0, 512, extents, 4 * 512 - 100);
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        let expected: Vec<u8> = [5u8, 6, 1, 3]
+            .into_iter()
+            .flat_map(|blk| [blk; 512])
+            .take(4 * 512 - 100)
+            .collect();
+        assert_eq!(data, expected);
+
+        let mut buf = [0u8; 20];
+        reader.seek(SeekFrom::Start(3 * 512 - 10)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..10], &[1; 10]);
+        assert_eq!(&buf[10..], &[3; 10]);
+    }
+
+    #[test]
+    fn extent_overflow() {
+        let rec = ExtDataRec::new(&[
+            ExtDescriptor { first_alloc_blk: 0, alloc_blk_count: 0xff00 },
+            ExtDescriptor { first_alloc_blk: 0xff00, alloc_blk_count: 0x100 },
+        ]);
+        assert!(matches!(rec.blk_count(), Err(HfsError::Corrupt(_))));
+        let rec = ExtDataRec::new(&[ExtDescriptor { first_alloc_blk: 0, alloc_blk_count: 0xffff }]);
+        assert_eq!(rec.blk_count().unwrap(), 0xffff);
+    }
+
+    #[test]
+    fn format_floppy() {
+        let vol = HfsVolume::format(Cursor::new(Vec::new()), 800 * 1024, "Untitled", FormatOptions::default()).unwrap();
+        assert_eq!(vol.hdr.mdb.alloc_blk_size, 512);
+        assert_eq!(vol.hdr.mdb.alloc_blk_start, 4);
+        assert_eq!(vol.hdr.mdb.alloc_blk_count, 1594);
+        assert_eq!(vol.hdr.mdb.catalog_file_size, 13 * 512);
+        assert_eq!(vol.reader.get_ref().len(), 800 * 1024);
+        assert_eq!(&vol.reader.get_ref()[800 * 1024 - 1024..][..2], b"BD");
+
+        let mut vol = reopen(vol);
+        let root = vol.root_dir();
+        assert_eq!(root.name(), "Untitled");
+        assert!(root.files().is_empty() && root.subdirs().is_empty());
+        let file = vol.create_file(&root, "Read Me", *b"TEXT", *b"ttxt").unwrap();
+        vol.write_fork(&file, Fork::Data, b"hello").unwrap();
+        vol.flush().unwrap();
+
+        let mut vol = reopen(vol);
+        let file = vol.root_dir().file("Read Me").unwrap().clone();
+        assert_eq!(vol.file_data(&file).unwrap(), b"hello");
+        assert_eq!(vol.hdr.mdb.free_blks, 1594 - 2 * 13 - 1);
+    }
+
+    #[test]
+    fn format_hard_disk() {
+        let size = 100 * 1024 * 1024;
+        let vol = HfsVolume::format(Cursor::new(Vec::new()), size, "Macintosh HD", FormatOptions::default()).unwrap();
+        let mdb = &vol.hdr.mdb;
+        assert_eq!(mdb.alloc_blk_size, 2048);
+        assert!(mdb.alloc_blk_start as u64 * 512 + mdb.alloc_blk_count as u64 * 2048 <= size - 1024);
+        assert!(matches!(
+            HfsVolume::format(Cursor::new(Vec::new()), size, "Macintosh HD", FormatOptions {
+                alloc_blk_size: Some(512),
+                ..FormatOptions::default()
+            }),
+            Err(HfsError::BadLayout(_)),
+        ));
+        let vol = reopen(vol);
+        assert_eq!(vol.root_dir().name(), "Macintosh HD");
+    }
+
+    fn reopen(vol: HfsVolume<Cursor<Vec<u8>>>) -> HfsVolume<Cursor<Vec<u8>>> {
+        let mut disk = vol.into_inner();
+        disk.set_position(0);
+        HfsVolume::new(disk).unwrap()
+    }
+
+    #[test]
+    fn lookalike_names() {
+        let mut vol = blank_volume();
+        let root = vol.root_dir();
+        for name in ["S", "ß", "I", "ı", "F", "ﬁ"] {
+            let file = vol.create_file(&root, name, *b"TEXT", *b"ttxt").unwrap();
+            vol.write_fork(&file, Fork::Data, name.as_bytes()).unwrap();
+        }
+        vol.create_file(&root, "ss", *b"TEXT", *b"ttxt").unwrap();
+        assert!(matches!(vol.create_file(&root, "s", *b"TEXT", *b"ttxt"), Err(HfsError::AlreadyExists(_))));
+        vol.flush().unwrap();
+
+        let mut vol = reopen(vol);
+        for name in ["S", "ß", "I", "ı", "F", "ﬁ"] {
+            let file = vol.root_dir().file(name).unwrap().clone();
+            assert_eq!(vol.file_data(&file).unwrap(), name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn write_then_reopen() {
+        let mut vol = blank_volume();
+        let free_blks = vol.hdr.mdb.free_blks;
+        let root = vol.root_dir();
+        let dir = vol.create_dir(&root, "Projects").unwrap();
+        assert!(matches!(vol.create_dir(&root, "projects"), Err(HfsError::AlreadyExists(_))));
+        assert!(matches!(vol.create_file(&dir, "a:b", *b"TEXT", *b"ttxt"), Err(HfsError::InvalidName(_))));
+
+        // enough records to split the catalog leaves and grow the tree
+        for i in 0..80 {
+            let file = vol.create_file(&dir, &format!("File {i:02}"), *b"TEXT", *b"ttxt").unwrap();
+            vol.write_fork(&file, Fork::Data, format!("contents of {i}").as_bytes()).unwrap();
+        }
+        let file = vol.root_dir().subdir("Projects").unwrap().file("File 07").unwrap().clone();
+        let file = vol.write_fork(&file, Fork::Resource, &[0xaa; 5000]).unwrap();
+        let file = vol.rename_file(&file, "Renamed").unwrap();
+        for i in (0..80).step_by(2) {
+            let dir = vol.root_dir().subdir("Projects").unwrap().clone();
+            vol.delete_file(dir.file(&format!("File {i:02}")).unwrap()).unwrap();
+        }
+        vol.flush().unwrap();
+
+        let mut vol = reopen(vol);
+        let dir = vol.root_dir().subdir("Projects").unwrap().clone();
+        assert_eq!(dir.files().len(), 40);
+        assert!(dir.file("File 08").is_none());
+        assert_eq!(vol.file_data(dir.file("File 09").unwrap()).unwrap(), b"contents of 9");
+        let renamed = dir.file("Renamed").unwrap();
+        assert_eq!(vol.file_data(renamed).unwrap(), b"contents of 7");
+        assert_eq!(vol.file_rsrc(renamed).unwrap(), vec![0xaa; 5000]);
+        assert_eq!(file.rsrc_len(), 5000);
+
+        // the deleted files left holes behind, so filling the disk needs overflow extents
+        let blk_size = vol.hdr.mdb.alloc_blk_size as usize;
+        let big = vol.create_file(&dir, "Big", *b"BINA", *b"????").unwrap();
+        let contents: Vec<u8> = (0..vol.hdr.mdb.free_blks as usize * blk_size).map(|i| (i / 512) as u8).collect();
+        let big = vol.write_fork(&big, Fork::Data, &contents).unwrap();
+        assert_eq!(vol.hdr.mdb.free_blks, 0);
+        assert!(vol.hdr.extents_overflow.header.header_record.leaf_count > 0);
+        assert!(matches!(vol.write_fork(&renamed.clone(), Fork::Data, &[0; 4096]), Err(HfsError::DiskFull)));
+        assert_eq!(vol.file_data(renamed).unwrap(), b"contents of 7");
+        vol.flush().unwrap();
+
+        let mut vol = reopen(vol);
+        assert_eq!(vol.file_data(&big).unwrap(), contents);
+        vol.delete_file(&big).unwrap();
+        assert_eq!(vol.hdr.extents_overflow.header.header_record.leaf_count, 0);
+
+        let dir = vol.root_dir().subdir("Projects").unwrap().clone();
+        assert!(matches!(vol.delete_dir(&dir), Err(HfsError::DirectoryNotEmpty(_))));
+        for file in dir.files() {
+            vol.delete_file(file).unwrap();
+        }
+        vol.delete_dir(&dir).unwrap();
+        vol.flush().unwrap();
+
+        let vol = reopen(vol);
+        assert!(vol.root_dir().subdir("Projects").is_none());
+        assert_eq!(vol.hdr.mdb.free_blks, free_blks);
+    }
+}
@@ -0,0 +1,1061 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::time::SystemTime;
+
+use crate::common::{DateTime, FinderInfo};
+use binrw::{BinRead, Endian};
+use binrw::io::{Cursor, Read, Seek, SeekFrom};
+use derivative::Derivative;
+use thiserror::Error;
+use super::hfs::{FileReader, Fork, Mdb};
+use super::{Entry, EntryKind, Volume};
+
+const ROOT_PARENT_ID: u32 = 1;
+const EXTENTS_FILE_ID: u32 = 3;
+const CATALOG_FILE_ID: u32 = 4;
+
+#[derive(Error, Debug)]
+pub enum HfsPlusError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("Not an HFS+ volume, nor an HFS wrapper around one")]
+    NotHfsPlus,
+    #[error("{0:?} does not exist")]
+    NotFound(String),
+    #[error("Damaged volume: {0}")]
+    Corrupt(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+    name: String,
+    id: u32,
+    parent: u32,
+    finder_info: FinderInfo,
+    ctime: DateTime,
+    mtime: DateTime,
+    data_fork: ForkData,
+    rsrc_fork: ForkData,
+}
+
+impl File {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn parent(&self) -> u32 {
+        self.parent
+    }
+    pub fn finder_info(&self) -> &FinderInfo {
+        &self.finder_info
+    }
+    pub fn data_len(&self) -> u64 {
+        self.data_fork.logical_size
+    }
+    pub fn rsrc_len(&self) -> u64 {
+        self.rsrc_fork.logical_size
+    }
+    pub fn fork_len(&self, fork: Fork) -> u64 {
+        self.fork(fork).logical_size
+    }
+    fn fork(&self, fork: Fork) -> &ForkData {
+        match fork {
+            Fork::Data => &self.data_fork,
+            Fork::Resource => &self.rsrc_fork,
+        }
+    }
+    // Entries only have room for 32-bit lengths, so forks of 4 GB and up are cut short there.
+    fn entry(&self) -> Entry<u32> {
+        let kind = EntryKind::File {
+            data_len: self.data_len().try_into().unwrap_or(u32::MAX),
+            rsrc_len: self.rsrc_len().try_into().unwrap_or(u32::MAX),
+        };
+        let (ctime, mtime) = (self.ctime.to_system_time(), self.mtime.to_system_time());
+        Entry::new(self.id, &self.name, kind, Some(self.finder_info.clone()), ctime, mtime)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Directory {
+    name: String,
+    id: u32,
+    parent: u32,
+    ctime: DateTime,
+    mtime: DateTime,
+    files: Vec<File>,
+    subdirs: Vec<Directory>,
+}
+
+impl Directory {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn parent(&self) -> u32 {
+        self.parent
+    }
+    pub fn subdirs(&self) -> &[Directory] {
+        &self.subdirs
+    }
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+    pub fn subdir(&self, name: &str) -> Option<&Directory> {
+        self.subdirs.iter().find(|dir| dir.name == name)
+    }
+    pub fn file(&self, name: &str) -> Option<&File> {
+        self.files.iter().find(|file| file.name == name)
+    }
+    fn entry(&self) -> Entry<u32> {
+        let (ctime, mtime) = (self.ctime.to_system_time(), self.mtime.to_system_time());
+        Entry::new(self.id, &self.name, EntryKind::Directory, None, ctime, mtime)
+    }
+    fn dir_by_id(&self, id: u32) -> Option<&Directory> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.subdirs.iter().find_map(|dir| dir.dir_by_id(id))
+    }
+    fn file_by_id(&self, id: u32) -> Option<&File> {
+        self.files
+            .iter()
+            .find(|file| file.id == id)
+            .or_else(|| self.subdirs.iter().find_map(|dir| dir.file_by_id(id)))
+    }
+}
+
+pub struct HfsPlusVolume<R: Read + Seek> {
+    hdr: VolumeHeader,
+    base: u64,
+    reader: R,
+    extents_overflow: BTree<ExtentsRecord>,
+    catalog: BTree<CatalogRecord>,
+    root_dir: Directory,
+}
+
+impl<R: Read + Seek> HfsPlusVolume<R> {
+    // Opens the HFS+ volume at the reader's position, which may also be an HFS wrapper volume
+    // with an HFS+ volume embedded in its allocation blocks.
+    pub fn new(mut reader: R) -> Result<Self, HfsPlusError> {
+        let mut base = reader.stream_position()?;
+        let mut sig = [0; 2];
+        reader.seek(SeekFrom::Start(base + 1024))?;
+        reader.read_exact(&mut sig)?;
+        if &sig == b"BD" {
+            reader.seek(SeekFrom::Start(base + 1024))?;
+            let mdb = Mdb::read(&mut reader)?;
+            let (offset, _) = mdb.embedded_hfs_plus().ok_or(HfsPlusError::NotHfsPlus)?;
+            base += offset;
+        }
+        reader.seek(SeekFrom::Start(base + 1024))?;
+        let hdr = VolumeHeader::read(&mut reader).map_err(|err| match err {
+            binrw::Error::BadMagic { .. } => HfsPlusError::NotHfsPlus,
+            err => err.into(),
+        })?;
+
+        let extents_overflow = BTree::open(&mut reader, base, hdr.blk_size, hdr.extents_file.extents(), hdr.extents_file.logical_size)?;
+        let mut catalog_extents = hdr.catalog_file.extents();
+        catalog_extents.extend(extents_overflow.overflow_extents(
+            &mut reader,
+            base,
+            hdr.blk_size,
+            &hdr.catalog_file,
+            Fork::Data,
+            CATALOG_FILE_ID,
+        )?);
+        let catalog = BTree::open(&mut reader, base, hdr.blk_size, catalog_extents, hdr.catalog_file.logical_size)?;
+
+        let mut vol = Self {
+            hdr,
+            base,
+            reader,
+            extents_overflow,
+            catalog,
+            root_dir: Directory {
+                name: String::new(),
+                id: 0,
+                parent: 0,
+                ctime: DateTime::default(),
+                mtime: DateTime::default(),
+                files: Vec::new(),
+                subdirs: Vec::new(),
+            },
+        };
+        vol.root_dir = vol.read_root_dir()?;
+
+        Ok(vol)
+    }
+    pub fn root_dir(&self) -> Directory {
+        self.root_dir.clone()
+    }
+    pub fn file_reader<'a>(&'a mut self, file: &File, fork: Fork) -> Result<FileReader<'a, R>, HfsPlusError> {
+        let fork_data = file.fork(fork);
+        let mut extents = fork_data.extents();
+        extents.extend(self.extents_overflow.overflow_extents(
+            &mut self.reader,
+            self.base,
+            self.hdr.blk_size,
+            fork_data,
+            fork,
+            file.id,
+        )?);
+
+        Ok(extents_reader(&mut self.reader, self.base, self.hdr.blk_size, &extents, fork_data.logical_size))
+    }
+    pub fn file_contents(&mut self, file: &File, fork: Fork) -> Result<Vec<u8>, HfsPlusError> {
+        let mut buf = Vec::new();
+        self.file_reader(file, fork)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+    pub fn file_data(&mut self, file: &File) -> Result<Vec<u8>, HfsPlusError> {
+        self.file_contents(file, Fork::Data)
+    }
+    pub fn file_rsrc(&mut self, file: &File) -> Result<Vec<u8>, HfsPlusError> {
+        self.file_contents(file, Fork::Resource)
+    }
+    pub fn hdr(&self) -> &VolumeHeader {
+        &self.hdr
+    }
+    // Goes through every leaf once and then assembles the tree from the root down, so records
+    // which aren't reachable from the root are left out.
+    fn read_root_dir(&mut self) -> Result<Directory, HfsPlusError> {
+        let mut dirs: HashMap<u32, Vec<Directory>> = HashMap::new();
+        let mut files: HashMap<u32, Vec<File>> = HashMap::new();
+        let mut n = self.catalog.header.first_leaf;
+        let mut nodes_left = self.catalog.header.node_count;
+        while n != 0 {
+            nodes_left = nodes_left
+                .checked_sub(1)
+                .ok_or_else(|| HfsPlusError::Corrupt("loop in the catalog leaf nodes".to_string()))?;
+            let node = self.catalog.node(&mut self.reader, self.base, self.hdr.blk_size, n)?;
+            for rec in node.recs {
+                let CatalogRecord::Leaf { key, data } = rec else {
+                    continue;
+                };
+                match *data {
+                    CatalogRecordData::Folder { id, ctime, mtime, .. } => {
+                        dirs.entry(key.parent_id).or_default().push(Directory {
+                            name: key.name.decode(),
+                            id,
+                            parent: key.parent_id,
+                            ctime,
+                            mtime,
+                            files: Vec::new(),
+                            subdirs: Vec::new(),
+                        });
+                    },
+                    CatalogRecordData::File { id, ctime, mtime, user_info, data_fork, rsrc_fork, .. } => {
+                        files.entry(key.parent_id).or_default().push(File {
+                            name: key.name.decode(),
+                            id,
+                            parent: key.parent_id,
+                            finder_info: user_info,
+                            ctime,
+                            mtime,
+                            data_fork,
+                            rsrc_fork,
+                        });
+                    },
+                    _ => (),
+                }
+            }
+            n = node.desc.forward_link;
+        }
+
+        let mut root = dirs
+            .remove(&ROOT_PARENT_ID)
+            .and_then(|mut roots| roots.pop())
+            .ok_or_else(|| HfsPlusError::Corrupt("no root folder record".to_string()))?;
+        fill_dir(&mut root, &mut dirs, &mut files, &mut BTreeSet::new())?;
+
+        Ok(root)
+    }
+}
+
+impl<R: Read + Seek> Volume for HfsPlusVolume<R> {
+    type Id = u32;
+    type ForkReader<'a> = FileReader<'a, R> where R: 'a;
+
+    fn volume_name(&self) -> String {
+        self.root_dir.name.clone()
+    }
+    fn created(&self) -> SystemTime {
+        self.hdr.ctime.to_system_time()
+    }
+    fn root(&self) -> Entry<u32> {
+        self.root_dir.entry()
+    }
+    fn read_dir(&mut self, dir: &Entry<u32>) -> std::io::Result<Vec<Entry<u32>>> {
+        if !dir.is_dir() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotADirectory));
+        }
+        let Some(dir) = self.root_dir.dir_by_id(dir.id()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no folder record for {}", dir.id()),
+            ));
+        };
+        let subdirs = dir.subdirs.iter().map(Directory::entry);
+        Ok(subdirs.chain(dir.files.iter().map(File::entry)).collect())
+    }
+    fn fork_reader(&mut self, file: &Entry<u32>, fork: super::Fork) -> std::io::Result<FileReader<'_, R>> {
+        if file.is_dir() {
+            return Err(std::io::Error::from(std::io::ErrorKind::IsADirectory));
+        }
+        let Some(file) = self.root_dir.file_by_id(file.id()).cloned() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no file record for {}", file.id()),
+            ));
+        };
+        self.file_reader(&file, fork.into()).map_err(std::io::Error::other)
+    }
+}
+
+fn fill_dir(
+    dir: &mut Directory,
+    dirs: &mut HashMap<u32, Vec<Directory>>,
+    files: &mut HashMap<u32, Vec<File>>,
+    visited: &mut BTreeSet<u32>,
+) -> Result<(), HfsPlusError> {
+    if !visited.insert(dir.id) {
+        return Err(HfsPlusError::Corrupt(format!("folder {:?} contains itself", dir.name)));
+    }
+    dir.files = files.remove(&dir.id).unwrap_or_default();
+    dir.subdirs = dirs.remove(&dir.id).unwrap_or_default();
+    for subdir in dir.subdirs.iter_mut() {
+        fill_dir(subdir, dirs, files, visited)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct VolumeHeader {
+    #[br(assert(&signature == b"H+" || &signature == b"HX"))]
+    signature: [u8; 2],
+    version: u16,
+    attrs: u32,
+    last_mounted_version: u32,
+    journal_info_blk: u32,
+    ctime: DateTime,
+    mtime: DateTime,
+    backup_time: DateTime,
+    checked_time: DateTime,
+    file_count: u32,
+    folder_count: u32,
+    blk_size: u32,
+    total_blks: u32,
+    free_blks: u32,
+    next_alloc: u32,
+    rsrc_clump_size: u32,
+    data_clump_size: u32,
+    next_catalog_id: u32,
+    write_count: u32,
+    encodings: u64,
+    finder_info: [u32; 8],
+    alloc_file: ForkData,
+    extents_file: ForkData,
+    catalog_file: ForkData,
+    attrs_file: ForkData,
+    startup_file: ForkData,
+}
+
+impl VolumeHeader {
+    pub fn file_count(&self) -> u32 {
+        self.file_count
+    }
+    pub fn folder_count(&self) -> u32 {
+        self.folder_count
+    }
+    pub fn blk_size(&self) -> u32 {
+        self.blk_size
+    }
+    pub fn is_case_sensitive(&self) -> bool {
+        &self.signature == b"HX"
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct ForkData {
+    logical_size: u64,
+    clump_size: u32,
+    total_blks: u32,
+    extents: ExtentRecord,
+}
+
+impl ForkData {
+    fn extents(&self) -> Vec<ExtentDescriptor> {
+        self.extents.extents()
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct ExtentRecord([ExtentDescriptor; 8]);
+
+impl ExtentRecord {
+    fn extents(&self) -> Vec<ExtentDescriptor> {
+        self.0.iter()
+            .filter(|extent| extent.blk_count != 0)
+            .cloned()
+            .collect()
+    }
+    fn blk_count(&self) -> Option<u32> {
+        self.0.iter().try_fold(0u32, |sum, extent| sum.checked_add(extent.blk_count))
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct ExtentDescriptor {
+    start_blk: u32,
+    blk_count: u32,
+}
+
+fn extents_reader<'a, R: Read + Seek>(
+    reader: &'a mut R,
+    base: u64,
+    blk_size: u32,
+    extents: &[ExtentDescriptor],
+    len: u64,
+) -> FileReader<'a, R> {
+    let extents = extents.iter().map(|extent| (extent.start_blk as u64, extent.blk_count as u64)).collect();
+    FileReader::from_runs(reader, base, blk_size as u64, extents, len)
+}
+
+// The B-trees are read from disk a node at a time instead of being loaded whole. That doesn't
+// keep the catalog out of memory: read_root_dir still goes through every leaf and keeps the file
+// and folder records, only the nodes they came from are let go.
+struct BTree<T: BTreeRecord> {
+    header: HeaderRecord,
+    extents: Vec<ExtentDescriptor>,
+    len: u64,
+    _records: std::marker::PhantomData<T>,
+}
+
+pub trait BTreeRecord: for<'a> BinRead<Args<'a> = (NodeKind,)> + Clone {
+    type Key: Ord;
+    fn key(&self) -> &Self::Key;
+    fn child(&self) -> Option<u32>;
+}
+
+impl<T: BTreeRecord> BTree<T> {
+    fn open<R: Read + Seek>(reader: &mut R, base: u64, blk_size: u32, extents: Vec<ExtentDescriptor>, len: u64) -> Result<Self, HfsPlusError> {
+        let mut fork = extents_reader(reader, base, blk_size, &extents, len);
+        fork.seek(SeekFrom::Start(14))?;
+        let header = HeaderRecord::read(&mut fork)?;
+        Ok(Self {
+            header,
+            extents,
+            len,
+            _records: std::marker::PhantomData,
+        })
+    }
+    fn node<R: Read + Seek>(&self, reader: &mut R, base: u64, blk_size: u32, n: u32) -> Result<Node<T>, HfsPlusError> {
+        if n >= self.header.node_count {
+            return Err(HfsPlusError::Corrupt(format!("B-tree node {} is out of range", n)));
+        }
+        let node_size = self.header.node_size as usize;
+        let mut buf = vec![0; node_size];
+        let mut fork = extents_reader(reader, base, blk_size, &self.extents, self.len);
+        fork.seek(SeekFrom::Start(n as u64 * node_size as u64))?;
+        fork.read_exact(&mut buf)?;
+
+        Node::parse(&buf).map_err(|err| match err {
+            HfsPlusError::Corrupt(message) => HfsPlusError::Corrupt(format!("{} in B-tree node {}", message, n)),
+            err => err,
+        })
+    }
+    // Finds the leaf record with exactly this key by descending from the root.
+    fn find<R: Read + Seek>(&self, reader: &mut R, base: u64, blk_size: u32, key: &T::Key) -> Result<Option<T>, HfsPlusError> {
+        let mut n = self.header.root;
+        if n == 0 {
+            return Ok(None);
+        }
+        for _ in 0..self.header.depth {
+            let node = self.node(reader, base, blk_size, n)?;
+            match node.desc.kind {
+                NodeKind::Leaf => {
+                    return Ok(node.recs.into_iter().find(|rec| rec.key().cmp(key) == Ordering::Equal));
+                },
+                NodeKind::Index => {
+                    n = node.recs
+                        .iter()
+                        .take_while(|rec| rec.key() <= key)
+                        .last()
+                        .or(node.recs.first())
+                        .and_then(|rec| rec.child())
+                        .ok_or_else(|| HfsPlusError::Corrupt(format!("empty index node {}", n)))?;
+                },
+                kind => return Err(HfsPlusError::Corrupt(format!("unexpected {:?} node {} in a B-tree", kind, n))),
+            }
+        }
+
+        Err(HfsPlusError::Corrupt("B-tree is deeper than its header says".to_string()))
+    }
+}
+
+impl BTree<ExtentsRecord> {
+    // Extents past the eight stored with the fork itself, chained through records keyed by the
+    // first allocation block of the fork they cover.
+    fn overflow_extents<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        base: u64,
+        blk_size: u32,
+        fork_data: &ForkData,
+        fork: Fork,
+        id: u32,
+    ) -> Result<Vec<ExtentDescriptor>, HfsPlusError> {
+        let mut extents = Vec::new();
+        let overflow = || HfsPlusError::Corrupt(format!("extents of file {} add up to more than 2^32 blocks", id));
+        let mut start = fork_data.extents.blk_count().ok_or_else(overflow)?;
+        if id == EXTENTS_FILE_ID || start >= fork_data.total_blks {
+            return Ok(extents);
+        }
+        while let Some(ExtentsRecord::Leaf { data, .. }) = self.find(reader, base, blk_size, &ExtentKey::new(fork, id, start))? {
+            let count = data.blk_count().ok_or_else(overflow)?;
+            if count == 0 {
+                break;
+            }
+            extents.extend(data.extents());
+            start = start.checked_add(count).ok_or_else(overflow)?;
+        }
+
+        Ok(extents)
+    }
+}
+
+struct Node<T: BTreeRecord> {
+    desc: NodeDescriptor,
+    recs: Vec<T>,
+}
+
+impl<T: BTreeRecord> Node<T> {
+    // Records are located through the offsets stored backwards from the end of the node.
+    fn parse(buf: &[u8]) -> Result<Self, HfsPlusError> {
+        let mut cursor = Cursor::new(buf);
+        let desc = NodeDescriptor::read(&mut cursor)?;
+        let mut recs = Vec::new();
+        if matches!(desc.kind, NodeKind::Index | NodeKind::Leaf) {
+            let count = desc.record_count as usize;
+            if 14 + 2 * count > buf.len() {
+                return Err(HfsPlusError::Corrupt(format!("{} record offsets don't fit", count)));
+            }
+            for i in 0..count {
+                let at = buf.len() - 2 * (i + 1);
+                let offset = u16::from_be_bytes([buf[at], buf[at + 1]]);
+                cursor.set_position(offset as u64);
+                recs.push(T::read_options(&mut cursor, Endian::Big, (desc.kind,))?);
+            }
+        }
+
+        Ok(Self {
+            desc,
+            recs,
+        })
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct NodeDescriptor {
+    forward_link: u32,
+    backward_link: u32,
+    kind: NodeKind,
+    height: u8,
+    #[br(pad_after = 2)]
+    record_count: u16,
+}
+
+#[derive(Derivative, Clone, Copy, BinRead, Eq, PartialEq)]
+#[derivative(Debug)]
+#[br(big)]
+pub enum NodeKind {
+    #[br(magic = b"\x00")] Index,
+    #[br(magic = b"\x01")] Header,
+    #[br(magic = b"\x02")] Map,
+    #[br(magic = b"\xff")] Leaf,
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct HeaderRecord {
+    depth: u16,
+    root: u32,
+    leaf_count: u32,
+    first_leaf: u32,
+    last_leaf: u32,
+    node_size: u16,
+    max_key_len: u16,
+    node_count: u32,
+    free_nodes: u32,
+    #[br(pad_before = 2)]
+    clump_size: u32,
+    btree_type: u8,
+    key_compare_type: u8,
+    attrs: u32,
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big, import(kind: NodeKind))]
+pub enum ExtentsRecord {
+    #[br(pre_assert(kind == NodeKind::Index))]
+    Index {
+        key: ExtentKey,
+        child: u32,
+    },
+    #[br(pre_assert(kind == NodeKind::Leaf))]
+    Leaf {
+        key: ExtentKey,
+        data: ExtentRecord,
+    },
+}
+
+impl BTreeRecord for ExtentsRecord {
+    type Key = ExtentKey;
+    fn key(&self) -> &ExtentKey {
+        match self {
+            ExtentsRecord::Index { key, .. } | ExtentsRecord::Leaf { key, .. } => key,
+        }
+    }
+    fn child(&self) -> Option<u32> {
+        match self {
+            ExtentsRecord::Index { child, .. } => Some(*child),
+            ExtentsRecord::Leaf { .. } => None,
+        }
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct ExtentKey {
+    key_len: u16,
+    #[br(pad_after = 1)]
+    fork: Fork,
+    id: u32,
+    start_blk: u32,
+}
+
+impl ExtentKey {
+    fn new(fork: Fork, id: u32, start_blk: u32) -> Self {
+        Self {
+            key_len: 10,
+            fork,
+            id,
+            start_blk,
+        }
+    }
+}
+
+impl Ord for ExtentKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+            .then(self.fork.cmp(&other.fork))
+            .then(self.start_blk.cmp(&other.start_blk))
+    }
+}
+
+impl PartialOrd for ExtentKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ExtentKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ExtentKey {}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big, import(kind: NodeKind))]
+pub enum CatalogRecord {
+    #[br(pre_assert(kind == NodeKind::Index))]
+    Index {
+        key: CatalogKey,
+        child: u32,
+    },
+    #[br(pre_assert(kind == NodeKind::Leaf))]
+    Leaf {
+        key: CatalogKey,
+        data: Box<CatalogRecordData>,
+    },
+}
+
+impl BTreeRecord for CatalogRecord {
+    type Key = CatalogKey;
+    fn key(&self) -> &CatalogKey {
+        match self {
+            CatalogRecord::Index { key, .. } | CatalogRecord::Leaf { key, .. } => key,
+        }
+    }
+    fn child(&self) -> Option<u32> {
+        match self {
+            CatalogRecord::Index { child, .. } => Some(*child),
+            CatalogRecord::Leaf { .. } => None,
+        }
+    }
+}
+
+// Index keys may be padded up to the maximum key length, so the key length is what decides
+// where the record data starts.
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct CatalogKey {
+    key_len: u16,
+    #[br(restore_position)]
+    parent_id: u32,
+    #[br(pad_before = 4, pad_size_to = key_len.saturating_sub(4))]
+    name: UniStr255,
+}
+
+// Names are compared with their case folded, which is only an approximation of the ordering
+// HFS+ uses, but good enough to find the records it needs (thread records have empty names).
+impl Ord for CatalogKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parent_id.cmp(&other.parent_id)
+            .then_with(|| self.name.folded().cmp(other.name.folded()))
+    }
+}
+
+impl PartialOrd for CatalogKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for CatalogKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CatalogKey {}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub struct UniStr255 {
+    len: u16,
+    #[br(count = len)]
+    #[derivative(Debug(format_with = "UniStr255::fmt_units"))]
+    units: Vec<u16>,
+}
+
+impl UniStr255 {
+    pub fn decode(&self) -> String {
+        String::from_utf16_lossy(&self.units)
+    }
+    fn folded(&self) -> impl Iterator<Item = u32> + '_ {
+        char::decode_utf16(self.units.iter().copied())
+            .map(|ch| ch.map_or(0xfffd, |ch| ch.to_lowercase().next().unwrap_or(ch) as u32))
+    }
+    fn fmt_units(units: &[u16], f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", String::from_utf16_lossy(units))
+    }
+}
+
+#[derive(Derivative, Clone, BinRead)]
+#[derivative(Debug)]
+#[br(big)]
+pub enum CatalogRecordData {
+    #[br(magic = 1u16)]
+    Folder {
+        flags: u16,
+        valence: u32,
+        id: u32,
+        ctime: DateTime,
+        mtime: DateTime,
+        attr_mtime: DateTime,
+        atime: DateTime,
+        backup_time: DateTime,
+        #[derivative(Debug = "ignore")]
+        permissions: [u8; 16],
+        #[derivative(Debug = "ignore")]
+        user_info: [u8; 16],
+        #[derivative(Debug = "ignore")]
+        finder_info: [u8; 16],
+        #[br(pad_after = 4)]
+        text_encoding: u32,
+    },
+    #[br(magic = 2u16)]
+    File {
+        #[br(pad_after = 4)]
+        flags: u16,
+        id: u32,
+        ctime: DateTime,
+        mtime: DateTime,
+        attr_mtime: DateTime,
+        atime: DateTime,
+        backup_time: DateTime,
+        #[derivative(Debug = "ignore")]
+        permissions: [u8; 16],
+        user_info: FinderInfo,
+        #[derivative(Debug = "ignore")]
+        finder_info: [u8; 16],
+        #[br(pad_after = 4)]
+        text_encoding: u32,
+        data_fork: ForkData,
+        rsrc_fork: ForkData,
+    },
+    #[br(magic = 3u16)]
+    FolderThread {
+        #[br(pad_before = 2)]
+        parent_id: u32,
+        name: UniStr255,
+    },
+    #[br(magic = 4u16)]
+    FileThread {
+        #[br(pad_before = 2)]
+        parent_id: u32,
+        name: UniStr255,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{HfsPlusError, HfsPlusVolume};
+    use crate::fs::{EntryKind, Fork, Volume};
+
+    const BLK_SIZE: usize = 4096;
+    const NODE_SIZE: usize = 4096;
+
+    fn put(buf: &mut [u8], offset: usize, bytes: &[u8]) {
+        buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn fork_data(len: u64, extents: &[(u32, u32)]) -> Vec<u8> {
+        let mut fork = Vec::new();
+        fork.extend(len.to_be_bytes());
+        fork.extend(0u32.to_be_bytes());
+        fork.extend(extents.iter().map(|(_, count)| count).sum::<u32>().to_be_bytes());
+        for i in 0..8 {
+            let (start, count) = extents.get(i).copied().unwrap_or((0, 0));
+            fork.extend(start.to_be_bytes());
+            fork.extend(count.to_be_bytes());
+        }
+        fork
+    }
+
+    fn catalog_key(parent: u32, name: &str) -> Vec<u8> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let mut key = Vec::new();
+        key.extend((6 + 2 * units.len() as u16).to_be_bytes());
+        key.extend(parent.to_be_bytes());
+        key.extend((units.len() as u16).to_be_bytes());
+        key.extend(units.iter().flat_map(|unit| unit.to_be_bytes()));
+        key
+    }
+
+    fn folder(parent: u32, name: &str, id: u32, valence: u32) -> Vec<u8> {
+        let mut rec = catalog_key(parent, name);
+        rec.extend(1u16.to_be_bytes());
+        rec.extend(0u16.to_be_bytes());
+        rec.extend(valence.to_be_bytes());
+        rec.extend(id.to_be_bytes());
+        rec.extend([0; 88 - 12]);
+        rec
+    }
+
+    fn thread(id: u32, kind: u16, parent: u32, name: &str) -> Vec<u8> {
+        let mut rec = catalog_key(id, "");
+        rec.extend(kind.to_be_bytes());
+        rec.extend([0; 2]);
+        rec.extend(catalog_key(parent, name)[2..].iter());
+        rec
+    }
+
+    fn file(parent: u32, name: &str, id: u32, data: Vec<u8>, rsrc: Vec<u8>) -> Vec<u8> {
+        let mut rec = catalog_key(parent, name);
+        rec.extend(2u16.to_be_bytes());
+        rec.extend([0; 6]);
+        rec.extend(id.to_be_bytes());
+        rec.extend([0; 20 + 16]);
+        rec.extend(b"TEXTttxt");
+        rec.extend([0; 8 + 16 + 8]);
+        rec.extend(data);
+        rec.extend(rsrc);
+        rec
+    }
+
+    fn leaf_node(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut node = vec![0; NODE_SIZE];
+        node[8] = 0xff;
+        node[9] = 1;
+        put(&mut node, 10, &(records.len() as u16).to_be_bytes());
+        let mut offset = 14;
+        for (i, rec) in records.iter().enumerate() {
+            put(&mut node, offset, rec);
+            put(&mut node, NODE_SIZE - 2 * (i + 1), &(offset as u16).to_be_bytes());
+            offset += rec.len();
+        }
+        put(&mut node, NODE_SIZE - 2 * (records.len() + 1), &(offset as u16).to_be_bytes());
+        node
+    }
+
+    fn header_node(root: u32, leaf_count: u32, node_count: u32) -> Vec<u8> {
+        let mut node = vec![0; NODE_SIZE];
+        node[8] = 1;
+        put(&mut node, 10, &3u16.to_be_bytes());
+        put(&mut node, 14, &(root.min(1) as u16).to_be_bytes());
+        put(&mut node, 16, &root.to_be_bytes());
+        put(&mut node, 20, &leaf_count.to_be_bytes());
+        put(&mut node, 24, &root.to_be_bytes());
+        put(&mut node, 28, &root.to_be_bytes());
+        put(&mut node, 32, &(NODE_SIZE as u16).to_be_bytes());
+        put(&mut node, 34, &516u16.to_be_bytes());
+        put(&mut node, 36, &node_count.to_be_bytes());
+        node
+    }
+
+    // A volume with a root folder holding a subfolder and a file, whose resource fork spills
+    // into the extents overflow file.
+    fn hfs_plus_image() -> Vec<u8> {
+        let mut image = vec![0; 32 * BLK_SIZE];
+        let mut hdr = Vec::new();
+        hdr.extend(b"H+");
+        hdr.extend(4u16.to_be_bytes());
+        hdr.extend([0; 28]);
+        hdr.extend(1u32.to_be_bytes());
+        hdr.extend(2u32.to_be_bytes());
+        hdr.extend((BLK_SIZE as u32).to_be_bytes());
+        hdr.extend(32u32.to_be_bytes());
+        hdr.extend([0; 80 - 48]);
+        hdr.extend([0; 32]);
+        hdr.extend(fork_data(BLK_SIZE as u64, &[(1, 1)]));
+        hdr.extend(fork_data(2 * NODE_SIZE as u64, &[(2, 2)]));
+        hdr.extend(fork_data(2 * NODE_SIZE as u64, &[(4, 2)]));
+        put(&mut image, 1024, &hdr);
+
+        let mut extent = Vec::new();
+        extent.extend(10u16.to_be_bytes());
+        extent.extend([0xff, 0]);
+        extent.extend(20u32.to_be_bytes());
+        extent.extend(8u32.to_be_bytes());
+        extent.extend(fork_data(0, &[(12, 1)])[16..].iter());
+        put(&mut image, 2 * BLK_SIZE, &header_node(1, 1, 2));
+        put(&mut image, 3 * BLK_SIZE, &leaf_node(&[extent]));
+
+        let rsrc_extents: Vec<(u32, u32)> = (0..8).map(|i| (20 - i, 1)).collect();
+        let mut rsrc_fork = fork_data(9 * BLK_SIZE as u64 - 10, &rsrc_extents);
+        put(&mut rsrc_fork, 12, &9u32.to_be_bytes());
+        put(&mut image, 4 * BLK_SIZE, &header_node(1, 6, 2));
+        put(&mut image, 5 * BLK_SIZE, &leaf_node(&[
+            folder(1, "Macintosh HD", 2, 2),
+            thread(2, 3, 1, "Macintosh HD"),
+            folder(2, "Documents", 16, 0),
+            file(2, "Read Me", 20, fork_data(5, &[(10, 1)]), rsrc_fork),
+            thread(16, 3, 2, "Documents"),
+            thread(20, 4, 2, "Read Me"),
+        ]));
+
+        put(&mut image, 10 * BLK_SIZE, b"hello");
+        for blk in 12..=20 {
+            image[blk * BLK_SIZE..(blk + 1) * BLK_SIZE].fill(blk as u8);
+        }
+        image
+    }
+
+    fn check_volume(image: Vec<u8>, offset: u64) {
+        let mut disk = Cursor::new(image);
+        disk.set_position(offset);
+        let mut vol = HfsPlusVolume::new(disk).unwrap();
+        let root = vol.root_dir();
+        assert_eq!(root.name(), "Macintosh HD");
+        assert!(root.subdir("Documents").unwrap().files().is_empty());
+        let read_me = root.file("Read Me").unwrap();
+        assert_eq!(vol.file_data(read_me).unwrap(), b"hello");
+
+        let rsrc = vol.file_rsrc(read_me).unwrap();
+        assert_eq!(rsrc.len(), 9 * BLK_SIZE - 10);
+        let expected_blks: Vec<u8> = (13..=20).rev().chain([12]).collect();
+        for (chunk, blk) in rsrc.chunks(BLK_SIZE).zip(expected_blks) {
+            assert!(chunk.iter().all(|b| *b == blk));
+        }
+    }
+
+    #[test]
+    fn read_volume() {
+        check_volume(hfs_plus_image(), 0);
+    }
+
+    #[test]
+    fn read_wrapped_volume() {
+        // the HFS wrapper has 512-byte allocation blocks starting at sector 4, and the HFS+
+        // volume starts at its allocation block 60
+        let mut image = vec![0; (4 + 60) * 512];
+        let mut mdb = vec![0; 162];
+        put(&mut mdb, 0, b"BD");
+        put(&mut mdb, 18, &300u16.to_be_bytes());
+        put(&mut mdb, 20, &512u32.to_be_bytes());
+        put(&mut mdb, 28, &4u16.to_be_bytes());
+        put(&mut mdb, 0x7c, b"H+");
+        put(&mut mdb, 0x7e, &60u16.to_be_bytes());
+        put(&mut mdb, 0x80, &256u16.to_be_bytes());
+        put(&mut image, 1024, &mdb);
+        image.extend(hfs_plus_image());
+
+        let mut with_offset = vec![0xaa; 512];
+        with_offset.extend(image.iter());
+        check_volume(image, 0);
+        check_volume(with_offset, 512);
+    }
+
+    #[test]
+    fn volume() {
+        let mut vol = HfsPlusVolume::new(Cursor::new(hfs_plus_image())).unwrap();
+        assert_eq!(vol.volume_name(), "Macintosh HD");
+        let root = vol.root();
+        let names: Vec<_> = vol.read_dir(&root).unwrap().iter().map(|e| e.name().to_string()).collect();
+        assert_eq!(names, ["Documents", "Read Me"]);
+        let read_me = vol.lookup("/Read Me").unwrap().unwrap();
+        assert_eq!(read_me.kind(), EntryKind::File { data_len: 5, rsrc_len: 9 * BLK_SIZE as u32 - 10 });
+        assert_eq!(read_me.finder_info().unwrap().file_type().as_inner(), b"TEXT");
+        assert_eq!(vol.read_fork(&read_me, Fork::Data).unwrap(), b"hello");
+        assert_eq!(vol.read_fork(&read_me, Fork::Resource).unwrap().len(), 9 * BLK_SIZE - 10);
+        let docs = vol.lookup("Documents").unwrap().unwrap();
+        assert!(vol.read_dir(&docs).unwrap().is_empty());
+        assert!(vol.fork_reader(&docs, Fork::Data).is_err());
+    }
+
+    #[test]
+    fn too_many_records() {
+        // more record offsets than fit in the catalog leaf node
+        let mut image = hfs_plus_image();
+        put(&mut image, 5 * BLK_SIZE + 10, &0xffffu16.to_be_bytes());
+        assert!(matches!(HfsPlusVolume::new(Cursor::new(image)), Err(HfsPlusError::Corrupt(_))));
+    }
+
+    #[test]
+    fn reject_plain_hfs() {
+        let mut image = vec![0; 4096];
+        image[1024..1026].copy_from_slice(b"BD");
+        image[1024 + 18..1024 + 20].copy_from_slice(&1u16.to_be_bytes());
+        image[1024 + 20..1024 + 24].copy_from_slice(&512u32.to_be_bytes());
+        assert!(matches!(HfsPlusVolume::new(Cursor::new(image)), Err(HfsPlusError::NotHfsPlus)));
+    }
+}
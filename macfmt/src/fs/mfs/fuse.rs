@@ -1,29 +1,59 @@
 use std::ffi::OsStr;
+use std::io::{Seek, Write};
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::mpsc::{self, Sender};
+use std::time::UNIX_EPOCH;
 
-use libc::{EINVAL, ENOENT};
-use fuser::{Filesystem, ReplyCreate, ReplyData, Request, MountOption, FileType, TimeOrNow, ReplyEntry, ReplyAttr, ReplyDirectory, FileAttr, ReplyWrite};
+use binrw::BinWrite;
+use libc::{EACCES, EEXIST, EFBIG, EILSEQ, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR};
+use fuser::{
+    Filesystem, ReplyCreate, ReplyData, ReplyEmpty, Request, MountOption, FileType, TimeOrNow, ReplyEntry,
+    ReplyAttr, ReplyDirectory, FileAttr, ReplyWrite,
+};
 
-use crate::mfs::{FileDirectoryBlock, Mfs, Fork};
-use crate::common::{DateTime, PascalString, BootBlocks, SizedString, DynamicPascalString};
+use crate::i18n::{MacRoman, MacScript};
+use super::{FileDirectoryBlock, FileHandle, Mfs, Fork, is_valid_name};
 
-#[derive(Clone, Debug)]
-pub struct MfsFuse {
+// A read-write mount of an MFS volume, which lives in memory until it's unmounted and written
+// back to `image`. Resource forks show up as separate files with a ".rsrc" suffix.
+#[derive(Debug)]
+pub struct MfsFuse<W: Write + Seek> {
     fs: Mfs,
+    image: W,
+    dirty: bool,
+    // where a failure to write the volume back on unmount goes, as there's no one to reply to
+    failed: Option<Sender<binrw::Error>>,
 }
 
-impl MfsFuse {
-    pub fn new(fs: Mfs) -> MfsFuse {
+impl<W: Write + Seek> MfsFuse<W> {
+    pub fn new(fs: Mfs, image: W) -> MfsFuse<W> {
         MfsFuse {
-            fs
+            fs,
+            image,
+            dirty: false,
+            failed: None,
         }
     }
-    pub fn mount(self, dir: &Path) -> std::io::Result<()> {
+    // Fails if the volume couldn't be written back after unmounting, too.
+    pub fn mount(mut self, dir: &Path) -> std::io::Result<()> {
         let opts = [
             MountOption::FSName("mfs".to_string()),
         ];
-        fuser::mount2(self, dir, &opts)
+        let (failed, failure) = mpsc::channel();
+        self.failed = Some(failed);
+        fuser::mount2(self, dir, &opts)?;
+        match failure.try_recv() {
+            Ok(err) => Err(std::io::Error::other(format!("Failed to write the MFS volume back: {}", err))),
+            Err(_) => Ok(()),
+        }
+    }
+    pub fn persist(&mut self) -> binrw::BinResult<()> {
+        if self.dirty {
+            self.fs.write_be(&mut self.image)?;
+            self.image.flush()?;
+            self.dirty = false;
+        }
+        Ok(())
     }
 }
 
@@ -56,8 +86,8 @@ const FILE_ATTR: FileAttr = FileAttr {
     ctime: UNIX_EPOCH,
     crtime: UNIX_EPOCH,
     kind: FileType::RegularFile,
-    perm: 0o755,
-    nlink: 2,
+    perm: 0o644,
+    nlink: 1,
     uid: 501,
     gid: 20,
     rdev: 0,
@@ -65,9 +95,9 @@ const FILE_ATTR: FileAttr = FileAttr {
     blksize: 512,
 };
 
-impl MfsFuse {
+impl<W: Write + Seek> MfsFuse<W> {
     fn root_attr(&self) -> FileAttr {
-        let mut attr = ROOT_DIR_ATTR.clone();
+        let mut attr = ROOT_DIR_ATTR;
 
         attr.atime = self.fs.creation_date();
         attr.mtime = self.fs.creation_date();
@@ -77,23 +107,14 @@ impl MfsFuse {
         attr
     }
     fn file_attr(&self, file: &FileDirectoryBlock, fork: Fork) -> FileAttr {
-        let div_ceil = |x: u32, y: u32| (x+y-1)/y;
-        let mut attr = FILE_ATTR.clone();
+        let mut attr = FILE_ATTR;
         attr.atime = file.modification_date();
         attr.mtime = file.modification_date();
-        attr.ctime = file.creation_date();
+        attr.ctime = file.modification_date();
         attr.crtime = file.creation_date();
-        match fork {
-            Fork::Data => {
-                attr.blocks = div_ceil(file.data_fork_size(), self.fs.alloc_block_size()) as u64;
-                attr.size = file.data_fork_size() as u64;
-            },
-            Fork::Resource => {
-                attr.blocks = div_ceil(file.resource_fork_size(), self.fs.alloc_block_size()) as u64;
-                attr.size = file.resource_fork_size() as u64;
-            },
-        }
-        attr.ino = self.ino_by_file(file, fork) as u64;
+        attr.size = file.fork_size(fork) as u64;
+        attr.blocks = file.fork_allocated_space(fork).div_ceil(512) as u64;
+        attr.ino = self.ino_by_file(file, fork);
 
         attr
     }
@@ -102,11 +123,11 @@ impl MfsFuse {
         ((file.number() as u64) << 1 | ((fork == Fork::Data) as u8) as u64 ) + 1
     }
 
-    fn file_by_ino(&self, mut ino: i64) -> Option<(&FileDirectoryBlock, Fork)> {
-        if ino == 1 {
+    fn file_by_ino(&self, ino: u64) -> Option<(FileHandle, Fork)> {
+        if ino <= 1 {
             return None;
         }
-        ino -= 1;
+        let ino = ino - 1;
         let fork = if ino & 1 == 1 {
             Fork::Data
         } else {
@@ -117,42 +138,82 @@ impl MfsFuse {
             .map(|v| (v, fork))
     }
 
-    fn name_to_fork<'a>(&self, name: &'a impl AsRef<str>) -> (&'a str, Fork) {
-        if let Some(filename) = name.as_ref().strip_suffix(".rsrc") {
-            (filename, Fork::Resource)
+    fn name_to_fork<'a>(&self, name: &'a OsStr) -> Option<(&'a str, Fork)> {
+        let name = name.to_str()?;
+        if let Some(filename) = name.strip_suffix(".rsrc") {
+            Some((filename, Fork::Resource))
         } else {
-            (name.as_ref(), Fork::Data)
+            Some((name, Fork::Data))
         }
     }
-}
 
-impl Filesystem for MfsFuse {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    // A file literally named "something.rsrc" takes precedence over the resource fork of
+    // "something".
+    fn lookup_file(&self, parent: u64, name: &OsStr) -> Result<(FileHandle, Fork), i32> {
         if parent != 1 {
-            reply.error(ENOENT);
-            return;
+            return Err(ENOENT);
         }
-        let name_str = name.to_string_lossy();
-        let (name, fork) = self.name_to_fork(&name_str);
-
-        let Some(file) = self.fs.file_by_name(&name) else {
-            reply.error(ENOENT);
-            return;
+        let Some((filename, fork)) = self.name_to_fork(name) else {
+            return Err(ENOENT);
         };
-        
-        reply.entry(&TTL, &self.file_attr(file, fork), 0);
+        if let Some(file) = name.to_str().and_then(|name| self.fs.file_by_name(name)) {
+            return Ok((file, Fork::Data));
+        }
+        match self.fs.file_by_name(filename) {
+            Some(file) => Ok((file, fork)),
+            None => Err(ENOENT),
+        }
     }
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        match ino {
-            1 => reply.attr(&TTL, &self.root_attr()),
-            _ => {
-                let Some((file, fork)) = self.file_by_ino(ino as i64) else {
-                    reply.error(ENOENT);
-                    return;
-                };
 
-                reply.attr(&TTL, &self.file_attr(file, fork));
-            },
+    fn attr_by_ino(&self, ino: u64) -> Option<FileAttr> {
+        if ino == 1 {
+            return Some(self.root_attr());
+        }
+        let (file, fork) = self.file_by_ino(ino)?;
+        Some(self.file_attr(self.fs.file(file), fork))
+    }
+
+    fn modify(&mut self, f: impl FnOnce(&mut Mfs) -> std::io::Result<()>) -> Result<(), i32> {
+        f(&mut self.fs).map_err(|err| match err.kind() {
+            std::io::ErrorKind::StorageFull => ENOSPC,
+            // forks are at most u32::MAX bytes long
+            std::io::ErrorKind::FileTooLarge => EFBIG,
+            _ => EIO,
+        })?;
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+// The errno for a name the volume can't hold.
+fn check_name(name: &str) -> Result<(), i32> {
+    if name.chars().any(|ch| MacRoman::encode(ch).is_err()) {
+        Err(EILSEQ)
+    } else if name.chars().count() > 255 {
+        Err(ENAMETOOLONG)
+    } else if !is_valid_name(name) {
+        Err(EINVAL)
+    } else {
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Filesystem for MfsFuse<W> {
+    fn destroy(&mut self) {
+        if let Err(err) = self.persist() && let Some(failed) = &self.failed {
+            let _ = failed.send(err);
+        }
+    }
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_file(parent, name) {
+            Ok((file, fork)) => reply.entry(&TTL, &self.file_attr(self.fs.file(file), fork), 0),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr_by_ino(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
         }
     }
     fn create(
@@ -165,68 +226,99 @@ impl Filesystem for MfsFuse {
         _flags: i32,
         reply: ReplyCreate,
     ) {
-        let name_str = name.to_string_lossy();
-        let (name, fork) = self.name_to_fork(&name_str);
+        if parent != 1 {
+            reply.error(ENOENT);
+            return;
+        }
+        let Some((filename, fork)) = self.name_to_fork(name) else {
+            reply.error(EINVAL);
+            return;
+        };
 
-        self.fs.add_file(&name.clone(), *b"DUPA", *b"MAJA");
-        let file = self.fs.file_by_name(&name).unwrap();
-        let attr = self.file_attr(&file, fork);
+        // creating "name.rsrc" for an existing file just hands out its resource fork
+        let file = match (self.fs.file_by_name(filename), fork) {
+            (Some(file), Fork::Resource) => file,
+            (Some(_), Fork::Data) => {
+                reply.error(EEXIST);
+                return;
+            },
+            (None, _) => {
+                if let Err(err) = check_name(filename) {
+                    reply.error(err);
+                    return;
+                }
+                if !self.fs.has_room_for(filename) {
+                    reply.error(ENOSPC);
+                    return;
+                }
+                self.dirty = true;
+                self.fs.add_file(filename, *b"????", *b"????")
+            },
+        };
+        let attr = self.file_attr(self.fs.file(file), fork);
         reply.created(&TTL, &attr, 0, 0, 0);
     }
     fn setattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        mode: Option<u32>,
-        uid: Option<u32>,
-        gid: Option<u32>,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
         size: Option<u64>,
         _atime: Option<TimeOrNow>,
         _mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        flags: Option<u32>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        match ino {
-            1 => reply.attr(&TTL, &self.root_attr()),
-            _ => {
-                let Some((file, fork)) = self.file_by_ino(ino as i64) else {
-                    reply.error(ENOENT);
-                    return;
-                };
+        if let Some(size) = size {
+            let Some((file, fork)) = self.file_by_ino(ino) else {
+                reply.error(if ino == 1 { EISDIR } else { ENOENT });
+                return;
+            };
+            let Ok(size) = u32::try_from(size) else {
+                reply.error(EFBIG);
+                return;
+            };
+            if let Err(err) = self.modify(|fs| fs.set_fork_len(file, fork, size)) {
+                reply.error(err);
+                return;
+            }
+        }
 
-                reply.attr(&TTL, &self.file_attr(file, fork));
-            },
+        match self.attr_by_ino(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
         }
     }
     fn write(
         &mut self,
         _req: &Request,
         ino: u64,
-        fh: u64,
+        _fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
-        #[allow(unused_variables)] flags: i32,
+        _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite
     ) {
-        match ino {
-            1 => reply.error(EINVAL),
-            _ => {
-                let Some((file, fork)) = self.file_by_ino(ino as i64) else {
-                    reply.error(ENOENT);
-                    return;
-                };
-                let file = file.clone();
-                let mut vec: Vec<u8> = data.to_vec();
-                self.fs.append_file_data(&file, &mut vec);
-                reply.written(data.len() as u32);
-            },
+        let Some((file, fork)) = self.file_by_ino(ino) else {
+            reply.error(if ino == 1 { EISDIR } else { ENOENT });
+            return;
+        };
+        let Ok(offset) = u32::try_from(offset.max(0)) else {
+            reply.error(EFBIG);
+            return;
+        };
+        match self.modify(|fs| fs.write_at(file, fork, offset, data)) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(err) => reply.error(err),
         }
     }
     fn read(
@@ -240,24 +332,92 @@ impl Filesystem for MfsFuse {
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
-        match ino {
-            1 => reply.error(EINVAL),
-            _ => {
-                let Some((file, fork)) = self.file_by_ino(ino as i64) else {
-                    reply.error(ENOENT);
-                    return;
-                };
-                let data = match fork {
-                    Fork::Resource => self.fs.file_rsrc(file),
-                    Fork::Data => self.fs.file_data(file),
-                };
-                
-                let len = if offset + size as i64 > data.len() as i64 { data.len() - offset as usize} else { size as usize };
-                reply.data(&data[offset as usize..][..len]);
+        let Some((file, fork)) = self.file_by_ino(ino) else {
+            reply.error(if ino == 1 { EISDIR } else { ENOENT });
+            return;
+        };
+        let data = self.fs.file_contents(file, fork);
+        let start = (offset.max(0) as usize).min(data.len());
+        let end = (start + size as usize).min(data.len());
+        reply.data(&data[start..end]);
+    }
+    // Unlinking a resource fork only empties it, the file goes away with its data fork.
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (file, fork) = match self.lookup_file(parent, name) {
+            Ok(found) => found,
+            Err(err) => {
+                reply.error(err);
+                return;
+            },
+        };
+        match fork {
+            Fork::Data => {
+                self.fs.remove_file(file);
+                self.dirty = true;
+                reply.ok();
+            },
+            Fork::Resource => match self.modify(|fs| fs.set_fork_len(file, fork, 0)) {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err),
             },
         }
     }
-
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (file, fork) = match self.lookup_file(parent, name) {
+            Ok(found) => found,
+            Err(err) => {
+                reply.error(err);
+                return;
+            },
+        };
+        if newparent != 1 {
+            reply.error(ENOTDIR);
+            return;
+        }
+        // forks can't be moved between files, only the file as a whole can be renamed
+        let Some((newname, newfork)) = self.name_to_fork(newname) else {
+            reply.error(EINVAL);
+            return;
+        };
+        if newfork != fork {
+            reply.error(EACCES);
+            return;
+        }
+        if let Err(err) = check_name(newname) {
+            reply.error(err);
+            return;
+        }
+        let old_number = self.fs.file(file).number();
+        let existing = self.fs.file_by_name(newname);
+        if existing.is_some_and(|existing| self.fs.file(existing).number() == old_number) {
+            reply.ok();
+            return;
+        }
+        // a longer name can need more room in the file directory than is left
+        if !self.fs.has_room_to_rename(file, newname, existing) {
+            reply.error(ENOSPC);
+            return;
+        }
+        if let Some(existing) = existing {
+            self.fs.remove_file(existing);
+        }
+        let Some(file) = self.fs.file_by_id(old_number) else {
+            reply.error(ENOENT);
+            return;
+        };
+        self.fs.rename_file(file, newname);
+        self.dirty = true;
+        reply.ok();
+    }
     fn readdir(
         &mut self,
         _req: &Request,
@@ -281,22 +441,18 @@ impl Filesystem for MfsFuse {
                 entries.push((
                     self.ino_by_file(f, Fork::Resource),
                     FileType::RegularFile,
-                    format!("{}.rsrc", f.name().to_string()),
+                    format!("{}.rsrc", f.name()),
                 ));
             }
-
-            if f.data_fork_size() != 0 || f.resource_fork_size() == 0 {
-                entries.push((self.ino_by_file(f, Fork::Data), FileType::RegularFile, f.name().to_string()));
-            }
+            entries.push((self.ino_by_file(f, Fork::Data), FileType::RegularFile, f.name().to_string()));
         }
 
         for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
             // i + 1 means the index of the next entry
-            if reply.add(entry.0 as u64, (i + 1) as i64, entry.1, entry.2) {
+            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
                 break;
             }
         }
         reply.ok();
     }
 }
-
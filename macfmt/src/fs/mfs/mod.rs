@@ -1,17 +1,32 @@
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::time::SystemTime;
 
 use binrw::{
-    BinRead, BinResult, BinWrite, binread,
-    io::{Read, Seek, SeekFrom},
+    BinRead, BinResult, BinWrite, Endian, binread,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
 };
 use bitflags::bitflags;
 use derivative::Derivative;
 
-use crate::common::{DateTime, DynamicPascalString, PascalString, SizedString};
-use super::BootBlocks;
+use crate::common::{DateTime, DynamicPascalString, FinderInfo, PascalString, SizedString};
+use crate::i18n::{MacRoman, MacScript};
+use crate::rsrc::{RawResource, ResourceType};
+use crate::rsrc::types::MfsFolder;
+use super::{BootBlocks, Entry, EntryKind, Volume};
 
-//pub mod fuse;
+pub mod fuse;
+
+const SECTOR_SIZE: usize = 512;
+const VOLUME_INFO_OFFSET: u64 = 0x400;
+const BLOCK_MAP_OFFSET: u64 = 0x440;
+// what's left of the two volume information sectors for 12-bit block map entries
+const MAX_ALLOC_BLOCKS: usize = (0x800 - BLOCK_MAP_OFFSET as usize) * 8 / 12;
+
+// Folder numbers the Finder gives special meaning to. Anything else is an FOBJ resource id.
+pub const ROOT_FOLDER: i16 = 0;
+pub const DESKTOP_FOLDER: i16 = -2;
+pub const TRASH_FOLDER: i16 = -3;
 
 // Inside_Macintosh_Promotional_Edition_1985 also talks about flie tags:
 // file_num: u32,
@@ -27,8 +42,26 @@ pub enum Fork {
     Data,
 }
 
+impl From<super::Fork> for Fork {
+    fn from(fork: super::Fork) -> Fork {
+        match fork {
+            super::Fork::Data => Fork::Data,
+            super::Fork::Resource => Fork::Resource,
+        }
+    }
+}
+
+// Handles are file numbers rather than positions in the directory, so they stay valid when other
+// files are added or removed.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct FileHandle(usize);
+pub struct FileHandle(u32);
+
+// How entries handed out through Volume are found again.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EntryId {
+    Folder(i16),
+    File(FileHandle),
+}
 
 #[derive(Debug)]
 pub struct FileWriter<'a> {
@@ -40,13 +73,10 @@ pub struct FileWriter<'a> {
 
 impl<'a> io::Write for FileWriter<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let _offset_blocks = self.offset / 512;
-        let _offset_rem = self.offset % 512;
-        let mut data = io::Cursor::new(self.mfs.file_contents(self.file, self.fork));
-        data.seek(SeekFrom::Start(self.offset))?;
-        data.write_all(buf)?;
-        self.mfs
-            .overwrite_contents(self.file, self.fork, data.into_inner())?;
+        let offset = u32::try_from(self.offset)
+            .map_err(|_| io::Error::from(io::ErrorKind::FileTooLarge))?;
+        self.mfs.write_at(self.file, self.fork, offset, buf)?;
+        self.offset += buf.len() as u64;
         Ok(buf.len())
     }
     fn flush(&mut self) -> io::Result<()> {
@@ -55,8 +85,22 @@ impl<'a> io::Write for FileWriter<'a> {
 }
 
 impl<'a> io::Seek for FileWriter<'a> {
-    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
-        todo!()
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.mfs.file(self.file).fork_size(self.fork) as u64;
+        let new_offset = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => len.checked_add_signed(off),
+            SeekFrom::Current(off) => self.offset.checked_add_signed(off),
+        };
+        let Some(new_offset) = new_offset else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+        self.offset = new_offset;
+
+        Ok(self.offset)
     }
 }
 
@@ -65,15 +109,17 @@ impl<'a> io::Seek for FileWriter<'a> {
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Mfs {
+    // blank disks have no boot blocks at all
     #[brw(pad_size_to = 0x400)]
-    boot: BootBlocks,
+    #[br(try)]
+    boot: Option<BootBlocks>,
     #[brw(pad_size_to = 0x40)]
     info: VolumeInformation,
-    #[br(count = (info.alloc_block_count*12)/8, map = gimme_block_map)]
+    #[br(count = BlockMap::packed_len(info.alloc_block_count), map = |v: Vec<u8>| gimme_block_map(v, info.alloc_block_count))]
     #[derivative(Debug = "ignore")]
     block_map: BlockMap,
     #[brw(seek_before = SeekFrom::Start(512*info.file_directory_start as u64))]
-    #[br(count = info.file_directory_length, map = Self::drop_nonexistent_files)]
+    #[br(count = info.file_directory_length as usize * SECTOR_SIZE, try_map = Self::parse_file_directory)]
     files: Vec<FileDirectoryBlock>,
     #[brw(seek_before = SeekFrom::Start(512 * info.alloc_block_start as u64))]
     #[br(count = info.alloc_block_count as u32 * info.alloc_block_size)]
@@ -85,6 +131,58 @@ impl Mfs {
     pub fn new<R: Read + Seek>(reader: &mut R) -> BinResult<Self> {
         Self::read(reader)
     }
+    // Lays out an empty volume the way the Finder does for 400K floppies: the file directory
+    // starts at sector 4, the allocation blocks follow it and the last two sectors are unused.
+    // Bigger volumes get bigger allocation blocks, as the block map has to fit next to the
+    // volume information. Without boot blocks the disk can't be started up from.
+    pub fn format(name: &str, size: u64, boot: Option<BootBlocks>) -> io::Result<Mfs> {
+        let name = PascalString::new(name)
+            .filter(|_| is_valid_name(name))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid volume name"))?;
+        let sectors = usize::try_from(size / SECTOR_SIZE as u64)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "volume too big"))?;
+        let dir_start = 4;
+        let dir_len = (sectors / 64).max(12);
+        let alloc_start = dir_start + dir_len;
+        let data_sectors = sectors.checked_sub(alloc_start + 2)
+            .filter(|data_sectors| *data_sectors >= 2)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "volume too small"))?;
+        let block_sectors = data_sectors.div_ceil(MAX_ALLOC_BLOCKS).next_multiple_of(2);
+        let alloc_block_size = u32::try_from(block_sectors * SECTOR_SIZE)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "volume too big"))?;
+        let alloc_block_count = (data_sectors / block_sectors) as u16;
+
+        Ok(Mfs {
+            boot,
+            info: VolumeInformation {
+                creation_date: DateTime::now(),
+                last_backup_date: DateTime::default(),
+                attributes: 0,
+                file_count: 0,
+                file_directory_start: dir_start as u16,
+                file_directory_length: dir_len as u16,
+                alloc_block_count,
+                alloc_block_size,
+                clump_size: 8 * alloc_block_size,
+                alloc_block_start: alloc_start as u16,
+                next_file_num: 1,
+                free_alloc_blocks: alloc_block_count,
+                name,
+            },
+            block_map: BlockMap(vec![0; alloc_block_count as usize]),
+            files: Vec::new(),
+            contents: vec![0; alloc_block_count as usize * alloc_block_size as usize],
+        })
+    }
+    pub fn name(&self) -> String {
+        self.info.name.decode()
+    }
+    pub fn boot_blocks(&self) -> Option<&BootBlocks> {
+        self.boot.as_ref()
+    }
+    pub fn set_boot_blocks(&mut self, boot: Option<BootBlocks>) {
+        self.boot = boot;
+    }
     pub fn alloc_block_size(&self) -> u32 {
         self.info.alloc_block_size
     }
@@ -119,19 +217,97 @@ impl Mfs {
         self.info.file_count += 1;
         self.info.next_file_num += 1;
 
-        FileHandle(self.files.len() - 1)
+        FileHandle(file_number)
+    }
+    // MFS itself has no directories, the Finder fakes them: each folder is an FOBJ resource in
+    // the Desktop file, with the folder number as its id and the folder name as its name.
+    fn desktop_folders(&self) -> Vec<(i16, String, MfsFolder)> {
+        let Some(desktop) = self.files.iter().find(|f| f.name().eq_ignore_ascii_case("Desktop")) else {
+            return Vec::new();
+        };
+        let Ok(rsrc) = RawResource::read(&mut Cursor::new(self.file_rsrc(FileHandle(desktop.file_number)))) else {
+            return Vec::new();
+        };
+        let Ok(folders) = rsrc.resources_of(&ResourceType::MfsFolderInfo) else {
+            return Vec::new();
+        };
+        folders
+            .into_iter()
+            .filter(|(id, ..)| *id != ROOT_FOLDER)
+            .filter_map(|(id, name, data)| {
+                let folder = MfsFolder::read(&mut Cursor::new(data)).ok()?;
+                Some((id, name.unwrap_or("").to_owned(), folder))
+            })
+            .collect()
+    }
+    // The folder hierarchy as the Finder shows it. Files in unknown folders (including the
+    // desktop and the trash) and folders that can't be reached from the root, for example
+    // because their parents form a cycle, are put in the root folder.
+    pub fn root_folder(&self) -> Folder {
+        let folders = self.desktop_folders();
+        let mut files: HashMap<i16, Vec<FileHandle>> = HashMap::new();
+        for file in self.files.iter() {
+            let number = match folders.iter().any(|(id, ..)| *id == file.folder_number) {
+                true => file.folder_number,
+                false => ROOT_FOLDER,
+            };
+            files.entry(number).or_default().push(FileHandle(file.file_number));
+        }
+
+        let mut visited = HashSet::new();
+        let mut root = Self::build_folder(ROOT_FOLDER, self.name(), None, &folders, &mut files, &mut visited);
+        for (id, name, info) in folders.iter() {
+            if !visited.contains(id) {
+                let orphan = Self::build_folder(*id, name.clone(), Some(info), &folders, &mut files, &mut visited);
+                root.folders.push(orphan);
+            }
+        }
+
+        root
+    }
+    fn build_folder(
+        number: i16,
+        name: String,
+        info: Option<&MfsFolder>,
+        folders: &[(i16, String, MfsFolder)],
+        files: &mut HashMap<i16, Vec<FileHandle>>,
+        visited: &mut HashSet<i16>,
+    ) -> Folder {
+        visited.insert(number);
+        let mut subfolders = Vec::new();
+        for (id, name, folder) in folders.iter() {
+            if folder.parent() == number && !visited.contains(id) {
+                subfolders.push(Self::build_folder(*id, name.clone(), Some(folder), folders, files, visited));
+            }
+        }
+
+        Folder {
+            number,
+            name,
+            info: info.cloned(),
+            folders: subfolders,
+            files: files.remove(&number).unwrap_or_default(),
+        }
+    }
+    // Handles of removed files are a bug in the caller, like an index out of bounds.
+    fn index(&self, file: FileHandle) -> usize {
+        self.files
+            .iter()
+            .position(|f| f.file_number == file.0)
+            .unwrap_or_else(|| panic!("file number {} is not on the volume", file.0))
+    }
+    pub fn file(&self, file: FileHandle) -> &FileDirectoryBlock {
+        &self.files[self.index(file)]
     }
     pub fn file_by_id(&self, num: u32) -> Option<FileHandle> {
         self.files
             .iter()
-            .enumerate()
-            .find_map(|(id, f)| (f.file_number == num).then_some(FileHandle(id)))
+            .find_map(|f| (f.file_number == num).then_some(FileHandle(num)))
     }
     pub fn file_by_name(&self, name: &str) -> Option<FileHandle> {
         self.files
             .iter()
-            .enumerate()
-            .find_map(|(id, f)| (f.name() == name).then_some(FileHandle(id)))
+            .find_map(|f| (f.name() == name).then_some(FileHandle(f.file_number)))
     }
     pub fn file_writer<'a>(&'a mut self, file: FileHandle, fork: Fork) -> FileWriter<'a> {
         FileWriter {
@@ -141,41 +317,81 @@ impl Mfs {
             fork,
         }
     }
-    pub fn append_file_data(&mut self, file: &FileDirectoryBlock, mut data: &[u8]) {
-        let free_size = file.data_fork_allocated_space - file.data_fork_size;
-        if data.len() <= free_size as usize {
-            let old_file_end_block = (file.data_fork_size / self.info.alloc_block_size) as usize;
-            let old_file_end_rem = (file.data_fork_size % self.info.alloc_block_size) as usize;
-            let block_size = self.info.alloc_block_size as usize;
-            let blocks: Vec<u16> = self
-                .block_map
-                .blocks_of(file.data_fork_start)
-                .skip(old_file_end_block)
-                .collect();
-            let mut iter = blocks.into_iter();
-            let to_write = (block_size as usize).min(data.len());
-
-            self.alloc_block_data_mut(iter.next().unwrap())[old_file_end_rem..][..to_write]
-                .copy_from_slice(&data[..to_write]);
-
-            if data.len() <= to_write {
-                return;
-            }
+    pub fn append_file_data(&mut self, file: FileHandle, data: &[u8]) -> io::Result<()> {
+        let offset = self.file(file).data_fork_size;
+        self.write_at(file, Fork::Data, offset, data)
+    }
+    // Writes into the middle of a fork, growing it if the data goes past its end.
+    pub fn write_at(&mut self, file: FileHandle, fork: Fork, offset: u32, data: &[u8]) -> io::Result<()> {
+        let end = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| offset.checked_add(len))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::FileTooLarge))?;
+        if end > self.file(file).fork_size(fork) {
+            self.set_fork_len(file, fork, end)?;
+        }
+        self.fill_fork(file, fork, offset as usize, data);
+        let i = self.index(file);
+        self.files[i].modification_date = DateTime::now();
 
-            data = &data[old_file_end_rem..];
+        Ok(())
+    }
+    // Grows or shrinks a fork in place, keeping the blocks it already has. Anything past the
+    // old end reads back as zeroes.
+    pub fn set_fork_len(&mut self, file: FileHandle, fork: Fork, len: u32) -> io::Result<()> {
+        let block_size = self.info.alloc_block_size;
+        let block_count = u16::try_from(len.div_ceil(block_size))
+            .map_err(|_| io::Error::from(io::ErrorKind::StorageFull))?;
+        let i = self.index(file);
+        let entry = &self.files[i];
+        let old_len = entry.fork_size(fork);
+        let start = self.block_map
+            .resize(entry.fork_start(fork), block_count)
+            .ok_or(io::ErrorKind::StorageFull)?;
 
-            for block in iter {
-                let to_write = (block_size as usize).min(data.len());
-                self.alloc_block_data_mut(block)[..to_write].copy_from_slice(&data[..to_write]);
-                if data.len() <= to_write {
-                    return;
-                }
-                data = &data[block_size..];
+        let allocated = block_count as u32 * block_size;
+        let entry = &mut self.files[i];
+        match fork {
+            Fork::Data => {
+                entry.data_fork_start = start;
+                entry.data_fork_size = len;
+                entry.data_fork_allocated_space = allocated;
+            },
+            Fork::Resource => {
+                entry.resource_fork_start = start;
+                entry.resource_fork_size = len;
+                entry.resource_fork_allocated_space = allocated;
+            },
+        }
+        entry.modification_date = DateTime::now();
+        self.info.free_alloc_blocks = self.block_map.free_count();
+
+        let zero_from = old_len.min(len);
+        self.fill_fork(file, fork, zero_from as usize, &vec![0; (allocated - zero_from) as usize]);
+
+        Ok(())
+    }
+    // Copies data into blocks the fork already has, without changing its size.
+    fn fill_fork(&mut self, file: FileHandle, fork: Fork, offset: usize, mut data: &[u8]) {
+        let block_size = self.info.alloc_block_size as usize;
+        let blocks: Vec<u16> = self.block_map
+            .blocks_of(self.file(file).fork_start(fork))
+            .skip(offset / block_size)
+            .take(data.len().div_ceil(block_size) + 1)
+            .collect();
+        let mut in_block = offset % block_size;
+        for block in blocks {
+            if data.is_empty() {
+                break;
             }
+            let to_write = (block_size - in_block).min(data.len());
+            self.alloc_block_data_mut(block)[in_block..][..to_write].copy_from_slice(&data[..to_write]);
+            data = &data[to_write..];
+            in_block = 0;
         }
     }
     pub fn file_contents(&self, file: FileHandle, fork: Fork) -> Vec<u8> {
-        let file = &self.files[file.0];
+        let file = self.file(file);
         self.block_map
             .blocks_of(file.fork_start(fork))
             .flat_map(|block| self.alloc_block_data(block))
@@ -183,18 +399,45 @@ impl Mfs {
             .map(|v| *v)
             .collect()
     }
-    fn overwrite_contents(
+    pub fn overwrite_contents(
         &mut self,
         file: FileHandle,
         fork: Fork,
-        data: Vec<u8>,
+        data: &[u8],
     ) -> io::Result<()> {
-        let file = &self.files[file.0];
-        if self.block_map.blocks_of(file.fork_start(fork)).count() < (data.len() + 511) / 512 {
-            self.block_map.ensure_len(file.fork_start(fork))?;
-        }
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::from(io::ErrorKind::FileTooLarge))?;
+        self.set_fork_len(file, fork, len)?;
+        self.fill_fork(file, fork, 0, data);
         Ok(())
     }
+    pub fn remove_file(&mut self, file: FileHandle) {
+        let entry = self.files.remove(self.index(file));
+        self.block_map.free(entry.data_fork_start);
+        self.block_map.free(entry.resource_fork_start);
+        self.info.file_count -= 1;
+        self.info.free_alloc_blocks = self.block_map.free_count();
+    }
+    pub fn rename_file(&mut self, file: FileHandle, name: &str) {
+        let i = self.index(file);
+        self.files[i].name = DynamicPascalString::new(name);
+    }
+    // Directory entries never straddle sectors, so this is checked against the packed layout
+    // rather than the total size.
+    pub fn has_room_for(&self, name: &str) -> bool {
+        let mut sizes: Vec<usize> = self.files.iter().map(|f| f.entry_size()).collect();
+        sizes.push(FileDirectoryBlock::entry_size_for(name));
+        pack_sectors(&sizes).len() <= self.info.file_directory_length as usize
+    }
+    // Whether a file can be renamed, after removing the file it replaces, if any.
+    pub fn has_room_to_rename(&self, file: FileHandle, name: &str, replacing: Option<FileHandle>) -> bool {
+        let sizes: Vec<usize> = self.files
+            .iter()
+            .filter(|f| replacing.is_none_or(|r| f.file_number != r.0))
+            .map(|f| if f.file_number == file.0 { FileDirectoryBlock::entry_size_for(name) } else { f.entry_size() })
+            .collect();
+        pack_sectors(&sizes).len() <= self.info.file_directory_length as usize
+    }
     pub fn file_data(&self, file: FileHandle) -> Vec<u8> {
         self.file_contents(file, Fork::Data)
     }
@@ -209,12 +452,73 @@ impl Mfs {
         let start = block as usize * self.info.alloc_block_size as usize;
         &self.contents[start as usize..][..self.info.alloc_block_size as usize]
     }
-    fn drop_nonexistent_files(files: Vec<FileDirectoryBlock>) -> Vec<FileDirectoryBlock> {
-        files
-            .into_iter()
-            .filter(|f| f.flags.contains(FileFlags::EXISTS))
-            .collect()
+    // Each sector holds entries up to the first unused one.
+    fn parse_file_directory(buf: Vec<u8>) -> BinResult<Vec<FileDirectoryBlock>> {
+        let mut files = Vec::new();
+        for sector in buf.chunks(SECTOR_SIZE) {
+            let mut cursor = Cursor::new(sector);
+            while let Some(flags) = sector.get(cursor.position() as usize) {
+                if flags & FileFlags::EXISTS.bits() == 0 {
+                    break;
+                }
+                files.push(FileDirectoryBlock::read(&mut cursor)?);
+            }
+        }
+        Ok(files)
     }
+    fn write_file_directory(&self) -> BinResult<Vec<u8>> {
+        let sizes: Vec<usize> = self.files.iter().map(|f| f.entry_size()).collect();
+        let sectors = pack_sectors(&sizes);
+        if sectors.len() > self.info.file_directory_length as usize {
+            return Err(binrw::Error::Io(io::Error::new(io::ErrorKind::StorageFull, "file directory is full")));
+        }
+
+        let mut buf = vec![0; self.info.file_directory_length as usize * SECTOR_SIZE];
+        let mut files = self.files.iter();
+        for (i, count) in sectors.into_iter().enumerate() {
+            let mut cursor = Cursor::new(&mut buf[i * SECTOR_SIZE..][..SECTOR_SIZE]);
+            for file in files.by_ref().take(count) {
+                file.write(&mut cursor)?;
+            }
+        }
+        Ok(buf)
+    }
+}
+
+impl BinWrite for Mfs {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, writer: &mut W, endian: Endian, _args: ()) -> BinResult<()> {
+        if let Some(boot) = &self.boot {
+            writer.seek(SeekFrom::Start(0))?;
+            boot.write_options(writer, endian, ())?;
+        }
+        writer.seek(SeekFrom::Start(VOLUME_INFO_OFFSET))?;
+        self.info.write_options(writer, endian, ())?;
+        writer.seek(SeekFrom::Start(BLOCK_MAP_OFFSET))?;
+        writer.write_all(&self.block_map.to_bytes())?;
+        writer.seek(SeekFrom::Start(512 * self.info.file_directory_start as u64))?;
+        writer.write_all(&self.write_file_directory()?)?;
+        writer.seek(SeekFrom::Start(512 * self.info.alloc_block_start as u64))?;
+        writer.write_all(&self.contents)?;
+
+        Ok(())
+    }
+}
+
+// Splits directory entries of the given sizes into how many of them go into each sector.
+fn pack_sectors(sizes: &[usize]) -> Vec<usize> {
+    let mut sectors = Vec::new();
+    let mut used = SECTOR_SIZE;
+    for size in sizes {
+        if used + size > SECTOR_SIZE {
+            sectors.push(0);
+            used = 0;
+        }
+        *sectors.last_mut().unwrap() += 1;
+        used += size;
+    }
+    sectors
 }
 
 #[inline(always)]
@@ -232,8 +536,10 @@ fn u16_from_nibs(n1: u8, n2: u8, n3: u8) -> u16 {
     (n1 as u16) << 8 | (n2 as u16) << 4 | (n3 as u16)
 }
 
-fn gimme_block_map(v: Vec<u8>) -> BlockMap {
-    let vec: Vec<u16> = v
+fn gimme_block_map(mut v: Vec<u8>, count: u16) -> BlockMap {
+    // an odd entry count ends in half a triple
+    v.resize(v.len().next_multiple_of(3), 0);
+    let mut vec: Vec<u16> = v
         .chunks_exact(3)
         .flat_map(|slice| {
             [
@@ -242,6 +548,7 @@ fn gimme_block_map(v: Vec<u8>) -> BlockMap {
             ]
         })
         .collect();
+    vec.truncate(count as usize);
     BlockMap(vec)
 }
 
@@ -259,7 +566,6 @@ impl<'a> Iterator for BlockIter<'a> {
             _ => {
                 let old = self.cur_idx;
                 self.cur_idx = *self.data.get(self.cur_idx as usize - 2)?;
-                println!("{:x} => {:x}", old, self.cur_idx);
                 Some(old - 2)
             }
         }
@@ -269,23 +575,73 @@ impl<'a> Iterator for BlockIter<'a> {
 #[derive(Clone, Debug)]
 pub struct BlockMap(Vec<u16>);
 
+// Entries are indexed from block 2, with 0 marking a free block and 1 the end of a chain.
 impl BlockMap {
-    fn ensure_len(&mut self, _block: u16) -> io::Result<()> {
-        todo!()
+    fn packed_len(count: u16) -> usize {
+        (count as usize * 12).div_ceil(8)
+    }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.0
+            .chunks(2)
+            .flat_map(|pair| {
+                let (a, b) = (pair[0], pair.get(1).copied().unwrap_or(0));
+                [(a >> 4) as u8, ((a & 0xf) << 4 | b >> 8) as u8, b as u8]
+            })
+            .collect();
+        bytes.truncate(Self::packed_len(self.0.len() as u16));
+        bytes
+    }
+    fn free_count(&self) -> u16 {
+        self.0.iter().filter(|v| **v == 0).count() as u16
     }
-    /*fn allocate_to(&mut self, num: Option<u16>) -> u16 {
-        let free = self
-            .0
+    // Chains `count` free blocks together and returns the first one's number, or 0 for an
+    // empty fork.
+    fn alloc(&mut self, count: u16) -> Option<u16> {
+        let free: Vec<usize> = self.0
             .iter()
             .enumerate()
-            .find(|(_, v)| **v == 0)
+            .filter(|(_, v)| **v == 0)
             .map(|(i, _)| i)
-            .unwrap() as u16;
-        if let Some(n) = num {
-            self.0[n as usize] = free;
+            .take(count as usize)
+            .collect();
+        if free.len() < count as usize {
+            return None;
+        }
+        for pair in free.windows(2) {
+            self.0[pair[0]] = pair[1] as u16 + 2;
+        }
+        let last = *free.last()?;
+        self.0[last] = 1;
+        Some(free[0] as u16 + 2)
+    }
+    // Cuts a chain short or extends it with free blocks, returning its (possibly new) start.
+    // Nothing changes if there aren't enough free blocks.
+    fn resize(&mut self, start: u16, count: u16) -> Option<u16> {
+        let blocks: Vec<u16> = self.blocks_of(start).take(self.0.len()).collect();
+        let count = count as usize;
+        if count <= blocks.len() {
+            for block in &blocks[count..] {
+                self.0[*block as usize] = 0;
+            }
+            let Some(last) = count.checked_sub(1) else {
+                return Some(0);
+            };
+            self.0[blocks[last] as usize] = 1;
+            return Some(start);
+        }
+
+        let extra = self.alloc((count - blocks.len()) as u16)?;
+        match blocks.last() {
+            Some(last) => {
+                self.0[*last as usize] = extra;
+                Some(start)
+            },
+            None => Some(extra),
         }
-        free
-    }*/
+    }
+    fn free(&mut self, start: u16) {
+        self.resize(start, 0);
+    }
     fn blocks_of<'a>(&'a self, start: u16) -> BlockIter<'a> {
         BlockIter {
             cur_idx: start,
@@ -377,6 +733,129 @@ impl FileDirectoryBlock {
     pub fn number(&self) -> u32 {
         self.file_number
     }
+    pub fn folder_number(&self) -> i16 {
+        self.folder_number
+    }
+    // The entry starts out with the same fields in the same order as an HFS FInfo.
+    pub fn finder_info(&self) -> FinderInfo {
+        let mut buf = Cursor::new(Vec::new());
+        (&self.file_type, &self.file_creator, self.finder_flags, self.position, self.folder_number)
+            .write_be(&mut buf)
+            .expect("writing to a Vec can't fail");
+        buf.set_position(0);
+        FinderInfo::read(&mut buf).expect("any 16 bytes are valid Finder info")
+    }
+    fn entry_size(&self) -> usize {
+        Self::entry_size_for(self.name.as_str())
+    }
+    fn entry_size_for(name: &str) -> usize {
+        (51 + name.chars().count()).next_multiple_of(2)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Folder {
+    number: i16,
+    name: String,
+    // what the Finder keeps about the folder, which the root doesn't have
+    info: Option<MfsFolder>,
+    folders: Vec<Folder>,
+    files: Vec<FileHandle>,
+}
+
+impl Folder {
+    pub fn number(&self) -> i16 {
+        self.number
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn folders(&self) -> &[Folder] {
+        &self.folders
+    }
+    pub fn files(&self) -> &[FileHandle] {
+        &self.files
+    }
+    pub fn folder_by_name(&self, name: &str) -> Option<&Folder> {
+        self.folders.iter().find(|f| f.name == name)
+    }
+    pub fn folder_by_number(&self, number: i16) -> Option<&Folder> {
+        if self.number == number {
+            return Some(self);
+        }
+        self.folders.iter().find_map(|f| f.folder_by_number(number))
+    }
+    pub fn info(&self) -> Option<&MfsFolder> {
+        self.info.as_ref()
+    }
+}
+
+impl Mfs {
+    fn folder_entry(&self, folder: &Folder) -> Entry<EntryId> {
+        let (created, modified) = match folder.info() {
+            Some(info) => (info.creation_date().to_system_time(), info.modification_date().to_system_time()),
+            None => (self.creation_date(), self.creation_date()),
+        };
+        Entry::new(EntryId::Folder(folder.number), folder.name(), EntryKind::Directory, None, created, modified)
+    }
+    fn file_entry(&self, handle: FileHandle) -> Entry<EntryId> {
+        let file = self.file(handle);
+        let kind = EntryKind::File {
+            data_len: file.data_fork_size(),
+            rsrc_len: file.resource_fork_size(),
+        };
+        Entry::new(
+            EntryId::File(handle),
+            file.name(),
+            kind,
+            Some(file.finder_info()),
+            file.creation_date(),
+            file.modification_date(),
+        )
+    }
+}
+
+impl Volume for Mfs {
+    type Id = EntryId;
+    type ForkReader<'a> = Cursor<Vec<u8>>;
+
+    fn volume_name(&self) -> String {
+        self.name()
+    }
+    fn created(&self) -> SystemTime {
+        self.creation_date()
+    }
+    fn root(&self) -> Entry<EntryId> {
+        self.folder_entry(&self.root_folder())
+    }
+    fn read_dir(&mut self, dir: &Entry<EntryId>) -> io::Result<Vec<Entry<EntryId>>> {
+        let EntryId::Folder(number) = dir.id() else {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+        };
+        let root = self.root_folder();
+        let Some(folder) = root.folder_by_number(number) else {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        };
+        let folders = folder.folders().iter().map(|f| self.folder_entry(f));
+        let files = folder.files().iter().map(|f| self.file_entry(*f));
+        Ok(folders.chain(files).collect())
+    }
+    fn fork_reader(&mut self, file: &Entry<EntryId>, fork: super::Fork) -> io::Result<Cursor<Vec<u8>>> {
+        match file.id() {
+            EntryId::File(handle) if self.file_by_id(handle.0).is_some() => {
+                Ok(Cursor::new(self.file_contents(handle, fork.into())))
+            },
+            EntryId::File(_) => Err(io::Error::from(io::ErrorKind::NotFound)),
+            EntryId::Folder(_) => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+        }
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let len = name.chars().count();
+    (1..=255).contains(&len)
+        && !name.contains(':')
+        && name.chars().all(|ch| MacRoman::encode(ch).is_ok())
 }
 
 bitflags! {
@@ -389,8 +868,10 @@ bitflags! {
 
 #[cfg(test)]
 mod tests {
-    use super::{Fork, Mfs};
-    use std::io::{Cursor, Write};
+    use super::{EntryId, Fork, Mfs};
+    use crate::fs::{self, EntryKind, Volume};
+    use binrw::BinWrite;
+    use std::io::{Cursor, Seek, SeekFrom, Write};
     const INFINITE_DSK: &'static [u8] = include_bytes!(concat!(
         env!("CARGO_MANIFEST_DIR"),
         "/testdata/infinite.dsk"
@@ -405,6 +886,22 @@ mod tests {
         assert_eq!(data, READ_ME);
     }
     #[test]
+    fn volume() {
+        let mut disk = Cursor::new(INFINITE_DSK.to_vec());
+        let mut mfs = Mfs::new(&mut disk).unwrap();
+        let root = mfs.root();
+        assert_eq!(root.id(), EntryId::Folder(0));
+        assert_eq!(root.name(), mfs.volume_name());
+        assert!(mfs.read_dir(&root).unwrap().iter().any(|e| e.name() == "Read Me"));
+
+        let read_me = mfs.lookup("/Read Me").unwrap().unwrap();
+        assert!(matches!(read_me.kind(), EntryKind::File { data_len, .. } if data_len as usize == READ_ME.len()));
+        assert_eq!(read_me.finder_info().unwrap().file_type().as_inner(), b"TEXT");
+        assert_eq!(mfs.read_fork(&read_me, fs::Fork::Data).unwrap(), READ_ME);
+        assert!(mfs.lookup("/Read Me/nope").unwrap().is_none());
+        assert!(mfs.read_dir(&read_me).is_err());
+    }
+    #[test]
     fn write_then_read() {
         let mut disk = Cursor::new(INFINITE_DSK.to_vec());
         let mut mfs = Mfs::new(&mut disk).unwrap();
@@ -415,4 +912,151 @@ mod tests {
             .unwrap();
         assert_eq!(mfs.file_data(file), b"test data");
     }
+    #[test]
+    fn seek_append_and_truncate() {
+        let mut disk = Cursor::new(INFINITE_DSK.to_vec());
+        let mut mfs = Mfs::new(&mut disk).unwrap();
+        let free = mfs.block_map.free_count();
+        let file = mfs.add_file("testfile", *b"TEST", *b"TEST");
+        let mut writer = mfs.file_writer(file, Fork::Data);
+        writer.write_all(&[1; 3000]).unwrap();
+        writer.seek(SeekFrom::Start(2990)).unwrap();
+        writer.write_all(&[2; 20]).unwrap();
+        mfs.append_file_data(file, &[3; 4000]).unwrap();
+
+        let data = mfs.file_data(file);
+        assert_eq!(data.len(), 7010);
+        assert!(data[..2990].iter().all(|b| *b == 1));
+        assert!(data[2990..3010].iter().all(|b| *b == 2));
+        assert!(data[3010..].iter().all(|b| *b == 3));
+        assert_eq!(mfs.block_map.free_count(), free - 3);
+        assert_eq!(mfs.info.free_alloc_blocks, free - 3);
+
+        mfs.set_fork_len(file, Fork::Data, 100).unwrap();
+        assert_eq!(mfs.file(file).fork_allocated_space(Fork::Data), 3072);
+        assert_eq!(mfs.block_map.free_count(), free - 1);
+        mfs.set_fork_len(file, Fork::Data, 200).unwrap();
+        assert_eq!(mfs.file_data(file)[100..], [0; 100]);
+        assert!(mfs.set_fork_len(file, Fork::Resource, u32::MAX / 2).is_err());
+        assert_eq!(mfs.block_map.free_count(), free - 1);
+    }
+    #[test]
+    fn format_floppy() {
+        let mut mfs = Mfs::format("Blank", 400 * 1024, None).unwrap();
+        assert_eq!(mfs.info.alloc_block_start, 16);
+        assert_eq!(mfs.info.alloc_block_count, 391);
+        assert_eq!(mfs.alloc_block_size(), 1024);
+        let file = mfs.add_file("Note", *b"TEXT", *b"ttxt");
+        mfs.overwrite_contents(file, Fork::Data, b"hello").unwrap();
+
+        let mut disk = Cursor::new(vec![0; 400 * 1024]);
+        mfs.write_be(&mut disk).unwrap();
+        assert_eq!(disk.get_ref().len(), 400 * 1024);
+        disk.set_position(0);
+        let mfs = Mfs::new(&mut disk).unwrap();
+        assert_eq!(mfs.name(), "Blank");
+        assert!(mfs.boot_blocks().is_none());
+        assert_eq!(mfs.info.free_alloc_blocks, 390);
+        assert_eq!(mfs.file_data(mfs.file_by_name("Note").unwrap()), b"hello");
+    }
+    #[test]
+    fn format_bootable() {
+        let boot = Mfs::new(&mut Cursor::new(INFINITE_DSK)).unwrap().boot_blocks().cloned();
+        let mfs = Mfs::format("Startup", 400 * 1024, boot).unwrap();
+        let mut disk = Cursor::new(vec![0; 400 * 1024]);
+        mfs.write_be(&mut disk).unwrap();
+        assert_eq!(disk.get_ref()[..1024], INFINITE_DSK[..1024]);
+        disk.set_position(0);
+        let mfs = Mfs::new(&mut disk).unwrap();
+        assert!(mfs.boot_blocks().is_some());
+    }
+    #[test]
+    fn format_high_density() {
+        let mfs = Mfs::format("Infinite HD", 1440 * 1024, None).unwrap();
+        assert_eq!(mfs.info.file_directory_length, 45);
+        assert_eq!(mfs.info.alloc_block_count, 471);
+        assert_eq!(mfs.alloc_block_size(), 3072);
+        assert!(Mfs::format("Tiny", 8 * 1024, None).is_err());
+        assert!(Mfs::format("A very long name for an MFS volume", 400 * 1024, None).is_err());
+    }
+    #[test]
+    fn room_to_rename() {
+        let mut mfs = Mfs::format("Blank", 400 * 1024, None).unwrap();
+        let mut i = 0;
+        while mfs.has_room_for(&format!("File {i}")) {
+            mfs.add_file(&format!("File {i}"), *b"TEXT", *b"ttxt");
+            i += 1;
+        }
+        let file = mfs.file_by_name("File 0").unwrap();
+        let other = mfs.file_by_name("File 1").unwrap();
+        assert!(mfs.has_room_to_rename(file, "File X", None));
+        assert!(!mfs.has_room_to_rename(file, &"x".repeat(200), None));
+        let long = (7..200).map(|n| "x".repeat(n)).find(|name| !mfs.has_room_to_rename(file, name, None)).unwrap();
+        // replacing another file frees its entry
+        assert!(mfs.has_room_to_rename(file, &long, Some(other)));
+    }
+    #[test]
+    fn directory_spans_sectors() {
+        let mut disk = Cursor::new(INFINITE_DSK.to_vec());
+        let mfs = Mfs::new(&mut disk).unwrap();
+        assert_eq!(mfs.files().len(), 32);
+        assert!(mfs.file_by_name("Sample - Expenses").is_some());
+    }
+    #[test]
+    fn finder_folders() {
+        let mut disk = Cursor::new(INFINITE_DSK.to_vec());
+        let mfs = Mfs::new(&mut disk).unwrap();
+        let root = mfs.root_folder();
+        assert_eq!(root.name(), "Infinite HD");
+        assert_eq!(root.folders().len(), 10);
+        let read_me = mfs.file_by_name("Read Me").unwrap();
+        assert!(root.files().contains(&read_me));
+
+        let macpaint = root.folder_by_name("MacPaint 1.5").unwrap();
+        assert_eq!(macpaint.number(), 8096);
+        let names: Vec<&str> = macpaint.files().iter().map(|f| mfs.file(*f).name()).collect();
+        assert_eq!(names, ["MacPaint", "Column", "Hello", "Scrapbook", "Woodblock"]);
+        assert!(root.folder_by_name("Empty Folder").unwrap().files().is_empty());
+
+        let total: usize = root.files().len() + root.folders().iter().map(|f| f.files().len()).sum::<usize>();
+        assert_eq!(total, mfs.files().len());
+    }
+    #[test]
+    fn handles_survive_removal() {
+        let mut disk = Cursor::new(INFINITE_DSK.to_vec());
+        let mut mfs = Mfs::new(&mut disk).unwrap();
+        let first = mfs.file_by_name(mfs.files()[0].name()).unwrap();
+        let last_name = mfs.files().last().unwrap().name().to_string();
+        let last = mfs.file_by_name(&last_name).unwrap();
+        let read_me = mfs.file_by_name("Read Me").unwrap();
+        mfs.remove_file(first);
+        assert_eq!(mfs.file(last).name(), last_name);
+        assert_eq!(mfs.file_data(read_me), READ_ME);
+        let added = mfs.add_file("testfile", *b"TEXT", *b"ttxt");
+        mfs.remove_file(read_me);
+        assert_eq!(mfs.file(added).name(), "testfile");
+        assert_eq!(mfs.file(last).name(), last_name);
+    }
+    #[test]
+    fn write_back() {
+        let mut disk = Cursor::new(INFINITE_DSK.to_vec());
+        let mut mfs = Mfs::new(&mut disk).unwrap();
+        let read_me = mfs.file_by_name("Read Me").unwrap();
+        mfs.rename_file(read_me, "Read Me Later");
+        let file = mfs.add_file("testfile", *b"TEXT", *b"ttxt");
+        mfs.overwrite_contents(file, Fork::Resource, &[0xa5; 5000]).unwrap();
+        mfs.remove_file(mfs.file_by_name("Hello").unwrap());
+        mfs.write_be(&mut disk).unwrap();
+
+        disk.set_position(0);
+        let mfs = Mfs::new(&mut disk).unwrap();
+        assert_eq!(mfs.files().len(), 32);
+        assert!(mfs.file_by_name("Hello").is_none());
+        let read_me = mfs.file_by_name("Read Me Later").unwrap();
+        assert_eq!(mfs.file_data(read_me), READ_ME);
+        let file = mfs.file_by_name("testfile").unwrap();
+        assert_eq!(mfs.file_rsrc(file), [0xa5; 5000]);
+        assert_eq!(mfs.file(file).fork_allocated_space(Fork::Resource), 2 * 3072);
+        assert_eq!(mfs.info.free_alloc_blocks, mfs.block_map.free_count());
+    }
 }
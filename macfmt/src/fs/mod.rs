@@ -1,10 +1,111 @@
 pub mod mfs;
 pub mod hfs;
+pub mod hfsplus;
 
 use binrw::{BinRead, BinWrite};
 use derivative::Derivative;
 use std::fmt;
-use crate::common::PascalString;
+use std::io::{self, Read, Seek};
+use std::time::SystemTime;
+use crate::common::{FinderInfo, PascalString};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fork {
+    Data,
+    Resource,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+    Directory,
+    File { data_len: u32, rsrc_len: u32 },
+}
+
+// A file or directory as every file system can describe it. The id is whatever the volume needs
+// to find the entry again.
+#[derive(Clone, Debug)]
+pub struct Entry<I> {
+    id: I,
+    name: String,
+    kind: EntryKind,
+    finder_info: Option<FinderInfo>,
+    created: SystemTime,
+    modified: SystemTime,
+}
+
+impl<I: Copy> Entry<I> {
+    pub(crate) fn new(
+        id: I,
+        name: &str,
+        kind: EntryKind,
+        finder_info: Option<FinderInfo>,
+        created: SystemTime,
+        modified: SystemTime,
+    ) -> Self {
+        Self { id, name: name.to_string(), kind, finder_info, created, modified }
+    }
+    pub fn id(&self) -> I {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+    pub fn fork_len(&self, fork: Fork) -> u32 {
+        match (self.kind, fork) {
+            (EntryKind::Directory, _) => 0,
+            (EntryKind::File { data_len, .. }, Fork::Data) => data_len,
+            (EntryKind::File { rsrc_len, .. }, Fork::Resource) => rsrc_len,
+        }
+    }
+    // Only files have one, the Finder keeps different information for folders.
+    pub fn finder_info(&self) -> Option<&FinderInfo> {
+        self.finder_info.as_ref()
+    }
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+// What tools that don't care which file system they're looking at need from a volume. Paths are
+// separated by '/', so names that contain one can't be looked up.
+pub trait Volume {
+    type Id: Copy + fmt::Debug;
+    type ForkReader<'a>: Read + Seek where Self: 'a;
+
+    fn volume_name(&self) -> String;
+    fn created(&self) -> SystemTime;
+    fn root(&self) -> Entry<Self::Id>;
+    fn read_dir(&mut self, dir: &Entry<Self::Id>) -> io::Result<Vec<Entry<Self::Id>>>;
+    fn fork_reader(&mut self, file: &Entry<Self::Id>, fork: Fork) -> io::Result<Self::ForkReader<'_>>;
+
+    fn lookup(&mut self, path: &str) -> io::Result<Option<Entry<Self::Id>>> {
+        let mut entry = self.root();
+        for seg in path.split('/').filter(|s| !s.is_empty()) {
+            if !entry.is_dir() {
+                return Ok(None);
+            }
+            match self.read_dir(&entry)?.into_iter().find(|e| e.name() == seg) {
+                Some(child) => entry = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(entry))
+    }
+    fn read_fork(&mut self, file: &Entry<Self::Id>, fork: Fork) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.fork_reader(file, fork)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
 
 #[derive(Derivative, Clone, BinRead, BinWrite)]
 #[derivative(Debug)]
@@ -27,8 +128,10 @@ pub struct BootBlocks {
     system_heap_size: u32,
     #[br(if(version & 0x2000 != 0))]
     extra_data: Option<BootBlockExtra>,
+    // the boot code fills the rest of the two sectors, and has to come along when the boot
+    // blocks are put on another disk
     #[derivative(Debug(format_with = "BootBlocks::code_vec_fmt"))]
-    #[br(count = 2)]
+    #[br(count = (1024 - 138 - if extra_data.is_some() { 10 } else { 0 }) / 2)]
     code: Vec<u16>,
 }
 
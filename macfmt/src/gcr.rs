@@ -0,0 +1,434 @@
+use thiserror::Error;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const TAG_SIZE: usize = 12;
+pub const TRACKS: u8 = 80;
+
+// Every 6-bit value as the disk byte the IWM writes for it. Disk bytes always have the top bit
+// set and never two zero bits in a row.
+const DISK_BYTES: [u8; 64] = [
+    0x96, 0x97, 0x9a, 0x9b, 0x9d, 0x9e, 0x9f, 0xa6, 0xa7, 0xab, 0xac, 0xad, 0xae, 0xaf, 0xb2, 0xb3,
+    0xb4, 0xb5, 0xb6, 0xb7, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf, 0xcb, 0xcd, 0xce, 0xcf, 0xd3,
+    0xd6, 0xd7, 0xd9, 0xda, 0xdb, 0xdc, 0xdd, 0xde, 0xdf, 0xe5, 0xe6, 0xe7, 0xe9, 0xea, 0xeb, 0xec,
+    0xed, 0xee, 0xef, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+];
+// and back, with 0xff for bytes that can't be on a disk
+const SIX_BITS: [u8; 256] = {
+    let mut table = [0xff; 256];
+    let mut i = 0;
+    while i < DISK_BYTES.len() {
+        table[DISK_BYTES[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+const ADDRESS_MARK: [u8; 3] = [0xd5, 0xaa, 0x96];
+const DATA_MARK: [u8; 3] = [0xd5, 0xaa, 0xad];
+const BIT_SLIP: [u8; 2] = [0xde, 0xaa];
+// 524 bytes in groups of three, each group as four nibbles: the top two bits of all three and
+// then the low six of each. The last group only has two bytes.
+const DATA_NIBBLES: usize = 699;
+const RAW_SIZE: usize = TAG_SIZE + SECTOR_SIZE;
+// how far past an address field its data field may start
+const DATA_WINDOW: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum GcrError {
+    #[error("Track {track} side {side} is missing sector {sector}")]
+    MissingSector { track: u8, side: u8, sector: u8 },
+    #[error("Bad data checksum in track {track} side {side} sector {sector}")]
+    DataChecksum { track: u8, side: u8, sector: u8 },
+}
+
+// The Sony drives spin slower towards the hub, so the outer tracks fit more sectors: 12 on
+// tracks 0-15 down to 8 on tracks 64-79.
+pub fn sectors_per_track(track: u8) -> u8 {
+    12 - track / 16
+}
+
+// The logical block a sector ends up in. Blocks go through both sides of a track before moving
+// on to the next one.
+pub fn block_of(track: u8, side: u8, sector: u8, sides: u8) -> usize {
+    let before: usize = (0..track).map(|t| sectors_per_track(t) as usize).sum();
+    let count = sectors_per_track(track) as usize;
+    before * sides as usize + side as usize * count + sector as usize
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Sector {
+    tag: [u8; TAG_SIZE],
+    data: [u8; SECTOR_SIZE],
+}
+
+impl Sector {
+    pub fn new(tag: [u8; TAG_SIZE], data: [u8; SECTOR_SIZE]) -> Self {
+        Self { tag, data }
+    }
+    pub fn tag(&self) -> &[u8; TAG_SIZE] {
+        &self.tag
+    }
+    pub fn data(&self) -> &[u8; SECTOR_SIZE] {
+        &self.data
+    }
+    fn from_raw(raw: &[u8; RAW_SIZE]) -> Self {
+        Self {
+            tag: raw[..TAG_SIZE].try_into().unwrap(),
+            data: raw[TAG_SIZE..].try_into().unwrap(),
+        }
+    }
+}
+
+// Finds every sector of one track in its bitstream, most significant bit first. The track is
+// circular, so the stream is gone through twice to pick up a sector that wraps around the end.
+pub fn decode_track(
+    bits: &[u8],
+    bit_count: usize,
+    track: u8,
+    side: u8,
+) -> Result<Vec<Sector>, GcrError> {
+    let count = sectors_per_track(track);
+    let nibbles = nibbles(bits, bit_count);
+    let mut sectors: Vec<Option<Sector>> = vec![None; count as usize];
+    let mut bad = vec![false; count as usize];
+
+    let mut i = 0;
+    while let Some(found) = find(&nibbles[i..], &ADDRESS_MARK) {
+        i += found + ADDRESS_MARK.len();
+        let Some(header) = six_bits(&nibbles[i..], 5) else {
+            continue;
+        };
+        let [track_lo, sector, side_hi, format, checksum] = header[..] else {
+            unreachable!();
+        };
+        if track_lo ^ sector ^ side_hi ^ format != checksum
+            || track_lo as u16 | (side_hi as u16 & 0x1f) << 6 != track as u16
+            || side_hi >> 5 & 1 != side
+            || sector >= count
+            || sectors[sector as usize].is_some()
+        {
+            continue;
+        }
+
+        let window = &nibbles[i..(i + DATA_WINDOW).min(nibbles.len())];
+        let Some(found) = find(window, &DATA_MARK) else {
+            continue;
+        };
+        let data = i + found + DATA_MARK.len();
+        let Some(raw) = six_bits(&nibbles[data..], 1 + DATA_NIBBLES + 4) else {
+            continue;
+        };
+        if raw[0] != sector {
+            continue;
+        }
+        match decode_data(&raw[1..]) {
+            Some(raw) => sectors[sector as usize] = Some(Sector::from_raw(&raw)),
+            None => bad[sector as usize] = true,
+        }
+    }
+
+    sectors
+        .into_iter()
+        .enumerate()
+        .map(|(sector, found)| {
+            let sector = sector as u8;
+            found.ok_or(match bad[sector as usize] {
+                true => GcrError::DataChecksum { track, side, sector },
+                false => GcrError::MissingSector { track, side, sector },
+            })
+        })
+        .collect()
+}
+
+// Lays a track out the way the Sony driver formats it: sectors at a 2:1 interleave, each one an
+// address field and a data field with self-sync bytes in between. `format` goes in every address
+// field, 0x22 for double-sided disks and 0x02 for single-sided ones.
+pub fn encode_track(track: u8, side: u8, format: u8, sectors: &[Sector]) -> (Vec<u8>, usize) {
+    let count = sectors.len();
+    let mut order = vec![None; count];
+    let mut pos = 0;
+    for sector in 0..count {
+        while order[pos].is_some() {
+            pos = (pos + 1) % count;
+        }
+        order[pos] = Some(sector);
+        pos = (pos + 2) % count;
+    }
+
+    let mut bits = BitWriter::default();
+    for sector in order.into_iter().flatten() {
+        let track_lo = track & 0x3f;
+        let side_hi = (track >> 6) | (side << 5);
+        let sector_num = sector as u8;
+        let checksum = track_lo ^ sector_num ^ side_hi ^ format;
+        bits.sync(14);
+        bits.nibbles(&ADDRESS_MARK);
+        for value in [track_lo, sector_num, side_hi, format, checksum] {
+            bits.nibble(DISK_BYTES[value as usize]);
+        }
+        bits.nibbles(&BIT_SLIP);
+        bits.sync(5);
+        bits.nibbles(&DATA_MARK);
+        bits.nibble(DISK_BYTES[sector]);
+        let mut raw = [0; RAW_SIZE];
+        raw[..TAG_SIZE].copy_from_slice(&sectors[sector].tag);
+        raw[TAG_SIZE..].copy_from_slice(&sectors[sector].data);
+        for value in encode_data(&raw) {
+            bits.nibble(DISK_BYTES[value as usize]);
+        }
+        bits.nibbles(&BIT_SLIP);
+    }
+    bits.sync(20);
+
+    (bits.bytes, bits.len)
+}
+
+// What the IWM's shift register sees: zero bits are skipped until a one comes along, and a
+// nibble is done once its top bit is set.
+fn nibbles(bits: &[u8], bit_count: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bit_count / 4);
+    let mut reg = 0u8;
+    for i in 0..bit_count * 2 {
+        let i = i % bit_count;
+        let bit = bits.get(i / 8).map_or(0, |byte| byte >> (7 - i % 8) & 1);
+        if reg == 0 && bit == 0 {
+            continue;
+        }
+        reg = reg << 1 | bit;
+        if reg & 0x80 != 0 {
+            out.push(reg);
+            reg = 0;
+        }
+    }
+
+    out
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn six_bits(nibbles: &[u8], count: usize) -> Option<Vec<u8>> {
+    nibbles
+        .get(..count)?
+        .iter()
+        .map(|n| Some(SIX_BITS[*n as usize]).filter(|v| *v != 0xff))
+        .collect()
+}
+
+// The three running checksums are mixed into the data as it's written, so both directions have
+// to keep them in step: c1 is rotated left before every group and the carries ripple from c1 to
+// c3 to c2 and back to c1.
+fn encode_data(raw: &[u8; RAW_SIZE]) -> Vec<u8> {
+    let mut groups = Vec::with_capacity(175);
+    let (mut c1, mut c2, mut c3) = (0u32, 0u32, 0u32);
+    let mut bytes = raw.iter().map(|b| *b as u32);
+    loop {
+        c1 = (c1 & 0xff) << 1;
+        if c1 & 0x100 != 0 {
+            c1 += 1;
+        }
+        let val = bytes.next().unwrap();
+        c3 += val;
+        if c1 & 0x100 != 0 {
+            c3 += 1;
+            c1 &= 0xff;
+        }
+        let b1 = (val ^ c1) as u8;
+
+        let val = bytes.next().unwrap();
+        c2 += val;
+        if c3 > 0xff {
+            c2 += 1;
+            c3 &= 0xff;
+        }
+        let b2 = (val ^ c3) as u8;
+
+        let Some(val) = bytes.next() else {
+            groups.push([b1, b2, 0]);
+            break;
+        };
+        c1 += val;
+        if c2 > 0xff {
+            c1 += 1;
+            c2 &= 0xff;
+        }
+        groups.push([b1, b2, (val ^ c2) as u8]);
+    }
+
+    let mut out = Vec::with_capacity(DATA_NIBBLES + 4);
+    let last = groups.len() - 1;
+    for (i, [b1, b2, b3]) in groups.into_iter().enumerate() {
+        out.push((b1 & 0xc0) >> 2 | (b2 & 0xc0) >> 4 | (b3 & 0xc0) >> 6);
+        out.push(b1 & 0x3f);
+        out.push(b2 & 0x3f);
+        if i != last {
+            out.push(b3 & 0x3f);
+        }
+    }
+    let (c1, c2, c3) = (c1 as u8, c2 as u8, c3 as u8);
+    let top = (c1 & 0xc0) >> 6 | (c2 & 0xc0) >> 4 | (c3 & 0xc0) >> 2;
+    out.extend([top, c3 & 0x3f, c2 & 0x3f, c1 & 0x3f]);
+
+    out
+}
+
+fn decode_data(values: &[u8]) -> Option<[u8; RAW_SIZE]> {
+    let mut raw = [0; RAW_SIZE];
+    let mut n = 0;
+    let (mut c1, mut c2, mut c3) = (0u32, 0u32, 0u32);
+    let mut groups = values[..DATA_NIBBLES].chunks(4);
+    loop {
+        let group = groups.next()?;
+        let top = group[0] as u32;
+        let b1 = group[1] as u32 | (top << 2 & 0xc0);
+        let b2 = group[2] as u32 | (top << 4 & 0xc0);
+
+        c1 = (c1 & 0xff) << 1;
+        if c1 & 0x100 != 0 {
+            c1 += 1;
+        }
+        let val = (b1 ^ c1) & 0xff;
+        c3 += val;
+        if c1 & 0x100 != 0 {
+            c3 += 1;
+            c1 &= 0xff;
+        }
+        raw[n] = val as u8;
+
+        let val = (b2 ^ c3) & 0xff;
+        c2 += val;
+        if c3 > 0xff {
+            c2 += 1;
+            c3 &= 0xff;
+        }
+        raw[n + 1] = val as u8;
+        n += 2;
+        if n == RAW_SIZE {
+            break;
+        }
+
+        let b3 = group[3] as u32 | (top << 6 & 0xc0);
+        let val = (b3 ^ c2) & 0xff;
+        c1 += val;
+        if c2 > 0xff {
+            c1 += 1;
+            c2 &= 0xff;
+        }
+        raw[n] = val as u8;
+        n += 1;
+    }
+
+    let sums = &values[DATA_NIBBLES..];
+    let top = sums[0];
+    let expected = (
+        sums[3] | (top << 6 & 0xc0),
+        sums[2] | (top << 4 & 0xc0),
+        sums[1] | (top << 2 & 0xc0),
+    );
+    (expected == (c1 as u8, c2 as u8, c3 as u8)).then_some(raw)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = (value >> i & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.len % 8);
+            self.len += 1;
+        }
+    }
+    fn nibble(&mut self, nibble: u8) {
+        self.bits(nibble as u32, 8);
+    }
+    fn nibbles(&mut self, nibbles: &[u8]) {
+        nibbles.iter().for_each(|n| self.nibble(*n));
+    }
+    // 0xff followed by two zero bits, which gets the shift register back in step no matter
+    // where it started reading
+    fn sync(&mut self, count: usize) {
+        for _ in 0..count {
+            self.bits(0xff << 2, 10);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DISK_BYTES, GcrError, Sector};
+    use super::{block_of, decode_track, encode_track, sectors_per_track};
+
+    fn sectors(track: u8) -> Vec<Sector> {
+        (0..sectors_per_track(track))
+            .map(|s| {
+                let mut tag = [0; 12];
+                tag[0] = track;
+                tag[11] = s;
+                let mut data = [0; 512];
+                data.iter_mut()
+                    .enumerate()
+                    .for_each(|(i, b)| *b = (i as u8).wrapping_mul(s + 1) ^ track);
+                Sector::new(tag, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for track in [0, 17, 40, 79] {
+            let (bits, len) = encode_track(track, 1, 0x22, &sectors(track));
+            assert_eq!(decode_track(&bits, len, track, 1).unwrap(), sectors(track));
+            assert!(decode_track(&bits, len, track, 0).is_err());
+        }
+    }
+
+    fn bit(bits: &[u8], i: usize) -> u8 {
+        bits[i / 8] >> (7 - i % 8) & 1
+    }
+
+    #[test]
+    fn wrapped_and_damaged() {
+        let (bits, len) = encode_track(3, 0, 0x22, &sectors(3));
+        // start the stream in the middle of a sector, as a capture usually does
+        let mut rotated = vec![0; bits.len()];
+        for i in 0..len {
+            rotated[i / 8] |= bit(&bits, (i + 1001) % len) << (7 - i % 8);
+        }
+        assert_eq!(decode_track(&rotated, len, 3, 0).unwrap(), sectors(3));
+
+        // swap a data nibble for another valid one so only the checksum notices
+        let mark = (0..len - 24)
+            .find(|i| (0..24).fold(0u32, |acc, n| acc << 1 | bit(&bits, i + n) as u32) == 0xd5aaad)
+            .unwrap();
+        let pos = mark + 24 + 8 * 20;
+        let nibble = (0..8).fold(0u8, |acc, n| acc << 1 | bit(&bits, pos + n));
+        let value = DISK_BYTES.iter().position(|b| *b == nibble).unwrap();
+        let replacement = DISK_BYTES[value ^ 1];
+        let mut damaged = bits.clone();
+        for n in 0..8 {
+            let i = pos + n;
+            damaged[i / 8] &= !(1 << (7 - i % 8));
+            damaged[i / 8] |= (replacement >> (7 - n) & 1) << (7 - i % 8);
+        }
+        assert!(matches!(
+            decode_track(&damaged, len, 3, 0),
+            Err(GcrError::DataChecksum { track: 3, side: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(block_of(0, 0, 0, 1), 0);
+        assert_eq!(block_of(16, 0, 0, 1), 192);
+        assert_eq!(block_of(79, 0, 7, 1), 799);
+        assert_eq!(block_of(0, 1, 0, 2), 12);
+        assert_eq!(block_of(79, 1, 7, 2), 1599);
+    }
+}
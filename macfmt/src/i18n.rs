@@ -420,8 +420,10 @@ pub trait MacScript: fmt::Debug + Copy + Clone + From<u8> + Into<u8> + TryFrom<c
     }
 }
 
+// Scripts whose first 128 characters are ASCII are declared with `ascii` and only list the rest;
+// the others, like Japanese with its yen sign at 0x5c, have to list all 256.
 macro_rules! script {
-    ($name: ident, $code: expr, $(($idx: literal => $unicode: literal)),*) => {
+    (@common $name: ident, $code: expr) => {
         #[derive(BinRead, BinWrite, Hash, Copy, Clone, Eq, PartialEq)]
         #[repr(transparent)]
         pub struct $name(u8);
@@ -436,10 +438,26 @@ macro_rules! script {
             const CODE: ScriptCode = $code;
         }
 
+        impl From<u8> for $name {
+            fn from(f: u8) -> $name {
+                Self(f)
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(f: $name) -> u8 {
+                f.0
+            }
+        }
+    };
+    ($name: ident, $code: expr, ascii, $(($idx: literal => $unicode: literal)),*) => {
+        script!(@common $name, $code);
+
         impl TryFrom<char> for $name {
             type Error = ScriptError;
             fn try_from(ch: char) -> Result<$name, ScriptError> {
                 match ch {
+                    '\0'..='\x7f' => Ok(Self(ch as u8)),
                     $($unicode => Ok(Self($idx)),)*
                     _ => Err(ScriptError::InvalidChar(ch, Self::CODE)),
                 }
@@ -454,22 +472,31 @@ macro_rules! script {
                 }
             }
         }
+    };
+    ($name: ident, $code: expr, $(($idx: literal => $unicode: literal)),*) => {
+        script!(@common $name, $code);
 
-        impl From<u8> for $name {
-            fn from(f: u8) -> $name {
-                Self(f)
+        impl TryFrom<char> for $name {
+            type Error = ScriptError;
+            fn try_from(ch: char) -> Result<$name, ScriptError> {
+                match ch {
+                    $($unicode => Ok(Self($idx)),)*
+                    _ => Err(ScriptError::InvalidChar(ch, Self::CODE)),
+                }
             }
         }
 
-        impl From<$name> for u8 {
-            fn from(f: $name) -> u8 {
-                f.0
+        impl From<$name> for char {
+            fn from(f: $name) -> char {
+                match f.0 {
+                    $($idx => $unicode,)*
+                }
             }
         }
-   }
+    };
 }
 
-script!(MacRoman, ScriptCode::Roman,
+script!(MacRoman, ScriptCode::Roman, ascii,
     (0x80 => 'Ä'),
     (0x81 => 'Å'),
     (0x82 => 'Ç'),
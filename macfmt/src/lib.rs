@@ -1,7 +1,12 @@
 pub mod apm;
 pub mod common;
+pub mod dc42;
+pub mod device;
+pub mod dmg;
+pub mod gcr;
 pub mod i18n;
 pub mod macbinary;
+pub mod moof;
 pub mod rsrc;
 pub mod single;
 pub mod fs;
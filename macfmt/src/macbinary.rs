@@ -35,6 +35,9 @@ pub struct MacBinary2 {
 }
 
 impl MacBinary2 {
+    pub fn data_fork(&self) -> &[u8] {
+        &self.data
+    }
     pub fn resource_fork(&self) -> &[u8] {
         &self.resource
     }
@@ -0,0 +1,323 @@
+use std::io;
+
+use binrw::io::{Cursor, Read, Seek, SeekFrom};
+use binrw::BinRead;
+use crc::Crc;
+use derivative::Derivative;
+use thiserror::Error;
+
+use crate::common::{PascalString, SizedString};
+use crate::dc42::{Dc42Error, DiskCopy42};
+use crate::gcr::{self, GcrError, SECTOR_SIZE, TAG_SIZE, TRACKS};
+
+const MAGIC: &[u8; 8] = b"MOOF\xff\n\r\n";
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Error, Debug)]
+pub enum MoofError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse a structure: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("Failed to decode a track: {0}")]
+    Gcr(#[from] GcrError),
+    #[error("Failed to build the sector image: {0}")]
+    Dc42(#[from] Dc42Error),
+    #[error("File checksum is {got:#010x}, expected {expected:#010x}")]
+    Checksum { expected: u32, got: u32 },
+    #[error("No {0} chunk found in the image")]
+    Missing(&'static str),
+    #[error("{0:?} disks can't be decoded")]
+    Unsupported(DiskType),
+    #[error("Track {track} side {side} wasn't captured")]
+    MissingTrack { track: u8, side: u8 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, BinRead)]
+#[br(repr = u8)]
+pub enum DiskType {
+    Gcr400K = 1,
+    Gcr800K = 2,
+    Mfm1440K = 3,
+    Twiggy = 4,
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+struct ChunkHeader {
+    id: [u8; 4],
+    size: u32,
+}
+
+#[derive(BinRead, Derivative)]
+#[derivative(Debug)]
+#[br(little)]
+struct Info {
+    _version: u8,
+    disk_type: DiskType,
+    write_protected: u8,
+    _synchronized: u8,
+    // in 125ns units, 16 for GCR disks
+    _optimal_bit_timing: u8,
+    creator: SizedString<32>,
+    #[derivative(Debug = "ignore")]
+    _rest: [u8; 23],
+}
+
+#[derive(BinRead, Debug)]
+#[br(little)]
+struct Track {
+    // in 512-byte blocks from the start of the file
+    start_block: u16,
+    block_count: u16,
+    bit_count: u32,
+}
+
+// An Applesauce capture of a Sony 400K or 800K floppy: for every track, the bits as they passed
+// the head. Decoding turns it back into what Disk Copy 4.2 would have made of the disk.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Moof {
+    info: Info,
+    // track * 2 + side to an index into tracks, 0xff for tracks that weren't captured
+    #[derivative(Debug = "ignore")]
+    tmap: [u8; 160],
+    #[derivative(Debug = "ignore")]
+    tracks: Vec<Track>,
+    meta: Vec<(String, String)>,
+    #[derivative(Debug = "ignore")]
+    file: Vec<u8>,
+}
+
+impl Moof {
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, MoofError> {
+        let mut file = Vec::new();
+        reader.read_to_end(&mut file)?;
+        if file.len() < 12 || &file[..8] != MAGIC {
+            return Err(MoofError::Missing("MOOF header"));
+        }
+        let expected = u32::from_le_bytes(file[8..12].try_into().unwrap());
+        let got = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&file[12..]);
+        // a zero checksum means the writer didn't bother
+        if expected != 0 && expected != got {
+            return Err(MoofError::Checksum { expected, got });
+        }
+
+        let (mut info, mut tmap, mut tracks, mut meta) = (None, None, None, Vec::new());
+        let mut cursor = Cursor::new(&file[..]);
+        cursor.set_position(12);
+        while cursor.position() < file.len() as u64 {
+            let header = ChunkHeader::read(&mut cursor)?;
+            let start = cursor.position();
+            match &header.id {
+                b"INFO" => info = Some(Info::read(&mut cursor)?),
+                b"TMAP" => tmap = Some(<[u8; 160]>::read(&mut cursor)?),
+                b"TRKS" => tracks = Some(<[Track; 160]>::read(&mut cursor)?),
+                // tab-separated key/value pairs, one per line
+                b"META" => {
+                    let mut text = vec![0; header.size as usize];
+                    cursor.read_exact(&mut text)?;
+                    meta = String::from_utf8_lossy(&text)
+                        .lines()
+                        .filter_map(|line| line.split_once('\t'))
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect();
+                },
+                // FLUX and whatever later versions add
+                _ => {},
+            }
+            cursor.seek(SeekFrom::Start(start + header.size as u64))?;
+        }
+
+        Ok(Self {
+            info: info.ok_or(MoofError::Missing("INFO"))?,
+            tmap: tmap.ok_or(MoofError::Missing("TMAP"))?,
+            tracks: tracks.ok_or(MoofError::Missing("TRKS"))?.into(),
+            meta,
+            file,
+        })
+    }
+    pub fn probe<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+        let start = reader.stream_position()?;
+        let mut magic = [0; 8];
+        let found = reader.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+        reader.seek(SeekFrom::Start(start))?;
+
+        Ok(found)
+    }
+    pub fn disk_type(&self) -> DiskType {
+        self.info.disk_type
+    }
+    pub fn is_write_protected(&self) -> bool {
+        self.info.write_protected != 0
+    }
+    pub fn creator(&self) -> String {
+        String::from_utf8_lossy(self.info.creator.as_inner()).trim_end().to_string()
+    }
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.meta.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+    pub fn sides(&self) -> u8 {
+        match self.info.disk_type {
+            DiskType::Gcr400K | DiskType::Twiggy => 1,
+            DiskType::Gcr800K | DiskType::Mfm1440K => 2,
+        }
+    }
+    // The bits of one track and how many of them there are; the last byte may be padded.
+    pub fn track_bits(&self, track: u8, side: u8) -> Option<(&[u8], usize)> {
+        let idx = *self.tmap.get(track as usize * 2 + side as usize)?;
+        let entry = self.tracks.get(idx as usize)?;
+        let start = entry.start_block as usize * BLOCK_SIZE;
+        let bits = self.file.get(start..)?.get(..entry.block_count as usize * BLOCK_SIZE)?;
+        (entry.bit_count as usize <= bits.len() * 8).then_some((bits, entry.bit_count as usize))
+    }
+    // Decodes every sector with its tag. Any sector that's missing or fails its checksum fails
+    // the whole disk, as there's nothing sensible to put in its place.
+    pub fn decode(&self) -> Result<DiskCopy42, MoofError> {
+        if !matches!(self.info.disk_type, DiskType::Gcr400K | DiskType::Gcr800K) {
+            return Err(MoofError::Unsupported(self.info.disk_type));
+        }
+        let sides = self.sides();
+        let sectors = gcr::block_of(TRACKS, 0, 0, sides);
+        let mut data = vec![0; sectors * SECTOR_SIZE];
+        let mut tags = vec![0; sectors * TAG_SIZE];
+        for track in 0..TRACKS {
+            for side in 0..sides {
+                let (bits, bit_count) = self
+                    .track_bits(track, side)
+                    .ok_or(MoofError::MissingTrack { track, side })?;
+                let decoded = gcr::decode_track(bits, bit_count, track, side)?;
+                for (sector, decoded) in decoded.iter().enumerate() {
+                    let block = gcr::block_of(track, side, sector as u8, sides);
+                    data[block * SECTOR_SIZE..][..SECTOR_SIZE].copy_from_slice(decoded.data());
+                    tags[block * TAG_SIZE..][..TAG_SIZE].copy_from_slice(decoded.tag());
+                }
+            }
+        }
+
+        let name = self.meta("title").filter(|t| PascalString::<63>::new(t).is_some());
+        Ok(DiskCopy42::new(name.unwrap_or(""), data, Some(tags))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::BinWrite;
+    use crc::Crc;
+
+    use super::{DiskType, MAGIC, Moof, MoofError};
+    use crate::fs::hfs::{FormatOptions, HfsVolume};
+    use crate::fs::mfs::{Fork, Mfs};
+    use crate::gcr::{self, Sector, TRACKS};
+
+    // Captures a disk the way Applesauce would, with a tag on every sector that says where it is.
+    fn capture(disk: &[u8], sides: u8) -> Vec<u8> {
+        let mut tracks = Vec::new();
+        for track in 0..TRACKS {
+            for side in 0..sides {
+                let sectors: Vec<_> = (0..gcr::sectors_per_track(track))
+                    .map(|sector| {
+                        let block = gcr::block_of(track, side, sector, sides);
+                        let mut tag = [0; 12];
+                        tag[..4].copy_from_slice(&(block as u32).to_be_bytes());
+                        Sector::new(tag, disk[block * 512..][..512].try_into().unwrap())
+                    })
+                    .collect();
+                let format = if sides == 2 { 0x22 } else { 0x02 };
+                tracks.push(gcr::encode_track(track, side, format, &sectors));
+            }
+        }
+
+        let mut info = vec![1, if sides == 2 { 2 } else { 1 }, 0, 1, 16];
+        info.extend_from_slice(&format!("{:32}", "macfmt").into_bytes());
+        info.resize(60, 0);
+        let mut tmap = [0xff; 160];
+        for (i, entry) in tmap.iter_mut().enumerate().filter(|(i, _)| (*i % 2) < sides as usize) {
+            *entry = (i / 2 * sides as usize + i % 2) as u8;
+        }
+        let meta = b"title\tTest Disk\nlanguage\tEnglish\n";
+
+        let mut body = Vec::new();
+        for (id, data) in [(b"INFO", &info[..]), (b"TMAP", &tmap[..]), (b"META", &meta[..])] {
+            body.extend_from_slice(id);
+            body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            body.extend_from_slice(data);
+        }
+        // the bits go in whole blocks after the track list, counted from the start of the file
+        let trks_start = 12 + body.len() + 8;
+        let mut next_block = (trks_start + 160 * 8).div_ceil(512);
+        let mut entries = Vec::new();
+        let mut bits = Vec::new();
+        for (data, len) in tracks.iter() {
+            let blocks = data.len().div_ceil(512);
+            entries.extend_from_slice(&(next_block as u16).to_le_bytes());
+            entries.extend_from_slice(&(blocks as u16).to_le_bytes());
+            entries.extend_from_slice(&(*len as u32).to_le_bytes());
+            let mut data = data.clone();
+            data.resize(blocks * 512, 0);
+            bits.extend_from_slice(&data);
+            next_block += blocks;
+        }
+        entries.resize(160 * 8, 0);
+        entries.resize((trks_start + 160 * 8).next_multiple_of(512) - trks_start, 0);
+        entries.extend_from_slice(&bits);
+        body.extend_from_slice(b"TRKS");
+        body.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        body.extend_from_slice(&entries);
+
+        let mut file = MAGIC.to_vec();
+        let crc = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&body);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file.extend_from_slice(&body);
+        file
+    }
+
+    #[test]
+    fn mfs_400k() {
+        let mut mfs = Mfs::format("Floppy", 400 * 1024, None).unwrap();
+        let file = mfs.add_file("Note", *b"TEXT", *b"ttxt");
+        mfs.overwrite_contents(file, Fork::Data, b"hello from the bits").unwrap();
+        let mut disk = Cursor::new(vec![0; 400 * 1024]);
+        mfs.write_be(&mut disk).unwrap();
+
+        let mut file = Cursor::new(capture(disk.get_ref(), 1));
+        assert!(Moof::probe(&mut file).unwrap());
+        let moof = Moof::read(&mut file).unwrap();
+        assert_eq!(moof.disk_type(), DiskType::Gcr400K);
+        assert_eq!(moof.creator(), "macfmt");
+        assert_eq!(moof.meta("language"), Some("English"));
+        assert!(moof.track_bits(0, 1).is_none());
+
+        let img = moof.decode().unwrap();
+        assert_eq!(img.name(), "Test Disk");
+        assert!(img.data() == disk.get_ref());
+        assert_eq!(img.sector_tag(799).unwrap().file_num(), 799);
+        let mfs = Mfs::new(&mut img.data_reader()).unwrap();
+        assert_eq!(mfs.file_data(mfs.file_by_name("Note").unwrap()), b"hello from the bits");
+    }
+
+    #[test]
+    fn hfs_800k() {
+        let mut disk = Cursor::new(vec![0; 800 * 1024]);
+        HfsVolume::format(&mut disk, 800 * 1024, "Double", FormatOptions::default()).unwrap();
+
+        let mut file = capture(disk.get_ref(), 2);
+        let moof = Moof::read(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(moof.disk_type(), DiskType::Gcr800K);
+        let img = moof.decode().unwrap();
+        assert!(img.data() == disk.get_ref());
+        assert_eq!(img.sector_tag(1599).unwrap().file_num(), 1599);
+        let mut data = img.data_reader();
+        assert_eq!(HfsVolume::new(&mut data).unwrap().root_dir().name(), "Double");
+
+        let last = file.len() - 1;
+        file[last] ^= 0xff;
+        assert!(matches!(
+            Moof::read(&mut Cursor::new(&file)),
+            Err(MoofError::Checksum { .. })
+        ));
+        assert!(!Moof::probe(&mut Cursor::new(&file[1..])).unwrap());
+    }
+}
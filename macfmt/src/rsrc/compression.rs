@@ -1,14 +1,13 @@
 use binrw::{BinRead, BinResult};
 use binrw::io::{Read, Seek};
+use bitflags::bitflags;
 use derivative::Derivative;
 
 #[derive(Clone, Derivative, BinRead)]
 #[derivative(Debug)]
-#[brw(big)]
+#[brw(big, magic = 0xa89f6572_u32)]
 pub struct Header {
-    sig: u32,
     hdrlen: u16,
-    #[br(dbg)]
     version: u8,
     attrs: u8,
     biglen: u32,
@@ -28,17 +27,54 @@ pub enum Variant {
         alg_id: u16,
         tab_id: u16,
     },
+    #[br(pre_assert(version == 0x09))]
+    GreggyBits {
+        alg_id: u16,
+        #[derivative(Debug = "ignore")]
+        _unknown: u16,
+        table_count_minus_one: u8,
+        #[br(map = |b: u8| GreggyFlags::from_bits_retain(b))]
+        flags: GreggyFlags,
+    },
 }
 
+bitflags! {
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub struct GreggyFlags: u8 {
+        // every 8 codes are preceded by a byte telling which are table references
+        const TAGGED = 0x1;
+        // the data starts with the table to use instead of the built-in one
+        const CUSTOM_TABLE = 0x2;
+    }
+}
+
+fn error<R: Seek>(r: &mut R, message: String) -> binrw::Error {
+    binrw::Error::AssertFail {
+        pos: r.stream_position().unwrap_or(0),
+        message,
+    }
+}
 
 impl Header {
     pub fn decompress<R: Read + Seek>(&self, data: &mut R) -> BinResult<Vec<u8>> {
-        match self.variant {
-            Variant::DonnBits { .. } => undonnbits(data, self.biglen as usize),
+        let len = self.biglen as usize;
+        let ret = match self.variant {
+            Variant::DonnBits { alg_id: 0, .. } => undonnbits(data, len)?,
+            Variant::GreggyBits { alg_id: 2, table_count_minus_one, flags, .. } => {
+                ungreggybits(data, len, table_count_minus_one, flags)?
+            },
+            Variant::DonnBits { alg_id, .. } | Variant::GreggyBits { alg_id, .. } => {
+                return Err(error(data, format!("Unsupported decompressor 'dcmp' {}", alg_id)));
+            },
+        };
+        if ret.len() != len {
+            return Err(error(data, format!("Decompressed to {} bytes instead of {}", ret.len(), len)));
         }
+        Ok(ret)
     }
 }
 
+// The words the one-byte codes 0x4b to 0xfd stand for.
 const DONN_LUT: [u16; 180] = [
     0x0000, 0x4EBA, 0x0008, 0x4E75, 0x000C, 0x4EAD, 0x2053, 0x2F0B,
     0x6100, 0x0010, 0x7000, 0x2F00, 0x486E, 0x2050, 0x206E, 0x2F2E,
@@ -65,106 +101,304 @@ const DONN_LUT: [u16; 180] = [
     0x4400, 0x41E8, 0x4841, 0x0000,
 ];
 
-fn varint<R: Read + Seek>(r: &mut R) -> BinResult<usize> {
-    let mut val = [0];
-    r.read_exact(&mut val)?;
-    let val = val[0];
-    match val {
-        0..128 => Ok(val as usize),
-        255 => {
-            let mut int = [0; 4];
-            r.read_exact(&mut int)?;
-            Ok(u32::from_be_bytes(int) as usize)
-        },
-        _ => {
-            let mut add = [0];
-            r.read_exact(&mut add)?;
-            println!("0x{:02x} 0x{:02x}", val, add[0]);
-            let mut ret = 0;
-            ret |= ((val as usize) << 12) & 0xf00;
-            ret |= add[0] as usize;
-            println!("{:x}", ret);
-            Ok(ret)
-        },
+// Extended codes store their numbers in one byte if they fit in 7 bits, and otherwise in two
+// bytes offset by 0xc000 or a 0xff marker followed by four bytes, all signed.
+fn varint<R: Read + Seek>(r: &mut R) -> BinResult<i32> {
+    let head = u8::read(r)?;
+    Ok(match head {
+        0x00..0x80 => head as i32,
+        0xff => i32::read_be(r)?,
+        _ => i16::from_be_bytes([head.wrapping_sub(0xc0), u8::read(r)?]) as i32,
+    })
+}
+
+fn count<R: Read + Seek>(r: &mut R) -> BinResult<usize> {
+    let count = varint(r)?;
+    usize::try_from(count).map_err(|_| error(r, format!("Negative count {}", count)))
+}
+
+// The decompressed data, which is never allowed to grow past the length in the header, since a few
+// bytes of counts can otherwise ask for gigabytes.
+struct Output {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl Output {
+    fn new(len: usize) -> Self {
+        // the length comes from the header, so don't trust it with the allocation
+        Self { data: Vec::with_capacity(len.min(1 << 20)), len }
+    }
+
+    fn room<R: Seek>(&self, r: &mut R, more: usize) -> BinResult<()> {
+        match self.data.len().checked_add(more) {
+            Some(total) if total <= self.len => Ok(()),
+            _ => Err(error(r, format!("Decompressed data is longer than {} bytes", self.len))),
+        }
+    }
+
+    fn extend<R: Seek>(&mut self, r: &mut R, bytes: &[u8]) -> BinResult<()> {
+        self.room(r, bytes.len())?;
+        self.data.extend(bytes);
+        Ok(())
     }
 }
 
 fn undonnbits<R: Read + Seek>(r: &mut R, len: usize) -> BinResult<Vec<u8>> {
-    let mut ret = Vec::with_capacity(len as usize);
+    let mut ret = Output::new(len);
     let mut var_tab: Vec<Vec<u8>> = Vec::new();
 
     loop {
-        let mut op: [u8; 1] = [0];
-        println!("@{:x}", r.stream_position()?);
-        r.read_exact(&mut op)?;
-        let op: u8 = op[0];
-        println!("op {:x}", op);
+        let op = u8::read(r)?;
         match op {
+            // literal words, 0x10 and up also remember them for later
             0x00..0x20 => {
-                let save = op >= 0x10;
-                let len = if op == 0x00 || op == 0x10 {
-                    let mut len_byte = [0];
-                    r.read_exact(&mut len_byte)?;
-                    len_byte[0] as usize * 2
-                } else {
-                    (op & 0x0f) as usize * 2
-                };
+                let len = match op & 0x0f {
+                    0 => count(r)?,
+                    words => words as usize,
+                } * 2;
+                ret.room(r, len)?;
                 let mut tmp = vec![0; len];
                 r.read_exact(&mut tmp)?;
-                ret.extend(&tmp);
-                if save {
+                ret.extend(r, &tmp)?;
+                if op >= 0x10 {
                     var_tab.push(tmp);
                 }
             },
             0x20 | 0x21 => {
-                let mut tmp = [0];
-                r.read_exact(&mut tmp)?;
-                let idx = 0x28 + ((op as usize & 0xf) << 8) | (tmp[0] as usize);
-                ret.extend(var_tab.get(idx).unwrap());
+                let idx = 0x28 + (((op as usize & 1) << 8) | u8::read(r)? as usize);
+                let entry = var_entry(r, &var_tab, idx)?;
+                ret.extend(r, entry)?;
             },
             0x22 => {
-                let mut bytes = [0; 2];
-                r.read_exact(&mut bytes)?;
-                let idx = u16::from_be_bytes(bytes) + 0x28;
-                ret.extend(var_tab.get(idx as usize).unwrap());
+                let idx = 0x28 + u16::read_be(r)? as usize;
+                let entry = var_entry(r, &var_tab, idx)?;
+                ret.extend(r, entry)?;
+            },
+            0x23..0x4b => {
+                let entry = var_entry(r, &var_tab, (op - 0x23) as usize)?;
+                ret.extend(r, entry)?;
             },
-            0x23..0x4b => ret.extend(var_tab.get((op - 0x23) as usize).unwrap()),
-            0x4b..0xfe => ret.extend(DONN_LUT[op as usize - 0x4b].to_be_bytes()),
+            0x4b..0xfe => ret.extend(r, &DONN_LUT[op as usize - 0x4b].to_be_bytes())?,
             0xfe => {
-                let mut tmp = [0];
-                r.read_exact(&mut tmp)?;
-                let extop = tmp[0];
+                let extop = u8::read(r)?;
                 match extop {
+                    // segment loader jump table entries, all for the same segment: the first
+                    // entry's offset comes before this, the next one is given, and the ones after
+                    // that are deltas from the entry before, less the 6 bytes of the entry itself
                     0x00 => {
                         let segment = (varint(r)? as u16).to_be_bytes();
-                        let count = varint(r)?;
-                        let mut offset: u16 = 0;
-                        for _ in 0..count {
-                            let diff = varint(r)? as u16;
-                            offset = offset.wrapping_add(diff).wrapping_sub(6);
-                            ret.extend(offset.to_be_bytes());
-                            ret.extend([0x3f, 0x3c]);
-                            ret.extend(&segment);
-                            ret.extend([0xa9, 0xf0]);
+                        let tail = [0x3f, 0x3c, segment[0], segment[1], 0xa9, 0xf0];
+                        ret.extend(r, &tail)?;
+                        let count = count(r)?;
+                        if count == 0 {
+                            return Err(error(r, "Jump table with no entries".to_string()));
+                        }
+                        let mut offset = varint(r)?;
+                        for i in 0..count {
+                            if i > 0 {
+                                offset = offset.wrapping_add(varint(r)?).wrapping_sub(6);
+                            }
+                            ret.extend(r, &(offset as u16).to_be_bytes())?;
+                            ret.extend(r, &tail)?;
                         }
                     },
+                    // a run of one byte or one word
                     0x02 | 0x03 => {
-                        let val = match extop {
-                            0x02 => (varint(r)? as u8).to_be_bytes().to_vec(),
-                            0x03 => (varint(r)? as u16).to_be_bytes().to_vec(),
-                            _ => unreachable!(),
-                        };
-                        let count = varint(r)? + 1;
-                        for _ in 0..count {
-                            ret.extend(&val);
+                        let val = varint(r)?.to_be_bytes();
+                        let val = if extop == 0x02 { &val[3..] } else { &val[2..] };
+                        for _ in 0..=count(r)? {
+                            ret.extend(r, val)?;
+                        }
+                    },
+                    // words given as a first value and signed byte deltas from the one before
+                    0x04 => {
+                        let mut val = varint(r)? as u16;
+                        ret.extend(r, &val.to_be_bytes())?;
+                        for _ in 0..count(r)? {
+                            val = val.wrapping_add(i8::read(r)? as u16);
+                            ret.extend(r, &val.to_be_bytes())?;
+                        }
+                    },
+                    // longs given as a first value and deltas from the one before
+                    0x06 => {
+                        let mut val = varint(r)?;
+                        ret.extend(r, &val.to_be_bytes())?;
+                        for _ in 0..count(r)? {
+                            val = val.wrapping_add(varint(r)?);
+                            ret.extend(r, &val.to_be_bytes())?;
                         }
                     },
-                    _ => todo!("donnbits ext op 0x{:02x}", extop),
+                    // entry vectors, which nothing to check a decoding against has turned up for yet,
+                    // so these fail rather than decompress to something that might be wrong
+                    0x01 | 0x05 => {
+                        return Err(error(r, format!("DonnBits entry vectors (extended opcode 0x{:02x}) aren't supported", extop)));
+                    },
+                    _ => return Err(error(r, format!("Unsupported DonnBits extended opcode 0x{:02x}", extop))),
                 }
             },
             0xff => break,
         }
     }
 
-    Ok(ret)
+    Ok(ret.data)
+}
+
+fn var_entry<'a, R: Seek>(r: &mut R, var_tab: &'a [Vec<u8>], idx: usize) -> BinResult<&'a [u8]> {
+    var_tab.get(idx).map(Vec::as_slice).ok_or_else(|| error(r, format!("Reference to variable {} of {}", idx, var_tab.len())))
+}
+
+// Every code is a byte indexing a table of words, or with tagging, either that or a literal word.
+// An odd last byte of the output is stored as is.
+fn ungreggybits<R: Read + Seek>(r: &mut R, len: usize, table_count_minus_one: u8, flags: GreggyFlags) -> BinResult<Vec<u8>> {
+    if !flags.contains(GreggyFlags::CUSTOM_TABLE) {
+        // the standard table isn't here yet, and a wrong word in it would go unnoticed
+        return Err(error(r, "GreggyBits with the built-in table isn't supported".to_string()));
+    }
+    let mut table = Vec::new();
+    for _ in 0..=table_count_minus_one {
+        table.push(u16::read_be(r)?.to_be_bytes());
+    }
+    let mut input = Vec::new();
+    r.read_to_end(&mut input)?;
+    let (input, last) = match len % 2 {
+        1 => match input.split_last() {
+            Some((last, input)) => (input, Some(*last)),
+            None => return Err(error(r, "GreggyBits data ends early".to_string())),
+        },
+        _ => (&input[..], None),
+    };
+
+    let mut ret = Output::new(len);
+    let lookup = |idx: u8| match table.get(idx as usize) {
+        Some(word) => Ok(*word),
+        None => Err(format!("Reference to table entry {} of {}", idx, table.len())),
+    };
+    let mut codes = input.iter().copied();
+    if flags.contains(GreggyFlags::TAGGED) {
+        while let Some(tag) = codes.next() {
+            for bit in (0..8).rev() {
+                let word = if tag & (1 << bit) != 0 {
+                    let Some(idx) = codes.next() else { break };
+                    lookup(idx).map_err(|message| error(r, message))?
+                } else {
+                    let (Some(hi), Some(lo)) = (codes.next(), codes.next()) else { break };
+                    [hi, lo]
+                };
+                ret.extend(r, &word)?;
+            }
+        }
+    } else {
+        for idx in codes {
+            let word = lookup(idx).map_err(|message| error(r, message))?;
+            ret.extend(r, &word)?;
+        }
+    }
+    if let Some(last) = last {
+        ret.extend(r, &[last])?;
+    }
+
+    Ok(ret.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Header;
+    use binrw::BinRead;
+    use std::io::Cursor;
+
+    fn decompress(version: u8, biglen: u32, params: [u8; 6], data: &[u8]) -> binrw::BinResult<Vec<u8>> {
+        let mut bytes = vec![0xa8, 0x9f, 0x65, 0x72, 0x00, 0x12, version, 0x01];
+        bytes.extend(biglen.to_be_bytes());
+        bytes.extend(params);
+        bytes.extend(data);
+        let mut cursor = Cursor::new(bytes);
+        Header::read(&mut cursor)?.decompress(&mut cursor)
+    }
+
+    #[test]
+    fn donnbits() {
+        let data = [
+            0x02, b'A', b'B', b'C', b'D', // literal
+            0x11, b'E', b'F', // saved literal
+            0x10, 0x02, b'G', b'H', b'I', b'J', // saved literal with a separate length
+            0x23, 0x24, // saved literals
+            0x4b, 0x4c, // table words
+            0xfe, 0x02, 0x41, 0x02, // byte run
+            0xfe, 0x03, 0xd2, 0x34, 0x01, // word run
+            0xfe, 0x04, 0x10, 0x03, 0x01, 0xff, 0x05, // word deltas
+            0xfe, 0x06, 0xff, 0x00, 0x01, 0x00, 0x00, 0x02, 0x7f, 0xbf, 0xff, // long deltas
+            0xfe, 0x00, 0x05, 0x02, 0x40, 0x20, // jump table
+            0xff,
+        ];
+        let expected = [
+            &b"ABCDEFGHIJEFGHIJ"[..],
+            &[0x00, 0x00, 0x4e, 0xba],
+            b"AAA",
+            &[0x12, 0x34, 0x12, 0x34],
+            &[0x00, 0x10, 0x00, 0x11, 0x00, 0x10, 0x00, 0x15],
+            &[0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x7f, 0x00, 0x01, 0x00, 0x7e],
+            &[0x3f, 0x3c, 0x00, 0x05, 0xa9, 0xf0],
+            &[0x00, 0x40, 0x3f, 0x3c, 0x00, 0x05, 0xa9, 0xf0],
+            &[0x00, 0x5a, 0x3f, 0x3c, 0x00, 0x05, 0xa9, 0xf0],
+        ].concat();
+        let out = decompress(0x08, expected.len() as u32, [0; 6], &data).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn donnbits_far_references() {
+        let mut data = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..0x129u16 {
+            data.push(0x11);
+            data.extend(i.to_be_bytes());
+            expected.extend(i.to_be_bytes());
+        }
+        data.extend([0x20, 0x00, 0x21, 0x00, 0x22, 0x00, 0x01, 0xff]);
+        expected.extend([0x00, 0x28, 0x01, 0x28, 0x00, 0x29]);
+        let out = decompress(0x08, expected.len() as u32, [0; 6], &data).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn donnbits_errors() {
+        // a reference to a literal that was never saved
+        assert!(decompress(0x08, 2, [0; 6], &[0x23, 0xff]).is_err());
+        // wrong length
+        assert!(decompress(0x08, 4, [0; 6], &[0x4b, 0xff]).is_err());
+        // runs and deltas that ask for far more than the header says
+        assert!(decompress(0x08, 16, [0; 6], &[0xfe, 0x02, 0x41, 0xff, 0x7f, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(decompress(0x08, 16, [0; 6], &[0xfe, 0x06, 0x00, 0xff, 0x7f, 0xff, 0xff, 0xff]).is_err());
+        assert!(decompress(0x08, 16, [0; 6], &[0x00, 0xff, 0x7f, 0xff, 0xff, 0xff]).is_err());
+        // negative counts
+        assert!(decompress(0x08, 2, [0; 6], &[0xfe, 0x04, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(decompress(0x08, 2, [0; 6], &[0xfe, 0x03, 0x00, 0xbf, 0xff, 0xff]).is_err());
+        // entry vectors
+        assert!(decompress(0x08, 2, [0; 6], &[0xfe, 0x01, 0x00, 0xff]).is_err());
+        assert!(decompress(0x08, 2, [0; 6], &[0xfe, 0x05, 0x00, 0xff]).is_err());
+        // not the DonnBits decompressor
+        assert!(decompress(0x08, 2, [0, 0, 0, 1, 0, 0], &[0x4b, 0xff]).is_err());
+    }
+
+    #[test]
+    fn greggybits() {
+        let table = [0x11, 0x11, 0x22, 0x22, 0x33, 0x33];
+        let untagged = [0x00, 0x02, 0x01];
+        let out = decompress(0x09, 6, [0, 2, 0, 0, 2, 0x02], &[&table[..], &untagged].concat()).unwrap();
+        assert_eq!(out, [0x11, 0x11, 0x33, 0x33, 0x22, 0x22]);
+
+        // odd lengths end with a plain byte
+        let out = decompress(0x09, 7, [0, 2, 0, 0, 2, 0x02], &[&table[..], &untagged, &[0x99]].concat()).unwrap();
+        assert_eq!(out, [0x11, 0x11, 0x33, 0x33, 0x22, 0x22, 0x99]);
+
+        let tagged = [0b1010_0000, 0x01, 0xab, 0xcd, 0x02];
+        let out = decompress(0x09, 6, [0, 2, 0, 0, 2, 0x03], &[&table[..], &tagged].concat()).unwrap();
+        assert_eq!(out, [0x22, 0x22, 0xab, 0xcd, 0x33, 0x33]);
+
+        // outside the table
+        assert!(decompress(0x09, 2, [0, 2, 0, 0, 2, 0x02], &[&table[..], &[0x03]].concat()).is_err());
+        // the built-in table
+        assert!(decompress(0x09, 2, [0, 2, 0, 0, 0xff, 0x00], &[0x00]).is_err());
+    }
 }
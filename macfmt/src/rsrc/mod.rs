@@ -1,14 +1,31 @@
 use crate::common::{DynamicPascalString, SizedString};
 use binrw::{
     BinRead, BinResult, BinWrite,
-    io::{Read, Seek, Cursor},
+    io::{Read, Seek, SeekFrom, Write, Cursor},
 };
 use bitflags::bitflags;
 use derivative::Derivative;
+use thiserror::Error;
 
 pub mod types;
 pub mod compression;
 
+#[derive(Error, Debug)]
+pub enum RsrcError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse the resource map: {0}")]
+    BinRw(#[from] binrw::Error),
+    #[error("The reference list of the {} resources is outside the map", .0.inner())]
+    BadReferenceList(ResourceType),
+    #[error("{} resource {id} at {offset:#x} runs past the end of the data", ty.inner())]
+    OutOfBounds { ty: ResourceType, id: i16, offset: u64 },
+    #[error("Failed to decompress {} resource {id} at {offset:#x}: {source}", ty.inner())]
+    Decompress { ty: ResourceType, id: i16, offset: u64, source: binrw::Error },
+    #[error("Failed to decode {} resource {id} at {offset:#x}: {source}", ty.inner())]
+    Decode { ty: ResourceType, id: i16, offset: u64, source: binrw::Error },
+}
+
 #[derive(Clone, Debug)]
 pub struct Resource {
     id: i16,
@@ -21,6 +38,20 @@ pub struct Resource {
     pub compressed: bool,
     data: types::Type,
     name: Option<DynamicPascalString>,
+    stored: Stored,
+}
+
+// How the resource was kept in the fork it was read from. Untouched resources are written back
+// as they were, which is also the only way to keep one compressed, as there is no compressor:
+// writing fails if compressed is set on a resource that was edited or wasn't compressed, and
+// clearing it writes the decompressed data.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+struct Stored {
+    attrs: Attributes,
+    #[derivative(Debug = "ignore")]
+    bytes: Vec<u8>,
+    data: types::Type,
 }
 
 impl Resource {
@@ -37,110 +68,385 @@ impl Resource {
         &self.ty
     }
     pub fn name(&self) -> Option<&str> {
-        self.name.as_ref().map(|v| v.try_as_str().unwrap())
+        self.name.as_ref().map(|v| v.as_str())
     }
-    pub fn read<R: Read + Seek>(reader: &mut R) -> BinResult<Vec<(ResourceType, Vec<Resource>)>> {
-        let raw = RawResource::read(reader)?;
-        println!("here");
-        let mut ret = Vec::new();
-        let mut decomped = false;
-        for t in raw.types.iter() {
-            let mut refs = Vec::new();
-            for r in raw.refs_of(t) {
-                let data = if r.attrs.contains(Attributes::COMPRESSED) {
-                    println!("{} compressed, skipping...", t.ty.inner());
-                    if decomped {
-                      continue;
-                    }
-                    decomped = true;
-                    let mut cursor = Cursor::new(raw.data_of(r));
-                    let header = compression::Header::read(&mut cursor)?;
-                    println!("{:#x?}", header);
-                    let data = header.decompress(&mut cursor)?;
-                    let mut counter = 0;
-                    println!("Decompressed {} ID {}", t.ty.inner(), r.res_id);
-                    for (i, byte) in data.iter().enumerate() {
-                        match counter {
-                            0 => {
-                                print!("{:06x}    ", i);
-                                print!("{:02x}", byte);
-                            },
-                            2 | 4 | 6 => print!("{:02x}", byte),
-                            1 | 3 | 5 => print!("{:02x} ", byte),
-                            7 => println!("{:02x}", byte),
-                            _ => unreachable!(),
-                        }
-                        counter += 1;
-                        if counter == 8 {
-                            counter = 0;
-                        }
-                    }
-                    data
-                } else {
-                    raw.data_of(r).to_owned()
-                };
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Vec<(ResourceType, Vec<Resource>)>, RsrcError> {
+        Ok(DecodedFork::read(reader)?.resources)
+    }
+    // The bytes to put in the fork and the attributes that go with them.
+    fn to_stored(&self) -> BinResult<(Vec<u8>, Attributes)> {
+        let mut attrs = self.stored.attrs & (Attributes::SYSTEM_REFERENCE | Attributes::WRITE_TO_RESOURCE_FILE);
+        attrs.set(Attributes::SYSTEM_HEAP, self.system_heap);
+        attrs.set(Attributes::PURGEABLE, self.purgeable);
+        attrs.set(Attributes::LOCKED, self.locked);
+        attrs.set(Attributes::PROTECTED, self.protected);
+        attrs.set(Attributes::PRELOAD, self.preload);
+        let unchanged = self.data == self.stored.data;
+        let stored_compressed = self.stored.attrs.contains(Attributes::COMPRESSED);
+        if self.compressed && !(unchanged && stored_compressed) {
+            return Err(binrw::Error::AssertFail {
+                pos: 0,
+                message: format!(
+                    "{} resource {} can't be compressed, only left as it was read",
+                    self.ty.inner(),
+                    self.id
+                ),
+            });
+        }
+        if unchanged && self.compressed == stored_compressed {
+            attrs.set(Attributes::COMPRESSED, self.compressed);
+            return Ok((self.stored.bytes.clone(), attrs));
+        }
+        Ok((self.data.encode()?, attrs))
+    }
+}
 
-                println!("decoding {:x?} {:x?}", t.ty, r.res_id);
-                let data = types::Type::new(&t.ty, r.res_id, data)?;
-                refs.push(Resource {
-                    id: r.res_id,
-                    ty: t.ty.clone(),
-                    system_heap: r.attrs.contains(Attributes::SYSTEM_HEAP),
-                    purgeable: r.attrs.contains(Attributes::PURGEABLE),
-                    locked: r.attrs.contains(Attributes::LOCKED),
-                    protected: r.attrs.contains(Attributes::PROTECTED),
-                    preload: r.attrs.contains(Attributes::PRELOAD),
-                    compressed: r.attrs.contains(Attributes::COMPRESSED),
-                    data,
-                    name: raw.name_of(r).cloned(),
+// A whole decoded resource fork, along with the attributes of the fork itself.
+#[derive(Clone, Debug, Default)]
+pub struct DecodedFork {
+    attrs: u16,
+    resources: Vec<(ResourceType, Vec<Resource>)>,
+}
+
+impl DecodedFork {
+    pub fn attrs(&self) -> u16 {
+        self.attrs
+    }
+    pub fn set_attrs(&mut self, attrs: u16) {
+        self.attrs = attrs;
+    }
+    pub fn resources(&self) -> &[(ResourceType, Vec<Resource>)] {
+        &self.resources
+    }
+    pub fn resources_mut(&mut self) -> &mut Vec<(ResourceType, Vec<Resource>)> {
+        &mut self.resources
+    }
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, RsrcError> {
+        Self::read_with(reader, false)
+    }
+    // Like read, but resources that can't be decompressed or decoded are kept as raw bytes
+    // instead of failing the whole fork. A damaged map still fails.
+    pub fn read_lenient<R: Read + Seek>(reader: &mut R) -> Result<Self, RsrcError> {
+        Self::read_with(reader, true)
+    }
+    fn read_with<R: Read + Seek>(reader: &mut R, lenient: bool) -> Result<Self, RsrcError> {
+        ResourceFork::new(reader)?.decode_all(lenient)
+    }
+    // Lays the fork out the way the Resource Manager does: the data of every resource in type
+    // order, followed by the map with its type list, reference lists and names.
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> BinResult<()> {
+        let too_big = |what: &str| binrw::Error::AssertFail {
+            pos: 0,
+            message: format!("{} doesn't fit in a resource fork", what),
+        };
+        let types: Vec<_> = self.resources.iter().filter(|(_, res)| !res.is_empty()).collect();
+        let ref_count = types.iter().map(|(_, res)| res.len()).sum::<usize>();
+        let type_count_minus_one = (types.len() as u16).wrapping_sub(1);
+        let type_list_len = 2 + types.len() * 8;
+
+        let mut data = Vec::new();
+        let mut type_list = Vec::new();
+        let mut refs = Vec::new();
+        let mut names = Vec::new();
+        let mut names_len = 0;
+        for (ty, res) in types.iter() {
+            let ref_list_offset = type_list_len + refs.len() * 12;
+            type_list.push(Type {
+                ty: ty.clone(),
+                ref_count_minus_one: u16::try_from(res.len() - 1).map_err(|_| too_big("resource count"))?,
+                ref_list_offset: u16::try_from(ref_list_offset).map_err(|_| too_big("type list"))?,
+            });
+            for r in res.iter() {
+                let (bytes, attrs) = r.to_stored()?;
+                let name_offset = match &r.name {
+                    Some(name) => {
+                        let offset = u16::try_from(names_len).map_err(|_| too_big("name list"))?;
+                        names_len += 1 + name.len();
+                        names.push(DynamicPascalString::new(name.as_str()));
+                        Some(offset)
+                    },
+                    None => None,
+                };
+                let Some(data_offset) = u32::try_from(data.len()).ok().filter(|off| *off < 1 << 24) else {
+                    return Err(too_big("resource data"));
+                };
+                refs.push(Reference {
+                    res_id: r.id,
+                    name_offset,
+                    attrs,
+                    data_offset,
+                    _reserved_handle: 0,
                 });
+                let len = u32::try_from(bytes.len()).map_err(|_| too_big("resource"))?;
+                data.extend_from_slice(&len.to_be_bytes());
+                data.extend_from_slice(&bytes);
             }
+        }
 
-            ret.push((t.ty.clone(), refs));
+        let name_list_offset = 28 + type_list_len + ref_count * 12;
+        let data_offset = 256;
+        let data_len = u32::try_from(data.len()).map_err(|_| too_big("resource data"))?;
+        let map_len = u32::try_from(name_list_offset + names_len).map_err(|_| too_big("map"))?;
+        let map_offset = data_offset + data_len;
+        let hdr = [data_offset, map_offset, data_len, map_len];
+        let mut hdr_copy = [0; 16];
+        for (chunk, word) in hdr_copy.chunks_mut(4).zip(hdr) {
+            chunk.copy_from_slice(&word.to_be_bytes());
         }
+        RawResource {
+            header: ForkHeader {
+                data_offset,
+                map_offset,
+                data_len,
+                map_len,
+            },
+            system_data: vec![0; data_offset as usize - 16],
+            data,
+            map: Map {
+                header: MapHeader {
+                    _reserved_hdr_copy: hdr_copy,
+                    _reserved_handle_next_map: 0,
+                    _reserved_file_reference: 0,
+                    fork_attrs: self.attrs,
+                    type_list_offset: 28,
+                    name_list_offset: u16::try_from(name_list_offset).map_err(|_| too_big("map"))?,
+                    type_count_minus_one,
+                },
+                types: type_list,
+                refs,
+                names,
+            },
+        }.write(writer)
+    }
+}
 
-        Ok(ret)
+// A resource fork of which only the map is read up front. The data of a resource is read, and
+// decoded, when it's asked for, so looking at one resource doesn't cost decoding all of them.
+// Writing one out lays it afresh with every resource as stored; to change resources, read the
+// fork as a DecodedFork and write that.
+pub struct ResourceFork<R: Read + Seek> {
+    reader: R,
+    base: u64,
+    header: ForkHeader,
+    map: Map,
+}
+
+impl<R: Read + Seek> ResourceFork<R> {
+    pub fn new(mut reader: R) -> Result<Self, RsrcError> {
+        let base = reader.stream_position()?;
+        let header = ForkHeader::read(&mut reader)?;
+        reader.seek(SeekFrom::Start(base + header.map_offset as u64))?;
+        let map = Map::read(&mut reader)?;
+        for t in map.types.iter() {
+            map.refs_of(t)?;
+        }
+        Ok(Self {
+            reader,
+            base,
+            header,
+            map,
+        })
+    }
+    pub fn attrs(&self) -> u16 {
+        self.map.header.fork_attrs
+    }
+    pub fn types(&self) -> Vec<&ResourceType> {
+        self.map.types.iter().map(|t| &t.ty).collect()
+    }
+    pub fn ids_of(&self, ty: &ResourceType) -> Vec<i16> {
+        self.map.types
+            .iter()
+            .filter(|t| &t.ty == ty)
+            .filter_map(|t| self.map.refs_of(t).ok())
+            .flat_map(|refs| refs.iter().map(|r| r.res_id))
+            .collect()
+    }
+    // The bytes of a resource as stored, so still compressed if it is.
+    pub fn raw(&mut self, ty: &ResourceType, id: i16) -> Result<Option<Vec<u8>>, RsrcError> {
+        let Some((t, r)) = self.find(ty, |_, r| r.res_id == id) else {
+            return Ok(None);
+        };
+        self.read_data(&t, &r).map(Some)
+    }
+    pub fn get(&mut self, ty: &ResourceType, id: i16) -> Result<Option<Resource>, RsrcError> {
+        let Some((t, r)) = self.find(ty, |_, r| r.res_id == id) else {
+            return Ok(None);
+        };
+        self.load(&t, &r, false).map(Some)
+    }
+    pub fn get_named(&mut self, ty: &ResourceType, name: &str) -> Result<Option<Resource>, RsrcError> {
+        let Some((t, r)) = self.find(ty, |map, r| map.name_of(r).is_some_and(|n| n.as_str() == name)) else {
+            return Ok(None);
+        };
+        self.load(&t, &r, false).map(Some)
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+    pub fn write<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), RsrcError> {
+        self.decode_all(true)?.write(writer)?;
+        Ok(())
+    }
+    fn decode_all(&mut self, lenient: bool) -> Result<DecodedFork, RsrcError> {
+        let mut resources = Vec::new();
+        for t in self.map.types.clone() {
+            let mut list = Vec::new();
+            for r in self.map.refs_of(&t)?.to_vec() {
+                list.push(self.load(&t, &r, lenient)?);
+            }
+            resources.push((t.ty, list));
+        }
+
+        Ok(DecodedFork {
+            attrs: self.attrs(),
+            resources,
+        })
+    }
+    fn find(&self, ty: &ResourceType, pred: impl Fn(&Map, &Reference) -> bool) -> Option<(Type, Reference)> {
+        self.map.types.iter().filter(|t| &t.ty == ty).find_map(|t| {
+            let r = self.map.refs_of(t).ok()?.iter().find(|r| pred(&self.map, r))?;
+            Some((t.clone(), r.clone()))
+        })
+    }
+    fn read_data(&mut self, t: &Type, r: &Reference) -> Result<Vec<u8>, RsrcError> {
+        let out_of_bounds = || RsrcError::OutOfBounds {
+            ty: t.ty.clone(),
+            id: r.res_id,
+            offset: self.header.offset_of(r),
+        };
+        let off = r.data_offset as u64;
+        let data_len = self.header.data_len as u64;
+        if off + 4 > data_len {
+            return Err(out_of_bounds());
+        }
+        self.reader.seek(SeekFrom::Start(self.base + self.header.data_offset as u64 + off))?;
+        let len = u32::read_be(&mut self.reader)? as u64;
+        if off + 4 + len > data_len {
+            return Err(out_of_bounds());
+        }
+        let mut data = vec![0; len as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+    fn load(&mut self, t: &Type, r: &Reference, lenient: bool) -> Result<Resource, RsrcError> {
+        let bytes = self.read_data(t, r)?;
+        let data = match decode(&t.ty, r, &bytes, self.header.offset_of(r)) {
+            Ok(data) => data,
+            Err(_) if lenient => types::Type::Other(bytes.clone()),
+            Err(err) => return Err(err),
+        };
+        Ok(Resource {
+            id: r.res_id,
+            ty: t.ty.clone(),
+            system_heap: r.attrs.contains(Attributes::SYSTEM_HEAP),
+            purgeable: r.attrs.contains(Attributes::PURGEABLE),
+            locked: r.attrs.contains(Attributes::LOCKED),
+            protected: r.attrs.contains(Attributes::PROTECTED),
+            preload: r.attrs.contains(Attributes::PRELOAD),
+            compressed: r.attrs.contains(Attributes::COMPRESSED),
+            name: self.map.name_of(r).cloned(),
+            stored: Stored {
+                attrs: r.attrs,
+                bytes,
+                data: data.clone(),
+            },
+            data,
+        })
     }
 }
 
+// Decompresses the data of a resource if it needs to be, and decodes it.
+fn decode(ty: &ResourceType, r: &Reference, bytes: &[u8], offset: u64) -> Result<types::Type, RsrcError> {
+    let id = r.res_id;
+    let mut data = bytes.to_owned();
+    if r.attrs.contains(Attributes::COMPRESSED) {
+        let mut cursor = Cursor::new(bytes);
+        data = compression::Header::read(&mut cursor)
+            .and_then(|header| header.decompress(&mut cursor))
+            .map_err(|source| RsrcError::Decompress { ty: ty.clone(), id, offset, source })?;
+    }
+    types::Type::new(ty, id, data).map_err(|source| RsrcError::Decode { ty: ty.clone(), id, offset, source })
+}
+
 #[derive(Clone, Derivative, BinRead, BinWrite)]
 #[derivative(Debug)]
 #[brw(big)]
-pub struct RawResource {
+pub struct ForkHeader {
     data_offset: u32,
     map_offset: u32,
     data_len: u32,
     map_len: u32,
+}
+
+impl ForkHeader {
+    // Where the data of a resource starts in the fork, for error messages.
+    fn offset_of(&self, r: &Reference) -> u64 {
+        self.data_offset as u64 + r.data_offset as u64
+    }
+}
+
+#[derive(Clone, Derivative, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct RawResource {
+    header: ForkHeader,
     #[derivative(Debug = "ignore")]
-    #[br(count = data_offset - 16)]
+    #[br(assert(header.data_offset >= 16), count = header.data_offset - 16)]
     system_data: Vec<u8>,
     #[derivative(Debug = "ignore")]
-    #[br(count = data_len)]
+    #[br(count = header.data_len)]
     data: Vec<u8>,
-    map: MapHeader,
-    #[br(count = map.type_count_minus_one + 1)]
+    #[br(seek_before = SeekFrom::Start(header.map_offset as u64))]
+    map: Map,
+}
+
+#[derive(Clone, Derivative, BinRead, BinWrite)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct Map {
+    header: MapHeader,
+    // an empty map has a count of -1
+    #[br(count = header.type_count_minus_one.wrapping_add(1))]
     types: Vec<Type>,
-    #[br(count = types.iter().map(|t| t.ref_count_minus_one+1).sum::<u16>())]
+    #[br(count = types.iter().map(|t| t.ref_count_minus_one as usize + 1).sum::<usize>())]
     refs: Vec<Reference>,
     #[br(count = refs.iter().filter(|r| r.name_offset.is_some()).count())]
     names: Vec<DynamicPascalString>,
 }
 
+// The id, name and data of a resource that hasn't been decoded.
+pub(crate) type RawEntry<'a> = (i16, Option<&'a str>, &'a [u8]);
+
 impl RawResource {
-    fn refs_of(&self, ty: &Type) -> &[Reference] {
-        let off = (ty.ref_list_offset as usize - self.types.len() * 8) / 12;
-        let count = ty.ref_count_minus_one + 1;
-        &self.refs[off..][..count as usize]
+    // The undecoded resources of one type, for callers that only care about a single type and
+    // don't want everything else decoded along with it.
+    pub(crate) fn resources_of(&self, ty: &ResourceType) -> Result<Vec<RawEntry<'_>>, RsrcError> {
+        let mut ret = Vec::new();
+        for t in self.map.types.iter().filter(|t| &t.ty == ty) {
+            for r in self.map.refs_of(t)? {
+                ret.push((r.res_id, self.map.name_of(r).map(|n| n.as_str()), self.data_of(ty, r)?));
+            }
+        }
+        Ok(ret)
     }
-    fn data_of(&self, r: &Reference) -> &[u8] {
+    pub(crate) fn data_of(&self, ty: &ResourceType, r: &Reference) -> Result<&[u8], RsrcError> {
         let off = r.data_offset as usize;
-        let count = u32::from_be_bytes([
-            self.data[off],
-            self.data[off + 1],
-            self.data[off + 2],
-            self.data[off + 3],
-        ]) as usize;
-        &self.data[off + 4..][..count]
+        self.data
+            .get(off..off + 4)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+            .and_then(|len| self.data.get(off + 4..)?.get(..len))
+            .ok_or_else(|| RsrcError::OutOfBounds { ty: ty.clone(), id: r.res_id, offset: self.header.offset_of(r) })
+    }
+}
+
+impl Map {
+    pub(crate) fn refs_of(&self, ty: &Type) -> Result<&[Reference], RsrcError> {
+        // the references follow the type list, which is a count and 8 bytes per type
+        let off = (ty.ref_list_offset as usize).checked_sub(2 + self.types.len() * 8);
+        let count = ty.ref_count_minus_one as usize + 1;
+        off.filter(|off| off % 12 == 0)
+            .and_then(|off| self.refs.get(off / 12..)?.get(..count))
+            .ok_or_else(|| RsrcError::BadReferenceList(ty.ty.clone()))
     }
     fn name_of(&self, r: &Reference) -> Option<&DynamicPascalString> {
         let mut off = r.name_offset? as usize;
@@ -148,8 +454,7 @@ impl RawResource {
             if off == 0 {
                 return Some(name);
             }
-            off -= 1;
-            off -= name.len();
+            off = off.checked_sub(1 + name.len())?;
         }
 
         None
@@ -366,3 +671,269 @@ sized_string_enum!(
     (b"TMPL" => Template),
     (b"KBDN" => KeyboardName),
 );
+
+#[cfg(test)]
+mod tests {
+    use super::{Attributes, DecodedFork, RawResource, ResourceFork, ResourceType, RsrcError};
+    use super::types::Type;
+    use crate::fs::mfs::{Fork, Mfs};
+    use binrw::BinRead;
+    use std::io::Cursor;
+
+    const INFINITE_DSK: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/infinite.dsk"
+    ));
+
+    fn rsrc_fork(name: &str) -> Vec<u8> {
+        let mfs = Mfs::new(&mut Cursor::new(INFINITE_DSK)).unwrap();
+        mfs.file_contents(mfs.file_by_name(name).unwrap(), Fork::Resource)
+    }
+
+    fn rewrite(fork: &DecodedFork) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        fork.write(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn write_unchanged() {
+        for name in ["MacPaint", "MacWrite", "DeskTop"] {
+            let orig = rsrc_fork(name);
+            let written = rewrite(&DecodedFork::read(&mut Cursor::new(&orig)).unwrap());
+            let orig = RawResource::read(&mut Cursor::new(orig)).unwrap();
+            let new = RawResource::read(&mut Cursor::new(&written)).unwrap();
+            assert_eq!(new.map.header.fork_attrs, orig.map.header.fork_attrs);
+            for t in orig.map.types.iter() {
+                assert_eq!(new.resources_of(&t.ty).unwrap(), orig.resources_of(&t.ty).unwrap(), "{} in {}", t.ty.inner(), name);
+                let attrs = |raw: &RawResource| raw.map.refs_of(t).unwrap().iter().map(|r| r.attrs).collect::<Vec<_>>();
+                assert_eq!(attrs(&new), attrs(&orig));
+            }
+
+            let again = rewrite(&DecodedFork::read(&mut Cursor::new(&written)).unwrap());
+            assert_eq!(again, written);
+        }
+    }
+
+    #[test]
+    fn encode_unchanged() {
+        let mfs = Mfs::new(&mut Cursor::new(INFINITE_DSK)).unwrap();
+        let mut decoded = 0;
+        let mut failed = Vec::new();
+        for file in mfs.files().iter() {
+            let rsrc = rsrc_fork(file.name());
+            if rsrc.is_empty() {
+                continue;
+            }
+            let raw = RawResource::read(&mut Cursor::new(&rsrc)).unwrap();
+            for t in raw.map.types.iter() {
+                for r in raw.map.refs_of(t).unwrap() {
+                    let data = raw.data_of(&t.ty, r).unwrap();
+                    let Ok(ty) = Type::new(&t.ty, r.res_id, data.to_vec()) else {
+                        failed.push(format!("{} {} in {}", t.ty.inner(), r.res_id, file.name()));
+                        continue;
+                    };
+                    assert_eq!(ty.encode().unwrap(), data, "{} {} in {}", t.ty.inner(), r.res_id, file.name());
+                    if !matches!(ty, Type::Other(_)) {
+                        decoded += 1;
+                    }
+                }
+            }
+        }
+        // only the damaged font read_lenient is about
+        assert_eq!(failed, ["FONT 268 in Sample Memo"]);
+        assert!(decoded > 1400, "only {} resources decoded", decoded);
+    }
+
+    #[test]
+    fn encode_unusual_bytes() {
+        // command keys that aren't KeyboardShortcuts, and a major version that isn't BCD
+        let menu = [
+            &[0, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x04, b'E', b'd', b'i', b't'][..],
+            &[4, b'P', b'l', b'u', b's', 0, b'+', 0, 0],
+            &[4, b'L', b'e', b'f', b't', 0, b'[', 0, 0],
+            &[5, b'S', b'm', b'a', b'l', b'l', 0, b'z', 0x12, 0],
+            &[4, b'I', b'c', b'o', b'n', 1, 0xa5, 0, 0],
+            &[0],
+        ].concat();
+        let ty = Type::new(&ResourceType::Menu, 128, menu.clone()).unwrap();
+        assert!(matches!(ty, Type::Menu(_)));
+        assert_eq!(ty.encode().unwrap(), menu);
+
+        let vers = [0x0a, 0x12, 0x80, 0, 0, 0, 3, b'1', b'0', b'1', 0];
+        let ty = Type::new(&ResourceType::VersionNumber, 1, vers.to_vec()).unwrap();
+        let Type::Version(v) = &ty else {
+            panic!("vers resource didn't decode as a version");
+        };
+        assert_eq!(v.major(), 10);
+        assert_eq!(ty.encode().unwrap(), vers);
+    }
+
+    #[test]
+    fn template_position() {
+        let header = [0, 40, 0, 40, 0, 140, 1, 40, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 128];
+        let even = [&header[..], &[2, b'H', b'i', 0, 0x30, 0x0a]].concat();
+        let odd = [&header[..], &[1, b'A', 0xb0, 0x0a]].concat();
+        let window = [&header[..18], &[0, 0, 0x70, 0x0a]].concat();
+        for (ty, data) in [(ResourceType::DialogBoxTemplate, even), (ResourceType::DialogBoxTemplate, odd), (ResourceType::WindowTemplate, window)] {
+            let decoded = Type::new(&ty, 128, data.clone()).unwrap();
+            let position = match &decoded {
+                Type::Dialog(dialog) => dialog.position(),
+                Type::Window(window) => window.position(),
+                other => panic!("expected a template, got {:?}", other),
+            };
+            assert_eq!(position.map(|p| p & 0xff), Some(0x0a));
+            assert_eq!(decoded.encode().unwrap(), data);
+        }
+        let bare = [&header[..], &[1, b'A']].concat();
+        let Type::Dialog(dialog) = Type::new(&ResourceType::DialogBoxTemplate, 128, bare.clone()).unwrap() else {
+            panic!("expected a dialog");
+        };
+        assert_eq!(dialog.position(), None);
+        assert_eq!(Type::Dialog(dialog).encode().unwrap(), bare);
+    }
+
+    #[test]
+    fn read_lenient() {
+        // Sample Memo has a FONT whose last character comes before its first one
+        let rsrc = rsrc_fork("Sample Memo");
+        match DecodedFork::read(&mut Cursor::new(&rsrc)) {
+            Err(RsrcError::Decode { ty: ResourceType::BitmapFont, id: 268, offset: 0x100, .. }) => {},
+            other => panic!("expected the FONT to fail, got {:?}", other.map(|_| ())),
+        }
+        let fork = DecodedFork::read_lenient(&mut Cursor::new(&rsrc)).unwrap();
+        let (_, fonts) = fork.resources().iter().find(|(ty, _)| *ty == ResourceType::BitmapFont).unwrap();
+        let font = fonts.iter().find(|r| r.id() == 268).unwrap();
+        let raw = RawResource::read(&mut Cursor::new(&rsrc)).unwrap();
+        let (.., data) = raw.resources_of(&ResourceType::BitmapFont).unwrap().into_iter().find(|(id, ..)| *id == 268).unwrap();
+        assert!(matches!(font.data(), Type::Other(bytes) if bytes == data));
+
+        let mfs = Mfs::new(&mut Cursor::new(INFINITE_DSK)).unwrap();
+        for file in mfs.files().iter().filter(|f| f.resource_fork_size() > 0) {
+            DecodedFork::read_lenient(&mut Cursor::new(rsrc_fork(file.name()))).unwrap();
+        }
+    }
+
+    #[test]
+    fn data_out_of_bounds() {
+        let mut rsrc = rsrc_fork("MacWrite");
+        let raw = RawResource::read(&mut Cursor::new(&rsrc)).unwrap();
+        let t = &raw.map.types[0];
+        // the 24-bit data offset of the first reference of the first type
+        let off = raw.header.map_offset as usize + 28 + t.ref_list_offset as usize + 5;
+        rsrc[off..off + 3].copy_from_slice(&[0xff, 0xff, 0xf0]);
+        let id = raw.map.refs_of(t).unwrap()[0].res_id;
+        for result in [DecodedFork::read(&mut Cursor::new(&rsrc)), DecodedFork::read_lenient(&mut Cursor::new(&rsrc))] {
+            match result {
+                Err(RsrcError::OutOfBounds { ty, id: err_id, offset }) => {
+                    assert_eq!((ty, err_id, offset), (t.ty.clone(), id, raw.header.data_offset as u64 + 0xfffff0));
+                },
+                other => panic!("expected an out of bounds error, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_fork() {
+        let rsrc = rsrc_fork("MacDraw");
+        let decoded = DecodedFork::read(&mut Cursor::new(&rsrc)).unwrap();
+        let raw = RawResource::read(&mut Cursor::new(&rsrc)).unwrap();
+        // the fork doesn't have to start at the beginning of the reader
+        let mut reader = Cursor::new([vec![0xaa; 100], rsrc.clone()].concat());
+        reader.set_position(100);
+        let mut fork = ResourceFork::new(reader).unwrap();
+        assert_eq!(fork.attrs(), decoded.attrs());
+        let types: Vec<_> = decoded.resources().iter().map(|(ty, _)| ty).collect();
+        assert_eq!(fork.types(), types);
+
+        let mut named = 0;
+        for (ty, list) in decoded.resources() {
+            assert_eq!(fork.ids_of(ty), list.iter().map(|r| r.id()).collect::<Vec<_>>());
+            for res in list {
+                let lazy = fork.get(ty, res.id()).unwrap().unwrap();
+                assert_eq!((lazy.name(), lazy.data()), (res.name(), res.data()));
+                let (.., data) = raw.resources_of(ty).unwrap().into_iter().find(|(id, ..)| *id == res.id()).unwrap();
+                assert_eq!(fork.raw(ty, res.id()).unwrap().unwrap(), data);
+                if let Some(name) = res.name() {
+                    assert_eq!(fork.get_named(ty, name).unwrap().unwrap().id(), res.id());
+                    named += 1;
+                }
+            }
+        }
+        assert!(named > 0);
+        assert!(fork.get(&ResourceType::String, 12345).unwrap().is_none());
+        assert!(fork.get_named(&ResourceType::String, "no such string").unwrap().is_none());
+        assert!(fork.ids_of(&ResourceType::Sound).is_empty());
+
+        let mut written = Cursor::new(Vec::new());
+        fork.write(&mut written).unwrap();
+        assert_eq!(written.into_inner(), rewrite(&decoded));
+    }
+
+    #[test]
+    fn write_changed() {
+        let mut fork = DecodedFork::read(&mut Cursor::new(rsrc_fork("MacWrite"))).unwrap();
+        fork.set_attrs(0x80);
+        let (_, strings) = fork.resources_mut().iter_mut().find(|(ty, _)| *ty == ResourceType::String).unwrap();
+        let id = strings[0].id();
+        strings[0].purgeable = !strings[0].purgeable;
+        let Type::String(s) = strings[0].data_mut() else {
+            panic!("STR resource didn't decode as a string");
+        };
+        *s.text_mut() = "Hello, world".to_string();
+
+        let written = rewrite(&fork);
+        let new = DecodedFork::read(&mut Cursor::new(written)).unwrap();
+        assert_eq!(new.attrs(), 0x80);
+        let (_, new_strings) = new.resources().iter().find(|(ty, _)| *ty == ResourceType::String).unwrap();
+        let (_, strings) = fork.resources().iter().find(|(ty, _)| *ty == ResourceType::String).unwrap();
+        assert_eq!(new_strings[0].id(), id);
+        assert_eq!(new_strings[0].purgeable, strings[0].purgeable);
+        assert_eq!(new_strings[0].data(), strings[0].data());
+        for (new, old) in new_strings.iter().zip(strings.iter()) {
+            assert_eq!((new.id(), new.name()), (old.id(), old.name()));
+        }
+    }
+
+    #[test]
+    fn compressed_flag() {
+        let mut fork = DecodedFork::read(&mut Cursor::new(rsrc_fork("MacWrite"))).unwrap();
+        fn strings(fork: &mut DecodedFork) -> &mut super::Resource {
+            let (_, strings) = fork.resources_mut().iter_mut().find(|(ty, _)| *ty == ResourceType::String).unwrap();
+            &mut strings[0]
+        }
+        // there's no compressor, so it can't be set on a resource that wasn't compressed
+        strings(&mut fork).compressed = true;
+        assert!(fork.write(&mut Cursor::new(Vec::new())).is_err());
+
+        // pretend the string was read compressed
+        let res = strings(&mut fork);
+        let id = res.id();
+        let plain = res.data().clone();
+        res.stored.attrs |= Attributes::COMPRESSED;
+        res.stored.bytes = b"compressed".to_vec();
+        let written = RawResource::read(&mut Cursor::new(rewrite(&fork))).unwrap();
+        let (.., data) = written.resources_of(&ResourceType::String).unwrap().into_iter().find(|(i, ..)| *i == id).unwrap();
+        assert_eq!(data, b"compressed");
+        let t = written.map.types.iter().find(|t| t.ty == ResourceType::String).unwrap();
+        let r = written.map.refs_of(t).unwrap().iter().find(|r| r.res_id == id).unwrap();
+        assert!(r.attrs.contains(Attributes::COMPRESSED));
+
+        // an edited one can't stay compressed
+        let Type::String(s) = strings(&mut fork).data_mut() else {
+            panic!("STR resource didn't decode as a string");
+        };
+        *s.text_mut() = "Hello, world".to_string();
+        assert!(fork.write(&mut Cursor::new(Vec::new())).is_err());
+
+        // but clearing the flag writes it decompressed
+        let res = strings(&mut fork);
+        res.compressed = false;
+        *res.data_mut() = plain.clone();
+        let new = DecodedFork::read(&mut Cursor::new(rewrite(&fork))).unwrap();
+        let (_, new_strings) = new.resources().iter().find(|(ty, _)| *ty == ResourceType::String).unwrap();
+        let res = new_strings.iter().find(|r| r.id() == id).unwrap();
+        assert!(!res.compressed);
+        assert_eq!(res.data(), &plain);
+    }
+}
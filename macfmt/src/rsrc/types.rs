@@ -22,9 +22,9 @@ const fn image_size(side: usize, bpp: usize) -> usize {
 #[derivative(Debug)]
 pub enum Type {
     Menu(Menu),
-    SystemVersion(DynamicPascalString),
-    String(DynamicPascalString),
-    KeyboardName(DynamicPascalString),
+    SystemVersion(PascalText),
+    String(PascalText),
+    KeyboardName(PascalText),
     StringList(StringList),
     RomOverride(RomOverride),
     MfsFolder(MfsFolder),
@@ -58,16 +58,16 @@ impl Type {
     pub fn new(kind: &ResourceType, id: i16, data: Vec<u8>) -> BinResult<Type> {
         let len = data.len();
         let mut cursor = std::io::Cursor::new(data);
-        Ok(match kind {
+        let ty = match kind {
             ResourceType::SystemFontIds => Type::SystemFonts(SystemFonts::read(&mut cursor)?),
             ResourceType::Menu => Type::Menu(Menu::read(&mut cursor)?),
             ResourceType::Code if id == 0 => Type::Code0(Code0::read(&mut cursor)?),
             ResourceType::RomResourceOverrideList => {
                 Type::RomOverride(RomOverride::read(&mut cursor)?)
             }
-            ResourceType::String => Type::String(DynamicPascalString::read(&mut cursor)?),
+            ResourceType::String => Type::String(PascalText::read(&mut cursor)?),
             /*ResourceType::SystemVersion => {
-                Type::SystemVersion(DynamicPascalString::read(&mut cursor)?)
+                Type::SystemVersion(PascalText::read(&mut cursor)?)
             }*/
             ResourceType::StringList => Type::StringList(StringList::read(&mut cursor)?),
             ResourceType::MfsFolderInfo => Type::MfsFolder(MfsFolder::read(&mut cursor)?),
@@ -93,21 +93,58 @@ impl Type {
             ResourceType::ItemList => Type::ItemList(ItemList::read(&mut cursor)?),
             ResourceType::VersionNumber => Type::Version(Version::read(&mut cursor)?),
             ResourceType::ColorLut => Type::ColorLut(ColorLut::read(&mut cursor)?),
-            ResourceType::KeyboardName => Type::KeyboardName(DynamicPascalString::read(&mut cursor)?),
+            ResourceType::KeyboardName => Type::KeyboardName(PascalText::read(&mut cursor)?),
             ResourceType::FileReference => Type::FileReference(FileReference::read(&mut cursor)?),
-            _ => Type::Other(cursor.into_inner()),
-        })
+            _ => return Ok(Type::Other(cursor.into_inner())),
+        };
+        Ok(ty)
+    }
+    // Gives back exactly what the resource was decoded from as long as it hasn't been changed.
+    pub fn encode(&self) -> BinResult<Vec<u8>> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        match self {
+            Type::Menu(v) => v.write(&mut cursor)?,
+            Type::SystemVersion(s) | Type::String(s) | Type::KeyboardName(s) => s.write(&mut cursor)?,
+            Type::StringList(v) => v.write(&mut cursor)?,
+            Type::RomOverride(v) => v.write(&mut cursor)?,
+            Type::MfsFolder(v) => v.write(&mut cursor)?,
+            Type::Window(v) => v.write(&mut cursor)?,
+            Type::Alert(v) => v.write(&mut cursor)?,
+            Type::Dialog(v) => v.write(&mut cursor)?,
+            Type::Size(v) => v.write(&mut cursor)?,
+            Type::SystemFonts(v) => v.write(&mut cursor)?,
+            Type::Font(v) => v.write(&mut cursor)?,
+            Type::FinderIcon(v) => v.write(&mut cursor)?,
+            Type::SmallIcon(v) => v.write(&mut cursor)?,
+            Type::SmallIcons(v) => v.write(&mut cursor)?,
+            Type::Icon(v) => v.write(&mut cursor)?,
+            Type::Pattern(v) => v.write(&mut cursor)?,
+            Type::LargeColorIcon4(v) => v.write(&mut cursor)?,
+            Type::LargeColorIcon8(v) => v.write(&mut cursor)?,
+            Type::SmallColorIcon4(v) => v.write(&mut cursor)?,
+            Type::SmallColorIcon8(v) => v.write(&mut cursor)?,
+            Type::FileReference(v) => v.write(&mut cursor)?,
+            Type::ItemList(v) => v.write(&mut cursor)?,
+            Type::Version(v) => v.write(&mut cursor)?,
+            Type::Cursor(v) => v.write(&mut cursor)?,
+            Type::Code0(v) => v.write(&mut cursor)?,
+            Type::ColorLut(v) => v.write(&mut cursor)?,
+            Type::Bundle(v) => v.write(&mut cursor)?,
+            Type::Template(v) => v.write(&mut cursor)?,
+            Type::Other(data) => return Ok(data.clone()),
+        }
+        Ok(cursor.into_inner())
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct ColorLut {
     seed: SizedString<4>,
     flags: u16,
     size: u16,
-    #[br(count = size+1)]
+    #[br(count = size as usize + 1)]
     entries: Vec<ClutEntry>,
 }
 
@@ -120,7 +157,7 @@ impl ColorLut {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct ClutEntry {
@@ -158,7 +195,7 @@ impl ClutEntry {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Template {
@@ -172,7 +209,7 @@ impl Template {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Field {
@@ -189,7 +226,7 @@ impl Field {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub enum FieldType {
@@ -277,7 +314,7 @@ pub enum FieldType {
 #[brw(big)]
 pub struct SystemFonts {
     count: u16,
-    #[br(count = count+1)]
+    #[br(count = count as usize + 1)]
     ids: Vec<i16>,
 }
 
@@ -287,6 +324,7 @@ pub struct SystemFonts {
 pub struct Font {
     font_type: u16,
     first_char: u16,
+    #[br(assert(last_char >= first_char))]
     last_char: u16,
     wid_max: u16,
     kern_max: u16,
@@ -298,16 +336,14 @@ pub struct Font {
     descent: u16,
     leading: u16,
     row_words: u16,
-    #[br(count = 2 * row_words * f_rect_height)]
+    #[br(count = 2 * row_words as usize * f_rect_height as usize)]
     bit_img: Vec<u8>,
-    #[br(count = 2 * (last_char - first_char + 3))]
+    #[br(count = 2 * (last_char - first_char) as usize + 6)]
     location_table: Vec<u8>,
-    #[br(try, count = 2 * (last_char - first_char + 3))]
-    offset_width_table: Option<Vec<u8>>,
-    #[br(try, count = 2 * (last_char - first_char + 3))]
-    char_width: Option<Vec<u8>>,
-    #[br(try, count = 2 * (last_char - first_char + 3))]
-    image_height: Option<Vec<u8>>,
+    // the offset/width table and the optional width and height tables, which some fonts cut short
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    tables: Vec<u8>,
 }
 
 #[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
@@ -317,7 +353,7 @@ pub struct Bundle {
     sig: SizedString<4>,
     version_res_id: i16,
     resource_type_count_minus_one: u16,
-    #[br(count = resource_type_count_minus_one + 1)]
+    #[br(count = resource_type_count_minus_one as usize + 1)]
     resources: Vec<BundleResType>,
 }
 
@@ -336,7 +372,7 @@ impl Bundle {
 pub struct BundleResType {
     res_type: SizedString<4>,
     res_count_minus_one: u16,
-    #[br(count = res_count_minus_one + 1)]
+    #[br(count = res_count_minus_one as usize + 1)]
     map: Vec<BundleResMap>,
 }
 
@@ -407,6 +443,29 @@ pub struct StringList {
     length: u16,
     #[br(count = length)]
     list: Vec<DynamicPascalString>,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
+}
+
+// A lone Pascal string, as in STR resources, which are often padded to an even length.
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct PascalText {
+    text: DynamicPascalString,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
+}
+
+impl PascalText {
+    pub fn as_str(&self) -> &str {
+        self.text.as_str()
+    }
+    pub fn text_mut(&mut self) -> &mut String {
+        self.text.as_mut()
+    }
 }
 
 impl StringList {
@@ -424,16 +483,15 @@ fn from_bcd(v: u8) -> u8 {
     hi * 10 + lo
 }
 
-fn to_bcd(v: &u8) -> u8 {
-    if *v & 0x0f > 9 { *v + 6 } else { *v }
+fn to_bcd(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
 }
 
 #[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Version {
-    #[br(map = from_bcd)]
-    #[bw(map = to_bcd)]
+    // kept as stored, as not every major version out there is valid BCD
     major: u8,
     minor: u8,
     development_stage: DevelopmentStage,
@@ -457,14 +515,14 @@ impl Version {
     pub fn region_code(&mut self) -> RegionCode {
         self.region
     }
-    pub fn major_mut(&mut self) -> &mut u8 {
-        &mut self.major
-    }
     pub fn minor(&self) -> u8 {
         self.minor
     }
     pub fn major(&self) -> u8 {
-        self.major
+        from_bcd(self.major)
+    }
+    pub fn set_major(&mut self, major: u8) {
+        self.major = to_bcd(major);
     }
     pub fn set_minor(&mut self, minor: u8) {
         self.minor = minor;
@@ -501,6 +559,9 @@ pub struct FileReference {
     ty: SizedString<4>,
     icon_id: i16,
     filename: DynamicPascalString,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
 }
 
 impl FileReference {
@@ -515,13 +576,16 @@ impl FileReference {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct ItemList {
     count_minus_one: u16,
-    #[br(count = count_minus_one + 1)]
+    #[br(count = count_minus_one as usize + 1)]
     list: Vec<Item>,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
 }
 
 impl ItemList {
@@ -533,12 +597,12 @@ impl ItemList {
     }
 }
 
-#[binrw::binread]
-#[derive(Clone, Derivative, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Item {
-    #[brw(pad_before = 4)]
+    #[derivative(Debug = "ignore")]
+    placeholder: u32,
     rect: Rect,
     ty: u8,
     #[br(args(ty))]
@@ -560,49 +624,44 @@ impl Item {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 #[br(import(ty: u8))]
 pub enum ItemType {
     #[br(pre_assert(ty & 0x7f == 4))]
     Button {
-        #[brw(align_after = 2)]
-        text: DynamicPascalString,
+        text: ItemText,
     },
     #[br(pre_assert(ty & 0x7f == 5))]
     Checkbox {
-        #[brw(align_after = 2)]
-        text: DynamicPascalString,
+        text: ItemText,
     },
     #[br(pre_assert(ty & 0x7f == 6))]
     RadioButton {
-        #[brw(align_after = 2)]
-        text: DynamicPascalString,
+        text: ItemText,
     },
     #[br(pre_assert(ty & 0x7f == 8))]
     StaticText {
-        #[brw(align_after = 2)]
-        text: DynamicPascalString,
+        text: ItemText,
     },
     #[br(pre_assert(ty & 0x7f == 16))]
     EditableText {
-        #[brw(align_after = 2)]
-        text: DynamicPascalString,
+        text: ItemText,
     },
     #[br(pre_assert(ty & 0x7f == 7))]
     Control {
-        #[brw(pad_before = 1)]
+        len: u8,
         res: i16,
     },
     #[br(pre_assert(ty & 0x7f == 32))]
     Icon {
-        #[brw(pad_before = 1)]
+        len: u8,
         res: i16,
     },
     #[br(pre_assert(ty & 0x7f == 64))]
     QuickDrawPicture {
-        #[brw(pad_before = 1)]
+        len: u8,
         res: i16,
     },
     #[br(pre_assert(ty & 0x7f == 0))]
@@ -614,6 +673,33 @@ pub enum ItemType {
     },
 }
 
+// Item texts are padded to an even length, and the pad byte isn't always zero.
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
+#[derivative(Debug)]
+#[brw(big)]
+pub struct ItemText {
+    text: DynamicPascalString,
+    #[derivative(Debug = "ignore")]
+    #[br(try, if(text.len() % 2 == 1))]
+    #[bw(if(text.len() % 2 == 1), map = |pad| pad.unwrap_or(0))]
+    pad: Option<u8>,
+}
+
+impl ItemText {
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.text.as_str().is_empty()
+    }
+    pub fn as_str(&self) -> &str {
+        self.text.as_str()
+    }
+    pub fn text_mut(&mut self) -> &mut String {
+        self.text.as_mut()
+    }
+}
+
 #[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
@@ -783,6 +869,10 @@ impl<const SIZE: usize> Icon<SIZE> {
 pub struct IconList<const SIZE: usize> {
     bw: Icon<SIZE>,
     mask: Icon<SIZE>,
+    // some apps keep more icons than the two the Finder uses
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
 }
 
 impl<const SIZE: usize> IconList<SIZE> {
@@ -809,10 +899,23 @@ pub struct Dialog {
     close_box_spec: u8,
     reference_constant: u32,
     item_list_id: i16,
-    #[brw(align_after = 2)]
     title: DynamicPascalString,
+    // the title is padded to an even length when the positioning word of later templates follows it
+    #[derivative(Debug = "ignore")]
+    #[br(try, if(title.len().is_multiple_of(2)))]
+    #[bw(if(title.len().is_multiple_of(2) && (pad.is_some() || position.is_some())), map = |pad| pad.unwrap_or(0))]
+    pad: Option<u8>,
     #[br(try)]
-    position: Option<Point>,
+    position: Option<u16>,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
+}
+
+impl Dialog {
+    pub fn position(&self) -> Option<u16> {
+        self.position
+    }
 }
 
 #[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
@@ -836,22 +939,42 @@ pub struct Window {
     close_box: u16,
     reference: u32,
     title: DynamicPascalString,
-    //positioning: u16,
+    // the title is padded to an even length when the positioning word of later templates follows it
+    #[derivative(Debug = "ignore")]
+    #[br(try, if(title.len().is_multiple_of(2)))]
+    #[bw(if(title.len().is_multiple_of(2) && (pad.is_some() || position.is_some())), map = |pad| pad.unwrap_or(0))]
+    pad: Option<u8>,
+    #[br(try)]
+    position: Option<u16>,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+impl Window {
+    pub fn position(&self) -> Option<u16> {
+        self.position
+    }
+}
+
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct Menu {
-    #[brw(pad_after = 4)]
     id: u16,
-    #[brw(pad_after = 2)]
+    width: u16,
+    height: u16,
     definition_res_id: u16,
+    #[derivative(Debug = "ignore")]
+    filler: u16,
     menu_state: u32,
     title: DynamicPascalString,
     #[br(parse_with = MenuItem::parse)]
     #[brw(pad_after = 1)]
     items: Vec<MenuItem>,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
 }
 
 impl Menu {
@@ -875,12 +998,13 @@ impl Menu {
     }
 }
 
-#[derive(Clone, Derivative, BinRead, Eq, PartialEq)]
+#[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
 #[derivative(Debug)]
 #[brw(big)]
 pub struct MenuItem {
     text: DynamicPascalString,
     #[br(parse_with = MenuItemConfig::parser)]
+    #[bw(write_with = MenuItemConfig::writer)]
     cfg: MenuItemConfig,
     style: Style,
 }
@@ -926,7 +1050,7 @@ impl MenuItem {
 pub enum MenuItemConfig {
     Plain {
         icon: Option<NonZeroU8>,
-        keyboard_shortcut: Option<KeyboardShortcut>,
+        keyboard_shortcut: Option<CommandKey>,
         marking_character: Option<MarkingCharacter>,
     },
     ScriptCode {
@@ -972,7 +1096,7 @@ impl MenuItemConfig {
                 },
                 _ => MenuItemConfig::Plain {
                     icon: Some(icon),
-                    keyboard_shortcut: KeyboardShortcut::from_repr(bytes[1]),
+                    keyboard_shortcut: CommandKey::new(bytes[1]),
                     marking_character: MarkingCharacter::new(bytes[2]),
                 },
             }
@@ -984,13 +1108,59 @@ impl MenuItemConfig {
                 },
                 _ => MenuItemConfig::Plain {
                     icon: None,
-                    keyboard_shortcut: KeyboardShortcut::from_repr(bytes[1]),
+                    keyboard_shortcut: CommandKey::new(bytes[1]),
                     marking_character: MarkingCharacter::new(bytes[2]),
                 },
             }
         };
         Ok(ret)
     }
+    #[binrw::writer(writer)]
+    fn writer(cfg: &MenuItemConfig) -> BinResult<()> {
+        let mark = |mark: Option<MarkingCharacter>| mark.map_or(0, MarkingCharacter::to_u8);
+        let bytes = match *cfg {
+            MenuItemConfig::Plain { icon, keyboard_shortcut, marking_character } => {
+                [icon.map_or(0, NonZeroU8::get), keyboard_shortcut.map_or(0, CommandKey::to_u8), mark(marking_character)]
+            },
+            MenuItemConfig::ScriptCode { code, marking_character } => [code.get(), 0x1c, mark(marking_character)],
+            MenuItemConfig::ReducedIcon { icon, marking_character } => [icon.get(), 0x1d, mark(marking_character)],
+            MenuItemConfig::SicnIcon { icon, marking_character } => [icon.get(), 0x1e, mark(marking_character)],
+            MenuItemConfig::Submenu { icon, submenu_id } => [icon.map_or(0, NonZeroU8::get), 0x1b, submenu_id],
+        };
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+// Any character can be a command key, KeyboardShortcut only has the common ones.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum CommandKey {
+    Key(KeyboardShortcut),
+    Other(MacRoman),
+}
+
+impl CommandKey {
+    pub fn new(v: u8) -> Option<Self> {
+        match v {
+            0x00 => None,
+            _ => Some(KeyboardShortcut::from_repr(v).map_or(Self::Other(MacRoman::from(v)), Self::Key)),
+        }
+    }
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Key(key) => key as u8,
+            Self::Other(v) => v.to_u8(),
+        }
+    }
+}
+
+impl std::fmt::Display for CommandKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, "{}", key),
+            Self::Other(v) => write!(f, "{}", v.to_char()),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Derivative, Eq, PartialEq, Hash)]
@@ -1007,14 +1177,17 @@ impl MarkingCharacter {
         match v {
             0x00 => None,
             0x12 => Some(Self::Checkmark),
+            0x13 => Some(Self::FullDiamond),
+            0xd7 => Some(Self::EmptyDiamond),
             _ => Some(Self::Other(MacRoman::from(v))),
         }
     }
     pub fn to_u8(self) -> u8 {
         match self {
             Self::Checkmark => 0x12,
+            Self::FullDiamond => 0x13,
+            Self::EmptyDiamond => 0xd7,
             Self::Other(v) => v.to_u8(),
-            _ => todo!(),
         }
     }
 }
@@ -1095,6 +1268,24 @@ pub struct MfsFolder {
     unk4: u16,
     bounds: Rect,
     scroll_offset: Point,
+    #[derivative(Debug = "ignore")]
+    #[br(parse_with = binrw::helpers::until_eof)]
+    trailing: Vec<u8>,
+}
+
+impl MfsFolder {
+    pub fn ty(&self) -> &MfsFolderType {
+        &self.ty
+    }
+    pub fn parent(&self) -> i16 {
+        self.parent
+    }
+    pub fn creation_date(&self) -> DateTime {
+        self.crtime
+    }
+    pub fn modification_date(&self) -> DateTime {
+        self.mtime
+    }
 }
 
 #[derive(Clone, Derivative, BinRead, BinWrite, Eq, PartialEq)]
@@ -1108,6 +1299,40 @@ pub enum MfsFolderType {
 #[derive(Display, EnumIter, FromRepr, Copy, Clone, Debug, Eq, PartialEq, Hash)]
 #[repr(u8)]
 pub enum KeyboardShortcut {
+    #[strum(to_string = ",")]
+    Comma = 0x2c,
+    #[strum(to_string = "-")]
+    Minus = 0x2d,
+    #[strum(to_string = ".")]
+    Period = 0x2e,
+    #[strum(to_string = "/")]
+    Slash = 0x2f,
+    #[strum(to_string = "0")]
+    Digit0 = 0x30,
+    #[strum(to_string = "1")]
+    Digit1,
+    #[strum(to_string = "2")]
+    Digit2,
+    #[strum(to_string = "3")]
+    Digit3,
+    #[strum(to_string = "4")]
+    Digit4,
+    #[strum(to_string = "5")]
+    Digit5,
+    #[strum(to_string = "6")]
+    Digit6,
+    #[strum(to_string = "7")]
+    Digit7,
+    #[strum(to_string = "8")]
+    Digit8,
+    #[strum(to_string = "9")]
+    Digit9,
+    #[strum(to_string = ";")]
+    Semicolon = 0x3b,
+    #[strum(to_string = "=")]
+    Equals = 0x3d,
+    #[strum(to_string = "?")]
+    Question = 0x3f,
     A = 0x41,
     B,
     C,
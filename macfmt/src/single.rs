@@ -23,6 +23,15 @@ impl AppleFile {
     pub fn entries(&self) -> impl Iterator<Item = &EntryData> {
         self.header().entries()
     }
+    pub fn data_fork(&self) -> Option<&[u8]> {
+        for entry in self.header().entries() {
+            if let EntryData::DataFork(vec) = entry {
+                return Some(vec);
+            }
+        }
+
+        None
+    }
     pub fn resource_fork(&self) -> Option<&[u8]> {
         for entry in self.header().entries() {
             if let EntryData::ResourceFork(vec) = entry {
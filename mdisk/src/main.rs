@@ -1,16 +1,19 @@
 use std::path::PathBuf;
-use std::io::{Read, Seek, SeekFrom};
-use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::fs::{File, OpenOptions};
 
 use anyhow::{bail, Result};
-use binrw::BinRead;
 use clap::{Parser, ValueEnum, Subcommand};
 use comfy_table::Table;
 use humansize::{format_size, DECIMAL};
-use macfmt::apm::{ApmDrive, Driver, Partition};
+use macfmt::apm::{ApmDrive, Driver, MapKind, Partition, PartitionType};
+use macfmt::device::{self, BlockDevice, Container, Detected, DeviceReader, Disk, Layout};
+use macfmt::device::{PartitionDevice, RawDevice, WritableBlockDevice};
 use macfmt::fs::{
-    hfs::HfsVolume,
-    mfs::Mfs,
+    self, EntryKind, Volume,
+    hfs::{HfsVolume, fuse::HfsFuse, lazy::LazyHfsVolume},
+    hfsplus::HfsPlusVolume,
+    mfs::{Mfs, fuse::MfsFuse},
 };
 
 #[derive(Debug, Clone, Parser)]
@@ -20,13 +23,17 @@ struct Args {
     input: PathBuf,
     #[arg(short, long)]
     mount: Option<PathBuf>,
+    // index or name of the partition to use on partitioned disks
+    #[arg(short, long)]
+    partition: Option<String>,
     #[command(subcommand)]
-    op: Operation,
+    op: Option<Operation>,
 }
 
 #[derive(Debug, Copy, Clone, ValueEnum)]
 enum Format {
     Hfs,
+    HfsPlus,
     Mfs,
     Apm,
     Autodetect,
@@ -49,6 +56,7 @@ enum Operation {
         fork: Fork,
         dst: Option<PathBuf>,
     },
+    Partitions,
 }
 
 fn show_partitions(partitions: &[Partition]) {
@@ -84,120 +92,182 @@ fn show_drivers(drivers: &[Driver]) {
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let mut file = File::open(args.input)?;
-    let fmt = match args.format {
-        Format::Autodetect => {
-            let mut sector0 = [0u8; 0x200];
-            file.read_exact(&mut sector0)?;
-            file.rewind()?;
-            let apm = &sector0[0..2] == b"ER";
-
-            if !apm {
-                let mut more_magic = [0u8; 2];
-                file.seek(SeekFrom::Start(1024))?;
-                file.read_exact(&mut more_magic)?;
-                file.rewind()?;
-
-                match &more_magic {
-                    b"\xD2\xD7" => Format::Mfs,
-                    b"BD" => Format::Hfs,
-                    _ => bail!("Unknown sector 1 magic bytes {:02x?}", more_magic),
-                }
-            } else {
-                Format::Apm
-            }
+    let file = File::open(&args.input)?;
+    let (container, layout, disk) = match device::detect(file)? {
+        Detected::Disk { container, layout, disk } => (container, layout, disk),
+        Detected::File { format, .. } => bail!("Not a disk image but a {:?} file", format),
+    };
+    let layout = match args.format {
+        Format::Hfs => Layout::Hfs,
+        Format::HfsPlus => Layout::HfsPlus,
+        Format::Mfs => Layout::Mfs,
+        Format::Apm => Layout::Apm,
+        Format::Autodetect => layout,
+    };
+
+    let op = match (args.op, &args.mount) {
+        (None, None) => bail!("No operation given"),
+        (Some(_), Some(_)) => bail!("--mount can't be combined with another operation"),
+        (op, _) => op,
+    };
+    let partition = args.partition.as_deref();
+    if partition.is_some() && layout != Layout::Apm {
+        bail!("--partition only works on partitioned disks");
+    }
+    // writable mounts go through a second handle on the file
+    let open_image = || {
+        if container != Container::Raw {
+            return Err(std::io::Error::other("Disk images can't be mounted writable yet"));
+        }
+        let file = OpenOptions::new().read(true).write(true).open(&args.input)?;
+        Ok(Disk::Raw(RawDevice::new(file)?))
+    };
+
+    match layout {
+        Layout::Apm => run_apm(disk, open_image, partition, args.mount, op),
+        layout => run_volume(layout, disk, open_image, args.mount, op),
+    }
+}
+
+fn run_volume<D, W, F>(
+    layout: Layout,
+    device: D,
+    open_image: F,
+    mount: Option<PathBuf>,
+    op: Option<Operation>,
+) -> Result<()>
+where
+    D: BlockDevice,
+    W: WritableBlockDevice,
+    F: FnOnce() -> std::io::Result<W>,
+{
+    match layout {
+        Layout::Hfs => run_hfs(DeviceReader::new(device), mount, op),
+        Layout::HfsPlus => run_hfs_plus(DeviceReader::new(device), mount, op),
+        Layout::Mfs => {
+            let open_image = || Ok(DeviceReader::new(open_image()?));
+            run_mfs(DeviceReader::new(device), open_image, mount, op)
         },
-        _ => args.format,
+        layout => bail!("Don't know how to open {:?} here", layout),
+    }
+}
+
+fn run_apm<D, W, F>(
+    disk: D,
+    open_image: F,
+    partition: Option<&str>,
+    mount: Option<PathBuf>,
+    op: Option<Operation>,
+) -> Result<()>
+where
+    D: BlockDevice,
+    W: WritableBlockDevice,
+    F: FnOnce() -> std::io::Result<W>,
+{
+    let mut reader = DeviceReader::new(disk);
+    let drive = ApmDrive::new(&mut reader)?;
+    if let Some(Operation::Partitions) = op {
+        let kind = match drive.map_kind() {
+            MapKind::Apple => "Apple",
+            MapKind::OldStyle => "Old style (TS)",
+        };
+        println!("{} partition map, {}-byte blocks", kind, drive.block_size());
+        show_partitions(drive.partitions());
+        show_drivers(drive.drivers());
+        return Ok(());
+    }
+    // a partition index or name, or else the first one with a file system we know
+    let selected = match partition {
+        Some(sel) => match sel.parse::<usize>() {
+            Ok(i) => drive.partitions().get(i),
+            Err(_) => drive.partitions().iter().find(|p| p.name() == Ok(sel)),
+        },
+        None => drive.partitions().iter().find(|p| {
+            matches!(p.kind(), PartitionType::AppleHfs | PartitionType::AppleMfs)
+        }),
+    };
+    let Some(selected) = selected.cloned() else {
+        bail!("No such partition: {}", partition.unwrap_or("HFS or MFS"));
     };
+    let (offset, len) = drive.partition_bounds(&selected);
+
+    let mut device = PartitionDevice::from_bounds(reader.into_inner(), offset, len)?;
+    let Some(layout) = device::probe_layout(&mut device)? else {
+        bail!("No file system found in partition {:?}", selected.name().unwrap_or("?"));
+    };
+    let open_image = || PartitionDevice::from_bounds(open_image()?, offset, len);
+    run_volume(layout, device, open_image, mount, op)
+}
+
+fn run_hfs<R: Read + Seek>(reader: R, mount: Option<PathBuf>, op: Option<Operation>) -> Result<()> {
+    if let Some(mountpoint) = mount {
+        HfsFuse::new(LazyHfsVolume::new(reader)?)?.mount(&mountpoint)?;
+        return Ok(());
+    }
+    run_op(&mut HfsVolume::new(reader)?, op)
+}
+
+fn run_hfs_plus<R: Read + Seek>(reader: R, mount: Option<PathBuf>, op: Option<Operation>) -> Result<()> {
+    if mount.is_some() {
+        bail!("HFS+ volumes can't be mounted yet");
+    }
+    run_op(&mut HfsPlusVolume::new(reader)?, op)
+}
+
+fn run_mfs<R, W, F>(mut reader: R, open_image: F, mount: Option<PathBuf>, op: Option<Operation>) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+    F: FnOnce() -> std::io::Result<W>,
+{
+    let mut fs = Mfs::new(&mut reader)?;
+    if let Some(mountpoint) = mount {
+        MfsFuse::new(fs, open_image()?).mount(&mountpoint)?;
+        return Ok(());
+    }
+    run_op(&mut fs, op)
+}
 
-    match fmt {
-        Format::Hfs => {
-            let mut fs = HfsVolume::new(file)?;
-            let root = fs.root_dir();
-            match args.op {
-                Operation::Ls { path } => {
-                    let mut dir = &root;
-                    for seg in path.split("/").filter(|s| !s.is_empty()) {
-                        if let Some(d) = dir.subdir(seg) {
-                            dir = d;
-                        } else {
-                            if let Some(file) = dir.file(&seg) {
-                                bail!("Not a directory: {:?}", seg);
-                            } else {
-                                bail!("No such directory: {:?}", seg);
-                            }
-                        }
-                    }
-                    for file in dir.files() {
-                        println!("File '{}'", file.name());
-                    }
-                    for subdir in dir.subdirs() {
-                        println!("Dir '{}'", subdir.name());
-                    }
-                    if dir.files().is_empty() && dir.subdirs().is_empty() {
-                        println!("<empty>");
-                    }
-                },
-                Operation::Get { src, fork, dst } => {
-                    let mut dir = &root;
-                    let (path, filename) = src.rsplit_once("/")
-                        .expect("i don't know how to name this error message");
-
-                    for seg in path.split("/").filter(|s| !s.is_empty()) {
-                        if let Some(d) = dir.subdir(seg) {
-                            dir = d;
-                        } else {
-                            if let Some(file) = dir.file(&seg) {
-                                bail!("Not a directory: {:?}", seg);
-                            } else {
-                                bail!("No such directory: {:?}", seg);
-                            }
-                        }
-                    }
-                    
-                    let Some(file) = dir.file(filename) else {
-                        bail!("No such file: '{:?}'", filename);
-                    };
-
-                    let data = fs.file_data(&file).unwrap();
-                    if data.len() == 0 {
-                        bail!("Refusing to write an empty file");
-                    }
-                    let dst = dst.unwrap_or(filename.into());
-                    std::fs::write(&dst, &data)?;
-                    println!("Written {} to {}", format_size(data.len(), DECIMAL), dst.display());
-                },
+fn run_op<V: Volume>(fs: &mut V, op: Option<Operation>) -> Result<()> {
+    let Some(op) = op else {
+        bail!("No operation given");
+    };
+    match op {
+        Operation::Ls { path } => {
+            let Some(dir) = fs.lookup(&path)? else {
+                bail!("No such directory: {:?}", path);
+            };
+            if !dir.is_dir() {
+                bail!("Not a directory: {:?}", path);
+            }
+            let entries = fs.read_dir(&dir)?;
+            for entry in entries.iter() {
+                match entry.kind() {
+                    EntryKind::File { .. } => println!("File '{}'", entry.name()),
+                    EntryKind::Directory => println!("Dir '{}'", entry.name()),
+                }
+            }
+            if entries.is_empty() {
+                println!("<empty>");
             }
         },
-        Format::Mfs => {
-            let mut fs = Mfs::new(&mut file)?;
-            println!("{:#x?}", fs);
-            match args.op {
-                Operation::Ls { .. } => {
-                    todo!()
-                },
-                Operation::Get { src, fork, dst } => {
-                    let src = src.trim_start_matches("/");
-                    let Some(file) = fs.file_by_name(src) else {
-                        bail!("No such file: {}", src);
-                    };
-                    use macfmt::fs::mfs;
-                    let fork = match fork {
-                        Fork::Resource => mfs::Fork::Resource,
-                        Fork::Data => mfs::Fork::Data,
-                    };
-                    let data = fs.file_contents(file, fork);
-                    if data.len() == 0 {
-                        bail!("Refusing to write an empty file");
-                    }
-                    let dst = dst.unwrap_or(src.into());
-                    std::fs::write(&dst, &data)?;
-                    println!("Written {} to {}", format_size(data.len(), DECIMAL), dst.display());
-                },
+        Operation::Get { src, fork, dst } => {
+            let Some(file) = fs.lookup(&src)?.filter(|entry| !entry.is_dir()) else {
+                bail!("No such file: {}", src);
+            };
+            let fork = match fork {
+                Fork::Resource => fs::Fork::Resource,
+                Fork::Data => fs::Fork::Data,
+            };
+            let data = fs.read_fork(&file, fork)?;
+            if data.is_empty() {
+                bail!("Refusing to write an empty file");
             }
+            let dst = dst.unwrap_or(file.name().into());
+            std::fs::write(&dst, &data)?;
+            println!("Written {} to {}", format_size(data.len(), DECIMAL), dst.display());
         },
-        Format::Autodetect => unreachable!(),
-        _ => todo!("{:?} disk format", fmt),
+        Operation::Partitions => bail!("Not a partitioned disk"),
     }
 
     Ok(())
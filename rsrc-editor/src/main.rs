@@ -1,18 +1,16 @@
-use binrw::BinRead;
 use clap::Parser;
 use eframe::egui;
 use egui::{RichText, Rect};
 use macfmt::i18n::RegionCode;
-use macfmt::macbinary::{MacBinary2, is_macbinary2};
-use macfmt::rsrc::{Resource, ResourceType};
+use macfmt::device::{Detected, detect};
+use macfmt::rsrc::{DecodedFork, ResourceType};
 use macfmt::rsrc::types::{
-    DevelopmentStage, ItemType, KeyboardShortcut, MarkingCharacter, MenuItem, MenuItemConfig,
+    CommandKey, DevelopmentStage, ItemType, KeyboardShortcut, MarkingCharacter, MenuItem, MenuItemConfig,
     SizeFlags, Type,
 };
-use macfmt::single::AppleFile;
 use std::fs::File;
-use std::io::{Cursor, Read, Seek};
-use std::path::PathBuf;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
 use strum::IntoEnumIterator;
 
 mod util;
@@ -21,29 +19,37 @@ use util::icon_editor;
 #[derive(Parser)]
 struct Args {
     file: PathBuf,
+    // where Save writes the edited resource fork to
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+// Files that aren't recognized as anything are read as a bare resource fork.
+fn open(path: &Path) -> Result<DecodedFork, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let rsrc_fork = match detect(file) {
+        Ok(Detected::File { rsrc_fork, .. }) => rsrc_fork,
+        Ok(Detected::Disk { container, layout, .. }) => {
+            return Err(format!("{:?} disk in a {:?} image has no resource fork of its own", layout, container));
+        },
+        Err(err) => {
+            let raw = std::fs::read(path).map_err(|err| err.to_string())?;
+            return DecodedFork::read_lenient(&mut Cursor::new(raw))
+                .map_err(|raw_err| format!("{}, and not a resource fork either: {}", err, raw_err));
+        },
+    };
+    DecodedFork::read_lenient(&mut Cursor::new(rsrc_fork)).map_err(|err| err.to_string())
 }
 
 fn main() -> eframe::Result {
     env_logger::init();
     let args = Args::parse();
-    let mut file = File::open(&args.file).unwrap();
-    let mut magic = [0u8; 128];
-    file.read_exact(&mut magic).unwrap();
-    file.rewind().unwrap();
-
-    let res = if is_macbinary2(&magic) {
-        let tmp = MacBinary2::read(&mut file).unwrap();
-        let mut cursor = Cursor::new(tmp.resource_fork());
-        Resource::read(&mut cursor).unwrap()
-    } else {
-        match magic[0..4] {
-            [0x00, 0x05, 0x16, 0x07] | [0x00, 0x05, 0x16, 0x0] => {
-                let tmp = AppleFile::read(&mut file).unwrap();
-                let mut cursor = Cursor::new(tmp.resource_fork().unwrap());
-                Resource::read(&mut cursor).unwrap()
-            }
-            _ => Resource::read(&mut file).unwrap(),
-        }
+    let fork = match open(&args.file) {
+        Ok(fork) => fork,
+        Err(err) => {
+            eprintln!("Can't open {}: {}", args.file.display(), err);
+            std::process::exit(1);
+        },
     };
 
     let mut fonts = egui::FontDefinitions::default();
@@ -55,7 +61,8 @@ fn main() -> eframe::Result {
 
     fonts.families.insert(egui::FontFamily::Name("Charcoal".into()), vec!["Charcoal".to_owned()]);
 
-    let luts: Vec<(String, Vec<(u16, image::Rgb<u16>)>)> = res
+    let luts: Vec<(String, Vec<(u16, image::Rgb<u16>)>)> = fork
+        .resources()
         .iter()
         .filter_map(|entry| {
             /*if let Type::ColorLut(clut) = entry.data() {
@@ -83,7 +90,9 @@ fn main() -> eframe::Result {
             egui_extras::install_image_loaders(&cc.egui_ctx);
             cc.egui_ctx.set_fonts(fonts);
             Ok(Box::new(MyApp {
-                res,
+                fork,
+                output: args.output,
+                status: None,
                 cur_res: None,
                 cur_ty: None,
                 scene_rect: Rect::ZERO,
@@ -95,7 +104,9 @@ fn main() -> eframe::Result {
 }
 
 struct MyApp {
-    res: Vec<(ResourceType, Vec<Resource>)>,
+    fork: DecodedFork,
+    output: Option<PathBuf>,
+    status: Option<String>,
     cur_ty: Option<(usize, ResourceType)>,
     cur_res: Option<usize>,
     scene_rect: Rect,
@@ -106,7 +117,9 @@ struct MyApp {
 impl Default for MyApp {
     fn default() -> Self {
         Self {
-            res: Vec::new(),
+            fork: DecodedFork::default(),
+            output: None,
+            status: None,
             cur_res: None,
             cur_ty: None,
             scene_rect: Rect::ZERO,
@@ -118,7 +131,22 @@ impl Default for MyApp {
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let fork = &mut self.fork;
         egui::SidePanel::left("Resource list").show(ctx, |ui| {
+            if let Some(output) = &self.output {
+                if ui.button(format!("Save to {}", output.display())).clicked() {
+                    let saved = File::create(output).map_err(|e| e.to_string()).and_then(|mut file| {
+                        fork.write(&mut file).map_err(|e| e.to_string())
+                    });
+                    self.status = Some(match saved {
+                        Ok(()) => "Saved".to_string(),
+                        Err(e) => format!("Couldn't save: {}", e),
+                    });
+                }
+                if let Some(status) = &self.status {
+                    ui.label(status);
+                }
+            }
             let ty_text = if let Some(ty) = &self.cur_ty {
                 format!("{}", ty.1.inner())
             } else {
@@ -129,7 +157,7 @@ impl eframe::App for MyApp {
                 .selected_text(RichText::new(ty_text).monospace())
                 .show_ui(ui, |ui| {
                     let old = self.cur_ty.clone();
-                    for (i, (ty, _)) in self.res.iter().enumerate() {
+                    for (i, (ty, _)) in fork.resources().iter().enumerate() {
                         let text = RichText::new(format!("{}", ty.inner())).monospace();
                         ui.selectable_value(&mut self.cur_ty, Some((i, ty.clone())), text);
                     }
@@ -147,7 +175,7 @@ impl eframe::App for MyApp {
                         ui.label("Name");
                         ui.end_row();
 
-                        for (i, res) in self.res[*ty].1.iter().enumerate() {
+                        for (i, res) in fork.resources()[*ty].1.iter().enumerate() {
                             ui.selectable_value(&mut self.cur_res, Some(i), format!("{}", res.id()));
                             if let Some(name) = res.name().as_ref() {
                                 ui.label(format!("{:?}", name));
@@ -163,7 +191,7 @@ impl eframe::App for MyApp {
                 egui::ScrollArea::vertical()
                     .auto_shrink(false)
                     .show(ui, |ui| {
-                        let res = &mut self.res[ty_idx].1[idx];
+                        let res = &mut fork.resources_mut()[ty_idx].1[idx];
                         egui::ComboBox::from_label("Heap")
                             .selected_text(if res.system_heap {
                                 "System"
@@ -181,10 +209,10 @@ impl eframe::App for MyApp {
                         ui.checkbox(&mut res.compressed, "Compressed");
                         match res.data_mut() {
                             Type::String(s) => {
-                                ui.text_edit_multiline(s.as_mut());
+                                ui.text_edit_multiline(s.text_mut());
                             },
                             Type::KeyboardName(s) => {
-                                ui.text_edit_singleline(s.as_mut());
+                                ui.text_edit_singleline(s.text_mut());
                             },
                             Type::Bundle(bundle) => {
                                 ui.label(format!("Signature: {}", bundle.sig()));
@@ -289,7 +317,7 @@ impl eframe::App for MyApp {
                                             //println!("item {:#?}", item);
                                             match item.data_mut() {
                                                 ItemType::Button { text } => {
-                                                    if text.len() == 0 {
+                                                    if text.is_empty() {
                                                         ui.put(place, egui::Separator::default());
                                                     } else {
                                                         ui.put(
@@ -324,7 +352,7 @@ impl eframe::App for MyApp {
                                                 ItemType::EditableText { text } => {
                                                     ui.put(
                                                         place,
-                                                        egui::TextEdit::singleline(text.as_mut()),
+                                                        egui::TextEdit::singleline(text.text_mut()),
                                                     );
                                                 }
                                                 ItemType::AppDefined { .. } => {
@@ -394,7 +422,7 @@ impl eframe::App for MyApp {
                                                         for v in KeyboardShortcut::iter() {
                                                             ui.selectable_value(
                                                                 &mut keyboard_shortcut,
-                                                                Some(v),
+                                                                Some(CommandKey::Key(v)),
                                                                 format!("{}", v),
                                                             );
                                                         }
@@ -460,7 +488,7 @@ impl eframe::App for MyApp {
                                 }
                             }
                             Type::SystemVersion(ver) => {
-                                ui.text_edit_singleline(ver.as_mut());
+                                ui.text_edit_singleline(ver.text_mut());
                             }
                             Type::Other(data) => {
                                 let mut lines = Vec::new();
@@ -488,7 +516,11 @@ impl eframe::App for MyApp {
                             Type::Version(vers) => {
                                 ui.horizontal(|ui| {
                                     ui.label("Version number:");
-                                    ui.add(egui::DragValue::new(vers.major_mut()).range(0..=99));
+                                    let mut major = vers.major();
+                                    ui.add(egui::DragValue::new(&mut major).range(0..=99));
+                                    if major != vers.major() {
+                                        vers.set_major(major);
+                                    }
                                     ui.label(".");
                                     let mut minor_hi = (vers.minor() & 0xf0) >> 4;
                                     let mut minor_lo = vers.minor() & 0x0f;